@@ -2,15 +2,16 @@ use bon::bon;
 use denim_sam_common::buffers::{InMemoryReceivingBuffer, InMemorySendingBuffer};
 
 use denim_sam_common::denim_message::deniable_message::MessageKind;
-use denim_sam_common::denim_message::{BlockRequest, KeyRequest, SeedUpdate};
+use denim_sam_common::denim_message::{
+    BlockListRequest, BlockRequest, KeyRequest, SeedUpdate, UnblockRequest,
+};
 use denim_sam_common::rng::seed::{KeyIdSeed, KeySeed};
 use libsignal_protocol::{IdentityKeyPair, IdentityKeyStore};
-use log::debug;
+use log::{debug, error, warn};
 use rand::rngs::OsRng;
 use rand::{CryptoRng, Rng};
 use sam_client::encryption::DecryptedEnvelope;
 
-use sam_common::address::DEFAULT_DEVICE_ID;
 use sam_common::{address::AccountId, address::RegistrationId, api::LinkDeviceToken, DeviceId};
 
 use sam_client::logic::{
@@ -26,22 +27,55 @@ use sam_client::{
     net::{api_trait::ApiClientConfig, ApiClient},
     storage::AccountStore,
 };
-use tokio::sync::broadcast::Receiver;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver};
 
 use crate::encryption::encrypt::encrypt;
 use crate::error::DenimClientError;
+use crate::message::buffer::SqliteSendingBuffer;
+use crate::message::cover::{CoverMessage, CoverTraffic, CoverTrafficPolicy};
+use crate::message::dead_letter::{DeadLetter, InMemoryDeadLetterQueue, SqliteDeadLetterQueue};
+use crate::message::error::MessageProcessingError;
+use crate::message::event::{emit, DenimEvent, SendFailure};
+use crate::message::key_request::{
+    KeyRequestFailure, KeyRequestPolicy, KeyRequests, PendingStatus,
+};
 use crate::message::process::{process_deniable_message, DenimResponse};
-use crate::message::queue::InMemoryMessageQueue;
-use crate::message::traits::{MessageQueue, MessageQueueConfig};
-use crate::protocol::SamDenimMessage;
+use crate::message::queue::{InMemoryMessageQueue, SqliteMessageQueue};
+use crate::message::traits::{
+    DeadLetterQueue, DeadLetterQueueConfig, MessageQueue, MessageQueueConfig,
+};
 use crate::protocol::{
     denim_client::{DenimProtocolClient, DenimSamClient},
     DenimProtocolConfig,
 };
+use crate::protocol::{ProtocolEvent, SamDenimMessage};
 use crate::store::inmem::InMemoryDeniableStoreType;
 use crate::store::sqlite::SqliteDeniableStoreType;
 use crate::store::{DeniableStore, DeniableStoreConfig, DeniableStoreType, DenimPreKeySeedStore};
-use tokio::sync::mpsc::Receiver as MpscReceiver;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Receiver as MpscReceiver, Sender as MpscSender};
+
+const EVENT_CAPACITY: usize = 64;
+// cover messages encrypted ahead of time for the task that sends them
+const PREPARED_COVER_MESSAGES: usize = 4;
+const SEED_RESEND_AFTER: Duration = Duration::from_secs(30);
+// the resend interval doubles up to this while the proxy does not acknowledge
+const MAX_SEED_RESEND_AFTER: Duration = Duration::from_secs(30 * 60);
+// senders cannot make the client resynchronize more often than this
+const SEED_RESYNC_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Cover traffic the protocol client sends, `messages` takes the prepared cover messages.
+struct PreparedCover {
+    policy: CoverTrafficPolicy,
+    messages: MpscSender<CoverMessage>,
+}
+
+/// Seed update the proxy has not acknowledged yet, sent again at `resend_at`.
+struct PendingSeedUpdate {
+    update: SeedUpdate,
+    resend_at: Instant,
+    resend_after: Duration,
+}
 
 pub trait DenimClientType {
     type Store: StoreType;
@@ -49,6 +83,7 @@ pub trait DenimClientType {
     type ApiClient: ApiClient;
     type ProtocolClient: DenimSamClient;
     type MessageQueue: MessageQueue;
+    type DeadLetterQueue: DeadLetterQueue;
     type Rng: Rng + CryptoRng + Default;
 }
 
@@ -57,15 +92,25 @@ pub struct DefaultDenimClientType<
     U: ApiClient,
     V: DenimSamClient,
     D: DeniableStoreType,
+    Q: MessageQueue = InMemoryMessageQueue,
+    L: DeadLetterQueue = InMemoryDeadLetterQueue,
 > {
     _store: std::marker::PhantomData<T>,
     _api: std::marker::PhantomData<U>,
     _protocol: std::marker::PhantomData<V>,
     _deniable_store: std::marker::PhantomData<D>,
+    _message_queue: std::marker::PhantomData<Q>,
+    _dead_letter_queue: std::marker::PhantomData<L>,
 }
 
-impl<T: StoreType, U: ApiClient, V: DenimSamClient, D: DeniableStoreType> DenimClientType
-    for DefaultDenimClientType<T, U, V, D>
+impl<
+        T: StoreType,
+        U: ApiClient,
+        V: DenimSamClient,
+        D: DeniableStoreType,
+        Q: MessageQueue,
+        L: DeadLetterQueue,
+    > DenimClientType for DefaultDenimClientType<T, U, V, D, Q, L>
 {
     type Store = T;
 
@@ -75,7 +120,9 @@ impl<T: StoreType, U: ApiClient, V: DenimSamClient, D: DeniableStoreType> DenimC
 
     type ProtocolClient = V;
 
-    type MessageQueue = InMemoryMessageQueue;
+    type MessageQueue = Q;
+
+    type DeadLetterQueue = L;
 
     type Rng = OsRng;
 }
@@ -89,8 +136,10 @@ pub type InMemoryDenimClientType = DefaultDenimClientType<
 pub type SqliteDenimClientType = DefaultDenimClientType<
     SqliteStoreType,
     HttpClient,
-    DenimProtocolClient<InMemorySendingBuffer, InMemoryReceivingBuffer>,
+    DenimProtocolClient<SqliteSendingBuffer, InMemoryReceivingBuffer>,
     SqliteDeniableStoreType,
+    SqliteMessageQueue,
+    SqliteDeadLetterQueue,
 >;
 
 pub struct DenimClient<T: DenimClientType> {
@@ -102,6 +151,14 @@ pub struct DenimClient<T: DenimClientType> {
     protocol_client: T::ProtocolClient,
     envelope_queue: MpscReceiver<SamDenimMessage>,
     waiting_messages: T::MessageQueue,
+    key_requests: KeyRequests,
+    dead_letters: T::DeadLetterQueue,
+    cover_traffic: Option<PreparedCover>,
+    events: broadcast::Sender<DenimEvent>,
+    blocked_users: Vec<AccountId>,
+    pending_seed_update: Option<PendingSeedUpdate>,
+    seed_resend_after: Duration,
+    last_resync: Option<Instant>,
     rng: T::Rng,
 }
 
@@ -114,6 +171,10 @@ impl<T: DenimClientType> DenimClient<T> {
         api_client_config: impl ApiClientConfig<ApiClient = T::ApiClient>,
         protocol_config: impl DenimProtocolConfig<ProtocolClient = T::ProtocolClient>,
         message_queue_config: impl MessageQueueConfig<MessageQueue = T::MessageQueue>,
+        dead_letter_config: impl DeadLetterQueueConfig<DeadLetterQueue = T::DeadLetterQueue>,
+        #[builder(default)] key_request_policy: KeyRequestPolicy,
+        cover_traffic: Option<CoverTrafficPolicy>,
+        #[builder(default = SEED_RESEND_AFTER)] seed_resend_after: Duration,
         device_name: &str,
         id_key_pair: IdentityKeyPair,
         token: LinkDeviceToken,
@@ -151,7 +212,7 @@ impl<T: DenimClientType> DenimClient<T> {
 
         let queue = protocol_client.connect().await?;
 
-        Ok(Self {
+        let mut client = Self {
             account_id,
             device_id,
             store,
@@ -159,9 +220,21 @@ impl<T: DenimClientType> DenimClient<T> {
             api_client,
             protocol_client,
             envelope_queue: queue,
-            waiting_messages: message_queue_config.create().await,
+            waiting_messages: message_queue_config.create().await?,
+            key_requests: KeyRequests::new(key_request_policy),
+            dead_letters: dead_letter_config.create().await?,
+            cover_traffic: None,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            blocked_users: Vec::new(),
+            pending_seed_update: None,
+            seed_resend_after,
+            last_resync: None,
             rng,
-        })
+        };
+        client.forward_events();
+        client.set_cover_traffic(cover_traffic).await;
+
+        Ok(client)
     }
 
     /// Register a new account.
@@ -172,6 +245,10 @@ impl<T: DenimClientType> DenimClient<T> {
         api_client_config: impl ApiClientConfig<ApiClient = T::ApiClient>,
         protocol_config: impl DenimProtocolConfig<ProtocolClient = T::ProtocolClient>,
         message_queue_config: impl MessageQueueConfig<MessageQueue = T::MessageQueue>,
+        dead_letter_config: impl DeadLetterQueueConfig<DeadLetterQueue = T::DeadLetterQueue>,
+        #[builder(default)] key_request_policy: KeyRequestPolicy,
+        cover_traffic: Option<CoverTrafficPolicy>,
+        #[builder(default = SEED_RESEND_AFTER)] seed_resend_after: Duration,
         username: &str,
         device_name: &str,
         #[builder(default = 100)] upload_prekey_count: usize,
@@ -217,11 +294,21 @@ impl<T: DenimClientType> DenimClient<T> {
             api_client,
             rng,
             protocol_client,
-            waiting_messages: message_queue_config.create().await,
+            waiting_messages: message_queue_config.create().await?,
+            key_requests: KeyRequests::new(key_request_policy),
+            dead_letters: dead_letter_config.create().await?,
+            cover_traffic: None,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            blocked_users: Vec::new(),
+            pending_seed_update: None,
+            seed_resend_after,
+            last_resync: None,
             envelope_queue: queue,
         };
 
-        client.update_key_seed().await?;
+        client.forward_events();
+        client.set_cover_traffic(cover_traffic).await;
+        client.rotate_deniable_seed().await?;
 
         Ok(client)
     }
@@ -234,6 +321,10 @@ impl<T: DenimClientType> DenimClient<T> {
         api_client_config: impl ApiClientConfig<ApiClient = T::ApiClient>,
         protocol_config: impl DenimProtocolConfig<ProtocolClient = T::ProtocolClient>,
         message_queue_config: impl MessageQueueConfig<MessageQueue = T::MessageQueue>,
+        dead_letter_config: impl DeadLetterQueueConfig<DeadLetterQueue = T::DeadLetterQueue>,
+        #[builder(default)] key_request_policy: KeyRequestPolicy,
+        cover_traffic: Option<CoverTrafficPolicy>,
+        #[builder(default = SEED_RESEND_AFTER)] seed_resend_after: Duration,
         #[builder(default = <T::Rng as Default>::default())] rng: T::Rng,
     ) -> Result<Self, DenimClientError> {
         let account_id = store.account_store.get_account_id().await?;
@@ -246,8 +337,9 @@ impl<T: DenimClientType> DenimClient<T> {
         )?;
 
         let queue = protocol_client.connect().await?;
+        let pending_seed_update = deniable_store.seed_store.get_pending_update().await?;
 
-        Ok(Self {
+        let mut client = Self {
             account_id,
             device_id,
             store,
@@ -255,9 +347,27 @@ impl<T: DenimClientType> DenimClient<T> {
             api_client: api_client_config.create().await?,
             protocol_client,
             envelope_queue: queue,
-            waiting_messages: message_queue_config.create().await,
+            waiting_messages: message_queue_config.create().await?,
+            key_requests: KeyRequests::new(key_request_policy),
+            dead_letters: dead_letter_config.create().await?,
+            cover_traffic: None,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            blocked_users: Vec::new(),
+            pending_seed_update: None,
+            seed_resend_after,
+            last_resync: None,
             rng,
-        })
+        };
+
+        client.forward_events();
+        client.set_cover_traffic(cover_traffic).await;
+        if let Some(update) = pending_seed_update {
+            // the proxy may not have received it before the client stopped
+            client.send_seed_update(update).await?;
+        }
+        client.drain_waiting_messages().await?;
+
+        Ok(client)
     }
 
     pub fn deniable_store(&self) -> &DeniableStore<T::DeniableStore> {
@@ -396,34 +506,87 @@ impl<T: DenimClientType> DenimClient<T> {
             .contact_store
             .contains_contact(recipient)
             .await?;
-        if contact_not_exists && self.waiting_messages.len(recipient).await == 0 {
-            self.fetch_denim_prekeys(recipient).await;
-
-            self.waiting_messages.enqueue(recipient, msg.into()).await;
-            return Ok(());
-        }
         if contact_not_exists {
-            self.waiting_messages.enqueue(recipient, msg.into()).await;
+            if !self.key_requests.is_outstanding(recipient) {
+                self.fetch_denim_prekeys(recipient).await?;
+            }
+            self.waiting_messages.enqueue(recipient, msg.into()).await?;
             return Ok(());
         }
 
         self.enqueue_deniable(recipient, msg.into()).await
     }
 
+    /// Sends messages left waiting by a previous run to recipients we have keys for,
+    /// and requests keys again for the others.
+    async fn drain_waiting_messages(&mut self) -> Result<(), DenimClientError> {
+        for recipient in self.waiting_messages.account_ids().await? {
+            if !self
+                .deniable_store
+                .contact_store
+                .contains_contact(recipient)
+                .await?
+            {
+                self.fetch_denim_prekeys(recipient).await?;
+                continue;
+            }
+            self.flush_waiting_messages(recipient).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends all messages waiting for `recipient` in order.
+    /// Messages that cannot be encrypted are reported as events.
+    async fn flush_waiting_messages(
+        &mut self,
+        recipient: AccountId,
+    ) -> Result<(), DenimClientError> {
+        // removed only once enqueued, so a crash in between keeps the message
+        while let Some(msg) = self.waiting_messages.peek(recipient).await? {
+            if let Err(error) = self.enqueue_deniable(recipient, msg.clone()).await {
+                warn!("Failed to send waiting message to {recipient} '{error}'");
+                self.emit(DenimEvent::DeniableSendFailed(SendFailure {
+                    account_id: recipient,
+                    message: msg,
+                    error: error.to_string(),
+                }));
+            }
+            self.waiting_messages.remove_first(recipient).await?;
+        }
+        Ok(())
+    }
+
     async fn enqueue_deniable(
         &mut self,
         recipient: AccountId,
         msg: Vec<u8>,
     ) -> Result<(), DenimClientError> {
-        if self.device_id != DEFAULT_DEVICE_ID.into() {
-            debug!("DenIM only supports primary devices");
-            return Err(DenimClientError::NotSupported);
+        // every device of the recipient gets its own copy of the message
+        let device_ids = self
+            .deniable_store
+            .contact_store
+            .get_contact(recipient)
+            .await?
+            .devices;
+        // encrypted for all devices before any is enqueued, so a failure sends no copies
+        let mut messages = Vec::with_capacity(device_ids.len());
+        for device_id in device_ids {
+            messages.push(
+                encrypt(
+                    msg.clone(),
+                    recipient,
+                    device_id,
+                    &mut self.store,
+                    &mut self.deniable_store,
+                )
+                .await?,
+            );
+        }
+        for message in messages {
+            self.protocol_client
+                .enqueue_deniable(MessageKind::DeniableMessage(message))
+                .await?;
         }
-        self.protocol_client
-            .enqueue_deniable(MessageKind::DeniableMessage(
-                encrypt(msg, recipient, &mut self.store, &mut self.deniable_store).await?,
-            ))
-            .await;
         Ok(())
     }
 
@@ -432,6 +595,16 @@ impl<T: DenimClientType> DenimClient<T> {
         &mut self,
         recipient: AccountId,
         msg: impl Into<Vec<u8>>,
+    ) -> Result<(), DenimClientError> {
+        self.send_regular(recipient, msg).await?;
+        self.emit(DenimEvent::MessageDelivered(recipient));
+        Ok(())
+    }
+
+    async fn send_regular(
+        &mut self,
+        recipient: AccountId,
+        msg: impl Into<Vec<u8>>,
     ) -> Result<(), DenimClientError> {
         let client_envelope = prepare_message(
             &mut self.store,
@@ -446,6 +619,67 @@ impl<T: DenimClientType> DenimClient<T> {
         Ok(())
     }
 
+    /// Sends regular cover messages on schedule, see [CoverTrafficPolicy]. `None` turns it off.
+    pub async fn set_cover_traffic(&mut self, policy: Option<CoverTrafficPolicy>) {
+        let Some(policy) = policy else {
+            self.protocol_client.stop_cover_traffic();
+            self.cover_traffic = None;
+            return;
+        };
+        let (messages, prepared) = mpsc::channel(PREPARED_COVER_MESSAGES);
+        let cover = CoverTraffic::new(policy.clone(), Instant::now(), &mut self.rng);
+        self.protocol_client.start_cover_traffic(cover, prepared);
+        self.cover_traffic = Some(PreparedCover { policy, messages });
+        self.prepare_cover_messages().await;
+    }
+
+    /// Encrypts cover messages until the sending task has enough of them.
+    async fn prepare_cover_messages(&mut self) {
+        let Some(cover) = self.cover_traffic.as_ref() else {
+            return;
+        };
+        while cover.messages.capacity() > 0 {
+            let content = cover.policy.content(&mut self.rng);
+            let size = content.len();
+            let envelope = match prepare_message(
+                &mut self.store,
+                &self.api_client,
+                cover.policy.contact,
+                content,
+                &mut self.rng,
+            )
+            .await
+            {
+                Ok(envelope) => envelope,
+                Err(error) => {
+                    warn!("Failed to prepare cover message '{error}'");
+                    return;
+                }
+            };
+            if cover
+                .messages
+                .try_send(CoverMessage { envelope, size })
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+
+    /// The earliest time the client has to wake up to retry key requests
+    /// or resend the seed update.
+    fn next_wakeup(&self) -> Option<Instant> {
+        [
+            self.key_requests.next_retry_at(),
+            self.pending_seed_update
+                .as_ref()
+                .map(|pending| pending.resend_at),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
     /// Returns a broadcast receiver for incoming messages that have been decrypted.
     pub fn regular_subscribe(&self) -> Receiver<DecryptedEnvelope> {
         self.store.message_store.subscribe()
@@ -455,32 +689,134 @@ impl<T: DenimClientType> DenimClient<T> {
         self.deniable_store.message_store.subscribe()
     }
 
+    /// Returns a broadcast receiver for everything that happens to the client,
+    /// including the messages of `regular_subscribe` and `deniable_subscribe`.
+    pub fn subscribe(&self) -> Receiver<DenimEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: DenimEvent) {
+        emit(&self.events, event);
+    }
+
+    /// Forwards messages from the stores and connection changes to the event stream.
+    fn forward_events(&self) {
+        let regular = self.store.message_store.subscribe();
+        forward(regular, self.events.clone(), |envelope| {
+            DenimEvent::MessageReceived {
+                deniable: false,
+                envelope,
+            }
+        });
+        let deniable = self.deniable_store.message_store.subscribe();
+        forward(deniable, self.events.clone(), |envelope| {
+            DenimEvent::MessageReceived {
+                deniable: true,
+                envelope,
+            }
+        });
+        forward(
+            self.protocol_client.subscribe(),
+            self.events.clone(),
+            |event| match event {
+                ProtocolEvent::QUpdated(q) => DenimEvent::QUpdated(q),
+                ProtocolEvent::Connected => DenimEvent::Connected,
+                ProtocolEvent::Disconnected => DenimEvent::Disconnected,
+                ProtocolEvent::Reconnecting(attempt) => DenimEvent::Reconnecting(attempt),
+                ProtocolEvent::Reconnected { restarted_message } => {
+                    DenimEvent::Reconnected { restarted_message }
+                }
+                ProtocolEvent::CoverMessagesExhausted => DenimEvent::CoverMessagesExhausted,
+            },
+        );
+    }
+
+    /// Status of the deniable messages to `recipient` that are waiting for keys,
+    /// `None` if no messages are waiting.
+    pub async fn pending_status(
+        &mut self,
+        recipient: AccountId,
+    ) -> Result<Option<PendingStatus>, DenimClientError> {
+        let waiting_messages = self.waiting_messages.len(recipient).await?;
+        if waiting_messages == 0 {
+            return Ok(None);
+        }
+        let request = self.key_requests.get(recipient);
+        Ok(Some(PendingStatus {
+            waiting_messages,
+            attempts: request.map(|request| request.attempts).unwrap_or_default(),
+            retry_at: request.map(|request| self.key_requests.retry_at(&request)),
+        }))
+    }
+
+    /// Requests keys again for key requests that timed out,
+    /// and drops the waiting messages of those that ran out of attempts.
+    pub async fn retry_key_requests(&mut self) -> Result<(), DenimClientError> {
+        let (retry, failed) = self.key_requests.timed_out(Instant::now());
+        for account_id in retry {
+            debug!("Key request for {account_id} timed out, retrying");
+            self.fetch_denim_prekeys(account_id).await?;
+        }
+        for (account_id, attempts) in failed {
+            self.fail_key_request(account_id, KeyRequestFailure::TimedOut(attempts))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn fail_key_request(
+        &mut self,
+        account_id: AccountId,
+        reason: KeyRequestFailure,
+    ) -> Result<(), DenimClientError> {
+        self.key_requests.finish(account_id);
+        // without keys the waiting messages can never be sent
+        let dropped = self.waiting_messages.clear(account_id).await?;
+        warn!(
+            "Key request for {account_id} failed '{reason:?}', dropped {} waiting messages",
+            dropped.len()
+        );
+        self.emit(DenimEvent::KeyExchangeFailed {
+            account_id,
+            reason,
+            dropped_messages: dropped.len(),
+        });
+        Ok(())
+    }
+
     async fn _process_messages(&mut self, block: bool) -> Result<(), DenimClientError> {
+        self.retry_key_requests().await?;
+        self.resend_seed_update().await?;
+        self.prepare_cover_messages().await;
         if !block && self.envelope_queue.is_empty() {
             return Ok(());
         }
-        while let Some(envelope) = self.envelope_queue.recv().await {
-            let denim_res = match envelope {
-                SamDenimMessage::Denim(den) => {
-                    process_deniable_message(
-                        den,
-                        &mut self.store,
-                        &mut self.deniable_store,
-                        &mut self.rng,
-                    )
-                    .await?
+        loop {
+            let wakeup = self.next_wakeup();
+            let refill = self
+                .cover_traffic
+                .as_ref()
+                .is_some_and(|cover| cover.messages.capacity() == 0);
+            let envelope = tokio::select! {
+                envelope = self.envelope_queue.recv() => envelope,
+                // wake up in time to retry outstanding key requests and resend the seed update
+                () = tokio::time::sleep_until(wakeup.unwrap_or_else(Instant::now).into()),
+                    if wakeup.is_some() =>
+                {
+                    self.retry_key_requests().await?;
+                    self.resend_seed_update().await?;
+                    continue;
                 }
-                SamDenimMessage::Sam(env) => {
-                    process_message(env, &mut self.store, &mut self.rng).await?;
-                    None
+                // an idle client refills the cover messages as the sending task takes them
+                () = cover_message_taken(self.cover_traffic.as_ref()), if refill => {
+                    self.prepare_cover_messages().await;
+                    continue;
                 }
             };
-            if let Some(DenimResponse::KeyResponse(account_id)) = denim_res {
-                let message = self.waiting_messages.dequeue(account_id).await;
-                if let Some(bytes) = message {
-                    self.enqueue_deniable(account_id, bytes).await?;
-                }
-            }
+            let Some(envelope) = envelope else {
+                break;
+            };
+            self.process_or_dead_letter(envelope).await;
             if self.envelope_queue.is_empty() {
                 break;
             }
@@ -488,6 +824,173 @@ impl<T: DenimClientType> DenimClient<T> {
         Ok(())
     }
 
+    /// Processes an envelope, keeping it as a dead letter if that fails
+    /// so the envelopes behind it are still processed.
+    async fn process_or_dead_letter(&mut self, envelope: SamDenimMessage) {
+        let Err(error) = self.process_envelope(envelope.clone()).await else {
+            return;
+        };
+        warn!("Failed to process envelope '{error}', keeping it as a dead letter");
+        match self.dead_letters.push(envelope, error.to_string()).await {
+            Ok(letter) => self.emit(DenimEvent::DeadLetter(letter)),
+            Err(e) => error!("Failed to store dead letter '{e}'"),
+        }
+        match self.dead_letters.drop_overflow().await {
+            Ok(dropped) => {
+                for letter in dropped {
+                    warn!("Dropping dead letter {} as the queue is full", letter.id);
+                    self.emit(DenimEvent::DeadLetterDropped(letter));
+                }
+            }
+            Err(e) => error!("Failed to drop overflowing dead letters '{e}'"),
+        }
+    }
+
+    /// Envelopes that failed processing, oldest first.
+    pub async fn dead_letters(&mut self) -> Result<Vec<DeadLetter>, DenimClientError> {
+        Ok(self.dead_letters.letters().await?)
+    }
+
+    /// Forgets an envelope that failed processing.
+    pub async fn discard_dead_letter(
+        &mut self,
+        id: u64,
+    ) -> Result<Option<DeadLetter>, DenimClientError> {
+        Ok(self.dead_letters.remove(id).await?)
+    }
+
+    /// Processes all dead letters again, e.g. after a session reset.
+    /// Letters that fail again are kept and reported again.
+    /// Returns the number of letters that were processed.
+    pub async fn retry_dead_letters(&mut self) -> Result<usize, DenimClientError> {
+        let mut processed = 0;
+        for letter in self.dead_letters.letters().await? {
+            match self.process_envelope(letter.envelope).await {
+                Ok(()) => {
+                    self.dead_letters.remove(letter.id).await?;
+                    processed += 1;
+                }
+                Err(error) => {
+                    if let Some(letter) = self
+                        .dead_letters
+                        .fail_retry(letter.id, error.to_string())
+                        .await?
+                    {
+                        self.emit(DenimEvent::DeadLetter(letter));
+                    }
+                }
+            }
+        }
+        Ok(processed)
+    }
+
+    async fn process_envelope(
+        &mut self,
+        envelope: SamDenimMessage,
+    ) -> Result<(), DenimClientError> {
+        let denim_res = match envelope {
+            SamDenimMessage::Denim(den) => match process_deniable_message(
+                den,
+                &mut self.store,
+                &mut self.deniable_store,
+                &mut self.rng,
+            )
+            .await
+            {
+                Err(MessageProcessingError::SeedDiverged(epoch)) => {
+                    self.resync_seed(epoch).await?;
+                    // kept as a dead letter, it may be decryptable once the sender has new keys
+                    Err(MessageProcessingError::SeedDiverged(epoch))?
+                }
+                res => res?,
+            },
+            SamDenimMessage::Sam(env) => {
+                process_message(env, &mut self.store, &mut self.rng).await?;
+                None
+            }
+        };
+        match denim_res {
+            Some(DenimResponse::KeyResponse(account_id)) => {
+                self.key_requests.finish(account_id);
+                self.flush_waiting_messages(account_id).await?;
+                self.emit(DenimEvent::KeyExchangeCompleted(account_id));
+            }
+            Some(DenimResponse::BlockListResponse(blocked_users)) => {
+                self.blocked_users = blocked_users;
+            }
+            Some(DenimResponse::KeyRequestFailed(account_id, error)) => {
+                self.fail_key_request(account_id, KeyRequestFailure::Rejected(error))
+                    .await?;
+            }
+            Some(DenimResponse::SeedUpdateAck(epoch)) => {
+                if self
+                    .pending_seed_update
+                    .as_ref()
+                    .is_some_and(|pending| pending.update.epoch == epoch)
+                {
+                    debug!("Proxy acknowledged seed epoch {epoch}");
+                    self.deniable_store
+                        .seed_store
+                        .set_pending_update(None)
+                        .await?;
+                    self.pending_seed_update = None;
+                }
+            }
+            Some(DenimResponse::SeedUpdateRejected(epoch, current_epoch)) => {
+                if self
+                    .pending_seed_update
+                    .as_ref()
+                    .is_some_and(|pending| pending.update.epoch == epoch)
+                {
+                    // the keys of the epochs in between cannot be derived by this client
+                    warn!("Proxy rejected seed epoch {epoch} as it uses {current_epoch}, resynchronizing");
+                    self.send_new_seed_past(current_epoch, true).await?;
+                }
+            }
+            Some(DenimResponse::SeedRequested) => match self.pending_seed_update.take() {
+                Some(pending) => self.send_seed_update(pending.update).await?,
+                None => {
+                    warn!("Proxy has no deniable seed, resynchronizing");
+                    self.send_new_seed(true).await?;
+                }
+            },
+            Some(DenimResponse::ProxyError(error)) => {
+                warn!("Proxy reported an error '{error}'");
+                self.emit(DenimEvent::ProxyError(error));
+            }
+            Some(DenimResponse::KeysReset(account_id, device_id)) => {
+                debug!("Keys of {account_id}.{device_id} were reset");
+                self.fetch_denim_prekeys(account_id).await?;
+            }
+            None => (),
+        }
+        Ok(())
+    }
+
+    /// Returns whether the proxy has acknowledged the current deniable seed,
+    /// so other users can send deniable messages to this client.
+    pub fn is_deniable_ready(&self) -> bool {
+        self.pending_seed_update.is_none()
+    }
+
+    /// Recieve and decrypt messages until the proxy has acknowledged the current deniable seed.
+    /// The seed update is sent again whenever it has not been acknowledged
+    /// within `seed_resend_after`.
+    pub async fn await_deniable_ready(&mut self) -> Result<(), DenimClientError> {
+        while let Some(resend_at) = self
+            .pending_seed_update
+            .as_ref()
+            .map(|pending| pending.resend_at)
+        {
+            match tokio::time::timeout_at(resend_at.into(), self.envelope_queue.recv()).await {
+                Ok(Some(envelope)) => self.process_or_dead_letter(envelope).await,
+                Ok(None) => return Err(DenimClientError::Disconnected),
+                Err(_) => self.resend_seed_update().await?,
+            }
+        }
+        Ok(())
+    }
+
     /// Recieve and decrypt messages. Block until at least one message is received.
     pub async fn process_messages_blocking(&mut self) -> Result<(), DenimClientError> {
         self._process_messages(true).await
@@ -529,51 +1032,652 @@ impl<T: DenimClientType> DenimClient<T> {
             .await?)
     }
 
-    async fn fetch_denim_prekeys(&mut self, account_id: AccountId) {
+    async fn fetch_denim_prekeys(&mut self, account_id: AccountId) -> Result<(), DenimClientError> {
         debug!("Fetching denim prekeys for {account_id}");
         self.protocol_client
             .enqueue_deniable(MessageKind::KeyRequest(
                 KeyRequest::builder()
                     .account_id(account_id.into())
-                    .specific_device_ids(vec![1])
+                    .specific_device_ids(vec![])
                     .build(),
             ))
-            .await
+            .await?;
+        self.key_requests.sent(account_id, Instant::now());
+        Ok(())
     }
 
-    pub async fn block_user(&mut self, account_id: AccountId) {
+    pub async fn block_user(&mut self, account_id: AccountId) -> Result<(), DenimClientError> {
         self.protocol_client
             .enqueue_deniable(MessageKind::BlockRequest(
                 BlockRequest::builder()
                     .account_id(account_id.into())
                     .build(),
             ))
-            .await;
+            .await?;
+        if !self.blocked_users.contains(&account_id) {
+            self.blocked_users.push(account_id);
+        }
+        Ok(())
+    }
+
+    pub async fn unblock_user(&mut self, account_id: AccountId) -> Result<(), DenimClientError> {
+        self.protocol_client
+            .enqueue_deniable(MessageKind::UnblockRequest(
+                UnblockRequest::builder()
+                    .account_id(account_id.into())
+                    .build(),
+            ))
+            .await?;
+        self.blocked_users.retain(|blocked| *blocked != account_id);
+        Ok(())
+    }
+
+    /// Ask the proxy for the accounts this account has blocked.
+    /// The answer is available through `blocked_users` once it has been processed.
+    pub async fn fetch_blocked_users(&mut self) -> Result<(), DenimClientError> {
+        Ok(self
+            .protocol_client
+            .enqueue_deniable(MessageKind::BlockListRequest(
+                BlockListRequest::builder().build(),
+            ))
+            .await?)
+    }
+
+    /// Accounts blocked by this account, as last reported by the proxy.
+    pub fn blocked_users(&self) -> &[AccountId] {
+        &self.blocked_users
+    }
+
+    /// Replace the deniable pre key seeds with fresh ones and send them to the proxy.
+    /// PreKey messages using keys of the previous seeds can still be decrypted
+    /// for a grace period. See `await_deniable_ready` to wait for the proxy to use them.
+    pub async fn rotate_deniable_seed(&mut self) -> Result<(), DenimClientError> {
+        self.send_new_seed(false).await
     }
 
-    async fn update_key_seed(&mut self) -> Result<(), DenimClientError> {
+    fn is_resyncing(&self) -> bool {
+        self.pending_seed_update
+            .as_ref()
+            .is_some_and(|pending| pending.update.resync())
+    }
+
+    /// Resynchronizes the seeds after the proxy derived a key in `epoch` that the client cannot.
+    async fn resync_seed(&mut self, epoch: u32) -> Result<(), DenimClientError> {
+        let current = self.deniable_store.seed_store.get_epoch().await?;
+        // messages of retired epochs cannot be recovered by a resync
+        if epoch < current || self.is_resyncing() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if self
+            .last_resync
+            .is_some_and(|last_resync| now < last_resync + SEED_RESYNC_INTERVAL)
+        {
+            warn!("Deniable seed diverged from the proxy, but it was resynchronized recently");
+            return Ok(());
+        }
+        warn!("Deniable seed diverged from the proxy, resynchronizing");
+        self.last_resync = Some(now);
+        self.send_new_seed(true).await
+    }
+
+    /// With `resync` the proxy discards every key of earlier seeds,
+    /// and tells their senders to request new keys.
+    async fn send_new_seed(&mut self, resync: bool) -> Result<(), DenimClientError> {
+        self.send_new_seed_past(0, resync).await
+    }
+
+    /// Like `send_new_seed`, with an epoch newer than `epoch`.
+    async fn send_new_seed_past(
+        &mut self,
+        epoch: u32,
+        resync: bool,
+    ) -> Result<(), DenimClientError> {
         let id_seed = KeyIdSeed::random(&mut self.rng);
         let key_seed = KeySeed::random(&mut self.rng);
 
-        self.deniable_store
+        let epoch = self
+            .deniable_store
             .seed_store
-            .set_key_seed(key_seed.clone().into())
+            .rotate_seeds_past(epoch, key_seed.clone().into(), id_seed.clone().into())
             .await?;
 
+        let update = SeedUpdate::builder()
+            .pre_key_id_seed(id_seed.into())
+            .pre_key_seed(key_seed.into())
+            .epoch(epoch)
+            .resync(resync)
+            .build();
+        // kept until acknowledged, so a restarted client can send it again
         self.deniable_store
             .seed_store
-            .set_key_id_seed(id_seed.clone().into())
+            .set_pending_update(Some(update.clone()))
             .await?;
+        self.send_seed_update(update).await
+    }
 
-        self.protocol_client
-            .enqueue_deniable(MessageKind::SeedUpdate(
-                SeedUpdate::builder()
-                    .pre_key_id_seed(id_seed.into())
-                    .pre_key_seed(key_seed.into())
-                    .build(),
-            ))
-            .await;
+    async fn send_seed_update(&mut self, update: SeedUpdate) -> Result<(), DenimClientError> {
+        self.enqueue_seed_update(update, self.seed_resend_after)
+            .await
+    }
 
+    /// Enqueues `update` in place of an unsent earlier one, so a slow queue holds one copy.
+    /// The update stays pending if that fails, so it is resent like an unacknowledged one.
+    async fn enqueue_seed_update(
+        &mut self,
+        update: SeedUpdate,
+        resend_after: Duration,
+    ) -> Result<(), DenimClientError> {
+        self.pending_seed_update = Some(PendingSeedUpdate {
+            update: update.clone(),
+            resend_at: Instant::now() + resend_after,
+            resend_after,
+        });
+        self.protocol_client
+            .replace_deniable(MessageKind::SeedUpdate(update))
+            .await?;
         Ok(())
     }
+
+    /// Sends the unacknowledged seed update again once its deadline has passed,
+    /// backing off while the proxy does not acknowledge it.
+    async fn resend_seed_update(&mut self) -> Result<(), DenimClientError> {
+        let Some(pending) = self
+            .pending_seed_update
+            .take_if(|pending| pending.resend_at <= Instant::now())
+        else {
+            return Ok(());
+        };
+        let resend_after =
+            (pending.resend_after * 2).min(MAX_SEED_RESEND_AFTER.max(self.seed_resend_after));
+        warn!(
+            "Seed epoch {} was not acknowledged, resending",
+            pending.update.epoch
+        );
+        self.enqueue_seed_update(pending.update, resend_after).await
+    }
+}
+
+/// Resolves once the sending task has taken a prepared cover message.
+async fn cover_message_taken(cover: Option<&PreparedCover>) {
+    if let Some(cover) = cover {
+        if cover.messages.reserve().await.is_ok() {
+            return;
+        }
+    }
+    // nothing is taken without cover traffic or once the task stopped
+    std::future::pending().await
+}
+
+/// Forwards a broadcast stream into the event stream until the stream closes.
+fn forward<E: Clone + Send + 'static>(
+    mut receiver: Receiver<E>,
+    events: broadcast::Sender<DenimEvent>,
+    into_event: impl Fn(E) -> DenimEvent + Send + 'static,
+) {
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => emit(&events, into_event(event)),
+                Err(RecvError::Lagged(skipped)) => warn!("Event stream skipped {skipped} events"),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::{mem::discriminant, time::Duration};
+
+    use denim_sam_common::{
+        denim_message::{
+            deniable_message::MessageKind, BlockListResponse, DeniableMessage, Error, KeyBundle,
+            KeyResponse, SeedUpdateRejected,
+        },
+        DenimBufferError,
+    };
+    use libsignal_protocol::{IdentityKeyPair, IdentityKeyStore, PreKeyStore};
+    use rand::rngs::OsRng;
+    use sam_client::{
+        net::{api_trait::ApiClientConfig, protocol::MessageStatus, HttpClient, HttpClientConfig},
+        storage::{
+            key_generation::SignedPreKeyGenerator, InMemoryStoreConfig, InMemoryStoreType,
+            StoreConfig,
+        },
+    };
+    use sam_common::{
+        address::{AccountId, RegistrationId},
+        api::{EcPreKey, Encode, SignedEcPreKey},
+        sam_message::ClientEnvelope,
+    };
+    use sam_security::key_gen::generate_ec_pre_key;
+    use tokio::sync::{
+        broadcast,
+        mpsc::{self, Receiver, Sender},
+    };
+
+    use crate::{
+        encryption::decrypt,
+        error::{DenimClientError, DenimProtocolError},
+        message::{
+            cover::{CoverMessage, CoverTraffic},
+            dead_letter::InMemoryDeadLetterQueue,
+            error::MessageError,
+            event::DenimEvent,
+            key_request::KeyRequests,
+            queue::InMemoryMessageQueue,
+        },
+        protocol::{denim_client::DenimSamClient, ProtocolEvent, SamDenimMessage},
+        store::{
+            inmem::InMemoryDeniableStoreType, DeniableStoreConfig, DenimPreKeySeedStore,
+            InMemoryDeniableStoreConfig,
+        },
+    };
+
+    use super::{DefaultDenimClientType, DenimClient, SEED_RESEND_AFTER};
+
+    /// Protocol client without a proxy, the test feeds envelopes and connection events
+    /// to the client directly.
+    struct TestProtocolClient {
+        events: broadcast::Sender<ProtocolEvent>,
+        enqueued: Vec<MessageKind>,
+        // enqueueing fails like a sending buffer that cannot store messages
+        failing: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl DenimSamClient for TestProtocolClient {
+        async fn connect(&mut self) -> Result<Receiver<SamDenimMessage>, DenimProtocolError> {
+            Err(DenimProtocolError::FailedToReceiveQStatus)
+        }
+        async fn disconnect(&mut self) -> Result<(), DenimProtocolError> {
+            Ok(())
+        }
+        async fn is_connected(&self) -> bool {
+            true
+        }
+        fn subscribe(&self) -> broadcast::Receiver<ProtocolEvent> {
+            self.events.subscribe()
+        }
+        async fn enqueue_deniable(
+            &mut self,
+            message: MessageKind,
+        ) -> Result<(), DenimProtocolError> {
+            if self.failing {
+                Err(MessageError::from(DenimBufferError::Storage(
+                    "database is gone".to_owned(),
+                )))?;
+            }
+            self.enqueued.push(message);
+            Ok(())
+        }
+        async fn replace_deniable(
+            &mut self,
+            message: MessageKind,
+        ) -> Result<(), DenimProtocolError> {
+            let kind = discriminant(&message);
+            match self
+                .enqueued
+                .iter_mut()
+                .find(|queued| discriminant(*queued) == kind)
+            {
+                Some(queued) => *queued = message,
+                None => self.enqueued.push(message),
+            }
+            Ok(())
+        }
+        async fn send_message(
+            &self,
+            _message: ClientEnvelope,
+        ) -> Result<MessageStatus, DenimProtocolError> {
+            Err(DenimProtocolError::ResponseTimeout)
+        }
+        fn start_cover_traffic(&mut self, _cover: CoverTraffic, _messages: Receiver<CoverMessage>) {
+        }
+        fn stop_cover_traffic(&mut self) {}
+    }
+
+    type TestClientType = DefaultDenimClientType<
+        InMemoryStoreType,
+        HttpClient,
+        TestProtocolClient,
+        InMemoryDeniableStoreType,
+        InMemoryMessageQueue,
+        InMemoryDeadLetterQueue,
+    >;
+
+    async fn test_client() -> (DenimClient<TestClientType>, Sender<SamDenimMessage>) {
+        let (envelopes, envelope_queue) = mpsc::channel(10);
+        let client = DenimClient {
+            account_id: AccountId::generate(),
+            device_id: 1.into(),
+            store: InMemoryStoreConfig::default()
+                .create_store(
+                    IdentityKeyPair::generate(&mut OsRng),
+                    RegistrationId::generate(&mut OsRng),
+                )
+                .await
+                .expect("Can create store"),
+            deniable_store: InMemoryDeniableStoreConfig::default()
+                .create_store()
+                .await
+                .expect("Can create deniable store"),
+            api_client: HttpClientConfig::new("127.0.0.1:0".to_owned())
+                .create()
+                .await
+                .expect("Can create api client"),
+            protocol_client: TestProtocolClient {
+                events: broadcast::channel(16).0,
+                enqueued: Vec::new(),
+                failing: false,
+            },
+            envelope_queue,
+            waiting_messages: InMemoryMessageQueue::default(),
+            key_requests: KeyRequests::new(Default::default()),
+            dead_letters: InMemoryDeadLetterQueue::default(),
+            cover_traffic: None,
+            events: broadcast::channel(16).0,
+            blocked_users: Vec::new(),
+            pending_seed_update: None,
+            seed_resend_after: SEED_RESEND_AFTER,
+            last_resync: None,
+            rng: OsRng,
+        };
+        (client, envelopes)
+    }
+
+    #[tokio::test]
+    async fn failing_envelope_does_not_block_the_next_one() {
+        let (mut client, envelopes) = test_client().await;
+        let mut events = client.subscribe();
+        let blocked = AccountId::generate();
+
+        // a deniable message without a kind cannot be processed
+        envelopes
+            .send(SamDenimMessage::Denim(DeniableMessage {
+                message_id: 0,
+                message_kind: None,
+            }))
+            .await
+            .expect("Can send envelope");
+        envelopes
+            .send(SamDenimMessage::Denim(DeniableMessage {
+                message_id: 1,
+                message_kind: Some(MessageKind::BlockListResponse(BlockListResponse {
+                    account_ids: vec![blocked.into()],
+                })),
+            }))
+            .await
+            .expect("Can send envelope");
+
+        tokio::time::timeout(Duration::from_secs(5), client.process_messages())
+            .await
+            .expect("Processing does not hang")
+            .expect("Can process messages");
+
+        assert_eq!(client.blocked_users(), [blocked]);
+        let letters = client.dead_letters().await.expect("Can get dead letters");
+        assert_eq!(letters.len(), 1);
+        assert!(matches!(
+            &letters[0].envelope,
+            SamDenimMessage::Denim(DeniableMessage { message_id: 0, .. })
+        ));
+        assert!(matches!(
+            events.try_recv(),
+            Ok(DenimEvent::DeadLetter(letter)) if letter.id == letters[0].id
+        ));
+    }
+
+    #[tokio::test]
+    async fn events_are_emitted() {
+        let (mut client, envelopes) = test_client().await;
+        client.forward_events();
+        let mut events = client.subscribe();
+
+        envelopes
+            .send(SamDenimMessage::Denim(DeniableMessage {
+                message_id: 0,
+                message_kind: Some(MessageKind::Error(Error {
+                    error: "proxy failed".to_owned(),
+                    account_id: None,
+                })),
+            }))
+            .await
+            .expect("Can send envelope");
+        client
+            .process_messages()
+            .await
+            .expect("Can process messages");
+        assert!(matches!(
+            events.try_recv(),
+            Ok(DenimEvent::ProxyError(error)) if error == "proxy failed"
+        ));
+
+        // connection changes of the protocol client are forwarded
+        let protocol_events = client.protocol_client.events.clone();
+        protocol_events
+            .send(ProtocolEvent::QUpdated(0.5))
+            .expect("Client is subscribed");
+        protocol_events
+            .send(ProtocolEvent::Reconnected {
+                restarted_message: Some(3),
+            })
+            .expect("Client is subscribed");
+        let forwarded = tokio::time::timeout(Duration::from_secs(5), async {
+            [
+                events.recv().await.expect("Can receive event"),
+                events.recv().await.expect("Can receive event"),
+            ]
+        })
+        .await
+        .expect("Events are forwarded");
+        assert!(
+            matches!(
+                forwarded,
+                [
+                    DenimEvent::QUpdated(q),
+                    DenimEvent::Reconnected {
+                        restarted_message: Some(3)
+                    }
+                ] if q == 0.5
+            ),
+            "unexpected events {forwarded:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn waiting_messages_are_flushed_in_order() {
+        let (mut alice, alice_envelopes) = test_client().await;
+        let (mut bob, _bob_envelopes) = test_client().await;
+
+        let messages = ["first secret", "second secret", "third secret"];
+        for message in messages {
+            alice
+                .enqueue_message(bob.account_id(), message)
+                .await
+                .expect("Can enqueue message");
+        }
+        assert!(matches!(
+            alice.protocol_client.enqueued.as_slice(),
+            [MessageKind::KeyRequest(_)]
+        ));
+
+        // the proxy answers with a bundle of a pre key bob knows
+        let identity = bob
+            .store
+            .identity_key_store
+            .get_identity_key_pair()
+            .await
+            .expect("Can get identity");
+        let pre_key = generate_ec_pre_key(1.into(), &mut OsRng).await;
+        bob.deniable_store
+            .pre_key_store
+            .save_pre_key(1.into(), &pre_key)
+            .await
+            .expect("Can save pre key");
+        let signed_pre_key = bob
+            .store
+            .signed_pre_key_store
+            .generate_key(&mut OsRng, identity.private_key())
+            .await
+            .expect("Can generate signed pre key");
+        let key_bundle = KeyBundle {
+            device_id: 1,
+            registration_id: bob
+                .store
+                .identity_key_store
+                .get_local_registration_id()
+                .await
+                .expect("Can get registration id"),
+            pre_key: EcPreKey::from(pre_key.clone())
+                .encode()
+                .expect("Can encode pre key"),
+            signed_pre_key: SignedEcPreKey::from(signed_pre_key)
+                .encode()
+                .expect("Can encode signed pre key"),
+        };
+        alice_envelopes
+            .send(SamDenimMessage::Denim(DeniableMessage {
+                message_id: 0,
+                message_kind: Some(MessageKind::KeyResponse(KeyResponse {
+                    account_id: bob.account_id().into(),
+                    identity_key: identity.identity_key().serialize().to_vec(),
+                    key_bundles: vec![key_bundle],
+                })),
+            }))
+            .await
+            .expect("Can send envelope");
+        let mut events = alice.subscribe();
+        alice
+            .process_messages()
+            .await
+            .expect("Can process messages");
+
+        let mut received = Vec::new();
+        for message in alice.protocol_client.enqueued.split_off(1) {
+            let mut message = match message {
+                MessageKind::DeniableMessage(message) => message,
+                message => panic!("Expected a deniable message, got {message:?}"),
+            };
+            // the proxy replaces the recipient with the sender
+            message.account_id = alice.account_id().into();
+            // the pre key is consumed by the first message, the others are sent with it too
+            bob.deniable_store
+                .pre_key_store
+                .save_pre_key(1.into(), &pre_key)
+                .await
+                .expect("Can save pre key");
+            let envelope = decrypt(message, &mut bob.store, &mut bob.deniable_store, &mut OsRng)
+                .await
+                .expect("Bob can decrypt message");
+            received.push(String::from_utf8_lossy(envelope.content_bytes()).to_string());
+        }
+        assert_eq!(received, messages);
+        assert!(alice
+            .pending_status(bob.account_id())
+            .await
+            .expect("Can get pending status")
+            .is_none());
+        assert!(matches!(
+            events.try_recv(),
+            Ok(DenimEvent::KeyExchangeCompleted(account_id)) if account_id == bob.account_id()
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejected_seed_update_resyncs_past_the_proxy_epoch() {
+        let (mut client, envelopes) = test_client().await;
+        client
+            .rotate_deniable_seed()
+            .await
+            .expect("Can rotate seed");
+
+        envelopes
+            .send(SamDenimMessage::Denim(DeniableMessage {
+                message_id: 0,
+                message_kind: Some(MessageKind::SeedUpdateRejected(SeedUpdateRejected {
+                    epoch: 1,
+                    current_epoch: 5,
+                })),
+            }))
+            .await
+            .expect("Can send envelope");
+        client
+            .process_messages()
+            .await
+            .expect("Can process messages");
+
+        assert!(!client.is_deniable_ready());
+        assert!(matches!(
+            client.protocol_client.enqueued.last(),
+            Some(MessageKind::SeedUpdate(update)) if update.epoch == 6 && update.resync()
+        ));
+        assert_eq!(
+            client
+                .deniable_store
+                .seed_store
+                .get_epoch()
+                .await
+                .expect("Can get epoch"),
+            6
+        );
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_seed_update_is_queued_once() {
+        let (mut client, _envelopes) = test_client().await;
+        client.seed_resend_after = Duration::from_millis(10);
+        client
+            .rotate_deniable_seed()
+            .await
+            .expect("Can rotate seed");
+
+        let mut resend_after = Vec::new();
+        for _ in 0..4 {
+            let resend_at = client
+                .pending_seed_update
+                .as_ref()
+                .expect("Seed update is pending")
+                .resend_at;
+            tokio::time::sleep_until(resend_at.into()).await;
+            client
+                .resend_seed_update()
+                .await
+                .expect("Can resend seed update");
+            resend_after.push(
+                client
+                    .pending_seed_update
+                    .as_ref()
+                    .expect("Seed update is pending")
+                    .resend_after,
+            );
+        }
+
+        let queued = client
+            .protocol_client
+            .enqueued
+            .iter()
+            .filter(|message| matches!(message, MessageKind::SeedUpdate(_)))
+            .count();
+        assert_eq!(queued, 1);
+        assert_eq!(resend_after, [20, 40, 80, 160].map(Duration::from_millis));
+    }
+
+    #[tokio::test]
+    async fn failed_enqueue_is_returned() {
+        let (mut client, _envelopes) = test_client().await;
+        client.protocol_client.failing = true;
+        let blocked = AccountId::generate();
+
+        assert!(matches!(
+            client.block_user(blocked).await,
+            Err(DenimClientError::Protocol(
+                DenimProtocolError::MessageError(MessageError::DenimBufferError(
+                    DenimBufferError::Storage(_)
+                ))
+            ))
+        ));
+        assert!(client.blocked_users().is_empty());
+    }
 }
@@ -1,7 +1,9 @@
 use std::time::SystemTime;
 
 use denim_sam_common::denim_message::{MessageType, UserMessage};
-use libsignal_protocol::{message_decrypt, message_encrypt, CiphertextMessage, ProtocolAddress};
+use libsignal_protocol::{
+    message_decrypt, message_encrypt, CiphertextMessage, PreKeyStore, ProtocolAddress,
+};
 
 use log::debug;
 use rand::{CryptoRng, Rng};
@@ -9,7 +11,7 @@ use sam_client::{
     encryption::DecryptedEnvelope,
     storage::{Store, StoreType},
 };
-use sam_common::{time_now_millis, AccountId};
+use sam_common::{address::DEFAULT_DEVICE_ID, time_now_millis, AccountId, DeviceId};
 
 use crate::store::{DeniableStore, DeniableStoreType};
 
@@ -18,10 +20,11 @@ use super::{error::EncryptionError, key::generate_key};
 pub async fn encrypt(
     message: Vec<u8>,
     recipient: AccountId,
+    device_id: DeviceId,
     store: &mut Store<impl StoreType>,
     deniable_store: &mut DeniableStore<impl DeniableStoreType>,
 ) -> Result<UserMessage, EncryptionError> {
-    let addr = ProtocolAddress::new(recipient.to_string(), 1.into());
+    let addr = ProtocolAddress::new(recipient.to_string(), (*device_id).into());
 
     let cipher = message_encrypt(
         &message,
@@ -35,6 +38,7 @@ pub async fn encrypt(
         .account_id(recipient.into())
         .message_type(MessageType::from(cipher.message_type()).into())
         .content(cipher.serialize().into())
+        .device_id(*device_id)
         .build())
 }
 
@@ -50,19 +54,33 @@ pub async fn decrypt<R: Rng + CryptoRng>(
         .inspect_err(|e| debug!("{e}"))
         .map_err(|_| EncryptionError::InvalidAccountId)?;
 
-    let addr = ProtocolAddress::new(source.to_string(), 1.into());
+    // messages without a device id come from the primary device
+    let source_device_id = message.device_id.unwrap_or(DEFAULT_DEVICE_ID);
+
+    let addr = ProtocolAddress::new(source.to_string(), source_device_id.into());
 
+    let mut derived = false;
     // A deniable message must contain a PreKey.
     if let CiphertextMessage::PreKeySignalMessage(ref prekey_message) = cipher {
         let key_id = prekey_message
             .pre_key_id()
             .ok_or(EncryptionError::NoPreKeyInMessage)?;
-        generate_key(
-            message.rng_counter.ok_or(EncryptionError::NoRngCounter)?,
-            key_id,
-            deniable_store,
-        )
-        .await?;
+        // the key is derived on demand, unless an earlier message already did
+        if deniable_store
+            .pre_key_store
+            .get_pre_key(key_id)
+            .await
+            .is_err()
+        {
+            generate_key(
+                message.seed_epoch.ok_or(EncryptionError::NoSeedEpoch)?,
+                message.rng_counter.ok_or(EncryptionError::NoRngCounter)?,
+                key_id,
+                deniable_store,
+            )
+            .await?;
+            derived = true;
+        }
     }
 
     let bytes = message_decrypt(
@@ -75,11 +93,18 @@ pub async fn decrypt<R: Rng + CryptoRng>(
         &mut store.kyber_pre_key_store,
         rng,
     )
-    .await?;
+    .await
+    .map_err(|err| {
+        if !derived {
+            return err.into();
+        }
+        debug!("Decryption with derived pre key failed '{err}'");
+        EncryptionError::DerivedPreKeyRejected
+    })?;
 
     Ok(DecryptedEnvelope::builder()
         .source_account_id(source)
-        .source_device_id(1.into())
+        .source_device_id(source_device_id.into())
         .content(bytes)
         .timestamp(time_now_millis())
         .build())
@@ -210,6 +235,7 @@ mod test {
         let mut cipher = encrypt(
             expected.clone().into_bytes(),
             receiver,
+            1.into(),
             sam_store,
             denim_store,
         )
@@ -13,6 +13,21 @@ pub enum EncryptionError {
     NoPreKeyInMessage,
     Key(KeyError),
     NoRngCounter,
+    NoSeedEpoch,
+    /// Decryption failed with a pre key derived from the seeds.
+    DerivedPreKeyRejected,
+}
+
+impl EncryptionError {
+    /// Whether the error means the deniable seeds of the client and proxy have diverged.
+    /// Only the counter and epoch attested by the proxy count, every other part
+    /// of the message is under the control of the sender.
+    pub fn is_seed_divergence(&self) -> bool {
+        matches!(
+            self,
+            Self::Key(KeyError::KeyIdMismatch | KeyError::UnknownSeedEpoch)
+        )
+    }
 }
 
 #[derive(Debug, Error, Display, From)]
@@ -21,4 +36,8 @@ pub enum KeyError {
     DecodeError(DecodeError),
     CurveError(CurveError),
     SeedStore(SeedStoreError),
+    /// The key id at the counter from the proxy is not the one in the message.
+    KeyIdMismatch,
+    /// The seeds of the epoch in the message are unknown or past their grace period.
+    UnknownSeedEpoch,
 }
@@ -1,7 +1,9 @@
-use denim_sam_common::{denim_message::KeyBundle, rng::RngState as _};
+use denim_sam_common::{
+    denim_message::KeyBundle,
+    rng::derive::{key_id_at, key_rng_at},
+};
 use libsignal_protocol::{IdentityKey, PreKeyBundle, PreKeyId, PreKeyStore, PublicKey};
 use log::debug;
-use rand::RngCore;
 use sam_common::api::{Decode, EcPreKey, Key, SignedEcPreKey, SignedKey};
 use sam_security::key_gen::generate_ec_pre_key;
 
@@ -31,23 +33,31 @@ pub fn into_libsignal_bundle(
 }
 
 pub async fn generate_key<T: DeniableStoreType>(
+    seed_epoch: u32,
     rng_counter: u64,
     prekey_id: PreKeyId,
     store: &mut DeniableStore<T>,
 ) -> Result<(), KeyError> {
-    debug!("Searching for prekey {prekey_id}. Counter from server: {rng_counter}");
-
-    while store.seed_store.get_rng_offset().await? <= rng_counter as u128 {
-        let mut id_rng = store.seed_store.get_key_id_seed().await?.into_rng();
-        let key_id = id_rng.next_u32().into();
-        store.seed_store.set_key_id_seed(id_rng.into()).await?;
-
-        let mut key_rng = store.seed_store.get_key_seed().await?.into_rng();
-        let pre_key = generate_ec_pre_key(key_id, &mut key_rng).await;
-        store.pre_key_store.save_pre_key(key_id, &pre_key).await?;
-        store.seed_store.set_key_seed(key_rng.into()).await?;
-        debug!("Successfully stored new prekey '{key_id}'.");
+    debug!("Deriving prekey {prekey_id}. Counter from server: {rng_counter} in epoch {seed_epoch}");
+
+    let (key_seed, key_id_seed) = store
+        .seed_store
+        .get_seeds_for_epoch(seed_epoch)
+        .await?
+        .ok_or(KeyError::UnknownSeedEpoch)?;
+
+    let key_id = key_id_at(key_id_seed, rng_counter);
+    if key_id != u32::from(prekey_id) {
+        return Err(KeyError::KeyIdMismatch);
     }
+
+    let mut key_rng = key_rng_at(key_seed, rng_counter);
+    let pre_key = generate_ec_pre_key(prekey_id, &mut key_rng).await;
+    store
+        .pre_key_store
+        .save_pre_key(prekey_id, &pre_key)
+        .await?;
+    debug!("Successfully stored new prekey '{prekey_id}'.");
     Ok(())
 }
 
@@ -62,14 +72,12 @@ mod test {
     use crate::store::DenimPreKeySeedStore;
     use crate::store::InMemoryDeniableStoreConfig;
 
-    use super::generate_key;
+    use super::{generate_key, KeyError};
 
     #[rstest]
     #[case(InMemoryDeniableStoreConfig::default())]
     #[tokio::test]
-    async fn generate_key_generates_key_with_right_id_eventually(
-        #[case] store_config: impl DeniableStoreConfig,
-    ) {
+    async fn generate_key_derives_key_at_counter(#[case] store_config: impl DeniableStoreConfig) {
         let id_rng_state_1 = ChaChaRngState::random(&mut OsRng);
         let id_rng_state_2 = id_rng_state_1.clone();
 
@@ -77,25 +85,45 @@ mod test {
 
         let mut store = store_config.create_store().await.expect("Can create store");
 
-        store
-            .seed_store
-            .set_key_id_seed(id_rng_state_2)
-            .await
-            .expect("can save key seed");
-
-        store
+        let epoch = store
             .seed_store
-            .set_key_seed(ChaChaRngState::random(&mut OsRng))
+            .rotate_seeds(ChaChaRngState::random(&mut OsRng), id_rng_state_2)
             .await
-            .expect("can save key id seed");
+            .expect("can rotate seeds");
 
         for _ in 0..10 {
             let _ = id_rng_1.next_u32();
         }
 
         let key_id = id_rng_1.next_u32().into();
-        assert!(generate_key(11, key_id, &mut store).await.is_ok());
+        assert!(generate_key(epoch, 10, key_id, &mut store).await.is_ok());
 
         assert!(store.pre_key_store.get_pre_key(key_id).await.is_ok())
     }
+
+    #[rstest]
+    #[case(InMemoryDeniableStoreConfig::default())]
+    #[tokio::test]
+    async fn generate_key_rejects_wrong_counter(#[case] store_config: impl DeniableStoreConfig) {
+        let id_rng_state = ChaChaRngState::random(&mut OsRng);
+        let key_id = id_rng_state.clone().into_rng().next_u32().into();
+
+        let mut store = store_config.create_store().await.expect("Can create store");
+
+        let epoch = store
+            .seed_store
+            .rotate_seeds(ChaChaRngState::random(&mut OsRng), id_rng_state)
+            .await
+            .expect("can rotate seeds");
+
+        assert!(matches!(
+            generate_key(epoch, 1, key_id, &mut store).await,
+            Err(KeyError::KeyIdMismatch)
+        ));
+        assert!(matches!(
+            generate_key(epoch + 1, 0, key_id, &mut store).await,
+            Err(KeyError::UnknownSeedEpoch)
+        ));
+        assert!(store.pre_key_store.get_pre_key(key_id).await.is_err())
+    }
 }
@@ -11,7 +11,9 @@ use sam_client::storage::error::StoreCreationError;
 use sam_net::error::WebSocketError;
 
 use crate::encryption::error::EncryptionError;
-use crate::message::error::{MessageError, MessageProcessingError};
+use crate::message::error::{
+    DeadLetterError, MessageError, MessageProcessingError, MessageQueueError,
+};
 use crate::store::SeedStoreError;
 
 #[derive(Debug, Error, Display, From)]
@@ -24,6 +26,10 @@ pub enum DenimProtocolError {
     ReceivedWrongResponseId,
     InvalidCredentials,
     FailedToReceiveQStatus,
+    /// The proxy did not send a `QStatus` in time.
+    QStatusTimeout,
+    /// The server did not answer a sent message in time.
+    ResponseTimeout,
 }
 
 #[derive(Debug, Error, Display, From)]
@@ -33,10 +39,13 @@ pub enum DenimClientError {
     AccountStore(AccountStoreError),
     ContactStore(ContactStoreError),
     SeedStore(SeedStoreError),
+    MessageQueue(MessageQueueError),
+    DeadLetter(DeadLetterError),
     Api(ApiClientError),
     MessageProcessingError(MessageProcessingError),
     EncryptionError(EncryptionError),
     SignalProtocol(SignalProtocolError),
     Protocol(DenimProtocolError),
     NotSupported,
+    Disconnected,
 }
@@ -0,0 +1,406 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use denim_sam_common::{
+    buffers::{
+        DeniablePayload, InMemorySendingBuffer, MessageId, PaddingScheme, PartialMessage,
+        SendingBuffer,
+    },
+    denim_message::DeniableMessage,
+    DenimBufferError,
+};
+use log::error;
+use prost::Message;
+use sqlx::{Row, SqlitePool};
+use tokio::sync::Mutex;
+
+use crate::store::cipher::StoreCipher;
+
+use super::error::SendingBufferError;
+
+const CREATE_OUTGOING_MESSAGES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS DenimOutgoingMessages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        message BLOB NOT NULL
+    )";
+
+const CREATE_PARTIAL_MESSAGE_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS DenimPartialMessage (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        message BLOB NOT NULL,
+        content BLOB NOT NULL,
+        message_id INTEGER NOT NULL,
+        next_sequence_number INTEGER NOT NULL
+    )";
+
+/// Sending buffer that keeps enqueued deniable messages and the position
+/// of the message being chunked in SQLite, so sending resumes after a restart.
+/// Messages are encrypted with `cipher`, as they may carry seeds.
+#[derive(Clone)]
+pub struct SqliteSendingBuffer {
+    pool: SqlitePool,
+    cipher: StoreCipher,
+    buffer: InMemorySendingBuffer,
+    // keeps the database in step with the buffer when clones are used concurrently
+    lock: Arc<Mutex<()>>,
+}
+
+impl SqliteSendingBuffer {
+    pub async fn new(
+        pool: SqlitePool,
+        cipher: StoreCipher,
+        q: f32,
+    ) -> Result<Self, SendingBufferError> {
+        sqlx::query(CREATE_OUTGOING_MESSAGES_TABLE)
+            .execute(&pool)
+            .await?;
+        sqlx::query(CREATE_PARTIAL_MESSAGE_TABLE)
+            .execute(&pool)
+            .await?;
+
+        let outgoing_messages = sqlx::query_scalar::<_, Vec<u8>>(
+            "SELECT message FROM DenimOutgoingMessages ORDER BY id",
+        )
+        .fetch_all(&pool)
+        .await?
+        .into_iter()
+        .map(|message| {
+            let message = cipher.decrypt("outgoing_message", &message)?;
+            Ok(DeniableMessage::decode(message.as_slice())?)
+        })
+        .collect::<Result<VecDeque<_>, SendingBufferError>>()?;
+
+        let current = match sqlx::query(
+            "SELECT message, content, message_id, next_sequence_number FROM DenimPartialMessage",
+        )
+        .fetch_optional(&pool)
+        .await?
+        {
+            Some(row) => PartialMessage {
+                message: cipher
+                    .decrypt("partial_message", &row.try_get::<Vec<u8>, _>("message")?)?,
+                content: cipher
+                    .decrypt("partial_content", &row.try_get::<Vec<u8>, _>("content")?)?,
+                message_id: row.try_get::<i64, _>("message_id")? as u32,
+                next_sequence_number: row.try_get::<i64, _>("next_sequence_number")? as u32,
+            },
+            None => PartialMessage::default(),
+        };
+
+        Ok(Self {
+            pool,
+            cipher,
+            buffer: InMemorySendingBuffer::restore(q, outgoing_messages, current)?,
+            lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    async fn persist_message(&self, message: &DeniableMessage) -> Result<(), SendingBufferError> {
+        sqlx::query("INSERT INTO DenimOutgoingMessages (message) VALUES (?)")
+            .bind(
+                self.cipher
+                    .encrypt("outgoing_message", &message.encode_to_vec())?,
+            )
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Overwrites the stored message at `position` among the messages that have not started chunking.
+    async fn persist_replacement(
+        &self,
+        position: usize,
+        message: &DeniableMessage,
+    ) -> Result<(), SendingBufferError> {
+        sqlx::query(
+            "UPDATE DenimOutgoingMessages SET message = ?
+             WHERE id = (SELECT id FROM DenimOutgoingMessages ORDER BY id LIMIT 1 OFFSET ?)",
+        )
+        .bind(
+            self.cipher
+                .encrypt("outgoing_message", &message.encode_to_vec())?,
+        )
+        .bind(position as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Removes the messages that started chunking and stores the current position.
+    async fn persist_progress(&self, started: usize) -> Result<(), SendingBufferError> {
+        let current = self.buffer.current().await;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "DELETE FROM DenimOutgoingMessages
+             WHERE id IN (SELECT id FROM DenimOutgoingMessages ORDER BY id LIMIT ?)",
+        )
+        .bind(started as i64)
+        .execute(&mut *tx)
+        .await?;
+        if current.content.is_empty() {
+            sqlx::query("DELETE FROM DenimPartialMessage")
+                .execute(&mut *tx)
+                .await?;
+        } else {
+            sqlx::query(
+                "INSERT INTO DenimPartialMessage
+                 (id, message, content, message_id, next_sequence_number)
+                 VALUES (0, ?, ?, ?, ?)
+                 ON CONFLICT(id) DO UPDATE SET message = excluded.message,
+                 content = excluded.content,
+                 message_id = excluded.message_id,
+                 next_sequence_number = excluded.next_sequence_number",
+            )
+            .bind(self.cipher.encrypt("partial_message", &current.message)?)
+            .bind(self.cipher.encrypt("partial_content", &current.content)?)
+            .bind(i64::from(current.message_id))
+            .bind(i64::from(current.next_sequence_number))
+            .execute(&mut *tx)
+            .await?;
+        }
+        Ok(tx.commit().await?)
+    }
+
+    /// Moves the interrupted message back to the front of the stored messages.
+    async fn persist_restart(&self, message: &DeniableMessage) -> Result<(), SendingBufferError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM DenimPartialMessage")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO DenimOutgoingMessages (id, message)
+             VALUES ((SELECT COALESCE(MIN(id), 1) - 1 FROM DenimOutgoingMessages), ?)",
+        )
+        .bind(
+            self.cipher
+                .encrypt("outgoing_message", &message.encode_to_vec())?,
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(tx.commit().await?)
+    }
+}
+
+#[async_trait]
+impl SendingBuffer for SqliteSendingBuffer {
+    async fn set_q(&mut self, q: f32) {
+        self.buffer.set_q(q).await
+    }
+
+    async fn get_q(&self) -> f32 {
+        self.buffer.get_q().await
+    }
+
+    async fn set_padding(&mut self, padding: Option<PaddingScheme>) {
+        self.buffer.set_padding(padding).await
+    }
+
+    async fn get_deniable_payload(
+        &mut self,
+        reg_message_len: u32,
+    ) -> Result<DeniablePayload, DenimBufferError> {
+        let _guard = self.lock.lock().await;
+        let outgoing_before = self.buffer.outgoing_len().await;
+        let payload = self.buffer.get_deniable_payload(reg_message_len).await?;
+        let started = outgoing_before - self.buffer.outgoing_len().await;
+
+        // the payload is sent regardless, so a failed write only risks resending after a restart
+        if let Err(e) = self.persist_progress(started).await {
+            error!("Failed to persist sending buffer progress '{e}'");
+        }
+        Ok(payload)
+    }
+
+    async fn enqueue_message(
+        &mut self,
+        deniable_message: DeniableMessage,
+    ) -> Result<(), DenimBufferError> {
+        let _guard = self.lock.lock().await;
+        // only enqueued once stored, so a restart cannot silently lose it
+        self.persist_message(&deniable_message)
+            .await
+            .map_err(|e| DenimBufferError::Storage(e.to_string()))?;
+        self.buffer.enqueue_message(deniable_message).await
+    }
+
+    async fn replace_message(
+        &mut self,
+        deniable_message: DeniableMessage,
+    ) -> Result<(), DenimBufferError> {
+        let _guard = self.lock.lock().await;
+        match self.buffer.queued_position(&deniable_message).await {
+            Some(position) => self.persist_replacement(position, &deniable_message).await,
+            None => self.persist_message(&deniable_message).await,
+        }
+        .map_err(|e| DenimBufferError::Storage(e.to_string()))?;
+        self.buffer.replace_message(deniable_message).await
+    }
+
+    async fn is_empty(&self) -> bool {
+        self.buffer.is_empty().await
+    }
+
+    async fn restart_current(&mut self, message_id: MessageId) -> Option<MessageId> {
+        let _guard = self.lock.lock().await;
+        let interrupted = self.buffer.current().await;
+        let restarted = self.buffer.restart_current(message_id).await?;
+
+        match DeniableMessage::decode(interrupted.message.as_slice()) {
+            Ok(mut message) => {
+                message.message_id = message_id;
+                if let Err(e) = self.persist_restart(&message).await {
+                    error!("Failed to persist restarted deniable message '{e}'");
+                }
+            }
+            Err(e) => error!("Failed to decode interrupted deniable message '{e}'"),
+        }
+        Some(restarted)
+    }
+
+    async fn next_message_id(&self) -> MessageId {
+        self.buffer.next_message_id().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use denim_sam_common::{
+        buffers::SendingBuffer,
+        denim_message::{deniable_message::MessageKind, DeniableMessage, SeedUpdate},
+        DenimBufferError,
+    };
+    use sam_client::storage::sqlite::sqlite_connector::SqliteConnector;
+
+    use crate::store::cipher::StoreCipher;
+
+    use super::SqliteSendingBuffer;
+
+    fn seed_update(message_id: u32) -> DeniableMessage {
+        DeniableMessage::builder()
+            .message_id(message_id)
+            .message_kind(MessageKind::SeedUpdate(
+                SeedUpdate::builder()
+                    .pre_key_seed(vec![1; 100])
+                    .pre_key_id_seed(vec![2; 100])
+                    .epoch(1)
+                    .build(),
+            ))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn reopened_buffer_resumes_chunking() {
+        let pool = SqliteConnector::migrate("sqlite::memory:")
+            .await
+            .expect("Can open database")
+            .pool();
+
+        let mut buffer = SqliteSendingBuffer::new(pool.clone(), StoreCipher::new([7; 32]), 0.5)
+            .await
+            .expect("Can create buffer");
+        buffer
+            .enqueue_message(seed_update(0))
+            .await
+            .expect("Can enqueue message");
+        buffer
+            .enqueue_message(seed_update(1))
+            .await
+            .expect("Can enqueue message");
+        let payload = buffer
+            .get_deniable_payload(100)
+            .await
+            .expect("Can get payload");
+        let first = &payload.denim_chunks()[0];
+        assert_eq!((first.message_id(), first.sequence_number()), (0, 0));
+        drop(buffer);
+
+        // the seeds in the messages are not stored in plaintext
+        let rows: Vec<Vec<u8>> = sqlx::query_scalar(
+            "SELECT message FROM DenimOutgoingMessages
+             UNION ALL SELECT message FROM DenimPartialMessage
+             UNION ALL SELECT content FROM DenimPartialMessage",
+        )
+        .fetch_all(&pool)
+        .await
+        .expect("Can read rows");
+        assert_eq!(rows.len(), 3);
+        assert!(rows
+            .iter()
+            .all(|row| !row.windows(32).any(|window| window == [1; 32])));
+
+        let mut buffer = SqliteSendingBuffer::new(pool, StoreCipher::new([7; 32]), 0.5)
+            .await
+            .expect("Can reopen buffer");
+        assert_eq!(buffer.next_message_id().await, 2);
+        let payload = buffer
+            .get_deniable_payload(100)
+            .await
+            .expect("Can get payload");
+        let next = &payload.denim_chunks()[0];
+        assert_eq!((next.message_id(), next.sequence_number()), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn replaced_message_survives_reopening() {
+        let pool = SqliteConnector::migrate("sqlite::memory:")
+            .await
+            .expect("Can open database")
+            .pool();
+
+        let mut buffer = SqliteSendingBuffer::new(pool.clone(), StoreCipher::new([7; 32]), 0.5)
+            .await
+            .expect("Can create buffer");
+        buffer
+            .enqueue_message(seed_update(0))
+            .await
+            .expect("Can enqueue message");
+        buffer
+            .replace_message(seed_update(1))
+            .await
+            .expect("Can enqueue message");
+        drop(buffer);
+
+        let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM DenimOutgoingMessages")
+            .fetch_one(&pool)
+            .await
+            .expect("Can count rows");
+        assert_eq!(stored, 1);
+
+        let mut buffer = SqliteSendingBuffer::new(pool, StoreCipher::new([7; 32]), 0.5)
+            .await
+            .expect("Can reopen buffer");
+        let payload = buffer
+            .get_deniable_payload(100)
+            .await
+            .expect("Can get payload");
+        let first = &payload.denim_chunks()[0];
+        assert_eq!((first.message_id(), first.sequence_number()), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn failed_write_is_reported_and_not_enqueued() {
+        let pool = SqliteConnector::migrate("sqlite::memory:")
+            .await
+            .expect("Can open database")
+            .pool();
+        let mut buffer = SqliteSendingBuffer::new(pool.clone(), StoreCipher::new([7; 32]), 0.5)
+            .await
+            .expect("Can create buffer");
+        sqlx::query("DROP TABLE DenimOutgoingMessages")
+            .execute(&pool)
+            .await
+            .expect("Can drop table");
+
+        assert!(matches!(
+            buffer.enqueue_message(seed_update(0)).await,
+            Err(DenimBufferError::Storage(_))
+        ));
+        assert!(matches!(
+            buffer.replace_message(seed_update(1)).await,
+            Err(DenimBufferError::Storage(_))
+        ));
+        assert!(buffer.is_empty().await);
+    }
+}
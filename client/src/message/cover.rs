@@ -0,0 +1,175 @@
+use std::{
+    collections::VecDeque,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+use denim_sam_common::rng::exponential::exponential;
+use rand::Rng;
+use sam_common::{sam_message::ClientEnvelope, AccountId};
+
+/// When cover messages are sent, independent of how much deniable traffic is queued.
+#[derive(Clone, Debug)]
+pub enum CoverTiming {
+    /// Exponentially distributed gaps with the given mean, like a Poisson process.
+    Exponential(Duration),
+    /// Gaps uniformly distributed in the range.
+    Uniform(RangeInclusive<Duration>),
+}
+
+impl CoverTiming {
+    fn gap(&self, rng: &mut impl Rng) -> Duration {
+        match self {
+            CoverTiming::Exponential(mean) => exponential(*mean, rng),
+            CoverTiming::Uniform(range) => rng.gen_range(range.clone()),
+        }
+    }
+}
+
+/// Regular messages sent to a cover contact so deniable messages are delivered
+/// while the user is silent. They are sent on schedule whether or not deniable
+/// messages are queued, an empty queue fills their deniable payload with padding,
+/// so their timing reveals nothing about deniable traffic.
+///
+/// The client encrypts a few cover messages ahead of time and a spawned task sends
+/// them, the client prepares new ones whenever it processes messages. While blocked
+/// in `process_messages_blocking` it does so as soon as the task takes one.
+#[derive(Clone, Debug)]
+pub struct CoverTrafficPolicy {
+    /// Receives the cover messages, e.g. another device or an account that expects them.
+    pub contact: AccountId,
+    pub timing: CoverTiming,
+    /// Length of the random content of a cover message.
+    pub message_size: RangeInclusive<usize>,
+    /// At most this many bytes of cover content are sent per `budget_period`.
+    pub budget: usize,
+    pub budget_period: Duration,
+}
+
+impl CoverTrafficPolicy {
+    pub fn new(contact: AccountId) -> Self {
+        Self {
+            contact,
+            timing: CoverTiming::Exponential(Duration::from_secs(60)),
+            message_size: 64..=512,
+            budget: 64 * 1024,
+            budget_period: Duration::from_secs(60 * 60),
+        }
+    }
+
+    /// Random content for a cover message.
+    pub fn content(&self, rng: &mut impl Rng) -> Vec<u8> {
+        let mut content = vec![0; rng.gen_range(self.message_size.clone())];
+        rng.fill_bytes(&mut content);
+        content
+    }
+}
+
+/// A cover message encrypted ahead of time, `size` is the length of its content.
+pub struct CoverMessage {
+    pub envelope: ClientEnvelope,
+    pub size: usize,
+}
+
+/// Decides when to send cover messages.
+pub struct CoverTraffic {
+    policy: CoverTrafficPolicy,
+    next_at: Instant,
+    sent: VecDeque<(Instant, usize)>,
+}
+
+impl CoverTraffic {
+    pub fn new(policy: CoverTrafficPolicy, now: Instant, rng: &mut impl Rng) -> Self {
+        let next_at = now + policy.timing.gap(rng);
+        Self {
+            policy,
+            next_at,
+            sent: VecDeque::new(),
+        }
+    }
+
+    pub fn next_at(&self) -> Instant {
+        self.next_at
+    }
+
+    /// Whether a cover message is due at `now`. Schedules the next one if it is,
+    /// whether or not a message ends up being sent.
+    pub fn poll(&mut self, now: Instant, rng: &mut impl Rng) -> bool {
+        if now < self.next_at {
+            return false;
+        }
+        // scheduled from now, a client that was not polled for a while does not burst
+        self.next_at = now + self.policy.timing.gap(rng);
+        true
+    }
+
+    /// Records a cover message of `size` bytes sent at `now`, `false` if it would exceed the budget.
+    pub fn spend(&mut self, now: Instant, size: usize) -> bool {
+        while self
+            .sent
+            .front()
+            .is_some_and(|(sent_at, _)| now.duration_since(*sent_at) >= self.policy.budget_period)
+        {
+            self.sent.pop_front();
+        }
+        let spent: usize = self.sent.iter().map(|(_, size)| size).sum();
+        if spent + size > self.policy.budget {
+            return false;
+        }
+        self.sent.push_back((now, size));
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use sam_common::AccountId;
+
+    use super::{CoverTiming, CoverTraffic, CoverTrafficPolicy};
+
+    #[test]
+    fn cover_messages_stay_within_budget() {
+        let mut rng = rand::thread_rng();
+        let policy = CoverTrafficPolicy {
+            message_size: 100..=100,
+            budget: 250,
+            budget_period: Duration::from_secs(60),
+            ..CoverTrafficPolicy::new(AccountId::generate())
+        };
+        assert_eq!(policy.content(&mut rng).len(), 100);
+        let mut cover = CoverTraffic::new(policy, Instant::now(), &mut rng);
+        let start = Instant::now();
+
+        assert!(cover.spend(start, 100));
+        assert!(cover.spend(start, 100));
+        assert!(!cover.spend(start, 100));
+
+        // the budget is freed once the period has passed
+        let later = start + Duration::from_secs(60);
+        assert!(cover.spend(later, 100));
+    }
+
+    #[test]
+    fn cover_messages_are_due_after_a_gap() {
+        let mut rng = rand::thread_rng();
+        let start = Instant::now();
+        let mut cover = CoverTraffic::new(
+            CoverTrafficPolicy {
+                timing: CoverTiming::Uniform(Duration::from_secs(5)..=Duration::from_secs(15)),
+                ..CoverTrafficPolicy::new(AccountId::generate())
+            },
+            start,
+            &mut rng,
+        );
+
+        for _ in 0..100 {
+            let due = cover.next_at();
+            assert!(!cover.poll(due - Duration::from_nanos(1), &mut rng));
+            assert!(cover.poll(due, &mut rng));
+            let gap = cover.next_at() - due;
+            assert!(Duration::from_secs(5) <= gap && gap <= Duration::from_secs(15));
+        }
+    }
+}
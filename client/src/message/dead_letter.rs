@@ -0,0 +1,423 @@
+use std::collections::VecDeque;
+
+use async_trait::async_trait;
+use denim_sam_common::denim_message::DeniableMessage;
+use prost::Message;
+use sam_common::sam_message::ServerEnvelope;
+use sqlx::{Row, SqlitePool};
+
+use crate::{protocol::SamDenimMessage, store::cipher::StoreCipher};
+
+use super::{
+    error::DeadLetterError,
+    traits::{DeadLetterQueue, DeadLetterQueueConfig},
+};
+
+const DEAD_LETTER_CAPACITY: usize = 1000;
+
+/// An envelope that could not be processed.
+#[derive(Clone, Debug)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub envelope: SamDenimMessage,
+    pub error: String,
+    /// Times processing the envelope has failed.
+    pub attempts: u32,
+}
+
+/// Envelopes that failed processing, kept so they can be retried later,
+/// e.g. after a session has been reset.
+#[derive(Default)]
+pub struct InMemoryDeadLetterQueue {
+    next_id: u64,
+    letters: VecDeque<DeadLetter>,
+}
+
+#[derive(Default)]
+pub struct InMemoryDeadLetterQueueConfig;
+
+#[async_trait]
+impl DeadLetterQueueConfig for InMemoryDeadLetterQueueConfig {
+    type DeadLetterQueue = InMemoryDeadLetterQueue;
+    async fn create(self) -> Result<Self::DeadLetterQueue, DeadLetterError> {
+        Ok(InMemoryDeadLetterQueue::default())
+    }
+}
+
+#[async_trait]
+impl DeadLetterQueue for InMemoryDeadLetterQueue {
+    async fn push(
+        &mut self,
+        envelope: SamDenimMessage,
+        error: String,
+    ) -> Result<DeadLetter, DeadLetterError> {
+        let letter = DeadLetter {
+            id: self.next_id,
+            envelope,
+            error,
+            attempts: 1,
+        };
+        self.next_id += 1;
+        self.letters.push_back(letter.clone());
+        Ok(letter)
+    }
+
+    async fn fail_retry(
+        &mut self,
+        id: u64,
+        error: String,
+    ) -> Result<Option<DeadLetter>, DeadLetterError> {
+        let Some(mut letter) = self.remove(id).await? else {
+            return Ok(None);
+        };
+        letter.error = error;
+        letter.attempts += 1;
+        self.letters.push_back(letter.clone());
+        Ok(Some(letter))
+    }
+
+    async fn drop_overflow(&mut self) -> Result<Vec<DeadLetter>, DeadLetterError> {
+        let overflow = self.letters.len().saturating_sub(DEAD_LETTER_CAPACITY);
+        Ok(self.letters.drain(..overflow).collect())
+    }
+
+    async fn letters(&mut self) -> Result<Vec<DeadLetter>, DeadLetterError> {
+        Ok(self.letters.iter().cloned().collect())
+    }
+
+    async fn remove(&mut self, id: u64) -> Result<Option<DeadLetter>, DeadLetterError> {
+        let Some(index) = self.letters.iter().position(|letter| letter.id == id) else {
+            return Ok(None);
+        };
+        Ok(self.letters.remove(index))
+    }
+}
+
+const CREATE_DEAD_LETTERS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS DenimDeadLetters (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        position INTEGER NOT NULL,
+        envelope BLOB NOT NULL,
+        error BLOB NOT NULL,
+        attempts INTEGER NOT NULL
+    )";
+
+const SAM_ENVELOPE: u8 = 0;
+const DENIM_ENVELOPE: u8 = 1;
+
+/// Dead letters kept in SQLite, so envelopes that were already acknowledged
+/// survive a restart of the client. Envelopes and errors are encrypted with `cipher`.
+pub struct SqliteDeadLetterQueue {
+    pool: SqlitePool,
+    cipher: StoreCipher,
+}
+
+pub struct SqliteDeadLetterQueueConfig {
+    pool: SqlitePool,
+    cipher: StoreCipher,
+}
+
+impl SqliteDeadLetterQueueConfig {
+    pub fn new(pool: SqlitePool, cipher: StoreCipher) -> Self {
+        Self { pool, cipher }
+    }
+}
+
+#[async_trait]
+impl DeadLetterQueueConfig for SqliteDeadLetterQueueConfig {
+    type DeadLetterQueue = SqliteDeadLetterQueue;
+    async fn create(self) -> Result<Self::DeadLetterQueue, DeadLetterError> {
+        sqlx::query(CREATE_DEAD_LETTERS_TABLE)
+            .execute(&self.pool)
+            .await?;
+        Ok(SqliteDeadLetterQueue {
+            pool: self.pool,
+            cipher: self.cipher,
+        })
+    }
+}
+
+impl SqliteDeadLetterQueue {
+    fn encrypt_envelope(&self, envelope: &SamDenimMessage) -> Result<Vec<u8>, DeadLetterError> {
+        // the kind is encrypted as well, it tells whether the envelope was deniable
+        let mut plaintext = match envelope {
+            SamDenimMessage::Sam(_) => vec![SAM_ENVELOPE],
+            SamDenimMessage::Denim(_) => vec![DENIM_ENVELOPE],
+        };
+        match envelope {
+            SamDenimMessage::Sam(envelope) => envelope.encode(&mut plaintext),
+            SamDenimMessage::Denim(message) => message.encode(&mut plaintext),
+        }
+        .map_err(|_| DeadLetterError::MalformedLetter)?;
+        Ok(self.cipher.encrypt("dead_letter_envelope", &plaintext)?)
+    }
+
+    fn decrypt_envelope(&self, bytes: &[u8]) -> Result<SamDenimMessage, DeadLetterError> {
+        let plaintext = self.cipher.decrypt("dead_letter_envelope", bytes)?;
+        match plaintext.split_first() {
+            Some((&SAM_ENVELOPE, envelope)) => {
+                Ok(SamDenimMessage::Sam(ServerEnvelope::decode(envelope)?))
+            }
+            Some((&DENIM_ENVELOPE, message)) => {
+                Ok(SamDenimMessage::Denim(DeniableMessage::decode(message)?))
+            }
+            _ => Err(DeadLetterError::MalformedLetter),
+        }
+    }
+
+    fn decrypt_row(&self, row: &sqlx::sqlite::SqliteRow) -> Result<DeadLetter, DeadLetterError> {
+        let error = self
+            .cipher
+            .decrypt("dead_letter_error", &row.try_get::<Vec<u8>, _>("error")?)?;
+        Ok(DeadLetter {
+            id: row.try_get::<i64, _>("id")? as u64,
+            envelope: self.decrypt_envelope(&row.try_get::<Vec<u8>, _>("envelope")?)?,
+            error: String::from_utf8(error).map_err(|_| DeadLetterError::MalformedLetter)?,
+            attempts: row.try_get::<i64, _>("attempts")? as u32,
+        })
+    }
+
+    async fn get(&self, id: u64) -> Result<Option<DeadLetter>, DeadLetterError> {
+        sqlx::query("SELECT id, envelope, error, attempts FROM DenimDeadLetters WHERE id = ?")
+            .bind(id as i64)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| self.decrypt_row(&row))
+            .transpose()
+    }
+}
+
+#[async_trait]
+impl DeadLetterQueue for SqliteDeadLetterQueue {
+    async fn push(
+        &mut self,
+        envelope: SamDenimMessage,
+        error: String,
+    ) -> Result<DeadLetter, DeadLetterError> {
+        let id: i64 = sqlx::query_scalar(
+            "INSERT INTO DenimDeadLetters (position, envelope, error, attempts)
+             VALUES ((SELECT COALESCE(MAX(position), 0) + 1 FROM DenimDeadLetters), ?, ?, 1)
+             RETURNING id",
+        )
+        .bind(self.encrypt_envelope(&envelope)?)
+        .bind(self.cipher.encrypt("dead_letter_error", error.as_bytes())?)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(DeadLetter {
+            id: id as u64,
+            envelope,
+            error,
+            attempts: 1,
+        })
+    }
+
+    async fn fail_retry(
+        &mut self,
+        id: u64,
+        error: String,
+    ) -> Result<Option<DeadLetter>, DeadLetterError> {
+        sqlx::query(
+            "UPDATE DenimDeadLetters
+             SET position = (SELECT MAX(position) + 1 FROM DenimDeadLetters),
+             error = ?, attempts = attempts + 1
+             WHERE id = ?",
+        )
+        .bind(self.cipher.encrypt("dead_letter_error", error.as_bytes())?)
+        .bind(id as i64)
+        .execute(&self.pool)
+        .await?;
+        self.get(id).await
+    }
+
+    async fn drop_overflow(&mut self) -> Result<Vec<DeadLetter>, DeadLetterError> {
+        let rows = sqlx::query(
+            "DELETE FROM DenimDeadLetters WHERE id IN (
+                 SELECT id FROM DenimDeadLetters ORDER BY position
+                 LIMIT MAX((SELECT COUNT(*) FROM DenimDeadLetters) - ?, 0)
+             )
+             RETURNING id, envelope, error, attempts",
+        )
+        .bind(DEAD_LETTER_CAPACITY as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(|row| self.decrypt_row(row)).collect()
+    }
+
+    async fn letters(&mut self) -> Result<Vec<DeadLetter>, DeadLetterError> {
+        let rows = sqlx::query(
+            "SELECT id, envelope, error, attempts FROM DenimDeadLetters ORDER BY position",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(|row| self.decrypt_row(row)).collect()
+    }
+
+    async fn remove(&mut self, id: u64) -> Result<Option<DeadLetter>, DeadLetterError> {
+        sqlx::query(
+            "DELETE FROM DenimDeadLetters WHERE id = ? RETURNING id, envelope, error, attempts",
+        )
+        .bind(id as i64)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| self.decrypt_row(&row))
+        .transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use denim_sam_common::denim_message::DeniableMessage;
+    use sam_client::storage::sqlite::sqlite_connector::SqliteConnector;
+
+    use super::{InMemoryDeadLetterQueue, SqliteDeadLetterQueueConfig, DEAD_LETTER_CAPACITY};
+    use crate::{
+        message::traits::{DeadLetterQueue, DeadLetterQueueConfig},
+        protocol::SamDenimMessage,
+        store::cipher::StoreCipher,
+    };
+
+    fn envelope(message_id: u32) -> SamDenimMessage {
+        SamDenimMessage::Denim(DeniableMessage {
+            message_id,
+            message_kind: None,
+        })
+    }
+
+    async fn sqlite_config() -> SqliteDeadLetterQueueConfig {
+        let pool = SqliteConnector::migrate("sqlite::memory:")
+            .await
+            .expect("Can open database")
+            .pool();
+        SqliteDeadLetterQueueConfig::new(pool, StoreCipher::new([7; 32]))
+    }
+
+    async fn failed_retries_keep_their_id(mut letters: impl DeadLetterQueue) {
+        let first = letters
+            .push(envelope(0), "first".to_owned())
+            .await
+            .expect("Can push");
+        let second = letters
+            .push(envelope(1), "second".to_owned())
+            .await
+            .expect("Can push");
+        assert_ne!(first.id, second.id);
+
+        let retried = letters
+            .fail_retry(first.id, "again".to_owned())
+            .await
+            .expect("Can fail retry")
+            .expect("letter is stored");
+        assert_eq!((retried.id, retried.attempts), (first.id, 2));
+        assert_eq!(retried.error, "again");
+
+        let ids: Vec<_> = letters
+            .letters()
+            .await
+            .expect("Can get letters")
+            .iter()
+            .map(|letter| letter.id)
+            .collect();
+        assert_eq!(ids, vec![second.id, first.id]);
+
+        assert!(letters
+            .remove(first.id)
+            .await
+            .expect("Can remove")
+            .is_some());
+        assert!(letters
+            .remove(first.id)
+            .await
+            .expect("Can remove")
+            .is_none());
+    }
+
+    async fn oldest_letters_are_dropped_when_full(mut letters: impl DeadLetterQueue) {
+        let oldest = letters
+            .push(envelope(0), "oldest".to_owned())
+            .await
+            .expect("Can push");
+        for message_id in 1..=DEAD_LETTER_CAPACITY as u32 {
+            letters
+                .push(envelope(message_id), "newer".to_owned())
+                .await
+                .expect("Can push");
+        }
+
+        let dropped = letters.drop_overflow().await.expect("Can drop overflow");
+        assert_eq!(
+            dropped.iter().map(|letter| letter.id).collect::<Vec<_>>(),
+            vec![oldest.id]
+        );
+        assert_eq!(
+            letters.letters().await.expect("Can get letters").len(),
+            DEAD_LETTER_CAPACITY
+        );
+        assert!(letters
+            .drop_overflow()
+            .await
+            .expect("Can drop overflow")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn inmem_failed_retries_keep_their_id() {
+        failed_retries_keep_their_id(InMemoryDeadLetterQueue::default()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_failed_retries_keep_their_id() {
+        let letters = sqlite_config()
+            .await
+            .create()
+            .await
+            .expect("Can create queue");
+        failed_retries_keep_their_id(letters).await;
+    }
+
+    #[tokio::test]
+    async fn inmem_oldest_letters_are_dropped_when_full() {
+        oldest_letters_are_dropped_when_full(InMemoryDeadLetterQueue::default()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_oldest_letters_are_dropped_when_full() {
+        let letters = sqlite_config()
+            .await
+            .create()
+            .await
+            .expect("Can create queue");
+        oldest_letters_are_dropped_when_full(letters).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_letters_survive_reopening_encrypted() {
+        let config = sqlite_config().await;
+        let pool = config.pool.clone();
+        let mut letters = config.create().await.expect("Can create queue");
+        let letter = letters
+            .push(envelope(42), "secret error".to_owned())
+            .await
+            .expect("Can push");
+        drop(letters);
+
+        let errors: Vec<Vec<u8>> = sqlx::query_scalar("SELECT error FROM DenimDeadLetters")
+            .fetch_all(&pool)
+            .await
+            .expect("Can read rows");
+        assert!(errors
+            .iter()
+            .all(|error| !error.windows(6).any(|window| window == b"secret")));
+
+        let mut letters = SqliteDeadLetterQueueConfig::new(pool, StoreCipher::new([7; 32]))
+            .create()
+            .await
+            .expect("Can reopen queue");
+        let stored = letters.letters().await.expect("Can get letters");
+        assert_eq!(stored.len(), 1);
+        assert_eq!((stored[0].id, &stored[0].error), (letter.id, &letter.error));
+        assert!(matches!(
+            stored[0].envelope,
+            SamDenimMessage::Denim(DeniableMessage { message_id: 42, .. })
+        ));
+    }
+}
@@ -3,7 +3,10 @@ use derive_more::{Display, Error, From};
 use libsignal_protocol::SignalProtocolError;
 use sam_client::storage::error::{ContactStoreError, MessageStoreError};
 
-use crate::encryption::error::{EncryptionError, KeyError};
+use crate::{
+    encryption::error::{EncryptionError, KeyError},
+    store::cipher::StoreCipherError,
+};
 
 #[derive(Debug, Error, Display, From)]
 pub enum MessageError {
@@ -12,6 +15,29 @@ pub enum MessageError {
     DenimEncodeDecodeError(DenimEncodeDecodeError),
 }
 
+#[derive(Debug, Error, Display, From)]
+pub enum MessageQueueError {
+    Database(sqlx::Error),
+    Encryption(StoreCipherError),
+    InvalidAccountId,
+}
+
+#[derive(Debug, Error, Display, From)]
+pub enum DeadLetterError {
+    Database(sqlx::Error),
+    Encryption(StoreCipherError),
+    Decode(prost::DecodeError),
+    MalformedLetter,
+}
+
+#[derive(Debug, Error, Display, From)]
+pub enum SendingBufferError {
+    Database(sqlx::Error),
+    Buffer(DenimBufferError),
+    Decode(prost::DecodeError),
+    Encryption(StoreCipherError),
+}
+
 #[derive(Debug, Error, Display, From)]
 pub enum MessageProcessingError {
     MessageKindWasNone,
@@ -22,4 +48,8 @@ pub enum MessageProcessingError {
     SignalProtocol(SignalProtocolError),
     ServerError(#[error(not(source))] String),
     ContactStore(ContactStoreError),
+    /// The proxy derived a pre key in the seed epoch that the client cannot derive.
+    #[from(skip)]
+    #[display("SeedDiverged({_0})")]
+    SeedDiverged(#[error(not(source))] u32),
 }
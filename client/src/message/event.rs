@@ -0,0 +1,56 @@
+use sam_client::encryption::DecryptedEnvelope;
+use sam_common::AccountId;
+use tokio::sync::broadcast;
+
+use super::{dead_letter::DeadLetter, key_request::KeyRequestFailure};
+
+/// A deniable message that could not be sent to `account_id`.
+#[derive(Clone, Debug)]
+pub struct SendFailure {
+    pub account_id: AccountId,
+    pub message: Vec<u8>,
+    pub error: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum DenimEvent {
+    MessageReceived {
+        deniable: bool,
+        envelope: DecryptedEnvelope,
+    },
+    QUpdated(f32),
+    /// Keys for `account_id` arrived and waiting messages were sent.
+    KeyExchangeCompleted(AccountId),
+    /// Keys for `account_id` could not be fetched and its waiting messages were dropped.
+    KeyExchangeFailed {
+        account_id: AccountId,
+        reason: KeyRequestFailure,
+        dropped_messages: usize,
+    },
+    /// The server accepted a regular message to `account_id`.
+    MessageDelivered(AccountId),
+    DeniableSendFailed(SendFailure),
+    /// An envelope failed processing and was kept as a dead letter.
+    DeadLetter(DeadLetter),
+    /// The oldest dead letter was dropped to make room for a newer one.
+    DeadLetterDropped(DeadLetter),
+    ProxyError(String),
+    Connected,
+    Disconnected,
+    /// Waiting before the given reconnect attempt.
+    Reconnecting(u32),
+    /// The connection was restored. The deniable message that was being sent
+    /// when it dropped is sent again from the start, this is its previous id.
+    Reconnected {
+        restarted_message: Option<u32>,
+    },
+    /// Cover traffic was due, but no cover message was prepared.
+    /// The client prepares them while it processes messages.
+    CoverMessagesExhausted,
+}
+
+/// Sends `event` to the current subscribers of `events`.
+pub(crate) fn emit<E>(events: &broadcast::Sender<E>, event: E) {
+    // nobody listening is not an error
+    let _ = events.send(event);
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use sam_common::AccountId;
+
+const DEFAULT_KEY_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_KEY_REQUEST_ATTEMPTS: u32 = 5;
+
+/// How long to wait for a `KeyResponse` before requesting keys again.
+/// The timeout doubles with every attempt.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyRequestPolicy {
+    pub timeout: Duration,
+    pub max_attempts: u32,
+}
+
+impl Default for KeyRequestPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_KEY_REQUEST_TIMEOUT,
+            max_attempts: DEFAULT_MAX_KEY_REQUEST_ATTEMPTS,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyRequestFailure {
+    /// No `KeyResponse` arrived after the given number of attempts.
+    TimedOut(u32),
+    /// The proxy answered with an error.
+    Rejected(String),
+}
+
+/// Status of the deniable messages waiting for keys of a recipient.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PendingStatus {
+    pub waiting_messages: usize,
+    /// Key requests sent so far.
+    pub attempts: u32,
+    /// When keys are requested again if no response arrives,
+    /// `None` if keys have not been requested.
+    pub retry_at: Option<Instant>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct KeyRequestState {
+    pub attempts: u32,
+    pub sent_at: Instant,
+}
+
+/// Outstanding key requests of a client.
+#[derive(Default)]
+pub struct KeyRequests {
+    policy: KeyRequestPolicy,
+    requests: HashMap<AccountId, KeyRequestState>,
+}
+
+impl KeyRequests {
+    pub fn new(policy: KeyRequestPolicy) -> Self {
+        Self {
+            policy,
+            requests: HashMap::new(),
+        }
+    }
+
+    pub fn is_outstanding(&self, account_id: AccountId) -> bool {
+        self.requests.contains_key(&account_id)
+    }
+
+    pub fn get(&self, account_id: AccountId) -> Option<KeyRequestState> {
+        self.requests.get(&account_id).copied()
+    }
+
+    /// Records that keys for `account_id` were requested at `now`.
+    pub fn sent(&mut self, account_id: AccountId, now: Instant) {
+        let state = self.requests.entry(account_id).or_insert(KeyRequestState {
+            attempts: 0,
+            sent_at: now,
+        });
+        state.attempts += 1;
+        state.sent_at = now;
+    }
+
+    pub fn finish(&mut self, account_id: AccountId) -> Option<KeyRequestState> {
+        self.requests.remove(&account_id)
+    }
+
+    pub fn retry_at(&self, state: &KeyRequestState) -> Instant {
+        let backoff = 2u32.saturating_pow(state.attempts.saturating_sub(1));
+        state.sent_at + self.policy.timeout.saturating_mul(backoff)
+    }
+
+    /// The earliest time an outstanding request times out.
+    pub fn next_retry_at(&self) -> Option<Instant> {
+        self.requests
+            .values()
+            .map(|state| self.retry_at(state))
+            .min()
+    }
+
+    /// Splits the requests that timed out at `now` into those to retry and those that
+    /// used up their attempts. The latter are no longer tracked.
+    pub fn timed_out(&mut self, now: Instant) -> (Vec<AccountId>, Vec<(AccountId, u32)>) {
+        let mut retry = Vec::new();
+        let mut failed = Vec::new();
+        for (account_id, state) in &self.requests {
+            if self.retry_at(state) > now {
+                continue;
+            }
+            if state.attempts >= self.policy.max_attempts {
+                failed.push((*account_id, state.attempts));
+            } else {
+                retry.push(*account_id);
+            }
+        }
+        for (account_id, _) in &failed {
+            self.requests.remove(account_id);
+        }
+        (retry, failed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use sam_common::AccountId;
+
+    use super::{KeyRequestPolicy, KeyRequests};
+
+    #[test]
+    fn key_requests_back_off_and_give_up() {
+        let mut requests = KeyRequests::new(KeyRequestPolicy {
+            timeout: Duration::from_secs(10),
+            max_attempts: 2,
+        });
+        let account_id = AccountId::generate();
+        let start = Instant::now();
+
+        requests.sent(account_id, start);
+        let (retry, failed) = requests.timed_out(start + Duration::from_secs(9));
+        assert!(retry.is_empty() && failed.is_empty());
+
+        let (retry, failed) = requests.timed_out(start + Duration::from_secs(10));
+        assert_eq!(retry, vec![account_id]);
+        assert!(failed.is_empty());
+
+        // the second attempt waits twice as long
+        let resent = start + Duration::from_secs(10);
+        requests.sent(account_id, resent);
+        let (retry, failed) = requests.timed_out(resent + Duration::from_secs(19));
+        assert!(retry.is_empty() && failed.is_empty());
+
+        let (retry, failed) = requests.timed_out(resent + Duration::from_secs(20));
+        assert!(retry.is_empty());
+        assert_eq!(failed, vec![(account_id, 2)]);
+        assert!(!requests.is_outstanding(account_id));
+    }
+}
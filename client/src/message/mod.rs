@@ -6,7 +6,12 @@ use error::MessageError;
 use prost::Message;
 use sam_common::sam_message::ClientMessage;
 
+pub mod buffer;
+pub mod cover;
+pub mod dead_letter;
 pub mod error;
+pub mod event;
+pub mod key_request;
 pub mod process;
 pub mod queue;
 pub mod traits;
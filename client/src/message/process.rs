@@ -1,14 +1,14 @@
 use std::time::SystemTime;
 
 use denim_sam_common::denim_message::{
-    deniable_message::MessageKind, DeniableMessage, KeyResponse,
+    deniable_message::MessageKind, BlockListResponse, DeniableMessage, KeyResponse,
 };
 use libsignal_core::ProtocolAddress;
 use libsignal_protocol::{process_prekey_bundle, IdentityKey};
-use log::{debug, error};
+use log::{debug, error, warn};
 use rand::{CryptoRng, Rng};
 use sam_client::storage::{ContactStore, MessageStore, Store, StoreType};
-use sam_common::AccountId;
+use sam_common::{AccountId, DeviceId};
 
 use crate::{
     encryption::{encrypt::decrypt, into_libsignal_bundle},
@@ -19,6 +19,14 @@ use super::error::MessageProcessingError;
 
 pub enum DenimResponse {
     KeyResponse(AccountId),
+    BlockListResponse(Vec<AccountId>),
+    KeyRequestFailed(AccountId, String),
+    SeedUpdateAck(u32),
+    /// The proxy rejected the seed update of the first epoch, it uses the second one.
+    SeedUpdateRejected(u32, u32),
+    SeedRequested,
+    KeysReset(AccountId, DeviceId),
+    ProxyError(String),
 }
 
 pub async fn process_deniable_message<R: Rng + CryptoRng>(
@@ -33,18 +41,22 @@ pub async fn process_deniable_message<R: Rng + CryptoRng>(
 
     let envelope = match kind {
         MessageKind::DeniableMessage(message) => {
-            let env = decrypt(message, store, deniable_store, rng).await?;
-            let source = env.source_account_id();
-            if !deniable_store
+            let seed_epoch = message.seed_epoch;
+            let env = match decrypt(message, store, deniable_store, rng).await {
+                Ok(env) => env,
+                Err(err) => match seed_epoch {
+                    Some(epoch) if err.is_seed_divergence() => {
+                        warn!("Failed to derive deniable pre key '{err}'");
+                        Err(MessageProcessingError::SeedDiverged(epoch))?
+                    }
+                    _ => Err(err)?,
+                },
+            };
+            // also records further devices of known contacts, so replies reach every device
+            deniable_store
                 .contact_store
-                .contains_contact(source)
-                .await?
-            {
-                deniable_store
-                    .contact_store
-                    .add_device(env.source_account_id(), env.source_device_id())
-                    .await?;
-            }
+                .add_device(env.source_account_id(), env.source_device_id())
+                .await?;
             env
         }
         MessageKind::KeyResponse(res) => {
@@ -52,12 +64,37 @@ pub async fn process_deniable_message<R: Rng + CryptoRng>(
                 .await
                 .map(Some);
         }
+        MessageKind::BlockListResponse(res) => {
+            return handle_block_list_response(res).map(Some);
+        }
+        MessageKind::SeedUpdateAck(ack) => {
+            return Ok(Some(DenimResponse::SeedUpdateAck(ack.epoch)));
+        }
+        MessageKind::SeedUpdateRejected(rejected) => {
+            return Ok(Some(DenimResponse::SeedUpdateRejected(
+                rejected.epoch,
+                rejected.current_epoch,
+            )));
+        }
+        MessageKind::SeedRequest(_) => return Ok(Some(DenimResponse::SeedRequested)),
+        MessageKind::KeysReset(reset) => {
+            let account_id = AccountId::try_from(reset.account_id)
+                .map_err(|_| MessageProcessingError::MalformedMessage)?;
+            return Ok(Some(DenimResponse::KeysReset(
+                account_id,
+                reset.device_id.into(),
+            )));
+        }
         MessageKind::Error(error) => {
-            let account_id = AccountId::try_from(error.account_id().to_vec())
+            // errors about an account are failed key requests for that account
+            let Some(account_id) = error.account_id else {
+                return Ok(Some(DenimResponse::ProxyError(error.error)));
+            };
+            let account_id = AccountId::try_from(account_id)
                 .map_err(|_| MessageProcessingError::MalformedMessage)?;
-            return Err(MessageProcessingError::ServerError(format!(
-                "AccountId '{}', error '{}'",
-                account_id, error.error
+            return Ok(Some(DenimResponse::KeyRequestFailed(
+                account_id,
+                error.error,
             )));
         }
         _ => Err(MessageProcessingError::MalformedMessage)?,
@@ -78,24 +115,46 @@ async fn handle_key_response<R: Rng + CryptoRng>(
         .map_err(|_| MessageProcessingError::MalformedMessage)?;
     let id_key = IdentityKey::decode(&response.identity_key)
         .inspect_err(|err| error!("Failed to decode Identity key from keybundle: {err}"))?;
-    let device_id = response.key_bundle.device_id;
+    if response.key_bundles.is_empty() {
+        debug!("Key response for '{account_id}' contains no key bundles");
+        return Err(MessageProcessingError::MalformedMessage);
+    }
 
-    let signal_bundle = into_libsignal_bundle(&id_key, response.key_bundle)?;
-    let addr = ProtocolAddress::new(account_id.to_string(), device_id.into());
-    process_prekey_bundle(
-        &addr,
-        &mut deniable_store.session_store,
-        &mut store.identity_key_store,
-        &signal_bundle,
-        SystemTime::now(),
-        rng,
-    )
-    .await?;
+    // a session is needed with every device of the account
+    for key_bundle in response.key_bundles {
+        let device_id = key_bundle.device_id;
 
-    deniable_store
-        .contact_store
-        .add_device(account_id, device_id.into())
+        let signal_bundle = into_libsignal_bundle(&id_key, key_bundle)?;
+        let addr = ProtocolAddress::new(account_id.to_string(), device_id.into());
+        process_prekey_bundle(
+            &addr,
+            &mut deniable_store.session_store,
+            &mut store.identity_key_store,
+            &signal_bundle,
+            SystemTime::now(),
+            rng,
+        )
         .await?;
 
+        deniable_store
+            .contact_store
+            .add_device(account_id, device_id.into())
+            .await?;
+    }
+
     Ok(DenimResponse::KeyResponse(account_id))
 }
+
+fn handle_block_list_response(
+    response: BlockListResponse,
+) -> Result<DenimResponse, MessageProcessingError> {
+    let blocked_users = response
+        .account_ids
+        .into_iter()
+        .map(AccountId::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .inspect_err(|e| debug!("{e}"))
+        .map_err(|_| MessageProcessingError::MalformedMessage)?;
+
+    Ok(DenimResponse::BlockListResponse(blocked_users))
+}
@@ -6,8 +6,14 @@ use std::{
 use async_trait::async_trait;
 use futures_util::lock::Mutex;
 use sam_common::AccountId;
+use sqlx::SqlitePool;
 
-use super::traits::{MessageQueue, MessageQueueConfig};
+use crate::store::cipher::StoreCipher;
+
+use super::{
+    error::MessageQueueError,
+    traits::{MessageQueue, MessageQueueConfig},
+};
 
 type Messages = HashMap<AccountId, VecDeque<Vec<u8>>>;
 #[derive(Default)]
@@ -21,8 +27,8 @@ pub struct InMemoryMessageQueueConfig;
 #[async_trait]
 impl MessageQueueConfig for InMemoryMessageQueueConfig {
     type MessageQueue = InMemoryMessageQueue;
-    async fn create(self) -> Self::MessageQueue {
-        InMemoryMessageQueue::default()
+    async fn create(self) -> Result<Self::MessageQueue, MessageQueueError> {
+        Ok(InMemoryMessageQueue::default())
     }
 }
 
@@ -32,34 +38,296 @@ fn create_bucket(messages: &mut Messages, account_id: AccountId) -> &mut VecDequ
 
 #[async_trait]
 impl MessageQueue for InMemoryMessageQueue {
-    async fn enqueue(&mut self, account_id: AccountId, msg: Vec<u8>) {
+    async fn enqueue(
+        &mut self,
+        account_id: AccountId,
+        msg: Vec<u8>,
+    ) -> Result<(), MessageQueueError> {
+        let mut messages = self.messages.lock().await;
+        create_bucket(&mut messages, account_id).push_back(msg);
+        Ok(())
+    }
+
+    async fn peek(&mut self, account_id: AccountId) -> Result<Option<Vec<u8>>, MessageQueueError> {
+        let mut messages = self.messages.lock().await;
+        Ok(create_bucket(&mut messages, account_id).front().cloned())
+    }
+
+    async fn remove_first(&mut self, account_id: AccountId) -> Result<(), MessageQueueError> {
         let mut messages = self.messages.lock().await;
-        create_bucket(&mut messages, account_id).push_back(msg)
+        create_bucket(&mut messages, account_id).pop_front();
+        Ok(())
     }
 
-    async fn dequeue(&mut self, account_id: AccountId) -> Option<Vec<u8>> {
+    async fn len(&mut self, account_id: AccountId) -> Result<usize, MessageQueueError> {
         let mut messages = self.messages.lock().await;
-        create_bucket(&mut messages, account_id).pop_front()
+        Ok(create_bucket(&mut messages, account_id).len())
     }
 
-    async fn len(&mut self, account_id: AccountId) -> usize {
+    async fn clear(&mut self, account_id: AccountId) -> Result<Vec<Vec<u8>>, MessageQueueError> {
         let mut messages = self.messages.lock().await;
-        create_bucket(&mut messages, account_id).len()
+        Ok(messages
+            .remove(&account_id)
+            .map(Vec::from)
+            .unwrap_or_default())
+    }
+
+    async fn account_ids(&mut self) -> Result<Vec<AccountId>, MessageQueueError> {
+        let messages = self.messages.lock().await;
+        Ok(messages
+            .iter()
+            .filter(|(_, bucket)| !bucket.is_empty())
+            .map(|(account_id, _)| *account_id)
+            .collect())
+    }
+}
+
+const CREATE_WAITING_MESSAGES_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS DenimWaitingMessages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        account_id BLOB NOT NULL,
+        message BLOB NOT NULL
+    )";
+
+/// Message queue that keeps messages waiting for keys in SQLite,
+/// so they survive a restart of the client. Messages are encrypted with `cipher`.
+pub struct SqliteMessageQueue {
+    pool: SqlitePool,
+    cipher: StoreCipher,
+}
+
+pub struct SqliteMessageQueueConfig {
+    pool: SqlitePool,
+    cipher: StoreCipher,
+}
+
+impl SqliteMessageQueueConfig {
+    pub fn new(pool: SqlitePool, cipher: StoreCipher) -> Self {
+        Self { pool, cipher }
+    }
+}
+
+#[async_trait]
+impl MessageQueueConfig for SqliteMessageQueueConfig {
+    type MessageQueue = SqliteMessageQueue;
+    async fn create(self) -> Result<Self::MessageQueue, MessageQueueError> {
+        sqlx::query(CREATE_WAITING_MESSAGES_TABLE)
+            .execute(&self.pool)
+            .await?;
+        Ok(SqliteMessageQueue {
+            pool: self.pool,
+            cipher: self.cipher,
+        })
+    }
+}
+
+impl SqliteMessageQueue {
+    // binding the recipient prevents moving messages to another recipient
+    fn decrypt(&self, account_id: AccountId, bytes: &[u8]) -> Result<Vec<u8>, MessageQueueError> {
+        Ok(self.cipher.decrypt(&account_id.to_string(), bytes)?)
+    }
+}
+
+#[async_trait]
+impl MessageQueue for SqliteMessageQueue {
+    async fn enqueue(
+        &mut self,
+        account_id: AccountId,
+        msg: Vec<u8>,
+    ) -> Result<(), MessageQueueError> {
+        sqlx::query("INSERT INTO DenimWaitingMessages (account_id, message) VALUES (?, ?)")
+            .bind(Vec::<u8>::from(account_id))
+            .bind(self.cipher.encrypt(&account_id.to_string(), &msg)?)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn peek(&mut self, account_id: AccountId) -> Result<Option<Vec<u8>>, MessageQueueError> {
+        let message: Option<Vec<u8>> = sqlx::query_scalar(
+            "SELECT message FROM DenimWaitingMessages WHERE account_id = ? ORDER BY id LIMIT 1",
+        )
+        .bind(Vec::<u8>::from(account_id))
+        .fetch_optional(&self.pool)
+        .await?;
+        message
+            .map(|message| self.decrypt(account_id, &message))
+            .transpose()
+    }
+
+    async fn remove_first(&mut self, account_id: AccountId) -> Result<(), MessageQueueError> {
+        sqlx::query(
+            "DELETE FROM DenimWaitingMessages
+             WHERE id = (SELECT MIN(id) FROM DenimWaitingMessages WHERE account_id = ?)",
+        )
+        .bind(Vec::<u8>::from(account_id))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn len(&mut self, account_id: AccountId) -> Result<usize, MessageQueueError> {
+        let len: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM DenimWaitingMessages WHERE account_id = ?")
+                .bind(Vec::<u8>::from(account_id))
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(len as usize)
+    }
+
+    async fn clear(&mut self, account_id: AccountId) -> Result<Vec<Vec<u8>>, MessageQueueError> {
+        let mut tx = self.pool.begin().await?;
+        let messages: Vec<Vec<u8>> = sqlx::query_scalar(
+            "SELECT message FROM DenimWaitingMessages WHERE account_id = ? ORDER BY id",
+        )
+        .bind(Vec::<u8>::from(account_id))
+        .fetch_all(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM DenimWaitingMessages WHERE account_id = ?")
+            .bind(Vec::<u8>::from(account_id))
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        messages
+            .iter()
+            .map(|message| self.decrypt(account_id, message))
+            .collect()
+    }
+
+    async fn account_ids(&mut self) -> Result<Vec<AccountId>, MessageQueueError> {
+        let account_ids: Vec<Vec<u8>> =
+            sqlx::query_scalar("SELECT DISTINCT account_id FROM DenimWaitingMessages")
+                .fetch_all(&self.pool)
+                .await?;
+        account_ids
+            .into_iter()
+            .map(|account_id| {
+                AccountId::try_from(account_id).map_err(|_| MessageQueueError::InvalidAccountId)
+            })
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod test {
+    use sam_client::storage::sqlite::sqlite_connector::SqliteConnector;
     use sam_common::AccountId;
 
-    use crate::message::{queue::InMemoryMessageQueue, traits::MessageQueue};
+    use crate::{
+        message::{
+            error::MessageQueueError,
+            queue::{InMemoryMessageQueue, SqliteMessageQueueConfig},
+            traits::{MessageQueue, MessageQueueConfig},
+        },
+        store::cipher::StoreCipher,
+    };
+
+    #[tokio::test]
+    async fn inmem_msg_queue_peek_remove() {
+        let mut msg_q = InMemoryMessageQueue::default();
+        let account_id = AccountId::generate();
+        msg_q
+            .enqueue(account_id, vec![1, 3, 3, 7])
+            .await
+            .expect("Can enqueue");
+        let msg = msg_q
+            .peek(account_id)
+            .await
+            .expect("Can peek")
+            .expect("is some");
+        assert!(msg == vec![1, 3, 3, 7]);
+        assert_eq!(msg_q.len(account_id).await.expect("Can get len"), 1);
+        msg_q.remove_first(account_id).await.expect("Can remove");
+        assert_eq!(msg_q.peek(account_id).await.expect("Can peek"), None)
+    }
 
     #[tokio::test]
-    async fn inmem_msg_queue_enqueue_dequeue() {
+    async fn inmem_msg_queue_clear() {
         let mut msg_q = InMemoryMessageQueue::default();
         let account_id = AccountId::generate();
-        msg_q.enqueue(account_id, vec![1, 3, 3, 7]).await;
-        let msg = msg_q.dequeue(account_id).await.expect("is some");
-        assert!(msg == vec![1, 3, 3, 7])
+        msg_q
+            .enqueue(account_id, vec![1])
+            .await
+            .expect("Can enqueue");
+        msg_q
+            .enqueue(account_id, vec![2])
+            .await
+            .expect("Can enqueue");
+        assert_eq!(
+            msg_q.clear(account_id).await.expect("Can clear"),
+            vec![vec![1], vec![2]]
+        );
+        assert_eq!(msg_q.len(account_id).await.expect("Can get len"), 0);
+    }
+
+    #[tokio::test]
+    async fn sqlite_msg_queue_survives_reopening() {
+        let pool = SqliteConnector::migrate("sqlite::memory:")
+            .await
+            .expect("Can open database")
+            .pool();
+        let alice = AccountId::generate();
+        let bob = AccountId::generate();
+
+        let mut msg_q = SqliteMessageQueueConfig::new(pool.clone(), StoreCipher::new([7; 32]))
+            .create()
+            .await
+            .expect("Can create queue");
+        msg_q.enqueue(alice, vec![1]).await.expect("Can enqueue");
+        msg_q.enqueue(bob, vec![2]).await.expect("Can enqueue");
+        msg_q.enqueue(alice, vec![3]).await.expect("Can enqueue");
+        drop(msg_q);
+
+        let mut msg_q = SqliteMessageQueueConfig::new(pool, StoreCipher::new([7; 32]))
+            .create()
+            .await
+            .expect("Can reopen queue");
+        let mut account_ids = msg_q.account_ids().await.expect("Can get account ids");
+        account_ids.sort_by_key(|account_id| Vec::<u8>::from(*account_id));
+        let mut expected = vec![alice, bob];
+        expected.sort_by_key(|account_id| Vec::<u8>::from(*account_id));
+        assert_eq!(account_ids, expected);
+
+        assert_eq!(msg_q.len(alice).await.expect("Can get len"), 2);
+        assert_eq!(msg_q.peek(alice).await.expect("Can peek"), Some(vec![1]));
+        msg_q.remove_first(alice).await.expect("Can remove");
+        assert_eq!(msg_q.clear(alice).await.expect("Can clear"), vec![vec![3]]);
+        assert_eq!(msg_q.peek(alice).await.expect("Can peek"), None);
+        assert_eq!(msg_q.len(bob).await.expect("Can get len"), 1);
+    }
+
+    #[tokio::test]
+    async fn sqlite_msg_queue_encrypts_messages() {
+        let pool = SqliteConnector::migrate("sqlite::memory:")
+            .await
+            .expect("Can open database")
+            .pool();
+        let alice = AccountId::generate();
+
+        let mut msg_q = SqliteMessageQueueConfig::new(pool.clone(), StoreCipher::new([7; 32]))
+            .create()
+            .await
+            .expect("Can create queue");
+        msg_q
+            .enqueue(alice, b"deniable".to_vec())
+            .await
+            .expect("Can enqueue");
+
+        let stored: Vec<u8> = sqlx::query_scalar("SELECT message FROM DenimWaitingMessages")
+            .fetch_one(&pool)
+            .await
+            .expect("Can read row");
+        assert!(!stored
+            .windows(b"deniable".len())
+            .any(|window| window == b"deniable"));
+
+        let mut msg_q = SqliteMessageQueueConfig::new(pool, StoreCipher::new([8; 32]))
+            .create()
+            .await
+            .expect("Can reopen queue");
+        assert!(matches!(
+            msg_q.peek(alice).await,
+            Err(MessageQueueError::Encryption(_))
+        ));
     }
 }
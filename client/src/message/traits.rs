@@ -1,16 +1,61 @@
 use async_trait::async_trait;
 use sam_common::AccountId;
 
+use crate::protocol::SamDenimMessage;
+
+use super::{
+    dead_letter::DeadLetter,
+    error::{DeadLetterError, MessageQueueError},
+};
+
 #[async_trait]
 pub trait MessageQueue {
-    async fn enqueue(&mut self, account_id: AccountId, msg: Vec<u8>);
-    async fn dequeue(&mut self, account_id: AccountId) -> Option<Vec<u8>>;
-    async fn len(&mut self, account_id: AccountId) -> usize;
+    async fn enqueue(
+        &mut self,
+        account_id: AccountId,
+        msg: Vec<u8>,
+    ) -> Result<(), MessageQueueError>;
+    /// Oldest message waiting for `account_id`, it stays queued until `remove_first`.
+    async fn peek(&mut self, account_id: AccountId) -> Result<Option<Vec<u8>>, MessageQueueError>;
+    /// Removes the oldest message waiting for `account_id`.
+    async fn remove_first(&mut self, account_id: AccountId) -> Result<(), MessageQueueError>;
+    async fn len(&mut self, account_id: AccountId) -> Result<usize, MessageQueueError>;
+    /// Removes all messages waiting for `account_id` and returns them.
+    async fn clear(&mut self, account_id: AccountId) -> Result<Vec<Vec<u8>>, MessageQueueError>;
+    /// Accounts that have messages waiting.
+    async fn account_ids(&mut self) -> Result<Vec<AccountId>, MessageQueueError>;
 }
 
 #[async_trait]
 pub trait MessageQueueConfig {
     type MessageQueue: MessageQueue;
 
-    async fn create(self) -> Self::MessageQueue;
+    async fn create(self) -> Result<Self::MessageQueue, MessageQueueError>;
+}
+
+/// Envelopes that failed processing, oldest first.
+#[async_trait]
+pub trait DeadLetterQueue {
+    async fn push(
+        &mut self,
+        envelope: SamDenimMessage,
+        error: String,
+    ) -> Result<DeadLetter, DeadLetterError>;
+    /// Records another failed attempt for letter `id` and moves it to the back.
+    async fn fail_retry(
+        &mut self,
+        id: u64,
+        error: String,
+    ) -> Result<Option<DeadLetter>, DeadLetterError>;
+    /// Removes the oldest letters beyond the capacity of the queue and returns them.
+    async fn drop_overflow(&mut self) -> Result<Vec<DeadLetter>, DeadLetterError>;
+    async fn letters(&mut self) -> Result<Vec<DeadLetter>, DeadLetterError>;
+    async fn remove(&mut self, id: u64) -> Result<Option<DeadLetter>, DeadLetterError>;
+}
+
+#[async_trait]
+pub trait DeadLetterQueueConfig {
+    type DeadLetterQueue: DeadLetterQueue;
+
+    async fn create(self) -> Result<Self::DeadLetterQueue, DeadLetterError>;
 }
@@ -1,23 +1,32 @@
-use std::sync::{
-    atomic::{AtomicU32, Ordering},
-    Arc,
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use denim_sam_common::{
     buffers::{ReceivingBuffer, SendingBuffer},
     denim_message::{deniable_message::MessageKind, DeniableMessage},
 };
-use log::{debug, error};
+use log::{debug, error, info, warn};
 
 use prost::Message as PMessage;
-use sam_client::net::protocol::{decode::ServerStatus, MessageStatus};
+use rand::Rng;
+use sam_client::net::protocol::MessageStatus;
 use sam_common::{
     address::MessageId,
     sam_message::{ClientEnvelope, ClientMessage, ClientMessageType},
 };
 use sam_net::{error::WebSocketError, websocket::WebSocketClient};
-use tokio::sync::{mpsc::channel, oneshot::Receiver as OneshotReceiver};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc::{channel, error::TryRecvError, Sender},
+    oneshot::Receiver as OneshotReceiver,
+};
 use tokio::sync::{mpsc::Receiver, Mutex};
+use tokio::task::JoinHandle;
 use tokio_tungstenite::tungstenite::{
     protocol::{frame::coding::CloseCode, CloseFrame},
     Message,
@@ -25,65 +34,133 @@ use tokio_tungstenite::tungstenite::{
 
 use crate::{
     error::DenimProtocolError,
-    message::create_message,
-    protocol::{DenimReceiver, SamDenimMessage},
+    message::{
+        cover::{CoverMessage, CoverTraffic},
+        create_message,
+        error::MessageError,
+        event::emit,
+    },
+    protocol::{DenimReceiver, PendingResponses, ProtocolEvent, SamDenimMessage},
 };
 
+const PROTOCOL_EVENT_CAPACITY: usize = 16;
+
 #[async_trait::async_trait]
 pub trait DenimSamClient {
     async fn connect(&mut self) -> Result<Receiver<SamDenimMessage>, DenimProtocolError>;
     async fn disconnect(&mut self) -> Result<(), DenimProtocolError>;
     async fn is_connected(&self) -> bool;
-    async fn enqueue_deniable(&mut self, message: MessageKind);
+    /// Returns a broadcast receiver for changes in the connection to the proxy.
+    fn subscribe(&self) -> broadcast::Receiver<ProtocolEvent>;
+    async fn enqueue_deniable(&mut self, message: MessageKind) -> Result<(), DenimProtocolError>;
+    /// Like `enqueue_deniable`, but replaces a queued message of the same kind
+    /// that has not been sent yet.
+    async fn replace_deniable(&mut self, message: MessageKind) -> Result<(), DenimProtocolError>;
+    /// Sends a regular message and waits for the server's response to it.
+    /// Several messages can be in flight at once, responses are matched by message id.
     async fn send_message(
-        &mut self,
+        &self,
         message: ClientEnvelope,
     ) -> Result<MessageStatus, DenimProtocolError>;
+    /// Sends the cover messages from `messages` on the schedule of `cover` until
+    /// `stop_cover_traffic`, replacing cover traffic that was started before.
+    fn start_cover_traffic(&mut self, cover: CoverTraffic, messages: Receiver<CoverMessage>);
+    fn stop_cover_traffic(&mut self);
 }
 
-pub struct DenimProtocolClient<T: SendingBuffer, U: ReceivingBuffer> {
+/// How to reconnect when the connection to the proxy drops.
+/// The delay doubles with every attempt up to `max_backoff`, with up to half of it as jitter.
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after this many failed attempts, `None` to never give up.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    fn delay(&self, attempt: u32, rng: &mut impl Rng) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        rng.gen_range(backoff / 2..=backoff)
+    }
+}
+
+/// How long to wait for the proxy and server before giving up on a request.
+#[derive(Clone, Copy, Debug)]
+pub struct ProtocolTimeouts {
+    /// Wait for the first `QStatus` of a connection before sending.
+    pub qstatus: Duration,
+    /// Wait for the server to answer a sent message.
+    pub response: Duration,
+}
+
+impl Default for ProtocolTimeouts {
+    fn default() -> Self {
+        Self {
+            qstatus: Duration::from_secs(10),
+            response: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The parts of a client shared with the tasks that reconnect it and send cover traffic.
+struct Connection<T: SendingBuffer, U: ReceivingBuffer> {
     client: Arc<Mutex<WebSocketClient>>,
-    status_messages: Option<Receiver<ServerStatus>>,
+    pending: PendingResponses,
+    qstatus_received: Arc<Mutex<Option<OneshotReceiver<()>>>>,
     channel_buffer_size: usize,
     sending_buffer: T,
     receiving_buffer: U,
-    denim_id: AtomicU32,
-    qstatus_received: Option<OneshotReceiver<()>>,
+    denim_id: Arc<AtomicU32>,
+    events: broadcast::Sender<ProtocolEvent>,
 }
 
-impl<T: SendingBuffer, U: ReceivingBuffer> DenimProtocolClient<T, U> {
-    pub fn new(
-        client: WebSocketClient,
-        channel_buffer_size: usize,
-        sending_buffer: T,
-        receiving_buffer: U,
-    ) -> Self {
+impl<T: SendingBuffer, U: ReceivingBuffer> Clone for Connection<T, U> {
+    fn clone(&self) -> Self {
         Self {
-            client: Arc::new(Mutex::new(client)),
-            status_messages: None,
-            channel_buffer_size,
-            sending_buffer,
-            receiving_buffer,
-            denim_id: AtomicU32::new(0),
-            qstatus_received: None,
+            client: self.client.clone(),
+            pending: self.pending.clone(),
+            qstatus_received: self.qstatus_received.clone(),
+            channel_buffer_size: self.channel_buffer_size,
+            sending_buffer: self.sending_buffer.clone(),
+            receiving_buffer: self.receiving_buffer.clone(),
+            denim_id: self.denim_id.clone(),
+            events: self.events.clone(),
         }
     }
 }
 
-#[async_trait::async_trait]
-impl<T: SendingBuffer, U: ReceivingBuffer> DenimSamClient for DenimProtocolClient<T, U> {
-    async fn connect(&mut self) -> Result<Receiver<SamDenimMessage>, DenimProtocolError> {
-        let (status_tx, status_rx) = channel(self.channel_buffer_size);
-        self.status_messages = Some(status_rx);
-        let (tx, rx) = channel(self.channel_buffer_size);
+impl<T: SendingBuffer, U: ReceivingBuffer> Connection<T, U> {
+    /// Connects to the proxy, delivering received messages to `enqueue_message`.
+    async fn open(
+        &self,
+        enqueue_message: Sender<SamDenimMessage>,
+    ) -> Result<(), DenimProtocolError> {
         let mut handler = DenimReceiver::new(
             self.client.clone(),
-            status_tx,
-            tx,
+            self.pending.clone(),
+            enqueue_message,
+            self.events.clone(),
             self.sending_buffer.clone(),
             self.receiving_buffer.clone(),
         );
-        self.qstatus_received = handler.take_qstatus_receiver();
+        // messages sent on the previous connection will never be answered
+        self.pending.clear().await;
+        *self.qstatus_received.lock().await = handler.take_qstatus_receiver();
 
         self.client
             .lock()
@@ -91,13 +168,236 @@ impl<T: SendingBuffer, U: ReceivingBuffer> DenimSamClient for DenimProtocolClien
             .connect(handler)
             .await
             .inspect_err(|e| error!("DenimProtocolClient Error: {e}"))
-            .map_err(DenimProtocolError::WebSocketError)?;
+            .map_err(DenimProtocolError::WebSocketError)
+    }
+
+    /// Sends a message with as much deniable payload as it can carry.
+    async fn send(&self, message: ClientMessage) -> Result<(), DenimProtocolError> {
+        // chunks must go out in the order they were taken from the buffer
+        let mut client = self.client.lock().await;
+        let msg = create_message(&mut self.sending_buffer.clone(), message).await?;
+        client
+            .send(Message::Binary(msg.encode_to_vec().into()))
+            .await
+            .map_err(DenimProtocolError::WebSocketError)
+    }
+
+    /// Sends a regular message and waits for the server's response to it.
+    async fn send_message(
+        &self,
+        timeouts: ProtocolTimeouts,
+        message: ClientEnvelope,
+    ) -> Result<MessageStatus, DenimProtocolError> {
+        let mut qstatus_received = self.qstatus_received.lock().await;
+        if let Some(receiver) = qstatus_received.as_mut() {
+            // the receiver is kept on timeout, so the next message waits for the QStatus again
+            let res = tokio::time::timeout(timeouts.qstatus, receiver)
+                .await
+                .map_err(|_| DenimProtocolError::QStatusTimeout)?;
+            *qstatus_received = None;
+            match res.inspect_err(|e| debug!("{}", e)) {
+                Ok(_) => (),
+                Err(_) => Err(DenimProtocolError::FailedToReceiveQStatus)?,
+            }
+        }
+        drop(qstatus_received);
+        let id = MessageId::generate();
+        let message = ClientMessage::builder()
+            .message(message)
+            .r#type(ClientMessageType::ClientMessage.into())
+            .id(id.into())
+            .build();
+
+        // register before sending, the response may arrive before the send returns
+        let response = self.pending.register(id).await;
+        if let Err(e) = self.send(message).await {
+            self.pending.cancel(id).await;
+            return Err(e);
+        }
+
+        let response = match tokio::time::timeout(timeouts.response, response).await {
+            Ok(response) => response
+                .map_err(|_| DenimProtocolError::WebSocketError(WebSocketError::Disconnected))?,
+            Err(_) => {
+                self.pending.cancel(id).await;
+                return Err(DenimProtocolError::ResponseTimeout);
+            }
+        };
+        response
+            .validate(id)?
+            .ok_or(DenimProtocolError::ReceivedWrongResponseId)
+    }
+
+    /// Sends the prepared cover messages on schedule until `messages` closes.
+    async fn send_cover_traffic(
+        self,
+        timeouts: ProtocolTimeouts,
+        mut cover: CoverTraffic,
+        mut messages: Receiver<CoverMessage>,
+    ) {
+        let mut next = None;
+        loop {
+            tokio::time::sleep_until(cover.next_at().into()).await;
+            let now = Instant::now();
+            if !cover.poll(now, &mut rand::thread_rng()) {
+                continue;
+            }
+            let message = match next.take() {
+                Some(message) => message,
+                None => match messages.try_recv() {
+                    Ok(message) => message,
+                    Err(TryRecvError::Empty) => {
+                        debug!("No cover message is prepared");
+                        self.emit(ProtocolEvent::CoverMessagesExhausted);
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => return,
+                },
+            };
+            if !cover.spend(now, message.size) {
+                debug!("Cover traffic budget is used up");
+                next = Some(message);
+                continue;
+            }
+            if let Err(e) = self.send_message(timeouts, message.envelope).await {
+                warn!("Failed to send cover message '{e}'");
+            }
+        }
+    }
+
+    fn next_denim_id(&self) -> u32 {
+        self.denim_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn emit(&self, event: ProtocolEvent) {
+        emit(&self.events, event);
+    }
+
+    /// Reconnects every time the connection drops, until the task is aborted.
+    async fn reconnect_on_disconnect(
+        self,
+        enqueue_message: Sender<SamDenimMessage>,
+        policy: ReconnectPolicy,
+        mut events: broadcast::Receiver<ProtocolEvent>,
+    ) {
+        loop {
+            match events.recv().await {
+                Ok(ProtocolEvent::Disconnected) => (),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            }
+
+            // chunks of the interrupted message may have been lost with the connection,
+            // it is restarted once so failed attempts do not use up message ids
+            let restarted_message = self
+                .sending_buffer
+                .clone()
+                .restart_current(self.next_denim_id())
+                .await;
+            let mut attempt = 0;
+            loop {
+                attempt += 1;
+                if policy.max_attempts.is_some_and(|max| attempt > max) {
+                    error!("Giving up reconnecting after {} attempts", attempt - 1);
+                    return;
+                }
+                self.emit(ProtocolEvent::Reconnecting(attempt));
+                // the rng is not held across the sleep, the task has to be Send
+                let delay = policy.delay(attempt, &mut rand::thread_rng());
+                tokio::time::sleep(delay).await;
+
+                match self.open(enqueue_message.clone()).await {
+                    Ok(()) => {
+                        info!("Reconnected after {attempt} attempts");
+                        self.emit(ProtocolEvent::Reconnected { restarted_message });
+                        break;
+                    }
+                    Err(e) => warn!("Reconnect attempt {attempt} failed '{e}'"),
+                }
+            }
+        }
+    }
+}
+
+pub struct DenimProtocolClient<T: SendingBuffer, U: ReceivingBuffer> {
+    connection: Connection<T, U>,
+    timeouts: ProtocolTimeouts,
+    reconnect: Option<ReconnectPolicy>,
+    reconnect_task: Option<JoinHandle<()>>,
+    cover_task: Option<JoinHandle<()>>,
+}
+
+impl<T: SendingBuffer, U: ReceivingBuffer> DenimProtocolClient<T, U> {
+    pub fn new(
+        client: WebSocketClient,
+        channel_buffer_size: usize,
+        sending_buffer: T,
+        receiving_buffer: U,
+        timeouts: ProtocolTimeouts,
+        reconnect: Option<ReconnectPolicy>,
+    ) -> Self {
+        Self {
+            connection: Connection {
+                client: Arc::new(Mutex::new(client)),
+                pending: PendingResponses::default(),
+                qstatus_received: Arc::new(Mutex::new(None)),
+                channel_buffer_size,
+                sending_buffer,
+                receiving_buffer,
+                denim_id: Arc::new(AtomicU32::new(0)),
+                events: broadcast::channel(PROTOCOL_EVENT_CAPACITY).0,
+            },
+            timeouts,
+            reconnect,
+            reconnect_task: None,
+            cover_task: None,
+        }
+    }
+
+    fn stop_reconnecting(&mut self) {
+        if let Some(task) = self.reconnect_task.take() {
+            task.abort();
+        }
+    }
+}
+
+impl<T: SendingBuffer, U: ReceivingBuffer> Drop for DenimProtocolClient<T, U> {
+    fn drop(&mut self) {
+        self.stop_reconnecting();
+        self.stop_cover_traffic();
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: SendingBuffer, U: ReceivingBuffer> DenimSamClient for DenimProtocolClient<T, U> {
+    async fn connect(&mut self) -> Result<Receiver<SamDenimMessage>, DenimProtocolError> {
+        self.stop_reconnecting();
+        let (tx, rx) = channel(self.connection.channel_buffer_size);
+        // messages restored by the sending buffer keep their ids
+        let next_id = self.connection.sending_buffer.next_message_id().await;
+        self.connection
+            .denim_id
+            .fetch_max(next_id, Ordering::Relaxed);
+        // subscribe before connecting so no disconnect is missed
+        let events = self.connection.events.subscribe();
+        self.connection.open(tx.clone()).await?;
+
+        if let Some(policy) = self.reconnect {
+            self.reconnect_task = Some(tokio::spawn(
+                self.connection
+                    .clone()
+                    .reconnect_on_disconnect(tx, policy, events),
+            ));
+        }
+        self.connection.emit(ProtocolEvent::Connected);
         Ok(rx)
     }
 
     async fn disconnect(&mut self) -> Result<(), DenimProtocolError> {
-        self.status_messages = None;
-        self.client
+        self.stop_reconnecting();
+        self.connection.pending.clear().await;
+        self.connection
+            .client
             .lock()
             .await
             .send(Message::Close(Some(CloseFrame {
@@ -109,92 +409,79 @@ impl<T: SendingBuffer, U: ReceivingBuffer> DenimSamClient for DenimProtocolClien
     }
 
     async fn is_connected(&self) -> bool {
-        self.client.lock().await.is_connected()
+        self.connection.client.lock().await.is_connected()
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<ProtocolEvent> {
+        self.connection.events.subscribe()
     }
 
-    async fn enqueue_deniable(&mut self, message: MessageKind) {
+    async fn enqueue_deniable(&mut self, message: MessageKind) -> Result<(), DenimProtocolError> {
         debug!("Enqueued {}", message);
-        self.sending_buffer
+        self.connection
+            .sending_buffer
             .enqueue_message(
                 DeniableMessage::builder()
-                    .message_id(self.denim_id.fetch_add(1, Ordering::Relaxed))
+                    .message_id(self.connection.next_denim_id())
                     .message_kind(message)
                     .build(),
             )
             .await
+            .map_err(MessageError::from)?;
+        Ok(())
+    }
+
+    async fn replace_deniable(&mut self, message: MessageKind) -> Result<(), DenimProtocolError> {
+        debug!("Enqueued {} in place of an unsent one", message);
+        self.connection
+            .sending_buffer
+            .replace_message(
+                DeniableMessage::builder()
+                    .message_id(self.connection.next_denim_id())
+                    .message_kind(message)
+                    .build(),
+            )
+            .await
+            .map_err(MessageError::from)?;
+        Ok(())
     }
 
     async fn send_message(
-        &mut self,
+        &self,
         message: ClientEnvelope,
     ) -> Result<MessageStatus, DenimProtocolError> {
-        let res = match self.qstatus_received.take() {
-            Some(receiver) => receiver.await,
-            None => Ok(()),
-        };
-        match res.inspect_err(|e| debug!("{}", e)) {
-            Ok(_) => (),
-            Err(_) => Err(DenimProtocolError::FailedToReceiveQStatus)?,
-        }
-        let id = MessageId::generate();
-        // Implement the logic to send a message here
-        let message = ClientMessage::builder()
-            .message(message)
-            .r#type(ClientMessageType::ClientMessage.into())
-            .id(id.into())
-            .build();
-        let msg = create_message(&mut self.sending_buffer, message).await?;
-        self.client
-            .lock()
-            .await
-            .send(Message::Binary(msg.encode_to_vec().into()))
-            .await
-            .map_err(DenimProtocolError::WebSocketError)?;
+        self.connection.send_message(self.timeouts, message).await
+    }
 
-        let response = match &mut self.status_messages {
-            // Client can only send one message at a time, and receive a response to that message
-            // This means that the next status in the queue is always for the current message
-            Some(status) => status
-                .recv()
-                .await
-                .ok_or(DenimProtocolError::WebSocketError(
-                    WebSocketError::Disconnected,
-                )),
-            None => Err(DenimProtocolError::WebSocketError(
-                WebSocketError::Disconnected,
-            )),
-        }?;
-
-        match response.validate(id)? {
-            Some(status) => Ok(status),
-            None => {
-                let res = self
-                    .client
-                    .lock()
-                    .await
-                    .send(Message::Close(Some(CloseFrame {
-                        code: CloseCode::Error,
-                        reason: "Request and Response Id did not match".into(),
-                    })))
-                    .await;
-                match res {
-                    Ok(()) => Err(DenimProtocolError::ReceivedWrongResponseId),
-                    Err(err) => Err(DenimProtocolError::WebSocketError(err)),
-                }
-            }
+    fn start_cover_traffic(&mut self, cover: CoverTraffic, messages: Receiver<CoverMessage>) {
+        self.stop_cover_traffic();
+        self.cover_task = Some(tokio::spawn(self.connection.clone().send_cover_traffic(
+            self.timeouts,
+            cover,
+            messages,
+        )));
+    }
+
+    fn stop_cover_traffic(&mut self) {
+        if let Some(task) = self.cover_task.take() {
+            task.abort();
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
     use crate::{
-        protocol::denim_client::{DenimProtocolClient, DenimSamClient},
+        error::DenimProtocolError,
+        message::cover::{CoverMessage, CoverTiming, CoverTraffic, CoverTrafficPolicy},
+        protocol::denim_client::{
+            DenimProtocolClient, DenimSamClient, ProtocolTimeouts, ReconnectPolicy,
+        },
         protocol::{
             receiver::test::{get_payload, make_user_message},
-            SamDenimMessage,
+            ProtocolEvent, SamDenimMessage,
         },
     };
     use denim_sam_common::{
@@ -220,7 +507,7 @@ mod test {
     use sam_test_utils::get_next_port;
     use tokio::{
         net::{TcpListener, TcpStream},
-        sync::mpsc::Receiver as MpscReceiver,
+        sync::mpsc::{channel, Receiver as MpscReceiver},
         sync::oneshot::{self, Receiver},
     };
     use tokio_tungstenite::{
@@ -265,6 +552,8 @@ mod test {
             10,
             InMemorySendingBuffer::new(1.0).expect("can create sending buffer"),
             InMemoryReceivingBuffer::default(),
+            ProtocolTimeouts::default(),
+            None,
         );
 
         let mut receiver = client.connect().await.expect("can connect");
@@ -475,7 +764,10 @@ mod test {
                     }
                     ServerAction::SendQStatus => Ok((
                         DenimEnvelope::builder()
-                            .message_kind(MessageKind::Status(QStatus { q: 1.0 }))
+                            .message_kind(MessageKind::Status(QStatus {
+                                q: 1.0,
+                                padding: None,
+                            }))
                             .build(),
                         None,
                     )),
@@ -584,7 +876,10 @@ mod test {
                     actual.push((action, Some(msg), den, false));
                 }
                 ServerAction::RecvDenim | ServerAction::RecvRegular => {
-                    client.enqueue_deniable(make_user_message(10)).await;
+                    client
+                        .enqueue_deniable(make_user_message(10))
+                        .await
+                        .expect("Can enqueue deniable message");
                     let status = client
                         .send_message(client_envelope())
                         .await
@@ -597,4 +892,325 @@ mod test {
         }
         actual
     }
+
+    #[tokio::test]
+    async fn concurrent_sends_are_matched_by_id() {
+        let addr = format!("127.0.0.1:{}", get_next_port());
+        let listener = TcpListener::bind(&addr).await.expect("can bind tcp");
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("can accept tcp");
+            let mut ws_stream = accept_async(stream).await.expect("can accept ws");
+            let mut sending = InMemorySendingBuffer::new(1.0).expect("can create sending buffer");
+            let mut receiving = InMemoryReceivingBuffer::default();
+            let qstatus = DenimEnvelope::builder()
+                .message_kind(MessageKind::Status(QStatus {
+                    q: 1.0,
+                    padding: None,
+                }))
+                .build();
+            ws_stream
+                .send(Message::Binary(qstatus.encode_to_vec().into()))
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                requests.push(
+                    tokio::time::timeout(Duration::from_secs(5), ws_stream.next())
+                        .await
+                        .map_err(|_| "Client failed to send in time".to_string()),
+                );
+            }
+            // answer the last message first
+            for request in requests.into_iter().rev() {
+                let ack = create_server_ack(&mut sending, &mut receiving, false, request).await?;
+                ws_stream
+                    .send(Message::Binary(ack.encode_to_vec().into()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+            Ok::<_, String>(ws_stream)
+        });
+
+        let mut client = DenimProtocolClient::new(
+            WebSocketClientConfig::builder()
+                .url(format!("ws://{}", addr))
+                .build()
+                .into(),
+            10,
+            InMemorySendingBuffer::new(1.0).expect("can create sending buffer"),
+            InMemoryReceivingBuffer::default(),
+            ProtocolTimeouts::default(),
+            None,
+        );
+        let _receiver = client.connect().await.expect("can connect");
+
+        let (first, second) = tokio::join!(
+            client.send_message(client_envelope()),
+            client.send_message(client_envelope())
+        );
+        assert!(matches!(
+            first.expect("first message is answered"),
+            MessageStatus::Ok
+        ));
+        assert!(matches!(
+            second.expect("second message is answered"),
+            MessageStatus::Ok
+        ));
+        server
+            .await
+            .expect("server task completes")
+            .expect("server works");
+    }
+
+    /// Proxy that accepts the connection but never answers a message.
+    async fn silent_server(send_qstatus: bool) -> String {
+        let addr = format!("127.0.0.1:{}", get_next_port());
+        let listener = TcpListener::bind(&addr).await.expect("can bind tcp");
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("can accept tcp");
+            let mut ws_stream = accept_async(stream).await.expect("can accept ws");
+            if send_qstatus {
+                let qstatus = DenimEnvelope::builder()
+                    .message_kind(MessageKind::Status(QStatus {
+                        q: 1.0,
+                        padding: None,
+                    }))
+                    .build();
+                ws_stream
+                    .send(Message::Binary(qstatus.encode_to_vec().into()))
+                    .await
+                    .expect("can send qstatus");
+            }
+            while let Some(Ok(_)) = ws_stream.next().await {}
+        });
+        addr
+    }
+
+    fn client_with_short_timeouts(
+        addr: &str,
+    ) -> DenimProtocolClient<InMemorySendingBuffer, InMemoryReceivingBuffer> {
+        DenimProtocolClient::new(
+            WebSocketClientConfig::builder()
+                .url(format!("ws://{}", addr))
+                .build()
+                .into(),
+            10,
+            InMemorySendingBuffer::new(1.0).expect("can create sending buffer"),
+            InMemoryReceivingBuffer::default(),
+            ProtocolTimeouts {
+                qstatus: Duration::from_millis(200),
+                response: Duration::from_millis(200),
+            },
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn missing_qstatus_times_out() {
+        let addr = silent_server(false).await;
+        let mut client = client_with_short_timeouts(&addr);
+        let _receiver = client.connect().await.expect("can connect");
+
+        let res = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.send_message(client_envelope()),
+        )
+        .await
+        .expect("send does not hang");
+        assert!(matches!(res, Err(DenimProtocolError::QStatusTimeout)));
+    }
+
+    #[tokio::test]
+    async fn missing_response_times_out() {
+        let addr = silent_server(true).await;
+        let mut client = client_with_short_timeouts(&addr);
+        let _receiver = client.connect().await.expect("can connect");
+
+        let res = tokio::time::timeout(
+            Duration::from_secs(5),
+            client.send_message(client_envelope()),
+        )
+        .await
+        .expect("send does not hang");
+        assert!(matches!(res, Err(DenimProtocolError::ResponseTimeout)));
+    }
+
+    #[tokio::test]
+    async fn cover_messages_are_sent_on_schedule_within_budget() {
+        let addr = format!("127.0.0.1:{}", get_next_port());
+        let listener = TcpListener::bind(&addr).await.expect("can bind tcp");
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.map_err(|e| e.to_string())?;
+            let mut ws_stream = accept_async(stream).await.map_err(|e| e.to_string())?;
+            let mut sending = InMemorySendingBuffer::new(1.0).expect("can create sending buffer");
+            let mut receiving = InMemoryReceivingBuffer::default();
+            let qstatus = DenimEnvelope::builder()
+                .message_kind(MessageKind::Status(QStatus {
+                    q: 1.0,
+                    padding: None,
+                }))
+                .build();
+            ws_stream
+                .send(Message::Binary(qstatus.encode_to_vec().into()))
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let mut received = 0;
+            while let Ok(Some(request)) =
+                tokio::time::timeout(Duration::from_secs(1), ws_stream.next()).await
+            {
+                let ack = create_server_ack(&mut sending, &mut receiving, false, Ok(Some(request)))
+                    .await?;
+                ws_stream
+                    .send(Message::Binary(ack.encode_to_vec().into()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                received += 1;
+            }
+            Ok::<_, String>(received)
+        });
+
+        let mut client = DenimProtocolClient::new(
+            WebSocketClientConfig::builder()
+                .url(format!("ws://{}", addr))
+                .build()
+                .into(),
+            10,
+            InMemorySendingBuffer::new(1.0).expect("can create sending buffer"),
+            InMemoryReceivingBuffer::default(),
+            ProtocolTimeouts::default(),
+            None,
+        );
+        let _receiver = client.connect().await.expect("can connect");
+
+        // no deniable messages are queued, the budget covers two of the three messages
+        let (messages, prepared) = channel(3);
+        for _ in 0..3 {
+            messages
+                .send(CoverMessage {
+                    envelope: client_envelope(),
+                    size: 100,
+                })
+                .await
+                .expect("Can prepare cover message");
+        }
+        let policy = CoverTrafficPolicy {
+            timing: CoverTiming::Uniform(Duration::from_millis(10)..=Duration::from_millis(10)),
+            budget: 200,
+            budget_period: Duration::from_secs(60),
+            ..CoverTrafficPolicy::new(AccountId::generate())
+        };
+        client.start_cover_traffic(
+            CoverTraffic::new(policy, Instant::now(), &mut rand::thread_rng()),
+            prepared,
+        );
+
+        let received = server
+            .await
+            .expect("server task completes")
+            .expect("server works");
+        assert_eq!(received, 2);
+        drop(messages);
+    }
+
+    #[tokio::test]
+    async fn interrupted_message_is_resent_after_reconnecting() {
+        let addr = format!("127.0.0.1:{}", get_next_port());
+        let listener = TcpListener::bind(&addr).await.expect("can bind tcp");
+        let server = tokio::spawn(async move {
+            let mut first_chunks = Vec::new();
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.map_err(|e| e.to_string())?;
+                let mut ws_stream = accept_async(stream).await.map_err(|e| e.to_string())?;
+                let qstatus = DenimEnvelope::builder()
+                    .message_kind(MessageKind::Status(QStatus {
+                        q: 1.0,
+                        padding: None,
+                    }))
+                    .build();
+                ws_stream
+                    .send(Message::Binary(qstatus.encode_to_vec().into()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let request = tokio::time::timeout(Duration::from_secs(5), ws_stream.next())
+                    .await
+                    .map_err(|_| "Client failed to send in time".to_string());
+                let msg = unpack_client_msg(request)?;
+                let chunk = msg
+                    .deniable_payload
+                    .denim_chunks()
+                    .first()
+                    .ok_or("Expected a deniable chunk")?;
+                first_chunks.push((chunk.message_id(), chunk.sequence_number()));
+                // the socket drops before the message is answered
+            }
+            Ok::<_, String>(first_chunks)
+        });
+
+        let mut client = DenimProtocolClient::new(
+            WebSocketClientConfig::builder()
+                .url(format!("ws://{}", addr))
+                .build()
+                .into(),
+            10,
+            InMemorySendingBuffer::new(1.0).expect("can create sending buffer"),
+            InMemoryReceivingBuffer::default(),
+            ProtocolTimeouts {
+                qstatus: Duration::from_secs(5),
+                response: Duration::from_millis(200),
+            },
+            Some(ReconnectPolicy {
+                initial_backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(50),
+                max_attempts: None,
+            }),
+        );
+        let mut events = client.subscribe();
+        let _receiver = client.connect().await.expect("can connect");
+
+        // large enough to be split over several regular messages
+        client
+            .enqueue_deniable(make_user_message(1000))
+            .await
+            .expect("Can enqueue deniable message");
+        let _ = client.send_message(client_envelope()).await;
+        let restarted_message = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match events.recv().await.expect("Can receive event") {
+                    ProtocolEvent::Reconnected { restarted_message } => break restarted_message,
+                    _ => continue,
+                }
+            }
+        })
+        .await
+        .expect("client reconnects");
+        let _ = client.send_message(client_envelope()).await;
+
+        let first_chunks = server
+            .await
+            .expect("server task completes")
+            .expect("server works");
+        let (interrupted, resent) = (first_chunks[0], first_chunks[1]);
+        assert_eq!(interrupted.1, 0);
+        assert_eq!(restarted_message, Some(interrupted.0));
+        assert_ne!(resent.0, interrupted.0);
+        assert_eq!(resent.1, 0);
+    }
+
+    #[rstest]
+    #[case(1, Duration::from_millis(100))]
+    #[case(3, Duration::from_millis(400))]
+    #[case(10, Duration::from_secs(1))]
+    fn reconnect_delay_backs_off_with_jitter(#[case] attempt: u32, #[case] backoff: Duration) {
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            max_attempts: None,
+        };
+        for _ in 0..10 {
+            let delay = policy.delay(attempt, &mut rand::thread_rng());
+            assert!(backoff / 2 <= delay && delay <= backoff);
+        }
+    }
 }
@@ -1,6 +1,6 @@
 use crate::error::DenimProtocolError;
-use denim_client::{DenimProtocolClient, DenimSamClient};
-use denim_sam_common::buffers::{ReceivingBuffer, SendingBuffer};
+use denim_client::{DenimProtocolClient, DenimSamClient, ProtocolTimeouts, ReconnectPolicy};
+use denim_sam_common::buffers::{ReceivingBuffer, SendingBuffer, PADDING_HEADER};
 use log::debug;
 use rustls::ClientConfig;
 use sam_client::net::protocol::{get_ws_auth, get_ws_url_and_connector};
@@ -11,7 +11,7 @@ use tokio_tungstenite::tungstenite::http;
 pub mod denim_client;
 pub mod receiver;
 
-pub use receiver::{DenimReceiver, SamDenimMessage};
+pub use receiver::{DenimReceiver, PendingResponses, ProtocolEvent, SamDenimMessage};
 
 pub struct DenimProtocolClientConfig<T, U> {
     base_url: String,
@@ -19,6 +19,9 @@ pub struct DenimProtocolClientConfig<T, U> {
     channel_buffer_size: usize,
     sending_buffer: T,
     receiving_buffer: U,
+    timeouts: ProtocolTimeouts,
+    reconnect: Option<ReconnectPolicy>,
+    padding: bool,
 }
 
 impl<T: SendingBuffer, U: ReceivingBuffer> DenimProtocolClientConfig<T, U> {
@@ -35,8 +38,29 @@ impl<T: SendingBuffer, U: ReceivingBuffer> DenimProtocolClientConfig<T, U> {
             channel_buffer_size,
             sending_buffer,
             receiving_buffer,
+            timeouts: ProtocolTimeouts::default(),
+            reconnect: None,
+            padding: false,
         }
     }
+
+    pub fn with_timeouts(mut self, timeouts: ProtocolTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Offer the proxy to pad messages to size buckets. Messages are padded
+    /// if the proxy agrees, using the scheme it announces.
+    pub fn with_padding(mut self) -> Self {
+        self.padding = true;
+        self
+    }
+
+    /// Reconnect automatically when the connection to the proxy drops.
+    pub fn with_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
 }
 
 pub trait DenimProtocolConfig {
@@ -61,15 +85,22 @@ impl<T: SendingBuffer, U: ReceivingBuffer> DenimProtocolConfig for DenimProtocol
     ) -> Result<Self::ProtocolClient, DenimProtocolError> {
         let (url, connector) = get_ws_url_and_connector(self.config, self.base_url);
         let basic = get_ws_auth(account_id, device_id, password);
+        let mut headers = vec![(
+            http::header::AUTHORIZATION,
+            http::HeaderValue::from_str(&basic)
+                .inspect_err(|e| debug!("{e}"))
+                .map_err(|_| DenimProtocolError::InvalidCredentials)?,
+        )];
+        if self.padding {
+            headers.push((
+                http::HeaderName::from_static(PADDING_HEADER),
+                http::HeaderValue::from_static("1"),
+            ));
+        }
         let ws_client = WebSocketClientConfig::builder()
             .maybe_tls(connector)
             .url(format!("{}/api/v1/websocket", url))
-            .headers(vec![(
-                http::header::AUTHORIZATION,
-                http::HeaderValue::from_str(&basic)
-                    .inspect_err(|e| debug!("{e}"))
-                    .map_err(|_| DenimProtocolError::InvalidCredentials)?,
-            )])
+            .headers(headers)
             .build()
             .into();
 
@@ -78,6 +109,8 @@ impl<T: SendingBuffer, U: ReceivingBuffer> DenimProtocolConfig for DenimProtocol
             self.channel_buffer_size,
             self.sending_buffer,
             self.receiving_buffer,
+            self.timeouts,
+            self.reconnect,
         ))
     }
 }
@@ -1,7 +1,7 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use denim_sam_common::{
-    buffers::{DenimChunk, DenimMessage, ReceivingBuffer, SendingBuffer},
+    buffers::{DenimChunk, DenimMessage, PaddingScheme, ReceivingBuffer, SendingBuffer},
     denim_message::{denim_envelope::MessageKind, DeniableMessage, DenimEnvelope},
 };
 use futures_util::{stream::SplitStream, StreamExt};
@@ -19,24 +19,84 @@ use sam_net::{
     websocket::{WebSocket, WebSocketClient, WebSocketReceiver},
 };
 use tokio::sync::{
+    broadcast,
     mpsc::Sender,
     oneshot::{self, Receiver as OneshotReceiver, Sender as OneshotSender},
     Mutex,
 };
 use tokio_tungstenite::tungstenite::Message;
 
-use crate::{error::DenimProtocolError, message::create_message};
+use crate::{
+    error::DenimProtocolError,
+    message::{create_message, event::emit},
+};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum SamDenimMessage {
     Denim(DeniableMessage),
     Sam(ServerEnvelope),
 }
 
+/// Changes in the connection to the proxy.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolEvent {
+    QUpdated(f32),
+    Connected,
+    Disconnected,
+    /// Waiting before the given reconnect attempt.
+    Reconnecting(u32),
+    /// The connection was restored. The deniable message that was being sent
+    /// when it dropped is sent again from the start, this is its previous id.
+    Reconnected {
+        restarted_message: Option<u32>,
+    },
+    /// Cover traffic was due, but no prepared cover message was left.
+    CoverMessagesExhausted,
+}
+
+fn ack(id: MessageId) -> ClientMessage {
+    ClientMessage::builder()
+        .id(id.into())
+        .r#type(ClientMessageType::ClientAck.into())
+        .build()
+}
+
+/// Sent messages waiting for the server's response, by message id.
+#[derive(Clone, Default)]
+pub struct PendingResponses(Arc<Mutex<HashMap<MessageId, OneshotSender<ServerStatus>>>>);
+
+impl PendingResponses {
+    /// Waits for the response to the message with `id`.
+    pub async fn register(&self, id: MessageId) -> OneshotReceiver<ServerStatus> {
+        let (tx, rx) = oneshot::channel();
+        self.0.lock().await.insert(id, tx);
+        rx
+    }
+
+    /// Stops waiting for the response to the message with `id`.
+    pub async fn cancel(&self, id: MessageId) {
+        self.0.lock().await.remove(&id);
+    }
+
+    /// Hands `status` to whoever waits for it. Returns false if nobody does.
+    pub async fn resolve(&self, id: MessageId, status: ServerStatus) -> bool {
+        match self.0.lock().await.remove(&id) {
+            Some(sender) => sender.send(status).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Fails every waiting message, used when the connection they were sent on is gone.
+    pub async fn clear(&self) {
+        self.0.lock().await.clear();
+    }
+}
+
 pub struct DenimReceiver<T: SendingBuffer, U: ReceivingBuffer> {
     client: Arc<Mutex<WebSocketClient>>,
-    enqueue_sam_status: Sender<ServerStatus>,
+    pending: PendingResponses,
     enqueue_message: Sender<SamDenimMessage>,
+    events: broadcast::Sender<ProtocolEvent>,
     first_qstatus_sender: Option<OneshotSender<()>>,
     first_qstatus_receiver: Option<OneshotReceiver<()>>,
     sending_buffer: T,
@@ -46,16 +106,18 @@ pub struct DenimReceiver<T: SendingBuffer, U: ReceivingBuffer> {
 impl<T: SendingBuffer, U: ReceivingBuffer> DenimReceiver<T, U> {
     pub fn new(
         client: Arc<Mutex<WebSocketClient>>,
-        enqueue_sam_status: Sender<ServerStatus>,
+        pending: PendingResponses,
         enqueue_message: Sender<SamDenimMessage>,
+        events: broadcast::Sender<ProtocolEvent>,
         sending_buffer: T,
         receiving_buffer: U,
     ) -> Self {
         let (tx, rx) = oneshot::channel();
         Self {
             client,
-            enqueue_sam_status,
+            pending,
             enqueue_message,
+            events,
             first_qstatus_sender: Some(tx),
             first_qstatus_receiver: Some(rx),
             sending_buffer,
@@ -77,15 +139,29 @@ impl<T: SendingBuffer, U: ReceivingBuffer> DenimReceiver<T, U> {
         }
     }
 
+    async fn update_q(&mut self, q: f32) {
+        if self.sending_buffer.get_q().await != q {
+            self.sending_buffer.set_q(q).await;
+            emit(&self.events, ProtocolEvent::QUpdated(q));
+        }
+    }
+
     async fn send_ack(&mut self, id: MessageId) -> Result<(), DenimProtocolError> {
-        let msg = create_message(
-            &mut self.sending_buffer,
-            ClientMessage::builder()
-                .id(id.into())
-                .r#type(ClientMessageType::ClientAck.into())
-                .build(),
-        )
-        .await?;
+        let msg = create_message(&mut self.sending_buffer, ack(id)).await?;
+        self.send(msg).await
+    }
+
+    /// Answers a decoy like an envelope, so an observer cannot tell them apart
+    /// by whether the client replies. The proxy does not forward the reply to the server.
+    async fn send_decoy_reply(&mut self) -> Result<(), DenimProtocolError> {
+        let mut msg = create_message(&mut self.sending_buffer, ack(MessageId::generate())).await?;
+        if let Some(MessageKind::DenimMessage(bytes)) = msg.message_kind.take() {
+            msg.message_kind = Some(MessageKind::Decoy(bytes));
+        }
+        self.send(msg).await
+    }
+
+    async fn send(&mut self, msg: DenimEnvelope) -> Result<(), DenimProtocolError> {
         self.client
             .lock()
             .await
@@ -98,9 +174,12 @@ impl<T: SendingBuffer, U: ReceivingBuffer> DenimReceiver<T, U> {
         &mut self,
         message: ServerMessage,
     ) -> Result<(), DenimProtocolError> {
+        let response_id = message.id.clone();
         let res = match EnvelopeOrStatus::try_from(message)? {
             EnvelopeOrStatus::Envelope(id, envelope) => self.dispatch_envelope(id, envelope).await,
-            EnvelopeOrStatus::Status(status) => self.dispatch_server_status(status).await,
+            EnvelopeOrStatus::Status(status) => {
+                self.dispatch_server_status(response_id, status).await
+            }
         };
 
         match res {
@@ -125,14 +204,16 @@ impl<T: SendingBuffer, U: ReceivingBuffer> DenimReceiver<T, U> {
 
     async fn dispatch_server_status(
         &mut self,
+        response_id: Vec<u8>,
         status: ServerStatus,
     ) -> Result<Option<MessageId>, DenimProtocolError> {
-        self.enqueue_sam_status
-            .send(status)
-            .await
-            .inspect_err(|e| debug!("{e}"))
-            .map_err(|_| DenimProtocolError::WebSocketError(WebSocketError::Disconnected))
-            .map(|_| None)
+        let id = MessageId::try_from(response_id)
+            .map_err(|_| DenimProtocolError::ReceivedWrongResponseId)?;
+        if !self.pending.resolve(id, status).await {
+            // the sender timed out or gave up on the message
+            debug!("Dropped response to a message nobody waits for");
+        }
+        Ok(None)
     }
 
     async fn handle_chunks(&mut self, chunks: Vec<DenimChunk>) {
@@ -182,11 +263,36 @@ impl<T: SendingBuffer, U: ReceivingBuffer> WebSocketReceiver for DenimReceiver<T
             let denim_bytes = match envelope.message_kind {
                 Some(MessageKind::DenimMessage(bytes)) => bytes,
                 Some(MessageKind::Status(q_status)) => {
+                    // the proxy only announces padding if we offered it
+                    self.sending_buffer
+                        .set_padding(q_status.padding.map(PaddingScheme::from))
+                        .await;
                     // Narrowing f64 into f32
-                    self.sending_buffer.set_q(q_status.q as f32).await;
+                    self.update_q(q_status.q as f32).await;
                     self.notify_qstatus_received();
                     continue;
                 }
+                Some(MessageKind::Decoy(bytes)) => {
+                    // only sent for the deniable payload, the regular payload is random
+                    match DenimMessage::decode(bytes) {
+                        Ok(msg) => {
+                            self.update_q(msg.q).await;
+                            self.handle_chunks(msg.deniable_payload.denim_chunks().to_owned())
+                                .await;
+                            match self.send_decoy_reply().await {
+                                Ok(()) => continue,
+                                Err(e) => {
+                                    error!("Failed to reply to decoy '{e}', disconnecting...");
+                                    break;
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            error!("Failed to decode decoy from server '{e}', disconnecting...");
+                            break;
+                        }
+                    }
+                }
                 None => {
                     error!("Malformed DenimEnvelope (No Body)");
                     break;
@@ -196,7 +302,7 @@ impl<T: SendingBuffer, U: ReceivingBuffer> WebSocketReceiver for DenimReceiver<T
             let (sam_message, denim_chunks) = match DenimMessage::decode(denim_bytes) {
                 Ok(msg) => {
                     // q is decided by the server
-                    self.sending_buffer.set_q(msg.q).await;
+                    self.update_q(msg.q).await;
 
                     let regular = ServerMessage::decode(Bytes::from(msg.regular_payload));
                     (regular, msg.deniable_payload)
@@ -229,12 +335,13 @@ impl<T: SendingBuffer, U: ReceivingBuffer> WebSocketReceiver for DenimReceiver<T
                 }
             };
         }
+        emit(&self.events, ProtocolEvent::Disconnected);
     }
 }
 
 #[cfg(test)]
 pub mod test {
-    use std::{sync::Arc, time::Duration};
+    use std::{collections::VecDeque, sync::Arc, time::Duration};
 
     use denim_sam_common::{
         buffers::{
@@ -242,10 +349,11 @@ pub mod test {
             SendingBuffer,
         },
         denim_message::{
-            deniable_message::MessageKind, DeniableMessage, DenimEnvelope, MessageType, UserMessage,
+            deniable_message::MessageKind, denim_envelope::MessageKind as EnvelopeKind,
+            DeniableMessage, DenimEnvelope, MessageType, UserMessage,
         },
     };
-    use futures_util::SinkExt;
+    use futures_util::{SinkExt, StreamExt};
     use prost::Message as PMessage;
     use rand::RngCore;
     use rstest::rstest;
@@ -254,8 +362,8 @@ pub mod test {
     use sam_common::{
         address::MessageId,
         sam_message::{
-            server_message::Content, SamMessageType, ServerEnvelope, ServerMessage,
-            ServerMessageType,
+            server_message::Content, ClientMessage, ClientMessageType, SamMessageType,
+            ServerEnvelope, ServerMessage, ServerMessageType,
         },
         AccountId,
     };
@@ -264,14 +372,15 @@ pub mod test {
     use tokio::{
         net::TcpListener,
         sync::{
-            mpsc::{self, channel},
+            broadcast,
+            mpsc::channel,
             oneshot::{self, Receiver},
             Mutex,
         },
     };
     use tokio_tungstenite::{accept_async, tungstenite::Message};
 
-    use super::SamDenimMessage;
+    use super::{PendingResponses, SamDenimMessage};
 
     impl SamDenimMessage {
         pub fn some_sam(self) -> Option<ServerEnvelope> {
@@ -300,6 +409,8 @@ pub mod test {
             message_type: MessageType::SignalMessage.into(),
             content: random_bytes,
             rng_counter: None,
+            seed_epoch: None,
+            device_id: None,
         })
     }
 
@@ -317,7 +428,10 @@ pub mod test {
     ) -> Result<DeniablePayload, String> {
         if denim {
             let msg = make_deniable_message(10);
-            buffer.enqueue_message(msg).await;
+            buffer
+                .enqueue_message(msg)
+                .await
+                .map_err(|_| "Failed to enqueue deniable message".to_string())?;
         }
         buffer
             .get_deniable_payload(len)
@@ -325,22 +439,44 @@ pub mod test {
             .map_err(|_| "Failed to get deniable payload".to_string())
     }
 
+    fn encode_denim_message(
+        payload: Result<DeniablePayload, String>,
+        regular_msg: Vec<u8>,
+        q: f32,
+    ) -> Result<Vec<u8>, String> {
+        DenimMessage::builder()
+            .regular_payload(regular_msg)
+            .deniable_payload(payload?)
+            .q(q)
+            .build()
+            .encode()
+            .map_err(|_| "Failed to encode DenimMessage".to_string())
+    }
+
     pub fn encode(
         payload: Result<DeniablePayload, String>,
         regular_msg: Vec<u8>,
         q: f32,
     ) -> Result<Vec<u8>, String> {
-        let payload = payload?;
         Ok(DenimEnvelope::builder()
             .message_kind(
                 denim_sam_common::denim_message::denim_envelope::MessageKind::DenimMessage(
-                    DenimMessage::builder()
-                        .regular_payload(regular_msg.clone())
-                        .deniable_payload(payload)
-                        .q(q)
-                        .build()
-                        .encode()
-                        .map_err(|_| "Failed to encode DenimMessage".to_string())?,
+                    encode_denim_message(payload, regular_msg, q)?,
+                ),
+            )
+            .build()
+            .encode_to_vec())
+    }
+
+    fn encode_decoy(
+        payload: Result<DeniablePayload, String>,
+        regular_msg: Vec<u8>,
+        q: f32,
+    ) -> Result<Vec<u8>, String> {
+        Ok(DenimEnvelope::builder()
+            .message_kind(
+                denim_sam_common::denim_message::denim_envelope::MessageKind::Decoy(
+                    encode_denim_message(payload, regular_msg, q)?,
                 ),
             )
             .build()
@@ -351,7 +487,7 @@ pub mod test {
         addr: &str,
         actions: Vec<ClientAction>,
         envelope: ServerMessage,
-        status: ServerMessage,
+        statuses: Vec<ServerMessage>,
         q: f32,
         stop_signal: Receiver<()>,
     ) -> Receiver<Option<String>> {
@@ -362,8 +498,7 @@ pub mod test {
         let (tx, rx) = oneshot::channel();
         let env_msg = envelope.encode_to_vec();
         let env_len: u32 = env_msg.len().try_into().expect("envelope fits");
-        let status_msg = status.encode_to_vec();
-        let status_len: u32 = status_msg.len().try_into().expect("message fits");
+        let mut statuses = statuses.into_iter();
 
         tokio::spawn(async move {
             let mut error = None;
@@ -389,13 +524,24 @@ pub mod test {
                         let payload = get_payload(&mut sending_buffer, true, env_len).await;
                         encode(payload, env_msg.clone(), sending_buffer.get_q().await)
                     }
+                    ClientAction::Decoy => {
+                        let payload = get_payload(&mut sending_buffer, true, env_len).await;
+                        let mut random = vec![0; env_msg.len()];
+                        rand::thread_rng().fill_bytes(&mut random);
+                        encode_decoy(payload, random, sending_buffer.get_q().await)
+                    }
                     ClientAction::Regular => {
                         let payload = get_payload(&mut sending_buffer, false, env_len).await;
                         encode(payload, env_msg.clone(), sending_buffer.get_q().await)
                     }
                     ClientAction::Status => {
+                        let status_msg = statuses
+                            .next()
+                            .expect("a status per action")
+                            .encode_to_vec();
+                        let status_len = status_msg.len().try_into().expect("message fits");
                         let payload = get_payload(&mut sending_buffer, false, status_len).await;
-                        encode(payload, status_msg.clone(), sending_buffer.get_q().await)
+                        encode(payload, status_msg, sending_buffer.get_q().await)
                     }
                 };
 
@@ -423,6 +569,7 @@ pub mod test {
     #[derive(Clone)]
     pub enum ClientAction {
         Deniable,
+        Decoy,
         Regular,
         Status,
     }
@@ -445,9 +592,9 @@ pub mod test {
             .build()
     }
 
-    fn create_status() -> ServerMessage {
+    fn create_status(id: MessageId) -> ServerMessage {
         ServerMessage::builder()
-            .id(MessageId::generate().into())
+            .id(id.into())
             .r#type(ServerMessageType::ServerAck.into())
             .build()
     }
@@ -457,19 +604,24 @@ pub mod test {
     #[case(vec![ClientAction::Deniable, ClientAction::Deniable, ClientAction::Deniable], get_next_port())]
     #[case(vec![ClientAction::Regular, ClientAction::Regular, ClientAction::Regular], get_next_port())]
     #[case(vec![ClientAction::Status, ClientAction::Status, ClientAction::Status], get_next_port())]
+    #[case(vec![ClientAction::Decoy, ClientAction::Regular, ClientAction::Decoy], get_next_port())]
     #[tokio::test]
     async fn receive_denim_message(#[case] actions: Vec<ClientAction>, #[case] port: u16) {
         let q = 1.0;
         let addr = format!("127.0.0.1:{port}");
 
         let envelope = create_envelope();
-        let status = create_status();
+        let status_ids: Vec<MessageId> = actions
+            .iter()
+            .filter(|action| matches!(action, ClientAction::Status))
+            .map(|_| MessageId::generate())
+            .collect();
         let (stop_tx, stop_rx) = oneshot::channel();
         let server_result = test_server(
             &addr,
             actions.clone(),
             envelope.clone(),
-            status.clone(),
+            status_ids.iter().copied().map(create_status).collect(),
             1.0,
             stop_rx,
         )
@@ -481,14 +633,19 @@ pub mod test {
                 .into(),
         ));
 
-        let (status_tx, mut status_rx) = mpsc::channel(10);
+        let pending = PendingResponses::default();
+        let mut status_rxs = VecDeque::new();
+        for id in status_ids {
+            status_rxs.push_back(pending.register(id).await);
+        }
         let send_buffer = InMemorySendingBuffer::new(q).expect("can create sending buffer");
         let recv_buffer = InMemoryReceivingBuffer::default();
         let (tx, mut chunk_rx) = channel(10);
         let receiver = DenimReceiver::new(
             client.clone(),
-            status_tx,
+            pending,
             tx,
+            broadcast::channel(10).0,
             send_buffer.clone(),
             recv_buffer,
         );
@@ -525,6 +682,21 @@ pub mod test {
                         )),
                     )
                 }
+                ClientAction::Decoy => {
+                    // only the deniable message is delivered
+                    let a_msg = tokio::time::timeout(Duration::from_millis(300), chunk_rx.recv())
+                        .await
+                        .expect("msg does not timeout")
+                        .expect("Can get msg");
+                    let den = a_msg.some_denim().expect("expects denim");
+                    (
+                        None,
+                        Some(matches!(
+                            den.message_kind,
+                            Some(MessageKind::DeniableMessage(_))
+                        )),
+                    )
+                }
                 ClientAction::Regular => {
                     let a_env = tokio::time::timeout(Duration::from_millis(300), chunk_rx.recv())
                         .await
@@ -533,9 +705,10 @@ pub mod test {
                     (Some(a_env.some_sam().expect("expects sam").content), None)
                 }
                 ClientAction::Status => {
-                    tokio::time::timeout(Duration::from_millis(300), status_rx.recv())
+                    let status_rx = status_rxs.pop_front().expect("a status per action");
+                    tokio::time::timeout(Duration::from_millis(300), status_rx)
                         .await
-                        .expect("chunk does not timeout")
+                        .expect("status does not timeout")
                         .expect("Can get status");
                     (None, None)
                 }
@@ -554,6 +727,10 @@ pub mod test {
                     );
                     assert!(matches!(is_denim, Some(true)));
                 }
+                ClientAction::Decoy => {
+                    assert!(data.is_none());
+                    assert!(matches!(is_denim, Some(true)));
+                }
                 ClientAction::Regular => {
                     assert!(
                         data.is_some_and(|x| x == vec![69; 100]),
@@ -568,4 +745,94 @@ pub mod test {
             }
         }
     }
+
+    #[tokio::test]
+    async fn decoys_are_answered_like_envelopes() {
+        let addr = format!("127.0.0.1:{}", get_next_port());
+        let listener = TcpListener::bind(&addr).await.expect("can bind tcp");
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.map_err(|e| e.to_string())?;
+            let mut ws_stream = accept_async(stream).await.map_err(|e| e.to_string())?;
+            let mut sending_buffer =
+                InMemorySendingBuffer::new(1.0).expect("can create sending buffer");
+            let env_msg = create_envelope().encode_to_vec();
+            let env_len: u32 = env_msg.len().try_into().expect("envelope fits");
+
+            let regular = encode(
+                get_payload(&mut sending_buffer, false, env_len).await,
+                env_msg.clone(),
+                1.0,
+            )?;
+            let mut random = vec![0; env_msg.len()];
+            rand::thread_rng().fill_bytes(&mut random);
+            let decoy = encode_decoy(
+                get_payload(&mut sending_buffer, true, env_len).await,
+                random,
+                1.0,
+            )?;
+
+            let mut replies = Vec::new();
+            for msg in [regular, decoy] {
+                ws_stream
+                    .send(Message::Binary(msg.into()))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let reply = match tokio::time::timeout(Duration::from_secs(5), ws_stream.next())
+                    .await
+                    .map_err(|_| "Client failed to reply in time")?
+                {
+                    Some(Ok(Message::Binary(reply))) => reply,
+                    _ => Err("Failed to receive reply from client")?,
+                };
+                replies.push(DenimEnvelope::decode(reply).map_err(|e| e.to_string())?);
+            }
+            Ok::<_, String>(replies)
+        });
+
+        let client: Arc<Mutex<WebSocketClient>> = Arc::new(Mutex::new(
+            WebSocketClientConfig::builder()
+                .url(format!("ws://{}", addr))
+                .build()
+                .into(),
+        ));
+        let (tx, _chunk_rx) = channel(10);
+        let receiver = DenimReceiver::new(
+            client.clone(),
+            PendingResponses::default(),
+            tx,
+            broadcast::channel(10).0,
+            InMemorySendingBuffer::new(1.0).expect("can create sending buffer"),
+            InMemoryReceivingBuffer::default(),
+        );
+        client
+            .lock()
+            .await
+            .connect(receiver)
+            .await
+            .expect("Can connect");
+
+        let replies = server
+            .await
+            .expect("server task completes")
+            .expect("server works");
+        let acks: Vec<_> = replies
+            .into_iter()
+            .map(|reply| match reply.message_kind {
+                Some(EnvelopeKind::DenimMessage(bytes)) => (false, bytes),
+                Some(EnvelopeKind::Decoy(bytes)) => (true, bytes),
+                kind => panic!("Unexpected reply {kind:?}"),
+            })
+            .map(|(decoy, bytes)| {
+                let msg = DenimMessage::decode(bytes).expect("Can decode reply");
+                let ack =
+                    ClientMessage::decode(msg.regular_payload.as_slice()).expect("Can decode ack");
+                assert_eq!(ack.r#type(), ClientMessageType::ClientAck);
+                (decoy, msg.regular_payload.len())
+            })
+            .collect();
+        // the envelope is acknowledged normally, the decoy gets an ack of the same size
+        assert!(!acks[0].0);
+        assert!(acks[1].0);
+        assert_eq!(acks[0].1, acks[1].1);
+    }
 }
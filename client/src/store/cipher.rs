@@ -0,0 +1,85 @@
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use derive_more::{Display, Error};
+use rand::rngs::OsRng;
+
+const NONCE_SIZE: usize = 12;
+
+#[derive(Debug, Display, Error)]
+pub enum StoreCipherError {
+    Encryption,
+    /// The value was encrypted with another key or associated data, or was modified.
+    Decryption,
+}
+
+/// Encrypts values the client keeps at rest. Every value is stored as its nonce
+/// followed by the ciphertext, the associated data binds it to where it is stored.
+#[derive(Clone)]
+pub struct StoreCipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl StoreCipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    pub fn encrypt(&self, aad: &str, plaintext: &[u8]) -> Result<Vec<u8>, StoreCipherError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| StoreCipherError::Encryption)?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    pub fn decrypt(&self, aad: &str, bytes: &[u8]) -> Result<Vec<u8>, StoreCipherError> {
+        if bytes.len() < NONCE_SIZE {
+            return Err(StoreCipherError::Decryption);
+        }
+        let (nonce, ciphertext) = bytes.split_at(NONCE_SIZE);
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| StoreCipherError::Decryption)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StoreCipher, StoreCipherError};
+
+    #[test]
+    fn values_only_decrypt_with_the_same_key_and_aad() {
+        let cipher = StoreCipher::new([7; 32]);
+        let bytes = cipher.encrypt("column:1", b"secret").expect("Can encrypt");
+
+        assert_eq!(
+            cipher.decrypt("column:1", &bytes).expect("Can decrypt"),
+            b"secret"
+        );
+        assert!(matches!(
+            cipher.decrypt("column:2", &bytes),
+            Err(StoreCipherError::Decryption)
+        ));
+        assert!(matches!(
+            StoreCipher::new([8; 32]).decrypt("column:1", &bytes),
+            Err(StoreCipherError::Decryption)
+        ));
+    }
+}
@@ -6,12 +6,13 @@ use sam_client::storage::{ContactStore, MessageStore, ProvidesKeyId};
 
 use crate::DenimClientError;
 
+pub mod cipher;
 pub mod sqlite;
 
 pub mod inmem;
 pub use inmem::InMemoryDeniableStoreConfig;
 mod seed;
-pub use seed::{DenimPreKeySeedStore, InMemoryPreKeySeedStore, SeedStoreError};
+pub use seed::{DenimPreKeySeedStore, InMemoryPreKeySeedStore, SeedStoreError, SqliteSeedStore};
 
 #[async_trait]
 pub trait DeniableStoreConfig {
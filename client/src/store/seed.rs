@@ -1,13 +1,26 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use async_trait::async_trait;
-use denim_sam_common::rng::RngState;
-use derive_more::{Display, Error};
+use denim_sam_common::{
+    denim_message::SeedUpdate,
+    rng::{chacha::ChaChaRngState, derive::KEY_STREAM_WORDS_PER_KEY, RngState},
+};
+use derive_more::{Display, Error, From};
 use log::error;
+use prost::Message;
+use sqlx::{Row, SqlitePool};
+
+use super::cipher::StoreCipher;
 
-/// Keygen uses the rng this amount of times for each key generated.
-const KEYGEN_RNG_USE_AMOUNT: u128 = 8;
+pub(crate) const DEFAULT_SEED_GRACE_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
-#[derive(Debug, Display, Error)]
-pub enum SeedStoreError {}
+#[derive(Debug, Display, Error, From)]
+pub enum SeedStoreError {
+    Database(sqlx::Error),
+    /// The stored seed could not be encrypted or decrypted, e.g. with a wrong key.
+    Encryption,
+    MalformedSeed,
+}
 
 #[async_trait]
 pub trait DenimPreKeySeedStore<T: RngState> {
@@ -16,12 +29,65 @@ pub trait DenimPreKeySeedStore<T: RngState> {
     async fn set_key_id_seed(&mut self, record: T) -> Result<(), SeedStoreError>;
     async fn set_key_seed(&mut self, record: T) -> Result<(), SeedStoreError>;
     async fn get_rng_offset(&self) -> Result<u128, SeedStoreError>;
+    /// Epoch of the current seeds. Epoch 0 means no seeds have been rotated in.
+    async fn get_epoch(&self) -> Result<u32, SeedStoreError>;
+    /// Replaces the current seeds, keeping them as a retired epoch for a grace period.
+    /// Returns the epoch of the new seeds.
+    async fn rotate_seeds(&mut self, key_seed: T, key_id_seed: T) -> Result<u32, SeedStoreError>;
+    /// Like `rotate_seeds`, but the new seeds get an epoch newer than `epoch`,
+    /// e.g. because the proxy already uses that epoch.
+    async fn rotate_seeds_past(
+        &mut self,
+        epoch: u32,
+        key_seed: T,
+        key_id_seed: T,
+    ) -> Result<u32, SeedStoreError>;
+    /// Key seed and key id seed of `epoch`, if it is the current epoch
+    /// or a retired epoch that is still in its grace period.
+    async fn get_seeds_for_epoch(&self, epoch: u32) -> Result<Option<(T, T)>, SeedStoreError>;
+    /// Seed update sent to the proxy that it has not acknowledged yet.
+    async fn get_pending_update(&self) -> Result<Option<SeedUpdate>, SeedStoreError>;
+    async fn set_pending_update(
+        &mut self,
+        update: Option<SeedUpdate>,
+    ) -> Result<(), SeedStoreError>;
+}
+
+#[derive(Debug)]
+struct RetiredSeeds<T: RngState> {
+    epoch: u32,
+    key_seed: T,
+    key_id_seed: T,
+    retired_at: Instant,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct InMemoryPreKeySeedStore<T: RngState> {
     key_seed: T,
     key_id_seed: T,
+    epoch: u32,
+    retired: Vec<RetiredSeeds<T>>,
+    grace_period: Duration,
+    pending_update: Option<SeedUpdate>,
+}
+
+impl<T: RngState> InMemoryPreKeySeedStore<T> {
+    pub fn new(grace_period: Duration) -> Self {
+        Self {
+            key_seed: T::default(),
+            key_id_seed: T::default(),
+            epoch: 0,
+            retired: Vec::new(),
+            grace_period,
+            pending_update: None,
+        }
+    }
+}
+
+impl<T: RngState> Default for InMemoryPreKeySeedStore<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_SEED_GRACE_PERIOD)
+    }
 }
 
 #[async_trait]
@@ -43,9 +109,571 @@ impl<T: RngState> DenimPreKeySeedStore<T> for InMemoryPreKeySeedStore<T> {
     async fn get_rng_offset(&self) -> Result<u128, SeedStoreError> {
         let key_offset = self.key_seed.offset();
         let id_offset = self.key_id_seed.offset();
-        if key_offset / KEYGEN_RNG_USE_AMOUNT != id_offset {
+        if key_offset / KEY_STREAM_WORDS_PER_KEY != id_offset {
             error!("Key offset ({key_offset}) did not match Key ID offset ({id_offset})");
         }
         Ok(id_offset)
     }
+    async fn get_epoch(&self) -> Result<u32, SeedStoreError> {
+        Ok(self.epoch)
+    }
+    async fn rotate_seeds(&mut self, key_seed: T, key_id_seed: T) -> Result<u32, SeedStoreError> {
+        self.rotate_seeds_past(0, key_seed, key_id_seed).await
+    }
+    async fn rotate_seeds_past(
+        &mut self,
+        epoch: u32,
+        key_seed: T,
+        key_id_seed: T,
+    ) -> Result<u32, SeedStoreError> {
+        let key_seed = std::mem::replace(&mut self.key_seed, key_seed);
+        let key_id_seed = std::mem::replace(&mut self.key_id_seed, key_id_seed);
+        self.retired
+            .retain(|retired| retired.retired_at.elapsed() < self.grace_period);
+        if self.epoch > 0 {
+            self.retired.push(RetiredSeeds {
+                epoch: self.epoch,
+                key_seed,
+                key_id_seed,
+                retired_at: Instant::now(),
+            });
+        }
+        self.epoch = self.epoch.max(epoch) + 1;
+        Ok(self.epoch)
+    }
+    async fn get_seeds_for_epoch(&self, epoch: u32) -> Result<Option<(T, T)>, SeedStoreError> {
+        if epoch == self.epoch {
+            return Ok(Some((self.key_seed.clone(), self.key_id_seed.clone())));
+        }
+        Ok(self
+            .retired
+            .iter()
+            .find(|retired| {
+                retired.epoch == epoch && retired.retired_at.elapsed() < self.grace_period
+            })
+            .map(|retired| (retired.key_seed.clone(), retired.key_id_seed.clone())))
+    }
+    async fn get_pending_update(&self) -> Result<Option<SeedUpdate>, SeedStoreError> {
+        Ok(self.pending_update.clone())
+    }
+    async fn set_pending_update(
+        &mut self,
+        update: Option<SeedUpdate>,
+    ) -> Result<(), SeedStoreError> {
+        self.pending_update = update;
+        Ok(())
+    }
+}
+
+const CREATE_SEED_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS DenimPreKeySeeds (
+        epoch INTEGER PRIMARY KEY NOT NULL,
+        key_seed BLOB NOT NULL,
+        key_id_seed BLOB NOT NULL,
+        retired_at INTEGER
+    )";
+
+const CREATE_PENDING_UPDATE_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS DenimPendingSeedUpdate (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        seed_update BLOB NOT NULL
+    )";
+
+const SEED_SIZE: usize = 32;
+
+/// Seed store that keeps the seeds of the current epoch and of retired epochs
+/// in their grace period in SQLite.
+/// Every seed is stored together with its word offset, encrypted with `encryption_key`,
+/// as is the seed update the proxy has not acknowledged yet.
+pub struct SqliteSeedStore {
+    pool: SqlitePool,
+    cipher: StoreCipher,
+    grace_period: Duration,
+}
+
+struct SeedRow {
+    key_seed: ChaChaRngState,
+    key_id_seed: ChaChaRngState,
+    retired_at: Option<i64>,
+}
+
+impl SqliteSeedStore {
+    pub async fn new(
+        pool: SqlitePool,
+        encryption_key: [u8; 32],
+        grace_period: Duration,
+    ) -> Result<Self, SeedStoreError> {
+        sqlx::query(CREATE_SEED_TABLE).execute(&pool).await?;
+        sqlx::query(CREATE_PENDING_UPDATE_TABLE)
+            .execute(&pool)
+            .await?;
+        Ok(Self {
+            pool,
+            cipher: StoreCipher::new(encryption_key),
+            grace_period,
+        })
+    }
+
+    fn encrypt(
+        &self,
+        epoch: u32,
+        column: &str,
+        state: &ChaChaRngState,
+    ) -> Result<Vec<u8>, SeedStoreError> {
+        let mut plaintext = state.seed().to_vec();
+        plaintext.extend_from_slice(&state.offset().to_be_bytes());
+
+        // binding the epoch and column prevents swapping stored seeds
+        self.cipher
+            .encrypt(&format!("{column}:{epoch}"), &plaintext)
+            .map_err(|_| SeedStoreError::Encryption)
+    }
+
+    fn decrypt(
+        &self,
+        epoch: u32,
+        column: &str,
+        bytes: &[u8],
+    ) -> Result<ChaChaRngState, SeedStoreError> {
+        let plaintext = self
+            .cipher
+            .decrypt(&format!("{column}:{epoch}"), bytes)
+            .map_err(|_| SeedStoreError::Encryption)?;
+
+        if plaintext.len() != SEED_SIZE + 16 {
+            return Err(SeedStoreError::MalformedSeed);
+        }
+        let (seed, offset) = plaintext.split_at(SEED_SIZE);
+        let seed = seed.try_into().map_err(|_| SeedStoreError::MalformedSeed)?;
+        let offset = offset
+            .try_into()
+            .map_err(|_| SeedStoreError::MalformedSeed)?;
+        Ok(ChaChaRngState::from_seed_and_offset(
+            seed,
+            u128::from_be_bytes(offset),
+        ))
+    }
+
+    async fn current_epoch(&self) -> Result<u32, SeedStoreError> {
+        let epoch: Option<i64> = sqlx::query_scalar("SELECT MAX(epoch) FROM DenimPreKeySeeds")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(epoch.unwrap_or_default() as u32)
+    }
+
+    async fn get_row(&self, epoch: u32) -> Result<Option<SeedRow>, SeedStoreError> {
+        let Some(row) = sqlx::query(
+            "SELECT key_seed, key_id_seed, retired_at FROM DenimPreKeySeeds WHERE epoch = ?",
+        )
+        .bind(i64::from(epoch))
+        .fetch_optional(&self.pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(SeedRow {
+            key_seed: self.decrypt(epoch, "key_seed", row.try_get("key_seed")?)?,
+            key_id_seed: self.decrypt(epoch, "key_id_seed", row.try_get("key_id_seed")?)?,
+            retired_at: row.try_get("retired_at")?,
+        }))
+    }
+
+    async fn current_row(&self) -> Result<SeedRow, SeedStoreError> {
+        let epoch = self.current_epoch().await?;
+        Ok(self.get_row(epoch).await?.unwrap_or(SeedRow {
+            key_seed: ChaChaRngState::default(),
+            key_id_seed: ChaChaRngState::default(),
+            retired_at: None,
+        }))
+    }
+
+    /// Stores both seeds of the current epoch in one statement,
+    /// so a seed is never stored without its offset or the other seed.
+    async fn store_current(
+        &self,
+        key_seed: Option<ChaChaRngState>,
+        key_id_seed: Option<ChaChaRngState>,
+    ) -> Result<(), SeedStoreError> {
+        let epoch = self.current_epoch().await?;
+        let current = self.current_row().await?;
+        let key_seed = key_seed.unwrap_or(current.key_seed);
+        let key_id_seed = key_id_seed.unwrap_or(current.key_id_seed);
+
+        sqlx::query(
+            "INSERT INTO DenimPreKeySeeds (epoch, key_seed, key_id_seed) VALUES (?, ?, ?)
+             ON CONFLICT(epoch) DO UPDATE
+             SET key_seed = excluded.key_seed, key_id_seed = excluded.key_id_seed",
+        )
+        .bind(i64::from(epoch))
+        .bind(self.encrypt(epoch, "key_seed", &key_seed)?)
+        .bind(self.encrypt(epoch, "key_id_seed", &key_id_seed)?)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+#[async_trait]
+impl DenimPreKeySeedStore<ChaChaRngState> for SqliteSeedStore {
+    async fn get_key_id_seed(&self) -> Result<ChaChaRngState, SeedStoreError> {
+        Ok(self.current_row().await?.key_id_seed)
+    }
+    async fn get_key_seed(&self) -> Result<ChaChaRngState, SeedStoreError> {
+        Ok(self.current_row().await?.key_seed)
+    }
+    async fn set_key_id_seed(&mut self, record: ChaChaRngState) -> Result<(), SeedStoreError> {
+        self.store_current(None, Some(record)).await
+    }
+    async fn set_key_seed(&mut self, record: ChaChaRngState) -> Result<(), SeedStoreError> {
+        self.store_current(Some(record), None).await
+    }
+    async fn get_rng_offset(&self) -> Result<u128, SeedStoreError> {
+        let current = self.current_row().await?;
+        let key_offset = current.key_seed.offset();
+        let id_offset = current.key_id_seed.offset();
+        if key_offset / KEY_STREAM_WORDS_PER_KEY != id_offset {
+            error!("Key offset ({key_offset}) did not match Key ID offset ({id_offset})");
+        }
+        Ok(id_offset)
+    }
+    async fn get_epoch(&self) -> Result<u32, SeedStoreError> {
+        self.current_epoch().await
+    }
+    async fn rotate_seeds(
+        &mut self,
+        key_seed: ChaChaRngState,
+        key_id_seed: ChaChaRngState,
+    ) -> Result<u32, SeedStoreError> {
+        self.rotate_seeds_past(0, key_seed, key_id_seed).await
+    }
+    async fn rotate_seeds_past(
+        &mut self,
+        epoch: u32,
+        key_seed: ChaChaRngState,
+        key_id_seed: ChaChaRngState,
+    ) -> Result<u32, SeedStoreError> {
+        let current = self.current_epoch().await?;
+        let epoch = current.max(epoch) + 1;
+        let key_seed = self.encrypt(epoch, "key_seed", &key_seed)?;
+        let key_id_seed = self.encrypt(epoch, "key_id_seed", &key_id_seed)?;
+
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE DenimPreKeySeeds SET retired_at = ? WHERE epoch = ?")
+            .bind(now_millis())
+            .bind(i64::from(current))
+            .execute(&mut *tx)
+            .await?;
+        // retired epochs are kept for their grace period, and epoch 0 never held rotated seeds
+        sqlx::query("DELETE FROM DenimPreKeySeeds WHERE retired_at <= ? OR epoch = 0")
+            .bind(now_millis() - self.grace_period.as_millis() as i64)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT INTO DenimPreKeySeeds (epoch, key_seed, key_id_seed) VALUES (?, ?, ?)")
+            .bind(i64::from(epoch))
+            .bind(key_seed)
+            .bind(key_id_seed)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(epoch)
+    }
+    async fn get_seeds_for_epoch(
+        &self,
+        epoch: u32,
+    ) -> Result<Option<(ChaChaRngState, ChaChaRngState)>, SeedStoreError> {
+        let Some(row) = self.get_row(epoch).await? else {
+            return Ok(None);
+        };
+        let grace_period = self.grace_period.as_millis() as i64;
+        if row
+            .retired_at
+            .is_some_and(|retired_at| now_millis() - retired_at >= grace_period)
+        {
+            return Ok(None);
+        }
+        Ok(Some((row.key_seed, row.key_id_seed)))
+    }
+    async fn get_pending_update(&self) -> Result<Option<SeedUpdate>, SeedStoreError> {
+        let Some(bytes) =
+            sqlx::query_scalar::<_, Vec<u8>>("SELECT seed_update FROM DenimPendingSeedUpdate")
+                .fetch_optional(&self.pool)
+                .await?
+        else {
+            return Ok(None);
+        };
+        let update = self
+            .cipher
+            .decrypt("seed_update", &bytes)
+            .map_err(|_| SeedStoreError::Encryption)?;
+        SeedUpdate::decode(update.as_slice())
+            .map(Some)
+            .map_err(|_| SeedStoreError::MalformedSeed)
+    }
+    async fn set_pending_update(
+        &mut self,
+        update: Option<SeedUpdate>,
+    ) -> Result<(), SeedStoreError> {
+        let Some(update) = update else {
+            sqlx::query("DELETE FROM DenimPendingSeedUpdate")
+                .execute(&self.pool)
+                .await?;
+            return Ok(());
+        };
+        sqlx::query(
+            "INSERT INTO DenimPendingSeedUpdate (id, seed_update) VALUES (0, ?)
+             ON CONFLICT(id) DO UPDATE SET seed_update = excluded.seed_update",
+        )
+        .bind(
+            self.cipher
+                .encrypt("seed_update", &update.encode_to_vec())
+                .map_err(|_| SeedStoreError::Encryption)?,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use denim_sam_common::{
+        denim_message::SeedUpdate,
+        rng::{chacha::ChaChaRngState, RngState as _},
+    };
+    use rand::{rngs::OsRng, RngCore};
+    use rstest::rstest;
+
+    use sam_client::storage::sqlite::sqlite_connector::SqliteConnector;
+
+    use super::{DenimPreKeySeedStore, InMemoryPreKeySeedStore, SeedStoreError, SqliteSeedStore};
+
+    #[rstest]
+    #[case(Duration::from_secs(60), true)]
+    #[case(Duration::ZERO, false)]
+    #[tokio::test]
+    async fn previous_epoch_is_kept_for_grace_period(
+        #[case] grace_period: Duration,
+        #[case] kept: bool,
+    ) {
+        let mut store = InMemoryPreKeySeedStore::<ChaChaRngState>::new(grace_period);
+        let key_seed = ChaChaRngState::random(&mut OsRng);
+        let key_id_seed = ChaChaRngState::random(&mut OsRng);
+
+        let first = store
+            .rotate_seeds(key_seed.clone(), key_id_seed.clone())
+            .await
+            .expect("Can rotate seeds");
+        let second = store
+            .rotate_seeds(
+                ChaChaRngState::random(&mut OsRng),
+                ChaChaRngState::random(&mut OsRng),
+            )
+            .await
+            .expect("Can rotate seeds");
+
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(store.get_epoch().await.expect("Can get epoch"), 2);
+        assert!(store
+            .get_seeds_for_epoch(2)
+            .await
+            .expect("Can get seeds")
+            .is_some());
+
+        let previous = store.get_seeds_for_epoch(1).await.expect("Can get seeds");
+        assert_eq!(previous.is_some(), kept);
+        if let Some((previous_key_seed, previous_key_id_seed)) = previous {
+            assert_eq!(
+                previous_key_seed.into_rng().next_u64(),
+                key_seed.into_rng().next_u64()
+            );
+            assert_eq!(
+                previous_key_id_seed.into_rng().next_u64(),
+                key_id_seed.into_rng().next_u64()
+            );
+        }
+    }
+
+    /// Rotates through three epochs and past an epoch the proxy already uses.
+    async fn assert_retired_epochs_are_kept(mut store: impl DenimPreKeySeedStore<ChaChaRngState>) {
+        for epoch in 1..=3 {
+            assert_eq!(
+                store
+                    .rotate_seeds(
+                        ChaChaRngState::random(&mut OsRng),
+                        ChaChaRngState::random(&mut OsRng),
+                    )
+                    .await
+                    .expect("Can rotate seeds"),
+                epoch
+            );
+        }
+        let epoch = store
+            .rotate_seeds_past(
+                7,
+                ChaChaRngState::random(&mut OsRng),
+                ChaChaRngState::random(&mut OsRng),
+            )
+            .await
+            .expect("Can rotate seeds");
+        assert_eq!(epoch, 8);
+        assert_eq!(store.get_epoch().await.expect("Can get epoch"), 8);
+
+        for epoch in [1, 2, 3, 8] {
+            assert!(
+                store
+                    .get_seeds_for_epoch(epoch)
+                    .await
+                    .expect("Can get seeds")
+                    .is_some(),
+                "seeds of epoch {epoch} are kept"
+            );
+        }
+        assert!(store
+            .get_seeds_for_epoch(7)
+            .await
+            .expect("Can get seeds")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn every_retired_epoch_is_kept_for_grace_period() {
+        assert_retired_epochs_are_kept(InMemoryPreKeySeedStore::new(Duration::from_secs(60))).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_every_retired_epoch_is_kept_for_grace_period() {
+        let pool = SqliteConnector::migrate("sqlite::memory:")
+            .await
+            .expect("Can open database")
+            .pool();
+        let store = SqliteSeedStore::new(pool, [7; 32], Duration::from_secs(60))
+            .await
+            .expect("Can create store");
+        assert_retired_epochs_are_kept(store).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_seeds_survive_reopening() {
+        let pool = SqliteConnector::migrate("sqlite::memory:")
+            .await
+            .expect("Can open database")
+            .pool();
+        let encryption_key = [7; 32];
+        let key_seed = ChaChaRngState::from_seed_and_offset([1; 32], 16);
+        let key_id_seed = ChaChaRngState::from_seed_and_offset([2; 32], 2);
+
+        let mut store = SqliteSeedStore::new(pool.clone(), encryption_key, Duration::from_secs(60))
+            .await
+            .expect("Can create store");
+        let epoch = store
+            .rotate_seeds(key_seed.clone(), key_id_seed.clone())
+            .await
+            .expect("Can rotate seeds");
+        drop(store);
+
+        let store = SqliteSeedStore::new(pool, encryption_key, Duration::from_secs(60))
+            .await
+            .expect("Can reopen store");
+        assert_eq!(store.get_epoch().await.expect("Can get epoch"), epoch);
+        assert_eq!(store.get_rng_offset().await.expect("Can get offset"), 2);
+        assert_eq!(
+            store
+                .get_key_seed()
+                .await
+                .expect("Can get key seed")
+                .into_rng()
+                .next_u64(),
+            key_seed.into_rng().next_u64()
+        );
+        assert_eq!(
+            store
+                .get_key_id_seed()
+                .await
+                .expect("Can get key id seed")
+                .into_rng()
+                .next_u64(),
+            key_id_seed.into_rng().next_u64()
+        );
+    }
+
+    #[tokio::test]
+    async fn sqlite_pending_update_survives_reopening() {
+        let pool = SqliteConnector::migrate("sqlite::memory:")
+            .await
+            .expect("Can open database")
+            .pool();
+        let update = SeedUpdate::builder()
+            .pre_key_seed(vec![1; 32])
+            .pre_key_id_seed(vec![2; 4])
+            .epoch(3)
+            .resync(true)
+            .build();
+
+        let mut store = SqliteSeedStore::new(pool.clone(), [7; 32], Duration::from_secs(60))
+            .await
+            .expect("Can create store");
+        store
+            .set_pending_update(Some(update.clone()))
+            .await
+            .expect("Can set pending update");
+        drop(store);
+
+        let mut store = SqliteSeedStore::new(pool, [7; 32], Duration::from_secs(60))
+            .await
+            .expect("Can reopen store");
+        assert_eq!(
+            store
+                .get_pending_update()
+                .await
+                .expect("Can get pending update"),
+            Some(update)
+        );
+        store
+            .set_pending_update(None)
+            .await
+            .expect("Can clear pending update");
+        assert!(store
+            .get_pending_update()
+            .await
+            .expect("Can get pending update")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn sqlite_seeds_cannot_be_read_with_another_key() {
+        let pool = SqliteConnector::migrate("sqlite::memory:")
+            .await
+            .expect("Can open database")
+            .pool();
+
+        let mut store = SqliteSeedStore::new(pool.clone(), [7; 32], Duration::from_secs(60))
+            .await
+            .expect("Can create store");
+        store
+            .rotate_seeds(
+                ChaChaRngState::random(&mut OsRng),
+                ChaChaRngState::random(&mut OsRng),
+            )
+            .await
+            .expect("Can rotate seeds");
+
+        let store = SqliteSeedStore::new(pool, [8; 32], Duration::from_secs(60))
+            .await
+            .expect("Can reopen store");
+        assert!(matches!(
+            store.get_key_seed().await,
+            Err(SeedStoreError::Encryption)
+        ));
+    }
 }
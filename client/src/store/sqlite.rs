@@ -1,13 +1,22 @@
 use async_trait::async_trait;
-use denim_sam_common::rng::chacha::ChaChaRngState;
+use rand::{rngs::OsRng, RngCore};
 use sam_client::storage::{
     error::DatabaseError, sqlite::sqlite_connector::SqliteConnector, SqliteContactStore,
     SqliteMessageStore, SqlitePreKeyStore, SqliteSessionStore,
 };
 
-use crate::DenimClientError;
+use crate::{
+    message::{
+        buffer::SqliteSendingBuffer, dead_letter::SqliteDeadLetterQueueConfig,
+        error::SendingBufferError, queue::SqliteMessageQueueConfig,
+    },
+    DenimClientError,
+};
 
-use super::{DeniableStore, DeniableStoreConfig, DeniableStoreType, InMemoryPreKeySeedStore};
+use super::{
+    cipher::StoreCipher, seed::DEFAULT_SEED_GRACE_PERIOD, DeniableStore, DeniableStoreConfig,
+    DeniableStoreType, SqliteSeedStore,
+};
 
 pub struct SqliteDeniableStoreType;
 
@@ -16,7 +25,7 @@ impl DeniableStoreType for SqliteDeniableStoreType {
     type MessageStore = SqliteMessageStore;
     type SessionStore = SqliteSessionStore;
     type PreKeyStore = SqlitePreKeyStore;
-    type SeedStore = InMemoryPreKeySeedStore<ChaChaRngState>;
+    type SeedStore = SqliteSeedStore;
 }
 
 pub type SqliteDeniableStore = DeniableStore<SqliteDeniableStoreType>;
@@ -24,21 +33,51 @@ pub type SqliteDeniableStore = DeniableStore<SqliteDeniableStoreType>;
 pub struct SqliteDeniableStoreConfig {
     buffer_size: usize,
     connector: SqliteConnector,
+    encryption_key: [u8; 32],
 }
 
 impl SqliteDeniableStoreConfig {
-    pub fn new(connector: SqliteConnector, buffer_size: usize) -> Self {
+    /// `encryption_key` encrypts the deniable key seeds, queued messages and dead letters at rest
+    /// and must stay the same across restarts.
+    pub fn new(connector: SqliteConnector, buffer_size: usize, encryption_key: [u8; 32]) -> Self {
         Self {
             buffer_size,
             connector,
+            encryption_key,
         }
     }
 
+    /// Queue for waiting messages kept in the same database as the store.
+    pub fn message_queue_config(&self) -> SqliteMessageQueueConfig {
+        SqliteMessageQueueConfig::new(self.connector.pool(), StoreCipher::new(self.encryption_key))
+    }
+
+    /// Dead letters kept in the same database as the store.
+    pub fn dead_letter_config(&self) -> SqliteDeadLetterQueueConfig {
+        SqliteDeadLetterQueueConfig::new(
+            self.connector.pool(),
+            StoreCipher::new(self.encryption_key),
+        )
+    }
+
+    /// Sending buffer kept in the same database as the store.
+    pub async fn sending_buffer(&self, q: f32) -> Result<SqliteSendingBuffer, SendingBufferError> {
+        SqliteSendingBuffer::new(
+            self.connector.pool(),
+            StoreCipher::new(self.encryption_key),
+            q,
+        )
+        .await
+    }
+
     pub async fn in_memory(buffer_size: usize) -> Result<Self, DatabaseError> {
         let connector = SqliteConnector::migrate("sqlite::memory:").await?;
+        let mut encryption_key = [0; 32];
+        OsRng.fill_bytes(&mut encryption_key);
         Ok(Self {
             buffer_size,
             connector,
+            encryption_key,
         })
     }
 }
@@ -50,6 +89,12 @@ impl DeniableStoreConfig for SqliteDeniableStoreConfig {
     async fn create_store(
         self,
     ) -> Result<DeniableStore<Self::DeniableStoreType>, DenimClientError> {
+        let seed_store = SqliteSeedStore::new(
+            self.connector.pool(),
+            self.encryption_key,
+            DEFAULT_SEED_GRACE_PERIOD,
+        )
+        .await?;
         Ok(SqliteDeniableStore::builder()
             .session_store(SqliteSessionStore::new(self.connector.pool()))
             .pre_key_store(SqlitePreKeyStore::new(self.connector.pool()))
@@ -58,7 +103,7 @@ impl DeniableStoreConfig for SqliteDeniableStoreConfig {
                 self.connector.pool(),
                 self.buffer_size,
             ))
-            .seed_store(InMemoryPreKeySeedStore::default())
+            .seed_store(seed_store)
             .build())
     }
 }
@@ -3,10 +3,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .type_attribute("DeniableMessage", "#[derive(bon::Builder)]")
         .type_attribute("UserMessage", "#[derive(bon::Builder)]")
         .type_attribute("BlockRequest", "#[derive(bon::Builder)]")
+        .type_attribute("UnblockRequest", "#[derive(bon::Builder)]")
+        .type_attribute("BlockListRequest", "#[derive(bon::Builder)]")
+        .type_attribute("BlockListResponse", "#[derive(bon::Builder)]")
         .type_attribute("KeyRequest", "#[derive(bon::Builder)]")
         .type_attribute("KeyResponse", "#[derive(bon::Builder)]")
         .type_attribute("KeyUpdate", "#[derive(bon::Builder)]")
         .type_attribute("SeedUpdate", "#[derive(bon::Builder)]")
+        .type_attribute("SeedUpdateAck", "#[derive(bon::Builder)]")
+        .type_attribute("SeedUpdateRejected", "#[derive(bon::Builder)]")
+        .type_attribute("SeedRequest", "#[derive(bon::Builder)]")
+        .type_attribute("KeysReset", "#[derive(bon::Builder)]")
         .type_attribute("Error", "#[derive(bon::Builder)]")
         .type_attribute("DummyPadding", "#[derive(bon::Builder)]")
         .type_attribute("KeyBundle", "#[derive(bon::Builder)]")
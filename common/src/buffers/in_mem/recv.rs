@@ -156,6 +156,8 @@ mod test {
             .message_kind(MessageKind::SeedUpdate(crate::denim_message::SeedUpdate {
                 pre_key_seed: vec![1],
                 pre_key_id_seed: vec![1],
+                epoch: 1,
+                resync: None,
             }))
             .build()
     }
@@ -1,29 +1,37 @@
 use crate::buffers::{
-    DeniablePayload, DenimChunk, Flag, MessageId, SendingBuffer, SendingBufferConfig,
-    SequenceNumber,
+    DeniablePayload, DenimChunk, DenimMessage, Flag, MessageId, PaddingScheme, SendingBuffer,
+    SendingBufferConfig, SequenceNumber,
 };
 use crate::denim_message::DeniableMessage;
 use crate::error::DenimBufferError;
 use async_trait::async_trait;
 use atomic_float::AtomicF32;
-use log::debug;
+use log::{debug, error};
 use prost::Message;
 use rand::RngCore;
 use std::collections::VecDeque;
 
-use std::mem::take;
+use std::mem::{discriminant, take};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
-struct Buffer {
-    content: Vec<u8>,
-    message_id: MessageId,
-    next_sequence_number: SequenceNumber,
+/// Remainder of the message a sending buffer is currently chunking.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PartialMessage {
+    /// The whole encoded message.
+    pub message: Vec<u8>,
+    /// The part of the message that has not been chunked yet.
+    pub content: Vec<u8>,
+    pub message_id: MessageId,
+    pub next_sequence_number: SequenceNumber,
 }
 
+type Buffer = PartialMessage;
+
 #[derive(Clone)]
 pub struct InMemorySendingBuffer {
     q: Arc<AtomicF32>,
+    padding: Arc<Mutex<Option<PaddingScheme>>>,
     chunk_size_without_payload: usize,
     outgoing_messages: Arc<Mutex<VecDeque<DeniableMessage>>>,
     buffer: Arc<Mutex<Buffer>>,
@@ -48,15 +56,23 @@ impl SendingBuffer for InMemorySendingBuffer {
     async fn get_q(&self) -> f32 {
         self.q.load(std::sync::atomic::Ordering::Relaxed)
     }
+    async fn set_padding(&mut self, padding: Option<PaddingScheme>) {
+        *self.padding.lock().await = padding;
+    }
     async fn get_deniable_payload(
         &mut self,
         reg_message_len: u32,
     ) -> Result<DeniablePayload, DenimBufferError> {
-        if self.q.load(std::sync::atomic::Ordering::Relaxed) == 0.0 {
+        let padding = self.padding.lock().await.clone();
+        if self.q.load(std::sync::atomic::Ordering::Relaxed) == 0.0 && padding.is_none() {
             return Ok(DeniablePayload::default());
         }
 
-        let mut available_bytes = self.calculate_deniable_payload_length(reg_message_len);
+        let mut available_bytes = DenimMessage::deniable_payload_length(
+            reg_message_len,
+            self.q.load(std::sync::atomic::Ordering::Relaxed),
+            padding.as_ref(),
+        )?;
 
         if available_bytes < self.chunk_size_without_payload {
             return Ok(DeniablePayload::builder()
@@ -115,42 +131,119 @@ impl SendingBuffer for InMemorySendingBuffer {
             .build())
     }
 
-    async fn enqueue_message(&mut self, deniable_message: DeniableMessage) {
+    async fn enqueue_message(
+        &mut self,
+        deniable_message: DeniableMessage,
+    ) -> Result<(), DenimBufferError> {
         self.outgoing_messages
             .lock()
             .await
             .push_back(deniable_message);
+        Ok(())
+    }
+
+    async fn replace_message(
+        &mut self,
+        deniable_message: DeniableMessage,
+    ) -> Result<(), DenimBufferError> {
+        let mut outgoing_messages = self.outgoing_messages.lock().await;
+        match queued_position(&outgoing_messages, &deniable_message) {
+            Some(position) => outgoing_messages[position] = deniable_message,
+            None => outgoing_messages.push_back(deniable_message),
+        }
+        Ok(())
+    }
+
+    async fn next_message_id(&self) -> MessageId {
+        let buffer = self.buffer.lock().await;
+        let current = (!buffer.content.is_empty()).then_some(buffer.message_id);
+        self.outgoing_messages
+            .lock()
+            .await
+            .iter()
+            .map(|message| message.message_id)
+            .chain(current)
+            .max()
+            .map_or(0, |message_id| message_id.wrapping_add(1))
+    }
+
+    async fn is_empty(&self) -> bool {
+        self.buffer.lock().await.content.is_empty()
+            && self.outgoing_messages.lock().await.is_empty()
+    }
+
+    async fn restart_current(&mut self, message_id: MessageId) -> Option<MessageId> {
+        let interrupted = {
+            let mut buffer = self.buffer.lock().await;
+            if buffer.content.is_empty() {
+                return None;
+            }
+            take(&mut *buffer)
+        };
+        let mut message = match DeniableMessage::decode(interrupted.message.as_slice()) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Failed to decode interrupted deniable message '{e}'");
+                return None;
+            }
+        };
+        message.message_id = message_id;
+        self.outgoing_messages.lock().await.push_front(message);
+        Some(interrupted.message_id)
     }
 }
 
 impl InMemorySendingBuffer {
     pub fn new(q: f32) -> Result<Self, DenimBufferError> {
+        Self::restore(q, VecDeque::new(), PartialMessage::default())
+    }
+
+    /// Creates a buffer that continues chunking `current` before sending `outgoing_messages`.
+    pub fn restore(
+        q: f32,
+        outgoing_messages: VecDeque<DeniableMessage>,
+        current: PartialMessage,
+    ) -> Result<Self, DenimBufferError> {
         let chunk_size_without_payload = DenimChunk::get_size_without_payload()?;
 
         Ok(Self {
             q: Arc::new(AtomicF32::new(q)),
+            padding: Arc::new(Mutex::new(None)),
             chunk_size_without_payload,
-            outgoing_messages: Arc::new(Mutex::new(VecDeque::new())),
-            buffer: Arc::new(Mutex::new(Buffer {
-                content: Vec::new(),
-                message_id: 0,
-                next_sequence_number: 0,
-            })),
+            outgoing_messages: Arc::new(Mutex::new(outgoing_messages)),
+            buffer: Arc::new(Mutex::new(current)),
         })
     }
-    fn calculate_deniable_payload_length(&self, reg_message_len: u32) -> usize {
-        (reg_message_len as f32 * self.q.load(std::sync::atomic::Ordering::Relaxed)).ceil() as usize
+
+    /// Number of enqueued messages that have not started chunking yet.
+    pub async fn outgoing_len(&self) -> usize {
+        self.outgoing_messages.lock().await.len()
+    }
+
+    /// Position of the first enqueued message of the same kind as `deniable_message`
+    /// that has not started chunking.
+    pub async fn queued_position(&self, deniable_message: &DeniableMessage) -> Option<usize> {
+        queued_position(&*self.outgoing_messages.lock().await, deniable_message)
     }
 
+    /// The message currently being chunked, empty if there is none.
+    pub async fn current(&self) -> PartialMessage {
+        self.buffer.lock().await.clone()
+    }
     async fn get_next_chunk(&mut self, available_bytes: usize) -> Option<DenimChunk> {
         if self.buffer.lock().await.content.is_empty() {
-            self.buffer = match self.outgoing_messages.lock().await.pop_front() {
+            // replaced in place so clones of the buffer continue the same message
+            *self.buffer.lock().await = match self.outgoing_messages.lock().await.pop_front() {
                 None => return None,
-                Some(message) => Arc::new(Mutex::new(Buffer {
-                    content: message.encode_to_vec(),
-                    message_id: message.message_id,
-                    next_sequence_number: 0,
-                })),
+                Some(message) => {
+                    let content = message.encode_to_vec();
+                    Buffer {
+                        message: content.clone(),
+                        content,
+                        message_id: message.message_id,
+                        next_sequence_number: 0,
+                    }
+                }
             }
         }
         let chunk_bytes;
@@ -200,12 +293,22 @@ impl InMemorySendingBuffer {
     }
 }
 
+fn queued_position(
+    outgoing_messages: &VecDeque<DeniableMessage>,
+    deniable_message: &DeniableMessage,
+) -> Option<usize> {
+    let kind = deniable_message.message_kind.as_ref().map(discriminant);
+    outgoing_messages
+        .iter()
+        .position(|queued| queued.message_kind.as_ref().map(discriminant) == kind)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::buffers::types::DenimMessage;
     use crate::denim_message::deniable_message::MessageKind;
-    use crate::denim_message::{MessageType, UserMessage};
+    use crate::denim_message::{MessageType, SeedUpdate, UserMessage};
     use rstest::rstest;
 
     fn make_deniable_messages(lengths: Vec<usize>) -> VecDeque<DeniableMessage> {
@@ -221,6 +324,8 @@ mod test {
                     message_type: MessageType::SignalMessage.into(),
                     content: random_bytes,
                     rng_counter: None,
+                    seed_epoch: None,
+                    device_id: None,
                 })),
             });
         }
@@ -245,7 +350,10 @@ mod test {
         let mut sending_buffer = InMemorySendingBuffer::new(q).expect("Can make SendingBuffer");
 
         for message in deniable_messages {
-            sending_buffer.enqueue_message(message).await;
+            sending_buffer
+                .enqueue_message(message)
+                .await
+                .expect("Can enqueue message");
         }
 
         let deniable_payload = sending_buffer
@@ -254,6 +362,171 @@ mod test {
             .expect("Can get deniable payload");
 
         assert_eq!(deniable_payload.denim_chunks().len(), expected_chunks);
+        assert_eq!(
+            deniable_payload.payload_length().ok(),
+            DenimMessage::deniable_payload_length(regular_msg_len, q, None).ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn restored_buffer_continues_chunking() {
+        let mut sending_buffer = InMemorySendingBuffer::new(0.5).expect("Can make SendingBuffer");
+        for message in make_deniable_messages(vec![200, 20]) {
+            sending_buffer
+                .enqueue_message(message)
+                .await
+                .expect("Can enqueue message");
+        }
+        let first = sending_buffer
+            .get_deniable_payload(100)
+            .await
+            .expect("Can get deniable payload");
+        assert_eq!(first.denim_chunks()[0].sequence_number(), 0);
+
+        let current = sending_buffer.current().await;
+        assert!(!current.content.is_empty());
+        assert_eq!(current.next_sequence_number, 1);
+        assert_eq!(sending_buffer.outgoing_len().await, 1);
+
+        let mut restored =
+            InMemorySendingBuffer::restore(0.5, make_deniable_messages(vec![20]), current.clone())
+                .expect("Can restore SendingBuffer");
+        assert_eq!(restored.next_message_id().await, current.message_id + 1);
+        let next = restored
+            .get_deniable_payload(100)
+            .await
+            .expect("Can get deniable payload");
+        let chunk = &next.denim_chunks()[0];
+        assert_eq!(chunk.message_id(), current.message_id);
+        assert_eq!(chunk.sequence_number(), 1);
+    }
+
+    #[tokio::test]
+    async fn restarted_message_is_chunked_again_from_the_start() {
+        let mut sending_buffer = InMemorySendingBuffer::new(0.5).expect("Can make SendingBuffer");
+        assert!(sending_buffer.is_empty().await);
+        assert_eq!(sending_buffer.next_message_id().await, 0);
+        for message in make_deniable_messages(vec![200]) {
+            sending_buffer
+                .enqueue_message(message)
+                .await
+                .expect("Can enqueue message");
+        }
+        assert!(!sending_buffer.is_empty().await);
+        sending_buffer
+            .get_deniable_payload(100)
+            .await
+            .expect("Can get deniable payload");
+
+        assert_eq!(sending_buffer.restart_current(7).await, Some(0));
+        assert_eq!(sending_buffer.restart_current(8).await, None);
+
+        let payload = sending_buffer
+            .get_deniable_payload(100)
+            .await
+            .expect("Can get deniable payload");
+        let chunk = &payload.denim_chunks()[0];
+        assert_eq!((chunk.message_id(), chunk.sequence_number()), (7, 0));
+    }
+
+    #[tokio::test]
+    async fn replaced_message_keeps_its_place() {
+        let seed_update = |message_id: u32, epoch: u32| DeniableMessage {
+            message_id,
+            message_kind: Some(MessageKind::SeedUpdate(
+                SeedUpdate::builder()
+                    .pre_key_seed(vec![1; 32])
+                    .pre_key_id_seed(vec![2; 32])
+                    .epoch(epoch)
+                    .build(),
+            )),
+        };
+        let mut sending_buffer = InMemorySendingBuffer::new(0.5).expect("Can make SendingBuffer");
+        for message in make_deniable_messages(vec![200, 200]) {
+            sending_buffer
+                .enqueue_message(message)
+                .await
+                .expect("Can enqueue message");
+        }
+        sending_buffer
+            .replace_message(seed_update(2, 1))
+            .await
+            .expect("Can enqueue message");
+        sending_buffer
+            .enqueue_message(make_deniable_messages(vec![20, 20, 20, 20])[3].clone())
+            .await
+            .expect("Can enqueue message");
+        sending_buffer
+            .replace_message(seed_update(4, 2))
+            .await
+            .expect("Can enqueue message");
+
+        // the first message started chunking, so only the rest can be replaced
+        sending_buffer
+            .get_deniable_payload(100)
+            .await
+            .expect("Can get deniable payload");
+        let mut replaced = make_deniable_messages(vec![20])[0].clone();
+        replaced.message_id = 5;
+        sending_buffer
+            .replace_message(replaced)
+            .await
+            .expect("Can enqueue message");
+
+        let outgoing: Vec<_> = sending_buffer
+            .outgoing_messages
+            .lock()
+            .await
+            .iter()
+            .map(|message| message.message_id)
+            .collect();
+        assert_eq!(outgoing, [5, 4, 3]);
+        assert!(matches!(
+            &sending_buffer.outgoing_messages.lock().await[1].message_kind,
+            Some(MessageKind::SeedUpdate(update)) if update.epoch == 2
+        ));
+    }
+
+    #[rstest]
+    #[case(PaddingScheme::PowersOfTwo, 10, 0.5, vec![], 64)]
+    #[case(PaddingScheme::PowersOfTwo, 100, 1.0, vec![20, 30], 256)]
+    #[case(PaddingScheme::PowersOfTwo, 100, 0.0, vec![], 128)]
+    #[case(PaddingScheme::Tiers(vec![512, 2048]), 300, 0.5, vec![500], 512)]
+    #[case(PaddingScheme::Tiers(vec![512, 2048]), 3000, 0.1, vec![], 4096)]
+    #[tokio::test]
+    async fn padded_messages_fill_their_bucket(
+        #[case] padding: PaddingScheme,
+        #[case] regular_msg_len: usize,
+        #[case] q: f32,
+        #[case] message_lengths: Vec<usize>,
+        #[case] bucket: usize,
+    ) {
+        let mut sending_buffer = InMemorySendingBuffer::new(q).expect("Can make SendingBuffer");
+        sending_buffer.set_padding(Some(padding.clone())).await;
+        for message in make_deniable_messages(message_lengths) {
+            sending_buffer
+                .enqueue_message(message)
+                .await
+                .expect("Can enqueue message");
+        }
+
+        let regular_msg = InMemorySendingBuffer::create_n_random_bytes(regular_msg_len);
+        let deniable_payload = sending_buffer
+            .get_deniable_payload(regular_msg_len as u32)
+            .await
+            .expect("Can get deniable payload");
+        assert_eq!(
+            deniable_payload.payload_length().ok(),
+            DenimMessage::deniable_payload_length(regular_msg_len as u32, q, Some(&padding)).ok()
+        );
+        let encoded = DenimMessage::builder()
+            .q(q)
+            .regular_payload(regular_msg)
+            .deniable_payload(deniable_payload)
+            .build()
+            .encode()
+            .expect("Can encode DenimMessage");
+        assert_eq!(encoded.len(), bucket);
     }
 
     #[rstest]
@@ -283,7 +556,10 @@ mod test {
         let mut sending_buffer = InMemorySendingBuffer::new(q).expect("Can make SendingBuffer");
 
         for message in deniable_messages {
-            sending_buffer.enqueue_message(message).await;
+            sending_buffer
+                .enqueue_message(message)
+                .await
+                .expect("Can enqueue message");
         }
 
         let deniable_payload = sending_buffer
@@ -1,7 +1,9 @@
 pub mod in_mem;
+mod padding;
 mod traits;
 pub mod types;
 
-pub use in_mem::{InMemoryReceivingBuffer, InMemorySendingBuffer};
+pub use in_mem::{InMemoryReceivingBuffer, InMemorySendingBuffer, PartialMessage};
+pub use padding::{PaddingScheme, PADDING_HEADER};
 pub use traits::{ReceivingBuffer, ReceivingBufferConfig, SendingBuffer, SendingBufferConfig};
 pub use types::{DeniablePayload, DenimChunk, DenimMessage, Flag, MessageId, SequenceNumber};
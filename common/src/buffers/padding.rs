@@ -0,0 +1,88 @@
+use crate::denim_message::Padding;
+
+/// Header a client sets when connecting to offer padding to the proxy.
+pub const PADDING_HEADER: &str = "x-denim-padding";
+
+/// Sizes every `DenimMessage` is padded up to, so the size of the regular message is hidden.
+/// The extra space is filled with deniable chunks or garbage.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PaddingScheme {
+    PowersOfTwo,
+    /// Bucket sizes in ascending order. Larger messages are padded to a multiple of the last tier.
+    Tiers(Vec<usize>),
+}
+
+impl PaddingScheme {
+    /// The smallest bucket that fits `len` bytes.
+    pub fn bucket(&self, len: usize) -> usize {
+        match self {
+            PaddingScheme::PowersOfTwo => len.next_power_of_two(),
+            PaddingScheme::Tiers(tiers) => match tiers.iter().find(|tier| **tier >= len) {
+                Some(tier) => *tier,
+                None => match tiers.last() {
+                    Some(&largest) if largest > 0 => len.div_ceil(largest) * largest,
+                    _ => len,
+                },
+            },
+        }
+    }
+}
+
+impl From<Padding> for PaddingScheme {
+    fn from(padding: Padding) -> Self {
+        if padding.tiers.is_empty() {
+            return PaddingScheme::PowersOfTwo;
+        }
+        let mut tiers: Vec<usize> = padding
+            .tiers
+            .into_iter()
+            .map(|tier| tier as usize)
+            .collect();
+        tiers.sort_unstable();
+        PaddingScheme::Tiers(tiers)
+    }
+}
+
+impl From<&PaddingScheme> for Padding {
+    fn from(scheme: &PaddingScheme) -> Self {
+        match scheme {
+            PaddingScheme::PowersOfTwo => Padding { tiers: Vec::new() },
+            PaddingScheme::Tiers(tiers) => Padding {
+                tiers: tiers
+                    .iter()
+                    .map(|tier| (*tier).try_into().unwrap_or(u32::MAX))
+                    .collect(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rstest::rstest;
+
+    use super::PaddingScheme;
+    use crate::denim_message::Padding;
+
+    #[rstest]
+    #[case(PaddingScheme::PowersOfTwo, 1, 1)]
+    #[case(PaddingScheme::PowersOfTwo, 100, 128)]
+    #[case(PaddingScheme::PowersOfTwo, 1024, 1024)]
+    #[case(PaddingScheme::Tiers(vec![256, 1024]), 10, 256)]
+    #[case(PaddingScheme::Tiers(vec![256, 1024]), 257, 1024)]
+    #[case(PaddingScheme::Tiers(vec![256, 1024]), 1500, 2048)]
+    fn messages_are_padded_to_the_next_bucket(
+        #[case] scheme: PaddingScheme,
+        #[case] len: usize,
+        #[case] bucket: usize,
+    ) {
+        assert_eq!(scheme.bucket(len), bucket);
+    }
+
+    #[rstest]
+    #[case(PaddingScheme::PowersOfTwo)]
+    #[case(PaddingScheme::Tiers(vec![256, 1024]))]
+    fn padding_scheme_survives_negotiation(#[case] scheme: PaddingScheme) {
+        assert_eq!(PaddingScheme::from(Padding::from(&scheme)), scheme);
+    }
+}
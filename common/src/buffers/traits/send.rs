@@ -1,6 +1,6 @@
 use async_trait::async_trait;
 
-use crate::buffers::DeniablePayload;
+use crate::buffers::{DeniablePayload, MessageId, PaddingScheme};
 use crate::denim_message::DeniableMessage;
 use crate::error::DenimBufferError;
 
@@ -8,12 +8,36 @@ use crate::error::DenimBufferError;
 pub trait SendingBuffer: Clone + Send + Sync + 'static {
     async fn set_q(&mut self, q: f32);
     async fn get_q(&self) -> f32;
+    /// Pads every payload so the whole `DenimMessage` fills a bucket of `padding`.
+    async fn set_padding(&mut self, padding: Option<PaddingScheme>);
     async fn get_deniable_payload(
         &mut self,
         reg_message_len: u32,
     ) -> Result<DeniablePayload, DenimBufferError>;
 
-    async fn enqueue_message(&mut self, deniable_message: DeniableMessage);
+    async fn enqueue_message(
+        &mut self,
+        deniable_message: DeniableMessage,
+    ) -> Result<(), DenimBufferError>;
+
+    /// Replaces the first enqueued message of the same kind that has not started chunking,
+    /// keeping its place in the queue. Enqueues the message if there is none.
+    async fn replace_message(
+        &mut self,
+        deniable_message: DeniableMessage,
+    ) -> Result<(), DenimBufferError>;
+
+    /// Whether there is no deniable message left to chunk.
+    async fn is_empty(&self) -> bool;
+
+    /// Sends the message currently being chunked again from its first chunk as `message_id`,
+    /// for when chunks may have been lost with a connection.
+    /// Returns the previous id of the message, `None` if no message was being chunked.
+    async fn restart_current(&mut self, message_id: MessageId) -> Option<MessageId>;
+
+    /// Smallest message id above the ids of all buffered messages,
+    /// so messages restored from a previous run do not share an id with new ones.
+    async fn next_message_id(&self) -> MessageId;
 }
 
 #[async_trait]
@@ -1,3 +1,4 @@
+use crate::buffers::PaddingScheme;
 use crate::error::DenimEncodeDecodeError;
 use bincode::config;
 use bincode::{Decode, Encode};
@@ -84,6 +85,13 @@ impl DeniablePayload {
     pub fn garbage(&self) -> &Vec<u8> {
         &self.garbage
     }
+
+    /// Bytes of chunks and garbage, what a sender budgets with [DenimMessage::deniable_payload_length].
+    pub fn payload_length(&self) -> Result<usize, DenimEncodeDecodeError> {
+        self.denim_chunks
+            .iter()
+            .try_fold(self.garbage.len(), |len, chunk| Ok(len + chunk.get_size()?))
+    }
 }
 
 #[derive(Encode, Decode, Builder, Clone)]
@@ -94,6 +102,36 @@ pub struct DenimMessage {
 }
 
 impl DenimMessage {
+    /// Length of the deniable payload sent with a regular payload of `regular_len` bytes,
+    /// `ceil(regular_len * q)` plus whatever fills the bucket of `padding`.
+    pub fn deniable_payload_length(
+        regular_len: u32,
+        q: f32,
+        padding: Option<&PaddingScheme>,
+    ) -> Result<usize, DenimEncodeDecodeError> {
+        let deniable_len = (regular_len as f32 * q).ceil() as usize;
+        Ok(match padding {
+            // the encoded message fills the bucket exactly
+            Some(padding) => {
+                let unpadded = DenimMessage::get_size_without_payloads()?
+                    + regular_len as usize
+                    + deniable_len;
+                padding.bucket(unpadded) - unpadded + deniable_len
+            }
+            None => deniable_len,
+        })
+    }
+
+    pub fn get_size_without_payloads() -> Result<usize, DenimEncodeDecodeError> {
+        DenimMessage::builder()
+            .q(0.0)
+            .regular_payload(Vec::new())
+            .deniable_payload(DeniablePayload::default())
+            .build()
+            .encode()
+            .map(|encoded| encoded.len())
+    }
+
     pub fn encode(self) -> Result<Vec<u8>, DenimEncodeDecodeError> {
         bincode::encode_to_vec(self, config::standard().with_fixed_int_encoding())
             .map_err(|_| DenimEncodeDecodeError::DenimMessageEncode)
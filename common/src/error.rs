@@ -5,6 +5,10 @@ pub enum DenimBufferError {
     MinPayloadLengthTooHighError,
     ChunkBufferNotFound,
     EncodingDecoding(DenimEncodeDecodeError),
+    /// The buffer could not store an enqueued message, it was not enqueued.
+    #[from(skip)]
+    #[display("Storage({_0})")]
+    Storage(#[error(not(source))] String),
 }
 
 #[derive(Debug, Display, Error, From)]
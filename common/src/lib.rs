@@ -63,7 +63,14 @@ impl Display for MessageKind {
             MessageKind::KeyRequest(_) => write!(f, "Key Request"),
             MessageKind::KeyResponse(_) => write!(f, "Key Response"),
             MessageKind::SeedUpdate(_) => write!(f, "Seed Update"),
+            MessageKind::SeedUpdateAck(_) => write!(f, "Seed Update Ack"),
+            MessageKind::SeedUpdateRejected(_) => write!(f, "Seed Update Rejected"),
+            MessageKind::SeedRequest(_) => write!(f, "Seed Request"),
+            MessageKind::KeysReset(_) => write!(f, "Keys Reset"),
             MessageKind::Error(_) => write!(f, "Error"),
+            MessageKind::UnblockRequest(_) => write!(f, "Unblock Request"),
+            MessageKind::BlockListRequest(_) => write!(f, "Block List Request"),
+            MessageKind::BlockListResponse(_) => write!(f, "Block List Response"),
         }
     }
 }
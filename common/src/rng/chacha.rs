@@ -34,6 +34,10 @@ impl ChaChaRngState {
         Self(seed, offset)
     }
 
+    pub fn seed(&self) -> &<ChaCha20Rng as SeedableRng>::Seed {
+        &self.0
+    }
+
     pub fn random(rng: &mut impl Rng) -> Self {
         let mut bytes = <ChaCha20Rng as SeedableRng>::Seed::default();
         rng.fill_bytes(&mut bytes);
@@ -50,6 +54,9 @@ impl RngState for ChaChaRngState {
     fn offset(&self) -> u128 {
         self.1
     }
+    fn with_offset(self, offset: u128) -> Self {
+        Self(self.0, offset)
+    }
 }
 
 impl From<ChaChaRngState> for ChaCha20Rng {
@@ -0,0 +1,49 @@
+use rand::RngCore;
+
+use super::RngState;
+
+/// Generating one pre key uses this many words of the key stream.
+pub const KEY_STREAM_WORDS_PER_KEY: u128 = 8;
+
+/// Id of the deniable pre key at `index`.
+/// Every key id uses one word of the key id stream.
+pub fn key_id_at<T: RngState>(key_id_seed: T, index: u64) -> u32 {
+    key_id_seed.with_offset(index.into()).into_rng().next_u32()
+}
+
+/// Rng that generates the deniable pre key at `index`.
+pub fn key_rng_at<T: RngState>(key_seed: T, index: u64) -> T::Rng {
+    key_seed
+        .with_offset(u128::from(index) * KEY_STREAM_WORDS_PER_KEY)
+        .into_rng()
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::OsRng, RngCore};
+
+    use crate::rng::{chacha::ChaChaRngState, RngState};
+
+    use super::{key_id_at, key_rng_at, KEY_STREAM_WORDS_PER_KEY};
+
+    #[test]
+    fn key_id_at_seeks_in_key_id_stream() {
+        let seed = ChaChaRngState::random(&mut OsRng);
+        let mut rng = seed.clone().into_rng();
+
+        for index in 0..10 {
+            assert_eq!(key_id_at(seed.clone(), index), rng.next_u32());
+        }
+    }
+
+    #[test]
+    fn key_rng_at_seeks_past_previous_keys() {
+        let seed = ChaChaRngState::random(&mut OsRng);
+        let mut rng = seed.clone().into_rng();
+        for _ in 0..3 * KEY_STREAM_WORDS_PER_KEY {
+            rng.next_u32();
+        }
+
+        assert_eq!(key_rng_at(seed, 3).next_u64(), rng.next_u64());
+    }
+}
@@ -0,0 +1,27 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Exponentially distributed duration with the given mean, like the gaps of a Poisson process.
+pub fn exponential(mean: Duration, rng: &mut impl Rng) -> Duration {
+    // inverse transform sampling, 1 - u avoids ln(0)
+    let u: f64 = rng.gen();
+    mean.mul_f64(-(1.0 - u).ln())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::exponential;
+
+    #[test]
+    fn exponential_samples_average_to_mean() {
+        let mut rng = rand::thread_rng();
+        let mean = (0..1000)
+            .map(|_| exponential(Duration::from_secs(10), &mut rng))
+            .sum::<Duration>()
+            / 1000;
+        assert!(Duration::from_secs(8) < mean && mean < Duration::from_secs(12));
+    }
+}
@@ -1,4 +1,6 @@
 pub mod chacha;
+pub mod derive;
+pub mod exponential;
 pub mod seed;
 mod state;
 
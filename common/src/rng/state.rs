@@ -5,4 +5,6 @@ pub trait RngState: Into<Self::Rng> + From<Self::Rng> + Send + Sync + Clone + De
 
     fn into_rng(self) -> Self::Rng;
     fn offset(&self) -> u128;
+    /// Returns the same stream positioned at word `offset`.
+    fn with_offset(self, offset: u128) -> Self;
 }
@@ -20,6 +20,8 @@ pub fn make_deniable_messages(lengths: Vec<usize>) -> VecDeque<DeniableMessage>
                 message_type: MessageType::SignalMessage.into(),
                 content,
                 rng_counter: None,
+                seed_epoch: None,
+                device_id: None,
             })),
         });
     }
@@ -45,7 +47,10 @@ pub async fn send_recv_buffer(
     let mut sending_buffer = InMemorySendingBuffer::new(q).expect("Can make SendingBuffer");
 
     for message in deniable_messages {
-        sending_buffer.enqueue_message(message).await;
+        sending_buffer
+            .enqueue_message(message)
+            .await
+            .expect("Can enqueue message");
     }
 
     let mut deniable_payloads: Vec<DeniablePayload> = Vec::new();
@@ -91,7 +96,9 @@ pub async fn send_recv_buffer(
                     account_id: vec![i as u8],
                     message_type: MessageType::SignalMessage.into(),
                     content,
-                    rng_counter: None
+                    rng_counter: None,
+                    seed_epoch: None,
+                    device_id: None
                 })
             )
         }
@@ -1,3 +1,4 @@
+use denim_sam_client::message::dead_letter::InMemoryDeadLetterQueueConfig;
 use denim_sam_client::message::queue::InMemoryMessageQueueConfig;
 use denim_sam_client::store::inmem::InMemoryDeniableStoreConfig;
 use denim_sam_client::DenimClient;
@@ -243,6 +244,7 @@ pub async fn two_clients_cannot_have_the_same_username(
         .deniable_store_config(InMemoryDeniableStoreConfig::default())
         .api_client_config(http_config(server.address(), None))
         .message_queue_config(InMemoryMessageQueueConfig)
+        .dead_letter_config(InMemoryDeadLetterQueueConfig)
         .protocol_config(DenimProtocolClientConfig::new(
             proxy.address().to_string(),
             None,
@@ -1,7 +1,6 @@
 use denim_sam_client::store::sqlite::SqliteDeniableStoreConfig;
 use denim_sam_client::{
-    client::SqliteDenimClientType, message::queue::InMemoryMessageQueueConfig,
-    protocol::DenimProtocolClientConfig, DenimClient,
+    client::SqliteDenimClientType, protocol::DenimProtocolClientConfig, DenimClient,
 };
 use denim_sam_common::buffers::{InMemoryReceivingBuffer, InMemorySendingBuffer};
 use denim_sam_proxy::state::DenimStateType;
@@ -61,24 +60,30 @@ async fn can_link_device(
         .await
         .expect("Can get id key pair");
 
+    let deniable_store_config = SqliteDeniableStoreConfig::in_memory(10)
+        .await
+        .expect("can create inmemory");
+    let message_queue_config = deniable_store_config.message_queue_config();
+    let dead_letter_config = deniable_store_config.dead_letter_config();
+    let sending_buffer = deniable_store_config
+        .sending_buffer(0.0)
+        .await
+        .expect("can make sending buffer");
     let new_device = DenimClient::<SqliteDenimClientType>::from_provisioning()
         .store_config(
             SqliteStoreConfig::in_memory(10)
                 .await
                 .expect("can create inmemory"),
         )
-        .deniable_store_config(
-            SqliteDeniableStoreConfig::in_memory(10)
-                .await
-                .expect("can create inmemory"),
-        )
+        .deniable_store_config(deniable_store_config)
         .api_client_config(HttpClientConfig::new(server.address().to_owned()))
-        .message_queue_config(InMemoryMessageQueueConfig)
+        .message_queue_config(message_queue_config)
+        .dead_letter_config(dead_letter_config)
         .protocol_config(DenimProtocolClientConfig::new(
             proxy.address().to_owned(),
             None,
             10,
-            InMemorySendingBuffer::new(0.0).expect("can make sending buffer"),
+            sending_buffer,
             InMemoryReceivingBuffer::default(),
         ))
         .device_name("Alice's Other Device")
@@ -133,6 +138,15 @@ async fn can_unlink_device(
         .await
         .expect("Can get id key pair");
 
+    let deniable_store_config = SqliteDeniableStoreConfig::in_memory(10)
+        .await
+        .expect("can create inmemory");
+    let message_queue_config = deniable_store_config.message_queue_config();
+    let dead_letter_config = deniable_store_config.dead_letter_config();
+    let sending_buffer = deniable_store_config
+        .sending_buffer(0.0)
+        .await
+        .expect("can make sending buffer");
     let other_client: DenimClient<SqliteDenimClientType> = DenimClient::from_provisioning()
         .api_client_config(HttpClientConfig::new(server.address().to_owned()))
         .store_config(
@@ -140,17 +154,14 @@ async fn can_unlink_device(
                 .await
                 .expect("can create inmemory"),
         )
-        .deniable_store_config(
-            SqliteDeniableStoreConfig::in_memory(10)
-                .await
-                .expect("can create inmemory"),
-        )
-        .message_queue_config(InMemoryMessageQueueConfig)
+        .deniable_store_config(deniable_store_config)
+        .message_queue_config(message_queue_config)
+        .dead_letter_config(dead_letter_config)
         .protocol_config(DenimProtocolClientConfig::new(
             proxy.address().to_owned(),
             None,
             10,
-            InMemorySendingBuffer::new(0.0).expect("can make sending buffer"),
+            sending_buffer,
             InMemoryReceivingBuffer::default(),
         ))
         .device_name("Alice's Other Device")
@@ -206,6 +217,15 @@ async fn can_delete_device(
         .await
         .expect("Can get id key pair");
 
+    let deniable_store_config = SqliteDeniableStoreConfig::in_memory(10)
+        .await
+        .expect("can create inmemory");
+    let message_queue_config = deniable_store_config.message_queue_config();
+    let dead_letter_config = deniable_store_config.dead_letter_config();
+    let sending_buffer = deniable_store_config
+        .sending_buffer(0.0)
+        .await
+        .expect("can make sending buffer");
     let other_client: DenimClient<SqliteDenimClientType> = DenimClient::from_provisioning()
         .api_client_config(HttpClientConfig::new(server.address().to_owned()))
         .store_config(
@@ -213,17 +233,14 @@ async fn can_delete_device(
                 .await
                 .expect("can create inmemory"),
         )
-        .deniable_store_config(
-            SqliteDeniableStoreConfig::in_memory(10)
-                .await
-                .expect("can create inmemory"),
-        )
-        .message_queue_config(InMemoryMessageQueueConfig)
+        .deniable_store_config(deniable_store_config)
+        .message_queue_config(message_queue_config)
+        .dead_letter_config(dead_letter_config)
         .protocol_config(DenimProtocolClientConfig::new(
             proxy.address().to_owned(),
             None,
             10,
-            InMemorySendingBuffer::new(0.0).expect("can make sending buffer"),
+            sending_buffer,
             InMemoryReceivingBuffer::default(),
         ))
         .device_name("Alice's Other Device")
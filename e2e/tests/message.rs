@@ -1,9 +1,17 @@
 use denim_sam_client::client::DenimClientType;
+use denim_sam_client::message::cover::{CoverTiming, CoverTrafficPolicy};
+use denim_sam_client::message::dead_letter::InMemoryDeadLetterQueueConfig;
+use denim_sam_client::message::event::DenimEvent;
+use denim_sam_client::message::key_request::{KeyRequestFailure, KeyRequestPolicy};
+use denim_sam_client::message::queue::InMemoryMessageQueueConfig;
+use denim_sam_client::protocol::DenimProtocolClientConfig;
+use denim_sam_client::store::InMemoryDeniableStoreConfig;
 use denim_sam_client::DenimClient;
 use denim_sam_common::buffers::{InMemoryReceivingBuffer, InMemorySendingBuffer};
 use denim_sam_proxy::state::DenimStateType;
 use rstest::rstest;
 use sam_client::encryption::DecryptedEnvelope;
+use sam_client::storage::InMemoryStoreConfig;
 use sam_common::AccountId;
 use sam_server::StateType;
 use sam_test_utils::get_next_port;
@@ -14,7 +22,7 @@ use utils::server::TestServerConfigs;
 use uuid::Uuid;
 mod utils;
 use crate::utils::server::{connection_str, in_memory_configs, postgres_configs};
-use utils::client::client_with_proxy;
+use utils::client::{client_with_proxy, http_config};
 use utils::server::TestServerConfig as _;
 
 const TIMEOUT_SECS: u64 = 20;
@@ -271,7 +279,10 @@ async fn alice_cannot_send_to_charlie_if_blocked(
         .await
         .expect("Bob can send message to Alice");
 
-    charlie.block_user(alice_id).await;
+    charlie
+        .block_user(alice_id)
+        .await
+        .expect("Charlie can block alice");
 
     charlie
         .send_message(bob_id, "Hello my very good friend")
@@ -650,7 +661,7 @@ async fn update_seed(
 
     let received_message = String::from_utf8_lossy(envelope.content_bytes()).to_string();
 
-    alice.update_key_seed().await.expect("Can update Seed");
+    alice.rotate_deniable_seed().await.expect("Can update Seed");
 
     assert_eq!(received_message, secret_message);
 
@@ -738,6 +749,287 @@ async fn deniable_messages_gets_enqueued_when_no_bundle_received(
     assert!(alice.enqueue_message(bob, "how goes?").await.is_ok())
 }
 
+#[rstest]
+#[case::in_memory(in_memory_configs(get_next_port(), get_next_port(), None))]
+#[ignore = "requires a postgres test database"]
+#[case::postgres(postgres_configs(get_next_port(), get_next_port(), None, connection_str()))]
+#[timeout(Duration::from_secs(TIMEOUT_SECS))]
+#[tokio::test]
+async fn waiting_messages_are_flushed_in_order(
+    #[future(awt)]
+    #[case]
+    server_configs: TestServerConfigs<impl StateType, impl DenimStateType>,
+) {
+    let mut server = server_configs.sam.start().await;
+    let mut proxy = server_configs.denim.start().await;
+
+    server
+        .started_rx()
+        .await
+        .expect("Should be able to start server");
+
+    proxy
+        .started_rx()
+        .await
+        .expect("Should be able to start server");
+
+    let mut alice = client_with_proxy(
+        proxy.address(),
+        server.address(),
+        &Uuid::new_v4().to_string(),
+        "alice device",
+        None,
+        InMemorySendingBuffer::new(0.0).expect("Can make sending buffer"),
+        InMemoryReceivingBuffer::default(),
+    )
+    .await;
+
+    let mut bob = client_with_proxy(
+        proxy.address(),
+        server.address(),
+        &Uuid::new_v4().to_string(),
+        "bob device",
+        None,
+        InMemorySendingBuffer::new(0.0).expect("Can make sending buffer"),
+        InMemoryReceivingBuffer::default(),
+    )
+    .await;
+
+    let mut charlie = client_with_proxy(
+        proxy.address(),
+        server.address(),
+        &Uuid::new_v4().to_string(),
+        "charlie device",
+        None,
+        InMemorySendingBuffer::new(0.0).expect("Can make sending buffer"),
+        InMemoryReceivingBuffer::default(),
+    )
+    .await;
+
+    let mut dorothy = client_with_proxy(
+        proxy.address(),
+        server.address(),
+        &Uuid::new_v4().to_string(),
+        "dorothy device",
+        None,
+        InMemorySendingBuffer::new(0.0).expect("Can make sending buffer"),
+        InMemoryReceivingBuffer::default(),
+    )
+    .await;
+
+    let mut alice_events = alice.subscribe();
+    let mut charlie_deniable_messages = charlie.deniable_subscribe();
+
+    communicate_deniable(&mut alice, &mut bob, &mut charlie, &mut dorothy).await;
+
+    let secret_messages = ["first secret", "second secret", "third secret"];
+    for message in secret_messages {
+        alice
+            .enqueue_message(charlie.account_id(), message)
+            .await
+            .expect("Can enqueue deniable message");
+    }
+    assert_eq!(
+        alice
+            .pending_status(charlie.account_id())
+            .await
+            .expect("Can get pending status")
+            .map(|status| status.waiting_messages),
+        Some(secret_messages.len())
+    );
+
+    let mut received = Vec::new();
+    while received.len() < secret_messages.len() {
+        communicate_deniable(&mut alice, &mut bob, &mut charlie, &mut dorothy).await;
+        while let Ok(result) =
+            timeout(Duration::from_millis(50), charlie_deniable_messages.recv()).await
+        {
+            let envelope = result.expect("Can get deniable message from Alice");
+            received.push(String::from_utf8_lossy(envelope.content_bytes()).to_string());
+        }
+    }
+    assert_eq!(received, secret_messages);
+
+    let mut completed = false;
+    while let Ok(event) = alice_events.try_recv() {
+        match event {
+            DenimEvent::KeyExchangeCompleted(account_id) => {
+                completed |= account_id == charlie.account_id()
+            }
+            DenimEvent::DeniableSendFailed(failure) => {
+                panic!("Waiting message failed to send '{}'", failure.error)
+            }
+            _ => (),
+        }
+    }
+    assert!(completed);
+    assert!(alice
+        .pending_status(charlie.account_id())
+        .await
+        .expect("Can get pending status")
+        .is_none());
+}
+
+#[rstest]
+#[case(in_memory_configs(get_next_port(), get_next_port(), None))]
+#[timeout(Duration::from_secs(TIMEOUT_SECS))]
+#[tokio::test]
+async fn waiting_messages_are_reported_when_keys_never_arrive(
+    #[future(awt)]
+    #[case]
+    server_configs: TestServerConfigs<impl StateType, impl DenimStateType>,
+) {
+    let mut server = server_configs.sam.start().await;
+    let mut proxy = server_configs.denim.start().await;
+
+    server
+        .started_rx()
+        .await
+        .expect("Should be able to start server");
+
+    proxy
+        .started_rx()
+        .await
+        .expect("Should be able to start server");
+
+    let mut alice = DenimClient::from_registration()
+        .username(&Uuid::new_v4().to_string())
+        .device_name("alice device")
+        .store_config(InMemoryStoreConfig::default())
+        .deniable_store_config(InMemoryDeniableStoreConfig::default())
+        .api_client_config(http_config(server.address(), None))
+        .message_queue_config(InMemoryMessageQueueConfig)
+        .dead_letter_config(InMemoryDeadLetterQueueConfig)
+        .protocol_config(DenimProtocolClientConfig::new(
+            proxy.address().to_owned(),
+            None,
+            10,
+            InMemorySendingBuffer::new(0.0).expect("Can make sending buffer"),
+            InMemoryReceivingBuffer::default(),
+        ))
+        .key_request_policy(KeyRequestPolicy {
+            timeout: Duration::from_millis(100),
+            max_attempts: 1,
+        })
+        .upload_prekey_count(5)
+        .call()
+        .await
+        .expect("Can register Client");
+    let mut alice_events = alice.subscribe();
+
+    // the proxy defers the request, as the account never uploads a seed
+    let bob = AccountId::generate();
+    alice
+        .enqueue_message(bob, "hello bob")
+        .await
+        .expect("Can enqueue first message");
+    alice
+        .enqueue_message(bob, "how goes?")
+        .await
+        .expect("Can enqueue second message");
+
+    sleep(Duration::from_millis(200)).await;
+    alice
+        .process_messages()
+        .await
+        .expect("Alice can process messages");
+
+    let failure = loop {
+        match alice_events.recv().await.expect("Can receive event") {
+            DenimEvent::KeyExchangeFailed {
+                account_id,
+                reason,
+                dropped_messages,
+            } => break (account_id, reason, dropped_messages),
+            _ => continue,
+        }
+    };
+    assert_eq!(failure, (bob, KeyRequestFailure::TimedOut(1), 2));
+    assert!(alice
+        .pending_status(bob)
+        .await
+        .expect("Can get pending status")
+        .is_none());
+}
+
+#[rstest]
+#[case(in_memory_configs(get_next_port(), get_next_port(), None))]
+#[timeout(Duration::from_secs(TIMEOUT_SECS))]
+#[tokio::test]
+async fn idle_client_keeps_sending_cover_messages(
+    #[future(awt)]
+    #[case]
+    server_configs: TestServerConfigs<impl StateType, impl DenimStateType>,
+) {
+    let mut server = server_configs.sam.start().await;
+    let mut proxy = server_configs.denim.start().await;
+
+    server
+        .started_rx()
+        .await
+        .expect("Should be able to start server");
+
+    proxy
+        .started_rx()
+        .await
+        .expect("Should be able to start server");
+
+    let mut alice = client_with_proxy(
+        proxy.address(),
+        server.address(),
+        &Uuid::new_v4().to_string(),
+        "alice device",
+        None,
+        InMemorySendingBuffer::new(0.0).expect("Can make sending buffer"),
+        InMemoryReceivingBuffer::default(),
+    )
+    .await;
+
+    let mut bob = client_with_proxy(
+        proxy.address(),
+        server.address(),
+        &Uuid::new_v4().to_string(),
+        "bob device",
+        None,
+        InMemorySendingBuffer::new(0.0).expect("Can make sending buffer"),
+        InMemoryReceivingBuffer::default(),
+    )
+    .await;
+
+    let mut bob_messages = bob.regular_subscribe();
+    alice
+        .set_cover_traffic(Some(CoverTrafficPolicy {
+            timing: CoverTiming::Uniform(Duration::from_millis(100)..=Duration::from_millis(100)),
+            ..CoverTrafficPolicy::new(bob.account_id())
+        }))
+        .await;
+
+    // more than the client prepares ahead, while alice only waits for envelopes
+    let expected = 10;
+    let bob_receives = async {
+        let mut received = 0;
+        while received < expected {
+            bob.process_messages_blocking()
+                .await
+                .expect("Bob can process messages");
+            while bob_messages.try_recv().is_ok() {
+                received += 1;
+            }
+        }
+    };
+    tokio::select! {
+        () = bob_receives => (),
+        _ = async {
+            loop {
+                alice
+                    .process_messages_blocking()
+                    .await
+                    .expect("Alice can wait for messages");
+            }
+        } => (),
+    }
+}
+
 #[rstest]
 #[ignore = "requires a postgres test database"]
 #[case(postgres_configs(get_next_port(), get_next_port(), None, connection_str()))]
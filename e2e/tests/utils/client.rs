@@ -1,3 +1,4 @@
+use denim_sam_client::message::dead_letter::InMemoryDeadLetterQueueConfig;
 use denim_sam_client::message::queue::InMemoryMessageQueueConfig;
 use denim_sam_client::protocol::DenimProtocolClientConfig;
 use denim_sam_client::DenimClient;
@@ -44,6 +45,7 @@ pub async fn client_with_proxy(
         .deniable_store_config(InMemoryDeniableStoreConfig::default())
         .api_client_config(http_config(sam_addr, tls.clone()))
         .message_queue_config(InMemoryMessageQueueConfig)
+        .dead_letter_config(InMemoryDeadLetterQueueConfig)
         .protocol_config(DenimProtocolClientConfig::new(
             proxy_addr.to_owned(),
             tls,
@@ -12,6 +12,7 @@ use tokio_tungstenite::Connector;
 
 use crate::{
     error::{ServerError, TlsError},
+    logic::payload::PayloadMismatchPolicy,
     state::{DenimState, DenimStateType},
 };
 
@@ -25,6 +26,17 @@ pub struct DenimCliConfig {
     pub tls: Option<TlsConfig>,
     pub channel_buffer_size: Option<usize>,
     pub key_generate_amount: Option<usize>,
+    pub key_request_ttl: Option<u64>, // seconds
+    pub max_deferred_key_requests: Option<usize>,
+    pub pending_key_ttl: Option<u64>,         // seconds
+    pub used_key_ttl: Option<u64>,            // seconds
+    pub key_compaction_interval: Option<u64>, // seconds
+    pub pre_key_low_watermark: Option<usize>,
+    pub pre_key_high_watermark: Option<usize>,
+    pub seed_grace_period: Option<u64>,    // seconds
+    pub decoy_interval: Option<u64>,       // milliseconds, no decoys if unset
+    pub padding_tiers: Option<Vec<usize>>, // bytes, empty for powers of two, no padding if unset
+    pub payload_mismatch_policy: Option<PayloadMismatchPolicy>,
     pub logging: Option<String>,
 }
 
@@ -56,6 +68,17 @@ impl DenimCliConfig {
         tls: Option<TlsConfig>,
         channel_buffer_size: Option<usize>,
         key_generate_amount: Option<usize>,
+        key_request_ttl: Option<u64>,
+        max_deferred_key_requests: Option<usize>,
+        pending_key_ttl: Option<u64>,
+        used_key_ttl: Option<u64>,
+        key_compaction_interval: Option<u64>,
+        pre_key_low_watermark: Option<usize>,
+        pre_key_high_watermark: Option<usize>,
+        seed_grace_period: Option<u64>,
+        decoy_interval: Option<u64>,
+        padding_tiers: Option<Vec<usize>>,
+        payload_mismatch_policy: Option<PayloadMismatchPolicy>,
         logging: Option<String>,
     ) -> Self {
         Self {
@@ -66,6 +89,17 @@ impl DenimCliConfig {
             tls,
             channel_buffer_size,
             key_generate_amount,
+            key_request_ttl,
+            max_deferred_key_requests,
+            pending_key_ttl,
+            used_key_ttl,
+            key_compaction_interval,
+            pre_key_low_watermark,
+            pre_key_high_watermark,
+            seed_grace_period,
+            decoy_interval,
+            padding_tiers,
+            payload_mismatch_policy,
             logging,
         }
     }
@@ -0,0 +1,55 @@
+use std::{ops::RangeInclusive, time::Duration};
+
+use denim_sam_common::rng::exponential::exponential;
+use rand::Rng;
+
+/// When the proxy sends decoy messages to a client, so deniable messages
+/// are delivered without waiting for regular traffic.
+#[derive(Clone, Debug)]
+pub struct DecoyPolicy {
+    /// Mean time between decoys. Gaps are exponentially distributed
+    /// and do not depend on the backlog.
+    pub mean_interval: Duration,
+    /// Length of the random regular payload of a decoy.
+    pub payload_size: RangeInclusive<usize>,
+}
+
+impl Default for DecoyPolicy {
+    fn default() -> Self {
+        Self {
+            mean_interval: Duration::from_secs(30),
+            payload_size: 128..=1024,
+        }
+    }
+}
+
+impl DecoyPolicy {
+    /// Time until the next decoy.
+    pub fn gap(&self, rng: &mut impl Rng) -> Duration {
+        exponential(self.mean_interval, rng)
+    }
+
+    /// Random regular payload for a decoy.
+    pub fn payload(&self, rng: &mut impl Rng) -> Vec<u8> {
+        let mut payload = vec![0; rng.gen_range(self.payload_size.clone())];
+        rng.fill_bytes(&mut payload);
+        payload
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::DecoyPolicy;
+
+    #[test]
+    fn decoy_payload_fits_size() {
+        let policy = DecoyPolicy {
+            mean_interval: Duration::from_secs(10),
+            payload_size: 10..=20,
+        };
+        let len = policy.payload(&mut rand::thread_rng()).len();
+        assert!((10..=20).contains(&len));
+    }
+}
@@ -1,23 +1,27 @@
 use denim_sam_common::{
     denim_message::{
-        deniable_message::MessageKind, BlockRequest, DeniableMessage, KeyRequest, KeyResponse,
-        MessageType, SeedUpdate, UserMessage,
-    },
-    rng::{
-        seed::{KeyIdSeed, KeySeed},
-        RngState,
+        deniable_message::MessageKind, BlockListResponse, BlockRequest, DeniableMessage, Error,
+        KeyBundle, KeyRequest, KeyResponse, KeysReset, MessageType, SeedRequest, SeedUpdate,
+        SeedUpdateAck, SeedUpdateRejected, UnblockRequest, UserMessage,
     },
+    rng::seed::{KeyIdSeed, KeySeed},
 };
 
 use libsignal_protocol::CiphertextMessage;
 use log::{debug, error};
-use sam_common::{address::DEFAULT_DEVICE_ID, AccountId};
+use sam_common::{
+    address::{DeviceAddress, DEFAULT_DEVICE_ID},
+    AccountId, DeviceId,
+};
 use sam_server::managers::traits::account_manager::AccountManager;
 
 use crate::managers::DenimEcPreKeyManager;
 use crate::{
     error::{DenimRouterError, LogicError},
-    logic::keys::{get_keys_for, remove_pending_key, store_pending_key, update_seed},
+    logic::keys::{
+        get_decoy_keys_for, get_keys_for, remove_pending_key, reset_seed, store_pending_key,
+        update_seed,
+    },
     managers::{
         default::ClientRequest,
         error::DenimKeyManagerError,
@@ -30,23 +34,32 @@ pub async fn denim_router<T: DenimStateType>(
     state: &mut DenimState<T>,
     request: ClientRequest,
     account_id: AccountId,
+    device_id: DeviceId,
 ) -> Result<(), DenimRouterError> {
     match request {
         ClientRequest::BlockRequest(_, block_request) => {
             debug!("Received Block Request");
             handle_block_request(state, block_request, account_id).await
         }
+        ClientRequest::UnblockRequest(_, unblock_request) => {
+            debug!("Received Unblock Request");
+            handle_unblock_request(state, unblock_request, account_id).await
+        }
+        ClientRequest::BlockListRequest(msg_id, _) => {
+            debug!("Received Block List Request");
+            handle_block_list_request(state, msg_id, account_id, device_id).await
+        }
         ClientRequest::KeyRequest(msg_id, key_request) => {
             debug!("Received Key Request");
-            handle_key_request(state, msg_id, key_request, account_id).await
+            handle_key_request(state, msg_id, key_request, account_id, device_id).await
         }
         ClientRequest::SeedUpdateRequest(msg_id, seed_update) => {
             debug!("Received Seed Update Request");
-            handle_seed_update(state, msg_id, seed_update, account_id).await
+            handle_seed_update(state, msg_id, seed_update, account_id, device_id).await
         }
         ClientRequest::UserMessage(_, message) => {
             debug!("Received User Message Request");
-            handle_user_message(state, message, account_id).await
+            handle_user_message(state, message, account_id, device_id).await
         }
     }
 }
@@ -66,56 +79,96 @@ pub async fn handle_block_request<T: DenimStateType>(
     Ok(())
 }
 
+pub async fn handle_unblock_request<T: DenimStateType>(
+    state: &mut DenimState<T>,
+    request: UnblockRequest,
+    sender_account_id: AccountId,
+) -> Result<(), DenimRouterError> {
+    let unblocked_account_id =
+        AccountId::try_from(request.account_id).map_err(|_| DenimRouterError::InvalidAccountId)?;
+    state
+        .block_list
+        .unblock_user(sender_account_id, unblocked_account_id)
+        .await;
+
+    Ok(())
+}
+
+pub async fn handle_block_list_request<T: DenimStateType>(
+    state: &mut DenimState<T>,
+    msg_id: u32,
+    sender_account_id: AccountId,
+    sender_device_id: DeviceId,
+) -> Result<(), DenimRouterError> {
+    let blocked_users = state.block_list.get_blocked_users(&sender_account_id).await;
+
+    let block_list_response = MessageKind::BlockListResponse(
+        BlockListResponse::builder()
+            .account_ids(blocked_users.into_iter().map(Into::into).collect())
+            .build(),
+    );
+
+    enqueue_message(
+        state,
+        msg_id,
+        block_list_response,
+        sender_account_id,
+        sender_device_id,
+    )
+    .await
+}
+
 pub async fn handle_key_request<T: DenimStateType>(
     state: &mut DenimState<T>,
     msg_id: u32,
     request: KeyRequest,
     sender_account_id: AccountId,
+    sender_device_id: DeviceId,
 ) -> Result<(), DenimRouterError> {
     let requested_account_id = AccountId::try_from(request.account_id)
         .map_err(|_| DenimRouterError::KeyRequestMalformed)?;
 
-    let requested_device_id = request
-        .specific_device_ids
-        .first()
-        .ok_or(DenimRouterError::NoDeviceIdInRequest)?;
-
-    let key_bundle = match get_keys_for(
+    let key_bundles = match get_key_bundles(
         state,
         requested_account_id,
-        requested_device_id.to_owned().into(),
+        &request.specific_device_ids,
+        sender_account_id,
     )
-    .await
+    .await?
     {
-        Ok(key_bundle) => key_bundle,
-        Err(LogicError::KeyManager(DenimKeyManagerError::NoSeed)) => {
-            debug!("{requested_account_id}.{requested_device_id} has not uploaded a key seed yet. Request will be defered.");
-            state
+        Some(key_bundles) => key_bundles,
+        None => {
+            debug!(
+                "{requested_account_id} has not uploaded a key seed yet. Request will be defered."
+            );
+            if let Err(e) = state
                 .key_request_manager
-                .store_requester(requested_account_id, sender_account_id)
-                .await;
+                .store_requester(
+                    requested_account_id,
+                    request.specific_device_ids,
+                    sender_account_id,
+                    sender_device_id,
+                )
+                .await
+            {
+                debug!("Could not defer key request from {sender_account_id}: {e}");
+                let error =
+                    key_request_error(requested_account_id, "Too many pending key requests");
+                enqueue_message(state, msg_id, error, sender_account_id, sender_device_id).await?;
+            }
             return Ok(());
         }
-        Err(err) => return Err(DenimRouterError::Logic(err)),
     };
-    let identity_key = state
-        .accounts
-        .get_account(requested_account_id)
-        .await?
-        .identity()
-        .to_owned();
-
-    let key_response = MessageKind::KeyResponse(
-        KeyResponse::builder()
-            .account_id(requested_account_id.into())
-            .identity_key(identity_key.serialize().to_vec())
-            .key_bundle(key_bundle)
-            .build(),
-    );
-
-    enqueue_message(state, msg_id, key_response, sender_account_id).await?;
 
-    Ok(())
+    let key_response = create_key_response(state, requested_account_id, key_bundles).await?;
+    enqueue_message(
+        state,
+        msg_id,
+        key_response,
+        sender_account_id,
+        sender_device_id,
+    )
+    .await
 }
 
 pub async fn handle_seed_update<T: DenimStateType>(
@@ -123,53 +176,224 @@ pub async fn handle_seed_update<T: DenimStateType>(
     msg_id: u32,
     request: SeedUpdate,
     sender_account_id: AccountId,
+    sender_device_id: DeviceId,
 ) -> Result<(), DenimRouterError> {
+    let resync = request.resync();
     let key_seed = KeySeed::try_from(request.pre_key_seed)?;
     let key_id_seed = KeyIdSeed::try_from(request.pre_key_id_seed)?;
 
-    update_seed(state, sender_account_id, 1.into(), key_seed, key_id_seed).await?;
+    let senders = if resync {
+        reset_seed(
+            state,
+            sender_account_id,
+            sender_device_id,
+            request.epoch,
+            key_seed,
+            key_id_seed,
+        )
+        .await
+    } else {
+        update_seed(
+            state,
+            sender_account_id,
+            sender_device_id,
+            request.epoch,
+            key_seed,
+            key_id_seed,
+        )
+        .await
+        .map(|_| Vec::new())
+    };
+    let senders = match senders {
+        Ok(senders) => senders,
+        Err(LogicError::KeyManager(DenimKeyManagerError::StaleSeedEpoch(current_epoch))) => {
+            debug!(
+                "Rejecting seed epoch {} of {sender_account_id}.{sender_device_id}, {current_epoch} is in use",
+                request.epoch
+            );
+            let rejected = MessageKind::SeedUpdateRejected(
+                SeedUpdateRejected::builder()
+                    .epoch(request.epoch)
+                    .current_epoch(current_epoch)
+                    .build(),
+            );
+            return enqueue_message(state, msg_id, rejected, sender_account_id, sender_device_id)
+                .await;
+        }
+        Err(e) => Err(e)?,
+    };
+
+    // sessions built on the lost keys are useless, so their senders need new keys
+    for (sender, sender_device) in senders {
+        debug!("Telling {sender}.{sender_device} to request new keys");
+        let msg_id = state.message_id_provider.get_message_id(sender).await;
+        let keys_reset = MessageKind::KeysReset(
+            KeysReset::builder()
+                .account_id(sender_account_id.into())
+                .device_id(*sender_device_id)
+                .build(),
+        );
+        enqueue_message(state, msg_id, keys_reset, sender, sender_device).await?;
+    }
+
+    // the client can now tell others that it is ready for deniable messages
+    enqueue_message(
+        state,
+        msg_id,
+        MessageKind::SeedUpdateAck(SeedUpdateAck::builder().epoch(request.epoch).build()),
+        sender_account_id,
+        sender_device_id,
+    )
+    .await?;
 
-    // defered key requests can now be processed
+    // defered key requests this device can answer can now be processed
     if let Some(requesters) = state
         .key_request_manager
-        .remove_requesters(sender_account_id)
+        .remove_requesters(sender_account_id, sender_device_id)
         .await
     {
-        for requester in requesters {
-            let key_bundle = get_keys_for(state, sender_account_id, 1.into()).await?;
-            let identity_key = state
-                .accounts
-                .get_account(sender_account_id)
-                .await?
-                .identity()
-                .to_owned();
-
-            let key_response = MessageKind::KeyResponse(
-                KeyResponse::builder()
-                    .account_id(sender_account_id.into())
-                    .identity_key(identity_key.serialize().to_vec())
-                    .key_bundle(key_bundle)
-                    .build(),
-            );
+        for (requester, requester_device_id, device_ids) in requesters {
+            let Some(key_bundles) =
+                get_key_bundles(state, sender_account_id, &device_ids, requester).await?
+            else {
+                continue;
+            };
 
-            enqueue_message(state, msg_id, key_response, requester).await?;
+            let key_response = create_key_response(state, sender_account_id, key_bundles).await?;
+            enqueue_message(state, msg_id, key_response, requester, requester_device_id).await?;
         }
     }
 
     Ok(())
 }
 
+/// Returns a key bundle for each of the `requested_device_ids` of `requested_account_id`
+/// that has uploaded a seed, where no ids means all devices.
+/// Returns `None` if the request has to wait for a seed.
+async fn get_key_bundles<T: DenimStateType>(
+    state: &mut DenimState<T>,
+    requested_account_id: AccountId,
+    requested_device_ids: &[u32],
+    requester_account_id: AccountId,
+) -> Result<Option<Vec<KeyBundle>>, DenimRouterError> {
+    let device_ids: Vec<DeviceId> = state
+        .keys
+        .pre_keys
+        .get_seeded_device_ids(requested_account_id)
+        .await?
+        .into_iter()
+        .filter(|device_id| {
+            requested_device_ids.is_empty() || requested_device_ids.contains(&**device_id)
+        })
+        .collect();
+
+    if device_ids.is_empty() {
+        return Ok(None);
+    }
+
+    // Blocked users are deferred and answered like everyone else, only with decoys,
+    // so neither the timing nor the shape of the response tells them that they are blocked.
+    let blocked = state
+        .block_list
+        .is_user_blocked(&requested_account_id, &requester_account_id)
+        .await;
+    if blocked {
+        debug!("{requester_account_id} is blocked by {requested_account_id}, sending decoy keys.");
+    }
+
+    let mut key_bundles = Vec::new();
+    for device_id in device_ids {
+        let key_bundle = if blocked {
+            get_decoy_keys_for(state, requested_account_id, device_id).await?
+        } else {
+            get_keys_for(state, requested_account_id, device_id).await?
+        };
+        key_bundles.push(key_bundle);
+    }
+    Ok(Some(key_bundles))
+}
+
+/// Answers deferred key requests that have waited too long for a seed with an error,
+/// so requesters do not wait forever.
+pub async fn handle_expired_key_requests<T: DenimStateType>(state: &mut DenimState<T>) {
+    for (requested, requester, requester_device_id) in
+        state.key_request_manager.remove_expired_requesters().await
+    {
+        debug!("Key request from {requester} for {requested} timed out");
+        let msg_id = state.message_id_provider.get_message_id(requester).await;
+        let error = key_request_error(requested, "Key request timed out");
+        // the requests are already removed, so the other requesters must still be answered
+        if let Err(e) = enqueue_message(state, msg_id, error, requester, requester_device_id).await
+        {
+            error!("Failed to answer expired key request from {requester} for {requested} '{e}'");
+        }
+    }
+}
+
+/// Asks a device for a new seed if the proxy has none for it,
+/// e.g. because the proxy lost its state.
+pub async fn request_missing_seed<T: DenimStateType>(
+    state: &mut DenimState<T>,
+    account_id: AccountId,
+    device_id: DeviceId,
+) -> Result<(), DenimRouterError> {
+    if state
+        .keys
+        .pre_keys
+        .get_seeded_device_ids(account_id)
+        .await?
+        .contains(&device_id)
+    {
+        return Ok(());
+    }
+    let msg_id = state.message_id_provider.get_message_id(account_id).await;
+    let seed_request = MessageKind::SeedRequest(SeedRequest::builder().build());
+    enqueue_message(state, msg_id, seed_request, account_id, device_id).await
+}
+
+fn key_request_error(requested_account_id: AccountId, error: &str) -> MessageKind {
+    MessageKind::Error(
+        Error::builder()
+            .error(error.to_string())
+            .account_id(requested_account_id.into())
+            .build(),
+    )
+}
+
+async fn create_key_response<T: DenimStateType>(
+    state: &mut DenimState<T>,
+    account_id: AccountId,
+    key_bundles: Vec<KeyBundle>,
+) -> Result<MessageKind, DenimRouterError> {
+    let identity_key = state
+        .accounts
+        .get_account(account_id)
+        .await?
+        .identity()
+        .to_owned();
+
+    Ok(MessageKind::KeyResponse(
+        KeyResponse::builder()
+            .account_id(account_id.into())
+            .identity_key(identity_key.serialize().to_vec())
+            .key_bundles(key_bundles)
+            .build(),
+    ))
+}
+
 pub async fn enqueue_message<T: DenimStateType>(
     state: &mut DenimState<T>,
     msg_id: u32,
     message: MessageKind,
     receiver: AccountId,
+    receiver_device_id: DeviceId,
 ) -> Result<(), DenimRouterError> {
     debug!("Enqueued {}", message);
     state
         .buffer_manager
         .enqueue_message(
             receiver,
+            receiver_device_id,
             DeniableMessage::builder()
                 .message_kind(message)
                 .message_id(msg_id)
@@ -183,6 +407,7 @@ pub async fn handle_user_message<T: DenimStateType>(
     state: &mut DenimState<T>,
     mut message: UserMessage,
     sender_account_id: AccountId,
+    sender_device_id: DeviceId,
 ) -> Result<(), DenimRouterError> {
     let id = state
         .message_id_provider
@@ -190,6 +415,8 @@ pub async fn handle_user_message<T: DenimStateType>(
         .await;
     let receiver_id =
         AccountId::try_from(message.account_id).map_err(|_| DenimRouterError::InvalidAccountId)?;
+    // clients that do not address a device are talking to the primary device
+    let receiver_device_id: DeviceId = message.device_id.unwrap_or(DEFAULT_DEVICE_ID).into();
     if state
         .block_list
         .is_user_blocked(&receiver_id, &sender_account_id)
@@ -198,33 +425,42 @@ pub async fn handle_user_message<T: DenimStateType>(
         return Ok(());
     }
 
-    // change message account id to sender
+    // change message account and device id to sender
     message.account_id = sender_account_id.into();
+    message.device_id = Some(*sender_device_id);
 
     match message.message_type() {
         MessageType::SignalMessage => {
-            remove_pending_key(state, sender_account_id, receiver_id).await?;
+            remove_pending_key(
+                state,
+                sender_account_id,
+                sender_device_id,
+                DeviceAddress::new(receiver_id, receiver_device_id),
+            )
+            .await?;
         }
         // first convert to ciphertext when we actually need it
         MessageType::PreKeySignalMessage => match message.ciphertext() {
             Ok(CiphertextMessage::PreKeySignalMessage(pre)) => {
-                store_pending_key(state, &pre, sender_account_id, receiver_id).await?;
-                // This will be None if rng_counter > u64::MAX.
-                message.rng_counter = state
-                    .keys
-                    .pre_keys
-                    .get_key_seed_for(
-                        message
-                            .account_id
-                            .clone()
-                            .try_into()
-                            .map_err(|_| DenimRouterError::InvalidAccountId)?,
-                        DEFAULT_DEVICE_ID.into(),
-                    )
-                    .await?
-                    .offset()
-                    .try_into()
-                    .ok()
+                store_pending_key(
+                    state,
+                    &pre,
+                    sender_account_id,
+                    sender_device_id,
+                    receiver_id,
+                    receiver_device_id,
+                )
+                .await?;
+                // the receiver derives the key directly from its index in the key stream
+                if let Some(key_id) = pre.pre_key_id() {
+                    let (epoch, index) = state
+                        .keys
+                        .pre_keys
+                        .get_key_index(receiver_id, receiver_device_id, key_id.into())
+                        .await?;
+                    message.seed_epoch = Some(epoch);
+                    message.rng_counter = Some(index);
+                }
             }
             Ok(_) => Err(DenimRouterError::MalformedUserMessage)?,
             Err(e) => {
@@ -238,6 +474,7 @@ pub async fn handle_user_message<T: DenimStateType>(
         .buffer_manager
         .enqueue_message(
             receiver_id,
+            receiver_device_id,
             DeniableMessage {
                 message_id: id,
                 message_kind: Some(MessageKind::DeniableMessage(message)),
@@ -248,25 +485,690 @@ pub async fn handle_user_message<T: DenimStateType>(
 
 #[cfg(test)]
 mod test {
+    use std::time::Duration;
+
     use denim_sam_common::{
-        denim_message::{MessageType, UserMessage},
+        buffers::{InMemoryReceivingBuffer, ReceivingBuffer},
+        denim_message::{
+            deniable_message::MessageKind, BlockListRequest, BlockRequest, KeyRequest, MessageType,
+            SeedUpdate, UnblockRequest, UserMessage,
+        },
         rng::seed::{KeyIdSeed, KeySeed},
     };
     use libsignal_protocol::{
         CiphertextMessage, IdentityKeyPair, PreKeySignalMessage, SignalMessage,
     };
     use rand::rngs::OsRng;
-    use sam_common::{address::DEFAULT_DEVICE_ID, AccountId};
-    use sam_server::managers::traits::key_manager::SignedPreKeyManager;
+    use rstest::rstest;
+    use sam_common::{
+        address::{DeviceAddress, DEFAULT_DEVICE_ID},
+        AccountId, DeviceId,
+    };
+    use sam_server::{
+        auth::password::Password,
+        managers::{
+            entities::{Account, Device},
+            traits::{
+                account_manager::AccountManager, device_manager::DeviceManager,
+                key_manager::SignedPreKeyManager,
+            },
+        },
+    };
     use sam_test_utils::server_utils::signed_ec_pre_key;
 
     use crate::{
-        denim_routes::denim_router,
+        denim_routes::{denim_router, handle_expired_key_requests, request_missing_seed},
         logic::keys::update_seed,
-        managers::{default::ClientRequest, DenimEcPreKeyManager},
+        managers::{
+            default::ClientRequest,
+            in_mem::InMemoryKeyRequestManager,
+            traits::{BlockList, KeyRequestManager},
+            DenimEcPreKeyManager,
+        },
         state::{DenimState, InMemoryDenimStateType},
     };
 
+    async fn add_account(state: &mut DenimState<InMemoryDenimStateType>) -> AccountId {
+        add_account_with_devices(state, &[DEFAULT_DEVICE_ID]).await
+    }
+
+    async fn add_account_with_devices(
+        state: &mut DenimState<InMemoryDenimStateType>,
+        device_ids: &[u32],
+    ) -> AccountId {
+        let pair = IdentityKeyPair::generate(&mut OsRng);
+        let account = Account::builder()
+            .id(AccountId::generate())
+            .identity(*pair.identity_key())
+            .username(AccountId::generate().to_string())
+            .build();
+        state
+            .accounts
+            .add_account(&account)
+            .await
+            .expect("Can add account");
+
+        for device_id in device_ids {
+            let device = Device::builder()
+                .id((*device_id).into())
+                .name("Phone".to_string())
+                .password(
+                    Password::generate("dave<3".to_string(), &mut OsRng)
+                        .expect("Can create password"),
+                )
+                .registration_id((*device_id).into())
+                .build();
+            state
+                .devices
+                .add_device(account.id(), &device)
+                .await
+                .expect("Can add device");
+
+            state
+                .keys
+                .signed_pre_keys
+                .set_signed_pre_key(
+                    account.id(),
+                    (*device_id).into(),
+                    pair.identity_key(),
+                    signed_ec_pre_key(22u32, &pair, OsRng),
+                )
+                .await
+                .expect("Can set signed pre key");
+        }
+        account.id()
+    }
+
+    #[rstest]
+    #[case(vec![], vec![DEFAULT_DEVICE_ID, 2, 3])]
+    #[case(vec![DEFAULT_DEVICE_ID, 3], vec![DEFAULT_DEVICE_ID, 3])]
+    #[case(vec![2], vec![2])]
+    #[tokio::test]
+    async fn key_request_returns_bundles_for_requested_devices(
+        #[case] requested_device_ids: Vec<u32>,
+        #[case] expected_device_ids: Vec<u32>,
+    ) {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8080".to_string());
+        let alice = add_account(&mut state).await;
+        let bob = add_account_with_devices(&mut state, &[DEFAULT_DEVICE_ID, 2, 3]).await;
+
+        for device_id in [DEFAULT_DEVICE_ID, 2, 3] {
+            update_seed(
+                &mut state,
+                bob,
+                device_id.into(),
+                1,
+                KeySeed::random(&mut OsRng),
+                KeyIdSeed::random(&mut OsRng),
+            )
+            .await
+            .expect("Can update seed");
+        }
+
+        denim_router(
+            &mut state,
+            ClientRequest::KeyRequest(
+                1u32,
+                KeyRequest::builder()
+                    .account_id(bob.into())
+                    .specific_device_ids(requested_device_ids)
+                    .build(),
+            ),
+            alice,
+            DEFAULT_DEVICE_ID.into(),
+        )
+        .await
+        .expect("Can route key request");
+
+        let payload = state
+            .buffer_manager
+            .get_deniable_payload(alice, DEFAULT_DEVICE_ID.into(), 5000)
+            .await
+            .expect("Can get deniable payload for alice");
+
+        let responses = InMemoryReceivingBuffer::default()
+            .process_chunks(payload.denim_chunks().to_owned())
+            .await;
+        assert_eq!(responses.len(), 1);
+
+        let mut device_ids: Vec<u32> = match responses
+            .into_iter()
+            .next()
+            .expect("Alice receives a response")
+            .expect("Can decode response")
+            .message_kind
+        {
+            Some(MessageKind::KeyResponse(response)) => response
+                .key_bundles
+                .into_iter()
+                .map(|bundle| bundle.device_id)
+                .collect(),
+            _ => panic!("Expected Key Response"),
+        };
+        device_ids.sort();
+
+        assert_eq!(device_ids, expected_device_ids);
+    }
+
+    #[tokio::test]
+    async fn deferred_key_request_times_out() {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8080".to_string());
+        state.key_request_manager = InMemoryKeyRequestManager::new(Duration::ZERO, 10);
+        let alice = add_account(&mut state).await;
+        let bob = add_account(&mut state).await;
+
+        denim_router(
+            &mut state,
+            ClientRequest::KeyRequest(
+                1u32,
+                KeyRequest::builder()
+                    .account_id(bob.into())
+                    .specific_device_ids(vec![])
+                    .build(),
+            ),
+            alice,
+            DEFAULT_DEVICE_ID.into(),
+        )
+        .await
+        .expect("Can route key request");
+
+        handle_expired_key_requests(&mut state).await;
+
+        assert!(state
+            .key_request_manager
+            .remove_requesters(bob, DEFAULT_DEVICE_ID.into())
+            .await
+            .is_none());
+
+        let payload = state
+            .buffer_manager
+            .get_deniable_payload(alice, DEFAULT_DEVICE_ID.into(), 1000)
+            .await
+            .expect("Can get deniable payload for alice");
+
+        let response = InMemoryReceivingBuffer::default()
+            .process_chunks(payload.denim_chunks().to_owned())
+            .await
+            .into_iter()
+            .next()
+            .expect("Alice receives a response")
+            .expect("Can decode response");
+
+        match response.message_kind {
+            Some(MessageKind::Error(error)) => {
+                assert_eq!(error.account_id, Some(Vec::<u8>::from(bob)))
+            }
+            _ => panic!("Expected Error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn deferred_request_is_answered_for_requested_device() {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8080".to_string());
+        let alice = add_account(&mut state).await;
+        let bob = add_account_with_devices(&mut state, &[DEFAULT_DEVICE_ID, 2]).await;
+
+        denim_router(
+            &mut state,
+            ClientRequest::KeyRequest(
+                1u32,
+                KeyRequest::builder()
+                    .account_id(bob.into())
+                    .specific_device_ids(vec![2])
+                    .build(),
+            ),
+            alice,
+            DEFAULT_DEVICE_ID.into(),
+        )
+        .await
+        .expect("Can route key request");
+
+        // the primary device cannot answer a request for device 2, the second one can
+        let mut responses = Vec::new();
+        for device_id in [DEFAULT_DEVICE_ID, 2] {
+            denim_router(
+                &mut state,
+                ClientRequest::SeedUpdateRequest(
+                    1u32,
+                    SeedUpdate::builder()
+                        .pre_key_seed(KeySeed::random(&mut OsRng).into())
+                        .pre_key_id_seed(KeyIdSeed::random(&mut OsRng).into())
+                        .epoch(1)
+                        .build(),
+                ),
+                bob,
+                device_id.into(),
+            )
+            .await
+            .expect("Can route seed update");
+
+            let payload = state
+                .buffer_manager
+                .get_deniable_payload(alice, DEFAULT_DEVICE_ID.into(), 5000)
+                .await
+                .expect("Can get deniable payload for alice");
+            responses.push(
+                InMemoryReceivingBuffer::default()
+                    .process_chunks(payload.denim_chunks().to_owned())
+                    .await,
+            );
+        }
+
+        assert!(responses[0].is_empty());
+        assert_eq!(responses[1].len(), 1);
+        let response = responses
+            .remove(1)
+            .into_iter()
+            .next()
+            .expect("Alice receives a response")
+            .expect("Can decode response");
+        match response.message_kind {
+            Some(MessageKind::KeyResponse(response)) => {
+                let device_ids: Vec<u32> = response
+                    .key_bundles
+                    .iter()
+                    .map(|bundle| bundle.device_id)
+                    .collect();
+                assert_eq!(device_ids, vec![2]);
+            }
+            _ => panic!("Expected Key Response"),
+        }
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[tokio::test]
+    async fn blocked_user_is_answered_like_others(#[case] seeded: bool) {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8080".to_string());
+        let alice = add_account(&mut state).await;
+        let bob = add_account_with_devices(&mut state, &[DEFAULT_DEVICE_ID, 2]).await;
+
+        state.block_list.block_user(bob, alice).await;
+        if seeded {
+            update_seed(
+                &mut state,
+                bob,
+                DEFAULT_DEVICE_ID.into(),
+                1,
+                KeySeed::random(&mut OsRng),
+                KeyIdSeed::random(&mut OsRng),
+            )
+            .await
+            .expect("Can update seed");
+        }
+
+        // device 2 has no seed, so at most the primary device is answered
+        denim_router(
+            &mut state,
+            ClientRequest::KeyRequest(
+                1u32,
+                KeyRequest::builder()
+                    .account_id(bob.into())
+                    .specific_device_ids(vec![])
+                    .build(),
+            ),
+            alice,
+            DEFAULT_DEVICE_ID.into(),
+        )
+        .await
+        .expect("Can route key request");
+        // device 3 does not exist, so the request waits like that of anyone else
+        denim_router(
+            &mut state,
+            ClientRequest::KeyRequest(
+                2u32,
+                KeyRequest::builder()
+                    .account_id(bob.into())
+                    .specific_device_ids(vec![3])
+                    .build(),
+            ),
+            alice,
+            DEFAULT_DEVICE_ID.into(),
+        )
+        .await
+        .expect("Can route key request");
+
+        // without a seed, the request for device 3 is merged into the one for all devices
+        let deferred = state
+            .key_request_manager
+            .remove_requesters(bob, 3.into())
+            .await
+            .expect("Requests for bob are deferred");
+        let device_ids = if seeded { vec![3] } else { vec![] };
+        assert_eq!(
+            deferred,
+            vec![(alice, DeviceId::from(DEFAULT_DEVICE_ID), device_ids)]
+        );
+
+        let payload = state
+            .buffer_manager
+            .get_deniable_payload(alice, DEFAULT_DEVICE_ID.into(), 5000)
+            .await
+            .expect("Can get deniable payload for alice");
+        let responses = InMemoryReceivingBuffer::default()
+            .process_chunks(payload.denim_chunks().to_owned())
+            .await;
+
+        if !seeded {
+            assert!(responses.is_empty());
+            return;
+        }
+
+        assert_eq!(responses.len(), 1);
+        let response = responses
+            .into_iter()
+            .next()
+            .expect("Alice receives a response")
+            .expect("Can decode response");
+        match response.message_kind {
+            Some(MessageKind::KeyResponse(response)) => {
+                let device_ids: Vec<u32> = response
+                    .key_bundles
+                    .iter()
+                    .map(|bundle| bundle.device_id)
+                    .collect();
+                assert_eq!(device_ids, vec![DEFAULT_DEVICE_ID]);
+            }
+            _ => panic!("Expected Key Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn can_unblock_and_list_blocked_users() {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8080".to_string());
+        let alice = AccountId::generate();
+        let bob = AccountId::generate();
+        let charlie = AccountId::generate();
+
+        for blocked in [bob, charlie] {
+            denim_router(
+                &mut state,
+                ClientRequest::BlockRequest(
+                    1u32,
+                    BlockRequest::builder().account_id(blocked.into()).build(),
+                ),
+                alice,
+                DEFAULT_DEVICE_ID.into(),
+            )
+            .await
+            .expect("Can route block request");
+        }
+
+        denim_router(
+            &mut state,
+            ClientRequest::UnblockRequest(
+                2u32,
+                UnblockRequest::builder().account_id(bob.into()).build(),
+            ),
+            alice,
+            DEFAULT_DEVICE_ID.into(),
+        )
+        .await
+        .expect("Can route unblock request");
+
+        assert!(!state.block_list.is_user_blocked(&alice, &bob).await);
+        assert!(state.block_list.is_user_blocked(&alice, &charlie).await);
+
+        denim_router(
+            &mut state,
+            ClientRequest::BlockListRequest(3u32, BlockListRequest::builder().build()),
+            alice,
+            DEFAULT_DEVICE_ID.into(),
+        )
+        .await
+        .expect("Can route block list request");
+
+        let payload = state
+            .buffer_manager
+            .get_deniable_payload(alice, DEFAULT_DEVICE_ID.into(), 500)
+            .await
+            .expect("Can get deniable payload for alice");
+
+        let mut receiving_buffer = InMemoryReceivingBuffer::default();
+        let response = receiving_buffer
+            .process_chunks(payload.denim_chunks().to_owned())
+            .await
+            .into_iter()
+            .next()
+            .expect("Alice receives a response")
+            .expect("Can decode response");
+
+        match response.message_kind {
+            Some(MessageKind::BlockListResponse(response)) => {
+                assert_eq!(response.account_ids, vec![Vec::<u8>::from(charlie)])
+            }
+            _ => panic!("Expected Block List Response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn seed_update_is_acknowledged() {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8080".to_string());
+        let alice = AccountId::generate();
+        let device_id = 2u32;
+
+        denim_router(
+            &mut state,
+            ClientRequest::SeedUpdateRequest(
+                1u32,
+                SeedUpdate::builder()
+                    .pre_key_seed(KeySeed::random(&mut OsRng).into())
+                    .pre_key_id_seed(KeyIdSeed::random(&mut OsRng).into())
+                    .epoch(3)
+                    .build(),
+            ),
+            alice,
+            device_id.into(),
+        )
+        .await
+        .expect("Can route seed update");
+
+        let payload = state
+            .buffer_manager
+            .get_deniable_payload(alice, device_id.into(), 500)
+            .await
+            .expect("Can get deniable payload for alice");
+
+        let mut receiving_buffer = InMemoryReceivingBuffer::default();
+        let response = receiving_buffer
+            .process_chunks(payload.denim_chunks().to_owned())
+            .await
+            .into_iter()
+            .next()
+            .expect("Alice receives a response")
+            .expect("Can decode response");
+
+        match response.message_kind {
+            Some(MessageKind::SeedUpdateAck(ack)) => assert_eq!(ack.epoch, 3),
+            _ => panic!("Expected Seed Update Ack"),
+        }
+    }
+
+    async fn next_response(
+        state: &mut DenimState<InMemoryDenimStateType>,
+        account_id: AccountId,
+        device_id: u32,
+    ) -> Option<MessageKind> {
+        let payload = state
+            .buffer_manager
+            .get_deniable_payload(account_id, device_id.into(), 500)
+            .await
+            .expect("Can get deniable payload");
+
+        InMemoryReceivingBuffer::default()
+            .process_chunks(payload.denim_chunks().to_owned())
+            .await
+            .into_iter()
+            .next()
+            .and_then(|response| response.expect("Can decode response").message_kind)
+    }
+
+    #[tokio::test]
+    async fn resync_tells_pending_senders_to_request_keys() {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8080".to_string());
+        let alice = AccountId::generate();
+        let bob = AccountId::generate();
+        let bob_device = 2u32;
+
+        update_seed(
+            &mut state,
+            bob,
+            bob_device.into(),
+            4,
+            KeySeed::random(&mut OsRng),
+            KeyIdSeed::random(&mut OsRng),
+        )
+        .await
+        .expect("Can update seed");
+        let key = state
+            .keys
+            .pre_keys
+            .get_ec_pre_key(bob, bob_device.into())
+            .await
+            .expect("Can get ec pre key");
+        state
+            .keys
+            .pre_keys
+            .store_pending_key(
+                alice,
+                DEFAULT_DEVICE_ID.into(),
+                bob,
+                bob_device.into(),
+                key.id(),
+            )
+            .await
+            .expect("Can store pending key");
+
+        // bob lost his seeds and resynchronizes at epoch 5
+        denim_router(
+            &mut state,
+            ClientRequest::SeedUpdateRequest(
+                1u32,
+                SeedUpdate::builder()
+                    .pre_key_seed(KeySeed::random(&mut OsRng).into())
+                    .pre_key_id_seed(KeyIdSeed::random(&mut OsRng).into())
+                    .epoch(5)
+                    .resync(true)
+                    .build(),
+            ),
+            bob,
+            bob_device.into(),
+        )
+        .await
+        .expect("Can route seed update");
+
+        assert!(
+            !state
+                .keys
+                .pre_keys
+                .has_pending_key(
+                    DeviceAddress::new(alice, DEFAULT_DEVICE_ID.into()),
+                    bob,
+                    bob_device.into()
+                )
+                .await
+        );
+        match next_response(&mut state, alice, DEFAULT_DEVICE_ID).await {
+            Some(MessageKind::KeysReset(reset)) => {
+                assert_eq!(reset.account_id, Vec::<u8>::from(bob));
+                assert_eq!(reset.device_id, bob_device);
+            }
+            _ => panic!("Expected Keys Reset"),
+        }
+        match next_response(&mut state, bob, bob_device).await {
+            Some(MessageKind::SeedUpdateAck(ack)) => assert_eq!(ack.epoch, 5),
+            _ => panic!("Expected Seed Update Ack"),
+        }
+    }
+
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[tokio::test]
+    async fn stale_seed_update_is_rejected(#[case] resync: bool) {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8080".to_string());
+        let alice = AccountId::generate();
+
+        update_seed(
+            &mut state,
+            alice,
+            DEFAULT_DEVICE_ID.into(),
+            4,
+            KeySeed::random(&mut OsRng),
+            KeyIdSeed::random(&mut OsRng),
+        )
+        .await
+        .expect("Can update seed");
+
+        denim_router(
+            &mut state,
+            ClientRequest::SeedUpdateRequest(
+                1u32,
+                SeedUpdate::builder()
+                    .pre_key_seed(KeySeed::random(&mut OsRng).into())
+                    .pre_key_id_seed(KeyIdSeed::random(&mut OsRng).into())
+                    .epoch(2)
+                    .resync(resync)
+                    .build(),
+            ),
+            alice,
+            DEFAULT_DEVICE_ID.into(),
+        )
+        .await
+        .expect("Can route seed update");
+
+        match next_response(&mut state, alice, DEFAULT_DEVICE_ID).await {
+            Some(MessageKind::SeedUpdateRejected(rejected)) => {
+                assert_eq!((rejected.epoch, rejected.current_epoch), (2, 4));
+            }
+            _ => panic!("Expected Seed Update Rejected"),
+        }
+    }
+
+    #[rstest]
+    #[case(true, false)]
+    #[case(false, true)]
+    #[tokio::test]
+    async fn requests_seed_only_when_missing(#[case] seeded: bool, #[case] requested: bool) {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8080".to_string());
+        let alice = AccountId::generate();
+
+        if seeded {
+            update_seed(
+                &mut state,
+                alice,
+                DEFAULT_DEVICE_ID.into(),
+                1,
+                KeySeed::random(&mut OsRng),
+                KeyIdSeed::random(&mut OsRng),
+            )
+            .await
+            .expect("Can update seed");
+        }
+
+        request_missing_seed(&mut state, alice, DEFAULT_DEVICE_ID.into())
+            .await
+            .expect("Can request missing seed");
+
+        assert_eq!(
+            matches!(
+                next_response(&mut state, alice, DEFAULT_DEVICE_ID).await,
+                Some(MessageKind::SeedRequest(_))
+            ),
+            requested
+        );
+    }
+
     #[tokio::test]
     async fn deletes_keys_when_reply_on_pre_key_message() {
         let mut state =
@@ -316,7 +1218,7 @@ mod test {
             .set_signed_pre_key(bob, id, id_pair.identity_key(), signed)
             .await
             .expect("can set signed pre key");
-        update_seed(&mut state, bob, id, seed, id_seed)
+        update_seed(&mut state, bob, id, 1, seed, id_seed)
             .await
             .expect("can update seed");
 
@@ -364,6 +1266,7 @@ mod test {
                     .build(),
             ),
             alice,
+            DEFAULT_DEVICE_ID.into(),
         )
         .await
         .expect("Can route prekey message");
@@ -372,7 +1275,11 @@ mod test {
             state
                 .keys
                 .pre_keys
-                .has_pending_key(alice, bob, DEFAULT_DEVICE_ID.into())
+                .has_pending_key(
+                    DeviceAddress::new(alice, DEFAULT_DEVICE_ID.into()),
+                    bob,
+                    DEFAULT_DEVICE_ID.into()
+                )
                 .await
         );
 
@@ -387,6 +1294,7 @@ mod test {
                     .build(),
             ),
             bob,
+            DEFAULT_DEVICE_ID.into(),
         )
         .await
         .expect("can route signal message");
@@ -394,7 +1302,11 @@ mod test {
             !state
                 .keys
                 .pre_keys
-                .has_pending_key(alice, bob, DEFAULT_DEVICE_ID.into())
+                .has_pending_key(
+                    DeviceAddress::new(alice, DEFAULT_DEVICE_ID.into()),
+                    bob,
+                    DEFAULT_DEVICE_ID.into()
+                )
                 .await
         );
     }
@@ -59,7 +59,6 @@ pub enum DenimRouterError {
     AccountManager(AccountManagerError),
     BufferManager(BufferManagerError),
     KeyManager(DenimKeyManagerError),
-    NoDeviceIdInRequest,
     InvalidAccountId,
     MalformedUserMessage,
 }
@@ -1,4 +1,5 @@
 pub mod config;
+pub mod decoy;
 mod denim_routes;
 pub mod error;
 pub mod logic;
@@ -4,12 +4,14 @@ use denim_sam_common::{
 };
 use libsignal_protocol::PreKeySignalMessage;
 use log::{debug, error};
+use rand::{rngs::OsRng, RngCore};
 
 use sam_common::{
-    address::DEFAULT_DEVICE_ID,
-    api::{Encode, SignedEcPreKey},
+    address::DeviceAddress,
+    api::{EcPreKey, Encode, SignedEcPreKey},
     AccountId, DeviceId,
 };
+use sam_security::key_gen::generate_ec_pre_key;
 use sam_server::managers::traits::{
     account_manager::AccountManager, device_manager::DeviceManager,
     key_manager::SignedPreKeyManager as _,
@@ -30,12 +32,37 @@ pub async fn get_keys_for<T: DenimStateType>(
         .keys
         .pre_keys
         .get_ec_pre_key(account_id, device_id)
-        .await?
-        .encode()
-        .map_err(|err| {
-            error!("{err}");
-            LogicError::Encode
-        })?;
+        .await?;
+
+    build_key_bundle(state, account_id, device_id, pre_key).await
+}
+
+/// Creates a key bundle that looks like the one returned by `get_keys_for`,
+/// but with a throwaway pre key that is not derived from the account's seed.
+/// It is given to blocked users so they cannot drain the deniable pre keys of the blocker,
+/// or learn whether the blocker has uploaded a seed.
+pub async fn get_decoy_keys_for<T: DenimStateType>(
+    state: &mut DenimState<T>,
+    account_id: AccountId,
+    device_id: DeviceId,
+) -> Result<KeyBundle, LogicError> {
+    let mut rng = OsRng;
+    let key_id = rng.next_u32();
+    let pre_key: EcPreKey = generate_ec_pre_key(key_id.into(), &mut rng).await.into();
+
+    build_key_bundle(state, account_id, device_id, pre_key).await
+}
+
+async fn build_key_bundle<T: DenimStateType>(
+    state: &mut DenimState<T>,
+    account_id: AccountId,
+    device_id: DeviceId,
+    pre_key: EcPreKey,
+) -> Result<KeyBundle, LogicError> {
+    let pre_key = pre_key.encode().map_err(|err| {
+        error!("{err}");
+        LogicError::Encode
+    })?;
 
     let signed_pre_key = state
         .keys
@@ -83,32 +110,60 @@ pub async fn update_seed<T: DenimStateType>(
     state: &mut DenimState<T>,
     account_id: AccountId,
     device_id: DeviceId,
+    epoch: u32,
     key_seed: KeySeed,
     key_id_seed: KeyIdSeed,
 ) -> Result<(), LogicError> {
-    state
+    Ok(state
         .keys
         .pre_keys
-        .store_key_id_seed_for(account_id, device_id, key_id_seed.into())
-        .await?;
-    state
+        .rotate_seeds(
+            account_id,
+            device_id,
+            epoch,
+            key_seed.into(),
+            key_id_seed.into(),
+        )
+        .await?)
+}
+
+/// Replaces the seeds of a device that lost them.
+/// Returns the senders whose PreKey messages to the device can no longer be decrypted.
+pub async fn reset_seed<T: DenimStateType>(
+    state: &mut DenimState<T>,
+    account_id: AccountId,
+    device_id: DeviceId,
+    epoch: u32,
+    key_seed: KeySeed,
+    key_id_seed: KeyIdSeed,
+) -> Result<Vec<(AccountId, DeviceId)>, LogicError> {
+    Ok(state
         .keys
         .pre_keys
-        .store_key_seed_for(account_id, device_id, key_seed.into())
-        .await?;
-    Ok(())
+        .reset_seeds(
+            account_id,
+            device_id,
+            epoch,
+            key_seed.into(),
+            key_id_seed.into(),
+        )
+        .await?)
 }
 
 pub async fn store_pending_key<T: DenimStateType>(
     state: &mut DenimState<T>,
     message: &PreKeySignalMessage,
     sender_account_id: AccountId,
+    sender_device_id: DeviceId,
     receiver_account_id: AccountId,
+    receiver_device_id: DeviceId,
 ) -> Result<(), LogicError> {
     let pre_key_id = match message.pre_key_id() {
         Some(id) => id,
         None => {
-            debug!("User '{sender_account_id}' failed to provide required prekey");
+            debug!(
+                "User '{sender_account_id}.{sender_device_id}' failed to provide required prekey"
+            );
             return Ok(());
         }
     };
@@ -118,8 +173,9 @@ pub async fn store_pending_key<T: DenimStateType>(
         .pre_keys
         .store_pending_key(
             sender_account_id,
+            sender_device_id,
             receiver_account_id,
-            DEFAULT_DEVICE_ID.into(),
+            receiver_device_id,
             pre_key_id.into(),
         )
         .await?)
@@ -128,13 +184,13 @@ pub async fn store_pending_key<T: DenimStateType>(
 pub async fn remove_pending_key<T: DenimStateType>(
     state: &mut DenimState<T>,
     sender_account_id: AccountId,
-    receiver_account_id: AccountId,
+    sender_device_id: DeviceId,
+    receiver: DeviceAddress,
 ) -> Result<(), LogicError> {
-    let device_id = DEFAULT_DEVICE_ID.into();
     if !state
         .keys
         .pre_keys
-        .has_pending_key(receiver_account_id, sender_account_id, device_id)
+        .has_pending_key(receiver, sender_account_id, sender_device_id)
         .await
     {
         return Ok(());
@@ -143,28 +199,43 @@ pub async fn remove_pending_key<T: DenimStateType>(
     let id = state
         .keys
         .pre_keys
-        .remove_pending_key(receiver_account_id, sender_account_id, device_id)
+        .remove_pending_key(receiver, sender_account_id, sender_device_id)
         .await?;
 
     Ok(state
         .keys
         .pre_keys
-        .mark_ec_pre_key_as_unused(sender_account_id, device_id, id)
+        .mark_ec_pre_key_as_unused(sender_account_id, sender_device_id, id)
         .await?)
 }
 
+/// Retires keys reserved by PreKey messages that were never answered.
+/// The keys were already sent to a peer, so they are not handed out again.
+pub async fn expire_pending_keys<T: DenimStateType>(state: &mut DenimState<T>) {
+    for (account_id, device_id, key_id) in state.keys.pre_keys.remove_expired_pending_keys().await {
+        debug!("Pending key '{key_id}' for {account_id}.{device_id} expired");
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use denim_sam_common::rng::seed::KeySeed;
+    use std::time::Duration;
+
+    use denim_sam_common::rng::{
+        chacha::ChaChaRngState,
+        derive::{key_id_at, key_rng_at},
+        seed::{KeyIdSeed, KeySeed},
+    };
     use libsignal_protocol::IdentityKeyPair;
     use rand::{rngs::OsRng, SeedableRng};
     use rand_chacha::ChaCha20Rng;
+    use rstest::rstest;
     use sam_common::{
-        address::DEFAULT_DEVICE_ID,
+        address::{DeviceAddress, DEFAULT_DEVICE_ID},
         api::{Decode as _, EcPreKey, Key},
         AccountId,
     };
-    use sam_security::key_gen::generate_signed_pre_key;
+    use sam_security::key_gen::{generate_ec_pre_key, generate_signed_pre_key};
     use sam_server::{
         auth::password::Password,
         managers::{
@@ -178,8 +249,10 @@ mod test {
 
     use crate::{
         error::LogicError,
-        logic::keys::get_keys_for,
-        managers::{error::DenimKeyManagerError, DenimEcPreKeyManager},
+        logic::keys::{expire_pending_keys, get_keys_for},
+        managers::{
+            error::DenimKeyManagerError, in_mem::InMemoryDenimEcPreKeyManager, DenimEcPreKeyManager,
+        },
         state::{DenimState, InMemoryDenimStateType},
     };
 
@@ -526,4 +599,487 @@ mod test {
                 .expect("Can remove Bob's ec pre key");
         }
     }
+
+    #[rstest]
+    #[case(Duration::ZERO, Duration::from_secs(60), false, 0)]
+    #[case(Duration::from_secs(60), Duration::ZERO, true, 1)]
+    #[case(Duration::ZERO, Duration::ZERO, false, 2)]
+    #[tokio::test]
+    async fn expires_pending_and_used_keys(
+        #[case] pending_key_ttl: Duration,
+        #[case] used_key_ttl: Duration,
+        #[case] still_pending: bool,
+        #[case] compacted: usize,
+    ) {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8000".to_owned());
+        state.keys.pre_keys = InMemoryDenimEcPreKeyManager::builder()
+            .pending_key_ttl(pending_key_ttl)
+            .used_key_ttl(used_key_ttl)
+            .build();
+        let alice_id = AccountId::generate();
+        let alice = DeviceAddress::new(alice_id, DEFAULT_DEVICE_ID.into());
+        let bob = AccountId::generate();
+        let device_id = DEFAULT_DEVICE_ID.into();
+
+        state
+            .keys
+            .pre_keys
+            .store_key_seed_for(bob, device_id, KeySeed::random(&mut OsRng).into())
+            .await
+            .expect("Can store key seed");
+        state
+            .keys
+            .pre_keys
+            .store_key_id_seed_for(bob, device_id, KeyIdSeed::random(&mut OsRng).into())
+            .await
+            .expect("Can store key id seed");
+
+        let key = state
+            .keys
+            .pre_keys
+            .get_ec_pre_key(bob, device_id)
+            .await
+            .expect("Can get ec pre key");
+        state
+            .keys
+            .pre_keys
+            .store_pending_key(alice_id, device_id, bob, device_id, key.id())
+            .await
+            .expect("Can store pending key");
+        // handed out, but never used in a PreKey message
+        state
+            .keys
+            .pre_keys
+            .get_ec_pre_key(bob, device_id)
+            .await
+            .expect("Can get ec pre key");
+
+        expire_pending_keys(&mut state).await;
+
+        assert_eq!(
+            state
+                .keys
+                .pre_keys
+                .has_pending_key(alice, bob, device_id)
+                .await,
+            still_pending
+        );
+        // pending keys are never compacted, expired ones are retired like used keys
+        assert_eq!(state.keys.pre_keys.compact_used_keys().await, compacted);
+    }
+
+    #[tokio::test]
+    async fn refills_pre_keys_below_low_watermark() {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8000".to_owned());
+        state.keys.pre_keys = InMemoryDenimEcPreKeyManager::builder()
+            .low_watermark(5)
+            .high_watermark(20)
+            .build();
+        let bob = AccountId::generate();
+        let device_id = DEFAULT_DEVICE_ID.into();
+
+        state
+            .keys
+            .pre_keys
+            .store_key_seed_for(bob, device_id, KeySeed::random(&mut OsRng).into())
+            .await
+            .expect("Can store key seed");
+        state
+            .keys
+            .pre_keys
+            .store_key_id_seed_for(bob, device_id, KeyIdSeed::random(&mut OsRng).into())
+            .await
+            .expect("Can store key id seed");
+
+        state
+            .keys
+            .pre_keys
+            .refill_ec_pre_keys()
+            .await
+            .expect("Can refill pre keys");
+        let unused = state
+            .keys
+            .pre_keys
+            .get_ec_pre_key_ids(bob, device_id)
+            .await
+            .expect("Can get ec pre key ids");
+        assert_eq!(unused.len(), 20);
+
+        // above the low watermark nothing is generated
+        for _ in 0..15 {
+            state
+                .keys
+                .pre_keys
+                .get_ec_pre_key(bob, device_id)
+                .await
+                .expect("Can get ec pre key");
+        }
+        state
+            .keys
+            .pre_keys
+            .refill_ec_pre_keys()
+            .await
+            .expect("Can refill pre keys");
+        let unused = state
+            .keys
+            .pre_keys
+            .get_ec_pre_key_ids(bob, device_id)
+            .await
+            .expect("Can get ec pre key ids");
+        assert_eq!(unused.len(), 5);
+
+        state
+            .keys
+            .pre_keys
+            .get_ec_pre_key(bob, device_id)
+            .await
+            .expect("Can get ec pre key");
+        state
+            .keys
+            .pre_keys
+            .refill_ec_pre_keys()
+            .await
+            .expect("Can refill pre keys");
+        let unused = state
+            .keys
+            .pre_keys
+            .get_ec_pre_key_ids(bob, device_id)
+            .await
+            .expect("Can get ec pre key ids");
+        assert_eq!(unused.len(), 20);
+    }
+
+    #[tokio::test]
+    async fn handed_out_key_can_be_derived_from_its_index() {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8000".to_owned());
+        let bob = AccountId::generate();
+        let device_id = DEFAULT_DEVICE_ID.into();
+        let key_seed: ChaChaRngState = KeySeed::random(&mut OsRng).into();
+        let key_id_seed: ChaChaRngState = KeyIdSeed::random(&mut OsRng).into();
+
+        state
+            .keys
+            .pre_keys
+            .store_key_seed_for(bob, device_id, key_seed.clone())
+            .await
+            .expect("Can store key seed");
+        state
+            .keys
+            .pre_keys
+            .store_key_id_seed_for(bob, device_id, key_id_seed.clone())
+            .await
+            .expect("Can store key id seed");
+
+        for _ in 0..15 {
+            let key = state
+                .keys
+                .pre_keys
+                .get_ec_pre_key(bob, device_id)
+                .await
+                .expect("Can get ec pre key");
+            let (_, index) = state
+                .keys
+                .pre_keys
+                .get_key_index(bob, device_id, key.id())
+                .await
+                .expect("Can get key index");
+
+            assert_eq!(key_id_at(key_id_seed.clone(), index), key.id());
+            let derived = EcPreKey::from(
+                generate_ec_pre_key(key.id().into(), &mut key_rng_at(key_seed.clone(), index))
+                    .await,
+            );
+            assert!(derived.public_key == key.public_key);
+        }
+    }
+
+    #[rstest]
+    #[case(Duration::from_secs(60), 0)]
+    #[case(Duration::ZERO, 1)]
+    #[tokio::test]
+    async fn rotated_seed_epoch_is_kept_for_grace_period(
+        #[case] seed_grace_period: Duration,
+        #[case] forgotten: usize,
+    ) {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8000".to_owned());
+        state.keys.pre_keys = InMemoryDenimEcPreKeyManager::builder()
+            .seed_grace_period(seed_grace_period)
+            .build();
+        let bob = AccountId::generate();
+        let device_id = DEFAULT_DEVICE_ID.into();
+
+        super::update_seed(
+            &mut state,
+            bob,
+            device_id,
+            1,
+            KeySeed::random(&mut OsRng),
+            KeyIdSeed::random(&mut OsRng),
+        )
+        .await
+        .expect("Can update seed");
+        let old_key = state
+            .keys
+            .pre_keys
+            .get_ec_pre_key(bob, device_id)
+            .await
+            .expect("Can get ec pre key");
+
+        super::update_seed(
+            &mut state,
+            bob,
+            device_id,
+            2,
+            KeySeed::random(&mut OsRng),
+            KeyIdSeed::random(&mut OsRng),
+        )
+        .await
+        .expect("Can rotate seed");
+
+        // unused keys of the old epoch are discarded
+        assert!(state
+            .keys
+            .pre_keys
+            .get_ec_pre_key_ids(bob, device_id)
+            .await
+            .expect("Can get ec pre key ids")
+            .is_empty());
+        let new_key = state
+            .keys
+            .pre_keys
+            .get_ec_pre_key(bob, device_id)
+            .await
+            .expect("Can get ec pre key");
+        let (epoch, _) = state
+            .keys
+            .pre_keys
+            .get_key_index(bob, device_id, new_key.id())
+            .await
+            .expect("Can get key index");
+        assert_eq!(epoch, 2);
+
+        assert!(super::update_seed(
+            &mut state,
+            bob,
+            device_id,
+            1,
+            KeySeed::random(&mut OsRng),
+            KeyIdSeed::random(&mut OsRng),
+        )
+        .await
+        .is_err_and(|err| matches!(
+            err,
+            LogicError::KeyManager(DenimKeyManagerError::StaleSeedEpoch(2))
+        )));
+
+        assert_eq!(state.keys.pre_keys.remove_expired_epochs().await, forgotten);
+        let old_index = state
+            .keys
+            .pre_keys
+            .get_key_index(bob, device_id, old_key.id())
+            .await;
+        if forgotten == 0 {
+            assert!(old_index.is_ok_and(|(epoch, _)| epoch == 1));
+        } else {
+            assert!(old_index.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn replayed_resync_keeps_newer_keys() {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8000".to_owned());
+        let alice = AccountId::generate();
+        let bob = AccountId::generate();
+        let device_id = DEFAULT_DEVICE_ID.into();
+
+        super::reset_seed(
+            &mut state,
+            bob,
+            device_id,
+            2,
+            KeySeed::random(&mut OsRng),
+            KeyIdSeed::random(&mut OsRng),
+        )
+        .await
+        .expect("Can reset seed");
+        super::update_seed(
+            &mut state,
+            bob,
+            device_id,
+            3,
+            KeySeed::random(&mut OsRng),
+            KeyIdSeed::random(&mut OsRng),
+        )
+        .await
+        .expect("Can rotate seed");
+        let key = state
+            .keys
+            .pre_keys
+            .get_ec_pre_key(bob, device_id)
+            .await
+            .expect("Can get ec pre key");
+        state
+            .keys
+            .pre_keys
+            .store_pending_key(alice, device_id, bob, device_id, key.id())
+            .await
+            .expect("Can store pending key");
+        let mut unused = state
+            .keys
+            .pre_keys
+            .get_ec_pre_key_ids(bob, device_id)
+            .await
+            .expect("Can get ec pre key ids");
+        unused.sort();
+
+        assert!(super::reset_seed(
+            &mut state,
+            bob,
+            device_id,
+            2,
+            KeySeed::random(&mut OsRng),
+            KeyIdSeed::random(&mut OsRng),
+        )
+        .await
+        .is_err_and(|err| matches!(
+            err,
+            LogicError::KeyManager(DenimKeyManagerError::StaleSeedEpoch(3))
+        )));
+        // a resync of the epoch in use is already applied
+        assert!(super::reset_seed(
+            &mut state,
+            bob,
+            device_id,
+            3,
+            KeySeed::random(&mut OsRng),
+            KeyIdSeed::random(&mut OsRng),
+        )
+        .await
+        .expect("Can replay resync")
+        .is_empty());
+
+        let mut kept = state
+            .keys
+            .pre_keys
+            .get_ec_pre_key_ids(bob, device_id)
+            .await
+            .expect("Can get ec pre key ids");
+        kept.sort();
+        assert_eq!(kept, unused);
+        assert!(
+            state
+                .keys
+                .pre_keys
+                .has_pending_key(DeviceAddress::new(alice, device_id), bob, device_id)
+                .await
+        );
+        assert!(state
+            .keys
+            .pre_keys
+            .get_key_index(bob, device_id, key.id())
+            .await
+            .is_ok_and(|(epoch, _)| epoch == 3));
+    }
+
+    #[tokio::test]
+    async fn expired_pending_key_is_not_handed_out_again() {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8000".to_owned());
+        state.keys.pre_keys = InMemoryDenimEcPreKeyManager::builder()
+            .key_generate_amount(1)
+            .pending_key_ttl(Duration::ZERO)
+            .build();
+        let alice = AccountId::generate();
+        let bob = AccountId::generate();
+        let device_id = DEFAULT_DEVICE_ID.into();
+
+        state
+            .keys
+            .pre_keys
+            .store_key_seed_for(bob, device_id, KeySeed::random(&mut OsRng).into())
+            .await
+            .expect("Can store key seed");
+        state
+            .keys
+            .pre_keys
+            .store_key_id_seed_for(bob, device_id, KeyIdSeed::random(&mut OsRng).into())
+            .await
+            .expect("Can store key id seed");
+
+        let expired = state
+            .keys
+            .pre_keys
+            .get_ec_pre_key(bob, device_id)
+            .await
+            .expect("Can get ec pre key");
+        state
+            .keys
+            .pre_keys
+            .store_pending_key(alice, device_id, bob, device_id, expired.id())
+            .await
+            .expect("Can store pending key");
+        expire_pending_keys(&mut state).await;
+
+        for _ in 0..50 {
+            let key = state
+                .keys
+                .pre_keys
+                .get_ec_pre_key(bob, device_id)
+                .await
+                .expect("Can get ec pre key");
+            assert_ne!(key.id(), expired.id());
+        }
+        // a late PreKey message for the expired key can still be routed
+        assert!(state
+            .keys
+            .pre_keys
+            .get_key_index(bob, device_id, expired.id())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn keys_of_every_retired_epoch_are_kept_for_grace_period() {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8000".to_owned());
+        let bob = AccountId::generate();
+        let device_id = DEFAULT_DEVICE_ID.into();
+
+        let mut handed_out = Vec::new();
+        for epoch in 1..=3 {
+            super::update_seed(
+                &mut state,
+                bob,
+                device_id,
+                epoch,
+                KeySeed::random(&mut OsRng),
+                KeyIdSeed::random(&mut OsRng),
+            )
+            .await
+            .expect("Can rotate seed");
+            let key = state
+                .keys
+                .pre_keys
+                .get_ec_pre_key(bob, device_id)
+                .await
+                .expect("Can get ec pre key");
+            handed_out.push((epoch, key.id()));
+        }
+
+        assert_eq!(state.keys.pre_keys.remove_expired_epochs().await, 0);
+        // quick rotations do not drop keys that are still in flight
+        for (epoch, key_id) in handed_out {
+            assert!(state
+                .keys
+                .pre_keys
+                .get_key_index(bob, device_id, key_id)
+                .await
+                .is_ok_and(|(key_epoch, _)| key_epoch == epoch));
+        }
+    }
 }
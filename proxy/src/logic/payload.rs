@@ -0,0 +1,118 @@
+use denim_sam_common::buffers::{DenimMessage, PaddingScheme};
+use log::warn;
+use sam_common::{AccountId, DeviceId};
+use serde::{Deserialize, Serialize};
+
+use crate::state::{DenimState, DenimStateType};
+
+/// What to do with a client whose deniable payload does not have the length
+/// that the advertised q and padding require.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PayloadMismatchPolicy {
+    #[default]
+    Log,
+    /// Log and count the mismatch, see `DenimState::payload_mismatches`.
+    Count,
+    /// Log, count and disconnect the client.
+    Disconnect,
+}
+
+/// Checks that the deniable payload of `msg` is as long as a compliant client makes it
+/// with the `q` and `padding` it was sent in QStatus.
+/// Returns false if the client has to be disconnected.
+pub fn validate_payload_length<T: DenimStateType>(
+    state: &DenimState<T>,
+    msg: &DenimMessage,
+    q: f32,
+    padding: Option<&PaddingScheme>,
+    account_id: AccountId,
+    device_id: DeviceId,
+) -> bool {
+    let expected = u32::try_from(msg.regular_payload.len())
+        .ok()
+        .and_then(|len| DenimMessage::deniable_payload_length(len, q, padding).ok());
+    let actual = msg.deniable_payload.payload_length().ok();
+    if expected.is_some() && expected == actual {
+        return true;
+    }
+
+    warn!(
+        "Deniable payload of {account_id}.{device_id} is {actual:?} bytes, expected {expected:?}"
+    );
+    let policy = state.payload_mismatch_policy();
+    if policy != PayloadMismatchPolicy::Log {
+        state.count_payload_mismatch();
+    }
+    policy != PayloadMismatchPolicy::Disconnect
+}
+
+#[cfg(test)]
+mod test {
+    use denim_sam_common::buffers::{
+        DeniablePayload, DenimMessage, InMemorySendingBuffer, PaddingScheme, SendingBuffer,
+    };
+    use rstest::rstest;
+    use sam_common::{address::DEFAULT_DEVICE_ID, AccountId};
+
+    use crate::{
+        logic::payload::{validate_payload_length, PayloadMismatchPolicy},
+        state::{DenimState, InMemoryDenimStateType},
+    };
+
+    async fn message(padding: Option<PaddingScheme>, compliant: bool) -> DenimMessage {
+        let mut buffer = InMemorySendingBuffer::new(1.0).expect("can create sending buffer");
+        buffer.set_padding(padding).await;
+        let deniable_payload = if compliant {
+            buffer
+                .get_deniable_payload(100)
+                .await
+                .expect("can get deniable payload")
+        } else {
+            DeniablePayload::default()
+        };
+        DenimMessage::builder()
+            .q(0.0)
+            .regular_payload(vec![1; 100])
+            .deniable_payload(deniable_payload)
+            .build()
+    }
+
+    #[rstest]
+    #[case(PayloadMismatchPolicy::Log, None, true, true, 0)]
+    #[case(PayloadMismatchPolicy::Log, None, false, true, 0)]
+    #[case(PayloadMismatchPolicy::Count, None, false, true, 1)]
+    #[case(PayloadMismatchPolicy::Disconnect, None, false, false, 1)]
+    #[case(
+        PayloadMismatchPolicy::Disconnect,
+        Some(PaddingScheme::PowersOfTwo),
+        true,
+        true,
+        0
+    )]
+    #[tokio::test]
+    async fn mismatched_payloads_are_handled_by_policy(
+        #[case] policy: PayloadMismatchPolicy,
+        #[case] padding: Option<PaddingScheme>,
+        #[case] compliant: bool,
+        #[case] keep_connection: bool,
+        #[case] mismatches: u64,
+    ) {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8000".to_owned());
+        state.set_payload_mismatch_policy(policy);
+        let msg = message(padding.clone(), compliant).await;
+
+        let keep = validate_payload_length(
+            &state,
+            &msg,
+            1.0,
+            padding.as_ref(),
+            AccountId::generate(),
+            DEFAULT_DEVICE_ID.into(),
+        );
+
+        assert_eq!(keep, keep_connection);
+        assert_eq!(state.payload_mismatches(), mismatches);
+    }
+}
@@ -1,11 +1,18 @@
 use clap::{Arg, Command};
+use denim_sam_common::buffers::PaddingScheme;
 use denim_sam_proxy::{
     config::DenimCliConfig,
+    decoy::DecoyPolicy,
     error::CliError,
+    managers::in_mem::{
+        DEFAULT_KEY_COMPACTION_INTERVAL, DEFAULT_KEY_REQUEST_TTL,
+        DEFAULT_MAX_DEFERRED_KEY_REQUESTS, DEFAULT_PENDING_KEY_TTL, DEFAULT_PRE_KEY_HIGH_WATERMARK,
+        DEFAULT_PRE_KEY_LOW_WATERMARK, DEFAULT_SEED_GRACE_PERIOD, DEFAULT_USED_KEY_TTL,
+    },
     server::{start_proxy, DenimConfig},
 };
 use log::{debug, error, info};
-use std::io::BufReader;
+use std::{io::BufReader, time::Duration};
 
 const DEFAULT_SAM_ADDR: &str = "127.0.0.1:8080";
 const DEFAULT_PROXY_ADDR: &str = "127.0.0.1:8081";
@@ -182,6 +189,59 @@ async fn cli() -> Result<(), CliError> {
                 .key_generate_amount
                 .unwrap_or(DEFAULT_KEY_GENERATE_AMOUNT),
         )
+        .key_request_ttl(
+            config
+                .key_request_ttl
+                .map_or(DEFAULT_KEY_REQUEST_TTL, Duration::from_secs),
+        )
+        .max_deferred_key_requests(
+            config
+                .max_deferred_key_requests
+                .unwrap_or(DEFAULT_MAX_DEFERRED_KEY_REQUESTS),
+        )
+        .pending_key_ttl(
+            config
+                .pending_key_ttl
+                .map_or(DEFAULT_PENDING_KEY_TTL, Duration::from_secs),
+        )
+        .used_key_ttl(
+            config
+                .used_key_ttl
+                .map_or(DEFAULT_USED_KEY_TTL, Duration::from_secs),
+        )
+        .key_compaction_interval(
+            config
+                .key_compaction_interval
+                .map_or(DEFAULT_KEY_COMPACTION_INTERVAL, Duration::from_secs),
+        )
+        .pre_key_low_watermark(
+            config
+                .pre_key_low_watermark
+                .unwrap_or(DEFAULT_PRE_KEY_LOW_WATERMARK),
+        )
+        .pre_key_high_watermark(
+            config
+                .pre_key_high_watermark
+                .unwrap_or(DEFAULT_PRE_KEY_HIGH_WATERMARK),
+        )
+        .seed_grace_period(
+            config
+                .seed_grace_period
+                .map_or(DEFAULT_SEED_GRACE_PERIOD, Duration::from_secs),
+        )
+        .maybe_decoy_policy(config.decoy_interval.map(|interval| DecoyPolicy {
+            mean_interval: Duration::from_millis(interval),
+            ..Default::default()
+        }))
+        .maybe_padding(config.padding_tiers.map(|mut tiers| {
+            tiers.sort_unstable();
+            if tiers.is_empty() {
+                PaddingScheme::PowersOfTwo
+            } else {
+                PaddingScheme::Tiers(tiers)
+            }
+        }))
+        .payload_mismatch_policy(config.payload_mismatch_policy.unwrap_or_default())
         .call()
         .await?;
     info!("Database: OK");
@@ -2,23 +2,25 @@ use std::{collections::HashMap, sync::Arc};
 
 use denim_sam_common::{
     buffers::{
-        DeniablePayload, DenimChunk, MessageId, ReceivingBuffer, ReceivingBufferConfig,
-        SendingBuffer, SendingBufferConfig,
+        DeniablePayload, DenimChunk, MessageId, PaddingScheme, ReceivingBuffer,
+        ReceivingBufferConfig, SendingBuffer, SendingBufferConfig,
     },
     denim_message::{
-        deniable_message::MessageKind, BlockRequest, DeniableMessage, KeyRequest, SeedUpdate,
-        UserMessage,
+        deniable_message::MessageKind, BlockListRequest, BlockRequest, DeniableMessage, KeyRequest,
+        SeedUpdate, UnblockRequest, UserMessage,
     },
 };
 use log::debug;
 
-use sam_common::AccountId;
+use sam_common::{address::DeviceAddress, AccountId, DeviceId};
 use tokio::sync::Mutex;
 
 use crate::{managers::error::BufferManagerError, state::BufferManagerType};
 
 pub enum ClientRequest {
     BlockRequest(MessageId, BlockRequest),
+    UnblockRequest(MessageId, UnblockRequest),
+    BlockListRequest(MessageId, BlockListRequest),
     KeyRequest(MessageId, KeyRequest),
     SeedUpdateRequest(MessageId, SeedUpdate),
     UserMessage(MessageId, UserMessage),
@@ -26,10 +28,11 @@ pub enum ClientRequest {
 
 #[derive(Clone)]
 pub struct BufferManager<T: BufferManagerType> {
-    receiving_buffers:
-        Arc<Mutex<HashMap<AccountId, <T::ReceivingBufferConfig as ReceivingBufferConfig>::Buffer>>>,
+    receiving_buffers: Arc<
+        Mutex<HashMap<DeviceAddress, <T::ReceivingBufferConfig as ReceivingBufferConfig>::Buffer>>,
+    >,
     sending_buffers:
-        Arc<Mutex<HashMap<AccountId, <T::SendingBufferConfig as SendingBufferConfig>::Buffer>>>,
+        Arc<Mutex<HashMap<DeviceAddress, <T::SendingBufferConfig as SendingBufferConfig>::Buffer>>>,
     receiving_config: T::ReceivingBufferConfig,
     sending_config: T::SendingBufferConfig,
     q: f32,
@@ -64,32 +67,40 @@ impl<T: BufferManagerType> BufferManager<T> {
     pub async fn enqueue_message(
         &mut self,
         account_id: AccountId,
+        device_id: DeviceId,
         deniable_message: DeniableMessage,
     ) -> Result<(), BufferManagerError> {
         let mut guard = self.sending_buffers.lock().await;
-        let buffer = guard.entry(account_id).or_insert(
-            self.sending_config
-                .create(self.q)
-                .await
-                .map_err(BufferManagerError::DenimBufferError)?,
-        );
-        buffer.enqueue_message(deniable_message).await;
-        Ok(())
+        let buffer = guard
+            .entry(DeviceAddress::new(account_id, device_id))
+            .or_insert(
+                self.sending_config
+                    .create(self.q)
+                    .await
+                    .map_err(BufferManagerError::DenimBufferError)?,
+            );
+        buffer
+            .enqueue_message(deniable_message)
+            .await
+            .map_err(BufferManagerError::DenimBufferError)
     }
 
     pub async fn get_deniable_payload(
         &mut self,
         account_id: AccountId,
+        device_id: DeviceId,
         reg_message_len: u32,
     ) -> Result<DeniablePayload, BufferManagerError> {
         let mut guard = self.sending_buffers.lock().await;
         // or_insert_with would be better, but you know async closures
-        let buffer = guard.entry(account_id).or_insert(
-            self.sending_config
-                .create(self.q)
-                .await
-                .map_err(BufferManagerError::DenimBufferError)?,
-        );
+        let buffer = guard
+            .entry(DeviceAddress::new(account_id, device_id))
+            .or_insert(
+                self.sending_config
+                    .create(self.q)
+                    .await
+                    .map_err(BufferManagerError::DenimBufferError)?,
+            );
 
         buffer
             .get_deniable_payload(reg_message_len)
@@ -97,15 +108,36 @@ impl<T: BufferManagerType> BufferManager<T> {
             .map_err(BufferManagerError::DenimBufferError)
     }
 
+    /// Pads the messages sent to the device with `padding`.
+    pub async fn set_padding(
+        &mut self,
+        account_id: AccountId,
+        device_id: DeviceId,
+        padding: Option<PaddingScheme>,
+    ) -> Result<(), BufferManagerError> {
+        let mut guard = self.sending_buffers.lock().await;
+        let buffer = guard
+            .entry(DeviceAddress::new(account_id, device_id))
+            .or_insert(
+                self.sending_config
+                    .create(self.q)
+                    .await
+                    .map_err(BufferManagerError::DenimBufferError)?,
+            );
+        buffer.set_padding(padding).await;
+        Ok(())
+    }
+
     pub async fn enqueue_chunks(
         &mut self,
         account_id: AccountId,
+        device_id: DeviceId,
         chunks: Vec<DenimChunk>,
     ) -> Result<Vec<Result<ClientRequest, BufferManagerError>>, BufferManagerError> {
         let chunks = {
             let mut guard = self.receiving_buffers.lock().await;
             guard
-                .entry(account_id)
+                .entry(DeviceAddress::new(account_id, device_id))
                 .or_insert(
                     self.receiving_config
                         .create()
@@ -147,11 +179,18 @@ impl<T: BufferManagerType> BufferManager<T> {
         let request = match kind {
             MessageKind::DeniableMessage(x) => ClientRequest::UserMessage(message_id, x),
             MessageKind::BlockRequest(x) => ClientRequest::BlockRequest(message_id, x),
+            MessageKind::UnblockRequest(x) => ClientRequest::UnblockRequest(message_id, x),
+            MessageKind::BlockListRequest(x) => ClientRequest::BlockListRequest(message_id, x),
             MessageKind::KeyRequest(x) => ClientRequest::KeyRequest(message_id, x),
             MessageKind::SeedUpdate(x) => ClientRequest::SeedUpdateRequest(message_id, x),
             // Client is not allowed to send these
             MessageKind::Error(_) => Err(BufferManagerError::ClientSendError(message_id))?,
-            MessageKind::KeyResponse(_) => {
+            MessageKind::KeyResponse(_)
+            | MessageKind::BlockListResponse(_)
+            | MessageKind::SeedUpdateAck(_)
+            | MessageKind::SeedUpdateRejected(_)
+            | MessageKind::SeedRequest(_)
+            | MessageKind::KeysReset(_) => {
                 Err(BufferManagerError::ClientSendServerResponse(message_id))?
             }
         };
@@ -167,13 +206,13 @@ mod test {
             Flag, SendingBuffer,
         },
         denim_message::{
-            deniable_message::MessageKind, BlockRequest, DeniableMessage, KeyRequest, MessageType,
-            SeedUpdate, UserMessage,
+            deniable_message::MessageKind, BlockListRequest, BlockRequest, DeniableMessage,
+            KeyRequest, MessageType, SeedUpdate, UnblockRequest, UserMessage,
         },
     };
 
     use rstest::rstest;
-    use sam_common::AccountId;
+    use sam_common::{address::DEFAULT_DEVICE_ID, AccountId};
 
     use crate::{
         managers::{default::ClientRequest, BufferManager},
@@ -196,6 +235,7 @@ mod test {
             .build();
         mgr.enqueue_message(
             account_id,
+            DEFAULT_DEVICE_ID.into(),
             DeniableMessage::builder()
                 .message_id(1)
                 .message_kind(MessageKind::DeniableMessage(user_msg))
@@ -204,7 +244,7 @@ mod test {
         .await
         .expect("Can enqueue");
         let payload = mgr
-            .get_deniable_payload(account_id, 50)
+            .get_deniable_payload(account_id, DEFAULT_DEVICE_ID.into(), 50)
             .await
             .expect("can get payload");
 
@@ -217,6 +257,8 @@ mod test {
     enum Request {
         Key,
         Block,
+        Unblock,
+        BlockList,
         Seed,
         Message,
     }
@@ -235,10 +277,19 @@ mod test {
                         .account_id(AccountId::generate().into())
                         .build(),
                 ),
+                Request::Unblock => MessageKind::UnblockRequest(
+                    UnblockRequest::builder()
+                        .account_id(AccountId::generate().into())
+                        .build(),
+                ),
+                Request::BlockList => {
+                    MessageKind::BlockListRequest(BlockListRequest::builder().build())
+                }
                 Request::Seed => MessageKind::SeedUpdate(
                     SeedUpdate::builder()
                         .pre_key_id_seed(vec![1, 2, 3])
                         .pre_key_seed(vec![5, 3, 1])
+                        .epoch(1)
                         .build(),
                 ),
                 Request::Message => MessageKind::DeniableMessage(
@@ -256,6 +307,8 @@ mod test {
     #[rstest]
     #[case(Request::Key, |req: &ClientRequest| matches!(req, ClientRequest::KeyRequest(_, _)))]
     #[case(Request::Block, |req: &ClientRequest| matches!(req, ClientRequest::BlockRequest(_, _)))]
+    #[case(Request::Unblock, |req: &ClientRequest| matches!(req, ClientRequest::UnblockRequest(_, _)))]
+    #[case(Request::BlockList, |req: &ClientRequest| matches!(req, ClientRequest::BlockListRequest(_, _)))]
     #[case(Request::Seed, |req: &ClientRequest| matches!(req, ClientRequest::SeedUpdateRequest(_, _)))]
     #[case(Request::Message, |req: &ClientRequest| matches!(req, ClientRequest::UserMessage(_, _)))]
     #[tokio::test]
@@ -277,16 +330,20 @@ mod test {
             .message_kind(kind)
             .build();
 
-        mgr.enqueue_message(account_id, msg)
+        mgr.enqueue_message(account_id, DEFAULT_DEVICE_ID.into(), msg)
             .await
             .expect("Can enqueue");
         let payload = mgr
-            .get_deniable_payload(account_id, 200)
+            .get_deniable_payload(account_id, DEFAULT_DEVICE_ID.into(), 200)
             .await
             .expect("Can get payload");
 
         let results = mgr
-            .enqueue_chunks(account_id, payload.denim_chunks().to_vec())
+            .enqueue_chunks(
+                account_id,
+                DEFAULT_DEVICE_ID.into(),
+                payload.denim_chunks().to_vec(),
+            )
             .await
             .expect("Can enqueue");
         assert!(results.len() == 1);
@@ -311,6 +368,7 @@ mod test {
         for account in accounts {
             mgr.enqueue_message(
                 account,
+                DEFAULT_DEVICE_ID.into(),
                 DeniableMessage {
                     message_id: 1u32,
                     message_kind: Some(MessageKind::BlockRequest(BlockRequest {
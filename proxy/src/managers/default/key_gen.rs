@@ -1,4 +1,7 @@
-use denim_sam_common::rng::RngState;
+use denim_sam_common::rng::{
+    derive::{key_rng_at, KEY_STREAM_WORDS_PER_KEY},
+    RngState,
+};
 use log::debug;
 use sam_common::{api::EcPreKey, AccountId, DeviceId};
 use sam_security::key_gen::generate_ec_pre_key;
@@ -11,36 +14,45 @@ pub async fn generate_ec_pre_keys<R: RngState>(
     device_id: DeviceId,
     amount: usize,
 ) -> Result<(), DenimKeyManagerError> {
-    let mut id_rng = key_manager
+    let id_seed = key_manager
         .get_key_id_seed_for(account_id, device_id)
-        .await?
-        .into_rng();
+        .await?;
+    let key_seed = key_manager.get_key_seed_for(account_id, device_id).await?;
 
-    let mut key_rng = key_manager
-        .get_key_seed_for(account_id, device_id)
-        .await?
-        .into_rng();
+    // every key uses one word of the key id stream, so its offset is the next key index
+    let mut index: u64 = id_seed
+        .offset()
+        .try_into()
+        .map_err(|_| DenimKeyManagerError::CouldNotGenerateKeyId)?;
 
     for _ in 0..amount {
-        let key_id = key_manager
-            .next_key_id(account_id, device_id, &mut id_rng)
+        let (key_index, key_id) = key_manager
+            .next_key_id(account_id, device_id, id_seed.clone(), index)
             .await?;
+        index = key_index + 1;
+
         debug!("Generating EC Pre Key '{key_id}' for {account_id}.{device_id}");
-        let pk: EcPreKey = generate_ec_pre_key(key_id.into(), &mut key_rng)
-            .await
-            .into();
+        let pk: EcPreKey =
+            generate_ec_pre_key(key_id.into(), &mut key_rng_at(key_seed.clone(), key_index))
+                .await
+                .into();
 
         key_manager
-            .add_ec_pre_key(account_id, device_id, pk.clone())
+            .add_ec_pre_key(account_id, device_id, pk, key_index)
             .await?;
+    }
 
-        key_manager
-            .store_key_id_seed_for(account_id, device_id, id_rng.clone().into())
-            .await?;
+    // the seeds are only persisted once per batch
+    key_manager
+        .store_key_id_seed_for(account_id, device_id, id_seed.with_offset(index.into()))
+        .await?;
 
-        key_manager
-            .store_key_seed_for(account_id, device_id, key_rng.clone().into())
-            .await?;
-    }
+    key_manager
+        .store_key_seed_for(
+            account_id,
+            device_id,
+            key_seed.with_offset(u128::from(index) * KEY_STREAM_WORDS_PER_KEY),
+        )
+        .await?;
     Ok(())
 }
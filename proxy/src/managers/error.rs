@@ -20,4 +20,14 @@ pub enum DenimKeyManagerError {
     CouldNotGenerateKeyId,
     AlreadyPending,
     NotPending,
+    NoKeyIndex,
+    /// The seed epoch is older than the epoch in use, which it carries.
+    #[from(skip)]
+    #[display("StaleSeedEpoch({_0})")]
+    StaleSeedEpoch(#[error(not(source))] u32),
+}
+
+#[derive(Debug, Display, Error)]
+pub enum KeyRequestManagerError {
+    TooManyRequesters,
 }
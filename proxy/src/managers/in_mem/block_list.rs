@@ -16,12 +16,24 @@ impl BlockList for InMemoryBlockList {
     async fn block_user(&mut self, users_account_id: AccountId, blocked_account_id: AccountId) {
         let mut block_list = self.block_list.lock().await;
         if let Some(vec) = block_list.get_mut(&users_account_id) {
-            vec.push(blocked_account_id);
+            if !vec.contains(&blocked_account_id) {
+                vec.push(blocked_account_id);
+            }
         } else {
             block_list.insert(users_account_id, vec![blocked_account_id]);
         }
     }
 
+    async fn unblock_user(&mut self, users_account_id: AccountId, blocked_account_id: AccountId) {
+        let mut block_list = self.block_list.lock().await;
+        if let Some(vec) = block_list.get_mut(&users_account_id) {
+            vec.retain(|account_id| *account_id != blocked_account_id);
+            if vec.is_empty() {
+                block_list.remove(&users_account_id);
+            }
+        }
+    }
+
     async fn is_user_blocked(
         &self,
         user_account_id: &AccountId,
@@ -32,6 +44,15 @@ impl BlockList for InMemoryBlockList {
         }
         false
     }
+
+    async fn get_blocked_users(&self, user_account_id: &AccountId) -> Vec<AccountId> {
+        self.block_list
+            .lock()
+            .await
+            .get(user_account_id)
+            .cloned()
+            .unwrap_or_default()
+    }
 }
 
 #[cfg(test)]
@@ -81,4 +102,41 @@ mod test {
             }
         }
     }
+
+    #[tokio::test]
+    async fn can_unblock_user() {
+        let mut block_list = InMemoryBlockList::default();
+
+        let user = AccountId::generate();
+        let blocked_users = vec![AccountId::generate(), AccountId::generate()];
+
+        for blocked_user in blocked_users.clone() {
+            block_list.block_user(user, blocked_user).await;
+        }
+
+        block_list.unblock_user(user, blocked_users[0]).await;
+
+        assert!(!block_list.is_user_blocked(&user, &blocked_users[0]).await);
+        assert!(block_list.is_user_blocked(&user, &blocked_users[1]).await);
+    }
+
+    #[tokio::test]
+    async fn can_list_blocked_users() {
+        let mut block_list = InMemoryBlockList::default();
+
+        let user = AccountId::generate();
+        let blocked_users = vec![AccountId::generate(), AccountId::generate()];
+
+        for blocked_user in blocked_users.clone() {
+            // blocking twice should not list the user twice
+            block_list.block_user(user, blocked_user).await;
+            block_list.block_user(user, blocked_user).await;
+        }
+
+        assert_eq!(block_list.get_blocked_users(&user).await, blocked_users);
+        assert!(block_list
+            .get_blocked_users(&AccountId::generate())
+            .await
+            .is_empty());
+    }
 }
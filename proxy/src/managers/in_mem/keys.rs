@@ -1,8 +1,8 @@
 use async_trait::async_trait;
-use denim_sam_common::rng::{chacha::ChaChaRngState, RngState};
+use bon::bon;
+use denim_sam_common::rng::{chacha::ChaChaRngState, derive::key_id_at, RngState};
 use futures_util::TryFutureExt;
-use log::debug;
-use rand::Rng;
+use log::{debug, error};
 use sam_common::{
     address::DeviceAddress,
     api::{EcPreKey, Key},
@@ -15,6 +15,7 @@ use sam_server::managers::{
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::sync::Mutex;
 
@@ -23,15 +24,22 @@ use crate::managers::{
     DenimKeyManagerType,
 };
 
+pub const DEFAULT_PENDING_KEY_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+pub const DEFAULT_USED_KEY_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+pub const DEFAULT_KEY_COMPACTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+pub const DEFAULT_PRE_KEY_LOW_WATERMARK: usize = 10;
+pub const DEFAULT_PRE_KEY_HIGH_WATERMARK: usize = 50;
+pub const DEFAULT_SEED_GRACE_PERIOD: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 
 struct PendingId {
-    pre_key_message_sender: AccountId,
+    pre_key_message_sender: DeviceAddress,
     address: DeviceAddress,
 }
 
 impl PendingId {
-    fn new(pre_key_message_sender: AccountId, address: DeviceAddress) -> Self {
+    fn new(pre_key_message_sender: DeviceAddress, address: DeviceAddress) -> Self {
         Self {
             pre_key_message_sender,
             address,
@@ -39,9 +47,19 @@ impl PendingId {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+struct PendingKey {
+    sender_account_id: AccountId,
+    sender_device_id: DeviceId,
+    account_id: AccountId,
+    device_id: DeviceId,
+    key_id: u32,
+    stored_at: Instant,
+}
+
 #[derive(Default, Clone)]
 struct UsedKeysMap {
-    keys: Arc<Mutex<HashMap<DeviceAddress, HashSet<u32>>>>,
+    keys: Arc<Mutex<HashMap<DeviceAddress, HashMap<u32, Instant>>>>,
 }
 
 impl UsedKeysMap {
@@ -51,8 +69,8 @@ impl UsedKeysMap {
             .lock()
             .await
             .entry(id)
-            .or_insert_with(HashSet::new)
-            .insert(key_id);
+            .or_insert_with(HashMap::new)
+            .insert(key_id, Instant::now());
     }
 
     async fn remove_pre_key(&mut self, account_id: AccountId, device_id: DeviceId, key_id: u32) {
@@ -61,7 +79,7 @@ impl UsedKeysMap {
             .lock()
             .await
             .entry(id)
-            .or_insert_with(HashSet::new)
+            .or_insert_with(HashMap::new)
             .remove(&key_id);
     }
 
@@ -76,43 +94,95 @@ impl UsedKeysMap {
             .lock()
             .await
             .entry(id)
-            .or_insert_with(HashSet::new)
-            .contains(&key_id)
+            .or_insert_with(HashMap::new)
+            .contains_key(&key_id)
+    }
+
+    /// Forgets keys used before `max_age` unless they are still `pending`.
+    /// Returns the forgotten keys.
+    async fn compact(
+        &mut self,
+        max_age: Duration,
+        pending: &HashSet<(DeviceAddress, u32)>,
+    ) -> Vec<(DeviceAddress, u32)> {
+        let mut removed = Vec::new();
+        let mut keys = self.keys.lock().await;
+        for (address, used) in keys.iter_mut() {
+            used.retain(|key_id, used_at| {
+                if used_at.elapsed() < max_age || pending.contains(&(*address, *key_id)) {
+                    return true;
+                }
+                removed.push((*address, *key_id));
+                false
+            });
+        }
+        keys.retain(|_, used| !used.is_empty());
+        removed
     }
 }
 
 #[derive(Clone)]
 pub struct InMemoryDenimEcPreKeyManager<T: RngState> {
     key_generate_amount: usize,
+    pending_key_ttl: Duration,
+    used_key_ttl: Duration,
+    low_watermark: usize,
+    high_watermark: usize,
+    seed_grace_period: Duration,
+    // serializes key generation, as it advances the seeds
+    key_generation: Arc<Mutex<()>>,
     unused_keys: InMemoryEcPreKeyManager,
     id_seeds: Arc<Mutex<HashMap<DeviceAddress, Option<T>>>>,
     key_seeds: Arc<Mutex<HashMap<DeviceAddress, Option<T>>>>,
-    pending_keys: Arc<Mutex<HashMap<PendingId, u32>>>,
+    seeded_devices: Arc<Mutex<HashMap<AccountId, Vec<DeviceId>>>>,
+    // current seed epoch of every device
+    epochs: Arc<Mutex<HashMap<DeviceAddress, u32>>>,
+    // retired seed epochs of every device still in their grace period, and when they were retired
+    retired_epochs: Arc<Mutex<HashMap<DeviceAddress, Vec<(u32, Instant)>>>>,
+    // key id to (epoch, index) of the keys of every device
+    key_indices: Arc<Mutex<HashMap<DeviceAddress, HashMap<u32, (u32, u64)>>>>,
+    pending_keys: Arc<Mutex<HashMap<PendingId, PendingKey>>>,
     used_keys: UsedKeysMap,
 }
 
+#[bon]
 impl<T: RngState> InMemoryDenimEcPreKeyManager<T> {
-    pub fn new(key_generate_amount: usize) -> Self {
+    #[builder]
+    pub fn new(
+        #[builder(default = 10)] key_generate_amount: usize,
+        #[builder(default = DEFAULT_PENDING_KEY_TTL)] pending_key_ttl: Duration,
+        #[builder(default = DEFAULT_USED_KEY_TTL)] used_key_ttl: Duration,
+        #[builder(default = DEFAULT_PRE_KEY_LOW_WATERMARK)] low_watermark: usize,
+        #[builder(default = DEFAULT_PRE_KEY_HIGH_WATERMARK)] high_watermark: usize,
+        #[builder(default = DEFAULT_SEED_GRACE_PERIOD)] seed_grace_period: Duration,
+    ) -> Self {
         Self {
             key_generate_amount,
-            ..Default::default()
-        }
-    }
-}
-
-impl<T: RngState> Default for InMemoryDenimEcPreKeyManager<T> {
-    fn default() -> Self {
-        Self {
-            key_generate_amount: 10,
+            pending_key_ttl,
+            used_key_ttl,
+            low_watermark,
+            high_watermark,
+            seed_grace_period,
+            key_generation: Arc::default(),
             unused_keys: InMemoryEcPreKeyManager::default(),
             id_seeds: Arc::default(),
             key_seeds: Arc::default(),
+            seeded_devices: Arc::default(),
+            epochs: Arc::default(),
+            retired_epochs: Arc::default(),
+            key_indices: Arc::default(),
             used_keys: UsedKeysMap::default(),
             pending_keys: Arc::default(),
         }
     }
 }
 
+impl<T: RngState> Default for InMemoryDenimEcPreKeyManager<T> {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
 #[async_trait]
 impl<T: RngState> DenimEcPreKeyManager<T> for InMemoryDenimEcPreKeyManager<T> {
     async fn get_ec_pre_key(
@@ -120,29 +190,34 @@ impl<T: RngState> DenimEcPreKeyManager<T> for InMemoryDenimEcPreKeyManager<T> {
         account_id: AccountId,
         device_id: DeviceId,
     ) -> Result<EcPreKey, DenimKeyManagerError> {
-        if let Some(pk) = self.unused_keys.get_pre_key(account_id, device_id).await? {
-            self.unused_keys
-                .remove_pre_key(account_id, device_id, pk.id())
-                .await?;
-            self.used_keys
-                .add_pre_key(account_id, device_id, pk.id())
-                .await;
-            Ok(pk)
-        } else {
-            generate_ec_pre_keys(self, account_id, device_id, self.key_generate_amount).await?;
-            let pk = self
-                .unused_keys
-                .get_pre_key(account_id, device_id)
-                .await?
-                .ok_or(DenimKeyManagerError::NoKeyInStore)?;
-            self.unused_keys
-                .remove_pre_key(account_id, device_id, pk.id())
-                .await?;
-            self.used_keys
-                .add_pre_key(account_id, device_id, pk.id())
-                .await;
-            Ok(pk)
-        }
+        let pk = match self.unused_keys.get_pre_key(account_id, device_id).await? {
+            Some(pk) => pk,
+            None => {
+                let key_generation = self.key_generation.clone();
+                let _generating = key_generation.lock().await;
+                // the pool might have been refilled while waiting
+                if self
+                    .unused_keys
+                    .get_pre_key(account_id, device_id)
+                    .await?
+                    .is_none()
+                {
+                    generate_ec_pre_keys(self, account_id, device_id, self.key_generate_amount)
+                        .await?;
+                }
+                self.unused_keys
+                    .get_pre_key(account_id, device_id)
+                    .await?
+                    .ok_or(DenimKeyManagerError::NoKeyInStore)?
+            }
+        };
+        self.unused_keys
+            .remove_pre_key(account_id, device_id, pk.id())
+            .await?;
+        self.used_keys
+            .add_pre_key(account_id, device_id, pk.id())
+            .await;
+        Ok(pk)
     }
 
     async fn get_ec_pre_key_ids(
@@ -163,13 +238,44 @@ impl<T: RngState> DenimEcPreKeyManager<T> for InMemoryDenimEcPreKeyManager<T> {
         account_id: AccountId,
         device_id: DeviceId,
         key: EcPreKey,
+        index: u64,
     ) -> Result<(), DenimKeyManagerError> {
+        let address = DeviceAddress::new(account_id, device_id);
+        // devices seeded without an epoch are in epoch 0
+        let epoch = self
+            .epochs
+            .lock()
+            .await
+            .get(&address)
+            .copied()
+            .unwrap_or_default();
+        self.key_indices
+            .lock()
+            .await
+            .entry(address)
+            .or_default()
+            .insert(key.id(), (epoch, index));
         self.unused_keys
             .add_pre_key(account_id, device_id, key)
             .await?;
         Ok(())
     }
 
+    async fn get_key_index(
+        &self,
+        account_id: AccountId,
+        device_id: DeviceId,
+        key_id: u32,
+    ) -> Result<(u32, u64), DenimKeyManagerError> {
+        self.key_indices
+            .lock()
+            .await
+            .get(&DeviceAddress::new(account_id, device_id))
+            .and_then(|indices| indices.get(&key_id))
+            .copied()
+            .ok_or(DenimKeyManagerError::NoKeyIndex)
+    }
+
     #[cfg(test)]
     async fn remove_ec_pre_key(
         &mut self,
@@ -192,24 +298,33 @@ impl<T: RngState> DenimEcPreKeyManager<T> for InMemoryDenimEcPreKeyManager<T> {
         self.used_keys
             .remove_pre_key(account_id, device_id, id)
             .await;
+        if let Some(indices) = self
+            .key_indices
+            .lock()
+            .await
+            .get_mut(&DeviceAddress::new(account_id, device_id))
+        {
+            indices.remove(&id);
+        }
         Ok(())
     }
 
-    async fn next_key_id<R: Rng + Send>(
+    async fn next_key_id(
         &mut self,
         account_id: AccountId,
         device_id: DeviceId,
-        rng: &mut R,
-    ) -> Result<u32, DenimKeyManagerError> {
-        for _ in 0..32 {
-            let key_id = rng.next_u32();
+        key_id_seed: T,
+        index: u64,
+    ) -> Result<(u64, u32), DenimKeyManagerError> {
+        for key_index in index..index.saturating_add(32) {
+            let key_id = key_id_at(key_id_seed.clone(), key_index);
             let reserved = self
                 .used_keys
                 .contains_key(account_id, device_id, key_id)
                 .await;
 
             if !reserved {
-                return Ok(key_id);
+                return Ok((key_index, key_id));
             }
         }
         Err(DenimKeyManagerError::CouldNotGenerateKeyId)
@@ -240,6 +355,11 @@ impl<T: RngState> DenimEcPreKeyManager<T> for InMemoryDenimEcPreKeyManager<T> {
             .lock()
             .await
             .insert(DeviceAddress::new(account_id, device_id), Some(seed));
+        let mut seeded_devices = self.seeded_devices.lock().await;
+        let devices = seeded_devices.entry(account_id).or_default();
+        if !devices.contains(&device_id) {
+            devices.push(device_id);
+        }
         Ok(())
     }
 
@@ -271,28 +391,151 @@ impl<T: RngState> DenimEcPreKeyManager<T> for InMemoryDenimEcPreKeyManager<T> {
         Ok(())
     }
 
+    async fn rotate_seeds(
+        &mut self,
+        account_id: AccountId,
+        device_id: DeviceId,
+        epoch: u32,
+        key_seed: T,
+        key_id_seed: T,
+    ) -> Result<(), DenimKeyManagerError> {
+        // keys must not be generated from the old seeds while rotating
+        let key_generation = self.key_generation.clone();
+        let _generating = key_generation.lock().await;
+
+        let address = DeviceAddress::new(account_id, device_id);
+        let current = self.epochs.lock().await.get(&address).copied();
+        match current {
+            Some(current) if epoch < current => {
+                return Err(DenimKeyManagerError::StaleSeedEpoch(current))
+            }
+            // the client resent an update that was already applied
+            Some(current) if epoch == current => return Ok(()),
+            Some(current) => {
+                let unused: HashSet<u32> = self
+                    .get_ec_pre_key_ids(account_id, device_id)
+                    .await?
+                    .into_iter()
+                    .collect();
+                for key_id in &unused {
+                    self.unused_keys
+                        .remove_pre_key(account_id, device_id, *key_id)
+                        .await?;
+                }
+                let mut retired_epochs = self.retired_epochs.lock().await;
+                let retired = retired_epochs.entry(address).or_default();
+                retired.push((current, Instant::now()));
+                // keys handed out in earlier epochs are kept until their grace period ends
+                if let Some(indices) = self.key_indices.lock().await.get_mut(&address) {
+                    indices.retain(|key_id, (key_epoch, _)| {
+                        retired.iter().any(|(retired, _)| retired == key_epoch)
+                            && !unused.contains(key_id)
+                    });
+                }
+            }
+            None => (),
+        }
+        debug!("Rotating seeds of {account_id}.{device_id} to epoch {epoch}");
+
+        self.epochs.lock().await.insert(address, epoch);
+        self.store_key_id_seed_for(account_id, device_id, key_id_seed)
+            .await?;
+        self.store_key_seed_for(account_id, device_id, key_seed)
+            .await
+    }
+
+    async fn reset_seeds(
+        &mut self,
+        account_id: AccountId,
+        device_id: DeviceId,
+        epoch: u32,
+        key_seed: T,
+        key_id_seed: T,
+    ) -> Result<Vec<(AccountId, DeviceId)>, DenimKeyManagerError> {
+        let key_generation = self.key_generation.clone();
+        let _generating = key_generation.lock().await;
+
+        let address = DeviceAddress::new(account_id, device_id);
+        match self.epochs.lock().await.get(&address).copied() {
+            Some(current) if epoch < current => {
+                return Err(DenimKeyManagerError::StaleSeedEpoch(current))
+            }
+            // a delayed or replayed resync must not discard the keys of its successor
+            Some(current) if epoch == current => return Ok(Vec::new()),
+            _ => (),
+        }
+        for key_id in self.get_ec_pre_key_ids(account_id, device_id).await? {
+            self.unused_keys
+                .remove_pre_key(account_id, device_id, key_id)
+                .await?;
+        }
+        self.key_indices.lock().await.remove(&address);
+        self.retired_epochs.lock().await.remove(&address);
+
+        let mut senders = Vec::new();
+        self.pending_keys.lock().await.retain(|id, pending| {
+            if id.address != address {
+                return true;
+            }
+            senders.push((pending.sender_account_id, pending.sender_device_id));
+            false
+        });
+        debug!("Resetting seeds of {account_id}.{device_id} to epoch {epoch}");
+
+        self.epochs.lock().await.insert(address, epoch);
+        self.store_key_id_seed_for(account_id, device_id, key_id_seed)
+            .await?;
+        self.store_key_seed_for(account_id, device_id, key_seed)
+            .await?;
+        Ok(senders)
+    }
+
+    async fn get_seeded_device_ids(
+        &self,
+        account_id: AccountId,
+    ) -> Result<Vec<DeviceId>, DenimKeyManagerError> {
+        Ok(self
+            .seeded_devices
+            .lock()
+            .await
+            .get(&account_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
     async fn store_pending_key(
         &mut self,
-        pre_key_msg_sender: AccountId,
+        sender_account_id: AccountId,
+        sender_device_id: DeviceId,
         account_id: AccountId,
         device_id: DeviceId,
         key_id: u32,
     ) -> Result<(), DenimKeyManagerError> {
         let mut pending_guard = self.pending_keys.lock().await;
         let id = PendingId::new(
-            pre_key_msg_sender,
+            DeviceAddress::new(sender_account_id, sender_device_id),
             DeviceAddress::new(account_id, device_id),
         );
         if pending_guard.contains_key(&id) {
             return Err(DenimKeyManagerError::AlreadyPending);
         }
-        pending_guard.insert(id, key_id);
+        pending_guard.insert(
+            id,
+            PendingKey {
+                sender_account_id,
+                sender_device_id,
+                account_id,
+                device_id,
+                key_id,
+                stored_at: Instant::now(),
+            },
+        );
         Ok(())
     }
 
     async fn has_pending_key(
         &self,
-        pre_key_msg_sender: AccountId,
+        pre_key_msg_sender: DeviceAddress,
         account_id: AccountId,
         device_id: DeviceId,
     ) -> bool {
@@ -305,7 +548,7 @@ impl<T: RngState> DenimEcPreKeyManager<T> for InMemoryDenimEcPreKeyManager<T> {
 
     async fn remove_pending_key(
         &mut self,
-        pre_key_msg_sender: AccountId,
+        pre_key_msg_sender: DeviceAddress,
         account_id: AccountId,
         device_id: DeviceId,
     ) -> Result<u32, DenimKeyManagerError> {
@@ -314,12 +557,112 @@ impl<T: RngState> DenimEcPreKeyManager<T> for InMemoryDenimEcPreKeyManager<T> {
             pre_key_msg_sender,
             DeviceAddress::new(account_id, device_id),
         );
-        if let Some(key_id) = pending_guard.remove(&id) {
-            Ok(key_id)
+        if let Some(pending) = pending_guard.remove(&id) {
+            Ok(pending.key_id)
         } else {
             Err(DenimKeyManagerError::NotPending)
         }
     }
+
+    async fn remove_expired_pending_keys(&mut self) -> Vec<(AccountId, DeviceId, u32)> {
+        let mut expired = Vec::new();
+        self.pending_keys.lock().await.retain(|_, pending| {
+            if pending.stored_at.elapsed() < self.pending_key_ttl {
+                return true;
+            }
+            expired.push((pending.account_id, pending.device_id, pending.key_id));
+            false
+        });
+        // the keys were already sent to a peer, so their ids stay reserved like used keys
+        for (account_id, device_id, key_id) in &expired {
+            self.used_keys
+                .add_pre_key(*account_id, *device_id, *key_id)
+                .await;
+        }
+        expired
+    }
+
+    async fn refill_ec_pre_keys(&mut self) -> Result<(), DenimKeyManagerError> {
+        let devices: Vec<(AccountId, DeviceId)> = self
+            .seeded_devices
+            .lock()
+            .await
+            .iter()
+            .flat_map(|(account_id, device_ids)| {
+                device_ids.iter().map(|device_id| (*account_id, *device_id))
+            })
+            .collect();
+
+        for (account_id, device_id) in devices {
+            let key_generation = self.key_generation.clone();
+            let _generating = key_generation.lock().await;
+
+            // a device that fails is logged, so the others are still refilled
+            let unused = match self.get_ec_pre_key_ids(account_id, device_id).await {
+                Ok(key_ids) => key_ids.len(),
+                Err(e) => {
+                    error!("Failed to count pre keys of {account_id}.{device_id} '{e}'");
+                    continue;
+                }
+            };
+            if unused >= self.low_watermark {
+                continue;
+            }
+            let amount = self.high_watermark.saturating_sub(unused);
+            debug!("Refilling {amount} EC Pre Keys for {account_id}.{device_id}");
+            if let Err(e) = generate_ec_pre_keys(self, account_id, device_id, amount).await {
+                error!("Failed to refill pre keys of {account_id}.{device_id} '{e}'");
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_expired_epochs(&mut self) -> usize {
+        let mut expired = Vec::new();
+        let mut retired_epochs = self.retired_epochs.lock().await;
+        for (address, retired) in retired_epochs.iter_mut() {
+            retired.retain(|(epoch, retired_at)| {
+                if retired_at.elapsed() < self.seed_grace_period {
+                    return true;
+                }
+                expired.push((*address, *epoch));
+                false
+            });
+        }
+        retired_epochs.retain(|_, retired| !retired.is_empty());
+
+        let mut removed = 0;
+        let mut key_indices = self.key_indices.lock().await;
+        for (address, epoch) in expired {
+            if let Some(indices) = key_indices.get_mut(&address) {
+                let before = indices.len();
+                indices.retain(|_, (key_epoch, _)| *key_epoch != epoch);
+                removed += before - indices.len();
+            }
+        }
+        key_indices.retain(|_, indices| !indices.is_empty());
+        removed
+    }
+
+    async fn compact_used_keys(&mut self) -> usize {
+        let pending = self
+            .pending_keys
+            .lock()
+            .await
+            .iter()
+            .map(|(id, pending)| (id.address, pending.key_id))
+            .collect();
+        let removed = self.used_keys.compact(self.used_key_ttl, &pending).await;
+
+        let mut key_indices = self.key_indices.lock().await;
+        for (address, key_id) in &removed {
+            if let Some(indices) = key_indices.get_mut(address) {
+                indices.remove(key_id);
+            }
+        }
+        key_indices.retain(|_, indices| !indices.is_empty());
+        removed.len()
+    }
 }
 
 #[derive(Clone)]
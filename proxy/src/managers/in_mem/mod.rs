@@ -5,5 +5,11 @@ mod request;
 
 pub use block_list::InMemoryBlockList;
 pub use id_provider::InMemoryMessageIdProvider;
-pub use keys::{InMemoryDenimEcPreKeyManager, InMemoryDenimKeyManager};
-pub use request::InMemoryKeyRequestManager;
+pub use keys::{
+    InMemoryDenimEcPreKeyManager, InMemoryDenimKeyManager, DEFAULT_KEY_COMPACTION_INTERVAL,
+    DEFAULT_PENDING_KEY_TTL, DEFAULT_PRE_KEY_HIGH_WATERMARK, DEFAULT_PRE_KEY_LOW_WATERMARK,
+    DEFAULT_SEED_GRACE_PERIOD, DEFAULT_USED_KEY_TTL,
+};
+pub use request::{
+    InMemoryKeyRequestManager, DEFAULT_KEY_REQUEST_TTL, DEFAULT_MAX_DEFERRED_KEY_REQUESTS,
+};
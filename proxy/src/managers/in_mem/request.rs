@@ -1,37 +1,148 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
-use sam_common::AccountId;
+use sam_common::{AccountId, DeviceId};
 use tokio::sync::Mutex;
 
-use crate::managers::traits::KeyRequestManager;
+use crate::managers::{error::KeyRequestManagerError, traits::KeyRequestManager};
 
-#[derive(Clone, Default)]
+pub const DEFAULT_KEY_REQUEST_TTL: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_DEFERRED_KEY_REQUESTS: usize = 100;
+
+#[derive(Clone)]
+struct DeferredRequest {
+    requester: AccountId,
+    requester_device: DeviceId,
+    // no ids means all devices
+    device_ids: Vec<u32>,
+    received_at: Instant,
+}
+
+impl DeferredRequest {
+    fn is_answered_by(&self, device_id: DeviceId) -> bool {
+        self.device_ids.is_empty() || self.device_ids.contains(&*device_id)
+    }
+}
+
+#[derive(Clone)]
 pub struct InMemoryKeyRequestManager {
-    requests: Arc<Mutex<HashMap<AccountId, Vec<AccountId>>>>,
+    ttl: Duration,
+    max_requesters: usize,
+    requests: Arc<Mutex<HashMap<AccountId, Vec<DeferredRequest>>>>,
+}
+
+impl InMemoryKeyRequestManager {
+    pub fn new(ttl: Duration, max_requesters: usize) -> Self {
+        Self {
+            ttl,
+            max_requesters,
+            requests: Arc::default(),
+        }
+    }
+}
+
+impl Default for InMemoryKeyRequestManager {
+    fn default() -> Self {
+        Self::new(DEFAULT_KEY_REQUEST_TTL, DEFAULT_MAX_DEFERRED_KEY_REQUESTS)
+    }
 }
 
 #[async_trait]
 impl KeyRequestManager for InMemoryKeyRequestManager {
-    async fn store_requester(&mut self, requested: AccountId, requester: AccountId) {
+    async fn store_requester(
+        &mut self,
+        requested: AccountId,
+        requested_device_ids: Vec<u32>,
+        requester: AccountId,
+        requester_device: DeviceId,
+    ) -> Result<(), KeyRequestManagerError> {
         let mut requests = self.requests.lock().await;
-        if let Some(vec) = requests.get_mut(&requested) {
-            vec.push(requester);
-        } else {
-            requests.insert(requested, vec![requester]);
+        let deferred = requests.entry(requested).or_default();
+
+        // retries keep the original request so it still times out, but add the devices they ask for
+        if let Some(request) = deferred
+            .iter_mut()
+            .find(|req| req.requester == requester && req.requester_device == requester_device)
+        {
+            if requested_device_ids.is_empty() {
+                request.device_ids.clear();
+            } else if !request.device_ids.is_empty() {
+                for device_id in requested_device_ids {
+                    if !request.device_ids.contains(&device_id) {
+                        request.device_ids.push(device_id);
+                    }
+                }
+            }
+            return Ok(());
+        }
+        if deferred.len() >= self.max_requesters {
+            return Err(KeyRequestManagerError::TooManyRequesters);
         }
+
+        deferred.push(DeferredRequest {
+            requester,
+            requester_device,
+            device_ids: requested_device_ids,
+            received_at: Instant::now(),
+        });
+        Ok(())
     }
 
-    async fn remove_requesters(&mut self, requested: AccountId) -> Option<Vec<AccountId>> {
-        self.requests.lock().await.remove(&requested)
+    async fn remove_requesters(
+        &mut self,
+        requested: AccountId,
+        device_id: DeviceId,
+    ) -> Option<Vec<(AccountId, DeviceId, Vec<u32>)>> {
+        let mut requests = self.requests.lock().await;
+        let deferred = requests.get_mut(&requested)?;
+        let (answered, waiting) = std::mem::take(deferred)
+            .into_iter()
+            .partition::<Vec<_>, _>(|req| req.is_answered_by(device_id));
+        *deferred = waiting;
+        if deferred.is_empty() {
+            requests.remove(&requested);
+        }
+        if answered.is_empty() {
+            return None;
+        }
+        Some(
+            answered
+                .into_iter()
+                .map(|req| (req.requester, req.requester_device, req.device_ids))
+                .collect(),
+        )
+    }
+
+    async fn remove_expired_requesters(&mut self) -> Vec<(AccountId, AccountId, DeviceId)> {
+        let mut expired = Vec::new();
+        let mut requests = self.requests.lock().await;
+        for (requested, deferred) in requests.iter_mut() {
+            deferred.retain(|req| {
+                if req.received_at.elapsed() < self.ttl {
+                    return true;
+                }
+                expired.push((*requested, req.requester, req.requester_device));
+                false
+            });
+        }
+        requests.retain(|_, deferred| !deferred.is_empty());
+        expired
     }
 }
 
 #[cfg(test)]
 mod test {
-    use sam_common::AccountId;
+    use std::time::Duration;
 
-    use crate::managers::{in_mem::InMemoryKeyRequestManager, traits::KeyRequestManager};
+    use sam_common::{address::DEFAULT_DEVICE_ID, AccountId, DeviceId};
+
+    use crate::managers::{
+        error::KeyRequestManagerError, in_mem::InMemoryKeyRequestManager, traits::KeyRequestManager,
+    };
 
     #[tokio::test]
     async fn can_get_stored_requesters() {
@@ -43,18 +154,21 @@ mod test {
         // accounts requests keys from other accounts that have not uploaded seed
         for requested in requested_accounts.clone() {
             for requester in requester_accounts.clone() {
-                request_manager.store_requester(requested, requester).await;
+                request_manager
+                    .store_requester(requested, vec![], requester, DEFAULT_DEVICE_ID.into())
+                    .await
+                    .expect("Can store requester");
             }
         }
 
         // requested accounts upload their seed
         for requested in requested_accounts {
             let receivers = request_manager
-                .remove_requesters(requested)
+                .remove_requesters(requested, DEFAULT_DEVICE_ID.into())
                 .await
                 .expect("Should contain receivers");
             for inserted_receiver in requester_accounts.clone() {
-                assert!(receivers.contains(&inserted_receiver))
+                assert!(receivers.contains(&(inserted_receiver, DEFAULT_DEVICE_ID.into(), vec![])))
             }
         }
     }
@@ -66,8 +180,125 @@ mod test {
         let requested_accounts = vec![AccountId::generate(), AccountId::generate()];
 
         for requested in requested_accounts {
-            let receivers = request_manager.remove_requesters(requested).await;
+            let receivers = request_manager
+                .remove_requesters(requested, DEFAULT_DEVICE_ID.into())
+                .await;
             assert_eq!(receivers, None)
         }
     }
+
+    #[tokio::test]
+    async fn stores_requester_once() {
+        let mut request_manager = InMemoryKeyRequestManager::default();
+        let requested = AccountId::generate();
+        let requester = AccountId::generate();
+
+        for _ in 0..3 {
+            request_manager
+                .store_requester(requested, vec![], requester, DEFAULT_DEVICE_ID.into())
+                .await
+                .expect("Can store requester");
+        }
+
+        let requesters = request_manager
+            .remove_requesters(requested, DEFAULT_DEVICE_ID.into())
+            .await
+            .expect("Should contain requester");
+        let device_id: DeviceId = DEFAULT_DEVICE_ID.into();
+        assert_eq!(requesters, vec![(requester, device_id, vec![])]);
+    }
+
+    #[tokio::test]
+    async fn caps_requesters_per_account() {
+        let mut request_manager = InMemoryKeyRequestManager::new(Duration::from_secs(60), 2);
+        let requested = AccountId::generate();
+
+        for _ in 0..2 {
+            request_manager
+                .store_requester(
+                    requested,
+                    vec![],
+                    AccountId::generate(),
+                    DEFAULT_DEVICE_ID.into(),
+                )
+                .await
+                .expect("Can store requester");
+        }
+
+        assert!(matches!(
+            request_manager
+                .store_requester(
+                    requested,
+                    vec![],
+                    AccountId::generate(),
+                    DEFAULT_DEVICE_ID.into()
+                )
+                .await,
+            Err(KeyRequestManagerError::TooManyRequesters)
+        ));
+    }
+
+    #[tokio::test]
+    async fn removes_expired_requesters() {
+        let mut request_manager = InMemoryKeyRequestManager::new(Duration::ZERO, 10);
+        let requested = AccountId::generate();
+        let requester = AccountId::generate();
+
+        request_manager
+            .store_requester(requested, vec![], requester, DEFAULT_DEVICE_ID.into())
+            .await
+            .expect("Can store requester");
+
+        let expired = request_manager.remove_expired_requesters().await;
+        let device_id: DeviceId = DEFAULT_DEVICE_ID.into();
+        assert_eq!(expired, vec![(requested, requester, device_id)]);
+        assert_eq!(
+            request_manager
+                .remove_requesters(requested, DEFAULT_DEVICE_ID.into())
+                .await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn removes_only_requests_the_device_can_answer() {
+        let mut request_manager = InMemoryKeyRequestManager::default();
+        let requested = AccountId::generate();
+        let all = AccountId::generate();
+        let second = AccountId::generate();
+        let retried = AccountId::generate();
+
+        for (requester, device_ids) in [
+            (all, vec![]),
+            (second, vec![2]),
+            (retried, vec![3]),
+            (retried, vec![2]),
+        ] {
+            request_manager
+                .store_requester(requested, device_ids, requester, DEFAULT_DEVICE_ID.into())
+                .await
+                .expect("Can store requester");
+        }
+
+        let device_id: DeviceId = DEFAULT_DEVICE_ID.into();
+        assert_eq!(
+            request_manager
+                .remove_requesters(requested, DEFAULT_DEVICE_ID.into())
+                .await,
+            Some(vec![(all, device_id, vec![])])
+        );
+        assert_eq!(
+            request_manager
+                .remove_requesters(requested, DEFAULT_DEVICE_ID.into())
+                .await,
+            None
+        );
+        assert_eq!(
+            request_manager.remove_requesters(requested, 2.into()).await,
+            Some(vec![
+                (second, device_id, vec![2]),
+                (retried, device_id, vec![3, 2])
+            ])
+        );
+    }
 }
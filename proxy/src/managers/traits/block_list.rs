@@ -4,9 +4,11 @@ use sam_common::AccountId;
 #[async_trait]
 pub trait BlockList: Send + Sync + Clone {
     async fn block_user(&mut self, users_account_id: AccountId, blocked_account_id: AccountId);
+    async fn unblock_user(&mut self, users_account_id: AccountId, blocked_account_id: AccountId);
     async fn is_user_blocked(
         &self,
         user_account_id: &AccountId,
         blocked_account_id: &AccountId,
     ) -> bool;
+    async fn get_blocked_users(&self, user_account_id: &AccountId) -> Vec<AccountId>;
 }
@@ -1,8 +1,7 @@
 use async_trait::async_trait;
 
 use denim_sam_common::rng::RngState;
-use rand::Rng;
-use sam_common::{api::EcPreKey, AccountId, DeviceId};
+use sam_common::{address::DeviceAddress, api::EcPreKey, AccountId, DeviceId};
 
 use crate::managers::error::DenimKeyManagerError;
 
@@ -20,13 +19,24 @@ pub trait DenimEcPreKeyManager<T: RngState>: Clone + Send + Sync {
         device_id: DeviceId,
     ) -> Result<Vec<u32>, DenimKeyManagerError>;
 
+    /// Adds a key generated at `index` of the key stream of the device.
     async fn add_ec_pre_key(
         &mut self,
         account_id: AccountId,
         device_id: DeviceId,
         key: EcPreKey,
+        index: u64,
     ) -> Result<(), DenimKeyManagerError>;
 
+    /// Seed epoch and index of the key stream a key was generated at,
+    /// which clients need to derive it.
+    async fn get_key_index(
+        &self,
+        account_id: AccountId,
+        device_id: DeviceId,
+        key_id: u32,
+    ) -> Result<(u32, u64), DenimKeyManagerError>;
+
     #[cfg(test)]
     async fn remove_ec_pre_key(
         &mut self,
@@ -42,12 +52,15 @@ pub trait DenimEcPreKeyManager<T: RngState>: Clone + Send + Sync {
         id: u32,
     ) -> Result<(), DenimKeyManagerError>;
 
-    async fn next_key_id<R: Rng + Send>(
+    /// Returns the first key index from `index` whose key id is not in use,
+    /// together with that key id.
+    async fn next_key_id(
         &mut self,
         account_id: AccountId,
         device_id: DeviceId,
-        rng: &mut R,
-    ) -> Result<u32, DenimKeyManagerError>;
+        key_id_seed: T,
+        index: u64,
+    ) -> Result<(u64, u32), DenimKeyManagerError>;
 
     async fn get_key_seed_for(
         &mut self,
@@ -75,23 +88,71 @@ pub trait DenimEcPreKeyManager<T: RngState>: Clone + Send + Sync {
         seed: T,
     ) -> Result<(), DenimKeyManagerError>;
 
+    /// Replaces the seeds of a device with the seeds of a newer `epoch`.
+    /// Unused keys of the old epoch are discarded, while keys already handed out
+    /// can still be derived until the grace period of the old epoch ends.
+    async fn rotate_seeds(
+        &mut self,
+        account_id: AccountId,
+        device_id: DeviceId,
+        epoch: u32,
+        key_seed: T,
+        key_id_seed: T,
+    ) -> Result<(), DenimKeyManagerError>;
+
+    /// Replaces the seeds of a device that lost its previous seeds with those of a newer `epoch`.
+    /// All keys derived from earlier seeds are discarded and their pending keys removed.
+    /// Returns the senders of the removed pending keys as `(account_id, device_id)`,
+    /// which is empty if `epoch` is already in use.
+    async fn reset_seeds(
+        &mut self,
+        account_id: AccountId,
+        device_id: DeviceId,
+        epoch: u32,
+        key_seed: T,
+        key_id_seed: T,
+    ) -> Result<Vec<(AccountId, DeviceId)>, DenimKeyManagerError>;
+
+    /// Devices of an account that have uploaded a key seed.
+    async fn get_seeded_device_ids(
+        &self,
+        account_id: AccountId,
+    ) -> Result<Vec<DeviceId>, DenimKeyManagerError>;
+
     async fn store_pending_key(
         &mut self,
-        pre_key_msg_sender: AccountId,
+        sender_account_id: AccountId,
+        sender_device_id: DeviceId,
         account_id: AccountId,
         device_id: DeviceId,
         key_id: u32,
     ) -> Result<(), DenimKeyManagerError>;
     async fn has_pending_key(
         &self,
-        pre_key_msg_sender: AccountId,
+        pre_key_msg_sender: DeviceAddress,
         account_id: AccountId,
         device_id: DeviceId,
     ) -> bool;
     async fn remove_pending_key(
         &mut self,
-        pre_key_msg_sender: AccountId,
+        pre_key_msg_sender: DeviceAddress,
         account_id: AccountId,
         device_id: DeviceId,
     ) -> Result<u32, DenimKeyManagerError>;
+
+    /// Removes pending keys that have outlived their ttl without a reply.
+    /// Their keys are retired like used keys and never handed out again.
+    /// Returns them as `(account_id, device_id, key_id)`.
+    async fn remove_expired_pending_keys(&mut self) -> Vec<(AccountId, DeviceId, u32)>;
+
+    /// Tops up the unused keys of every seeded device whose pool has run low.
+    async fn refill_ec_pre_keys(&mut self) -> Result<(), DenimKeyManagerError>;
+
+    /// Forgets the key indices of seed epochs whose grace period has ended.
+    /// Returns the number of forgotten indices.
+    async fn remove_expired_epochs(&mut self) -> usize;
+
+    /// Forgets used key ids that have outlived their ttl and are not pending.
+    /// Returns the number of forgotten ids.
+    async fn compact_used_keys(&mut self) -> usize;
 }
@@ -1,11 +1,31 @@
 use async_trait::async_trait;
-use sam_common::AccountId;
+use sam_common::{AccountId, DeviceId};
+
+use crate::managers::error::KeyRequestManagerError;
 
 #[async_trait]
 pub trait KeyRequestManager: Send + Sync + Clone {
     // requested is an account that have yet to upload seed
-    // requester is an account that want keys from an account that have not uploaded seed
+    // requester is a device that want keys from an account that have not uploaded seed
 
-    async fn store_requester(&mut self, requested: AccountId, requester: AccountId);
-    async fn remove_requesters(&mut self, requested: AccountId) -> Option<Vec<AccountId>>;
+    /// Stores a deferred key request for the `requested_device_ids` of `requested`,
+    /// where no ids means all devices. A requester is only stored once per requested account.
+    async fn store_requester(
+        &mut self,
+        requested: AccountId,
+        requested_device_ids: Vec<u32>,
+        requester: AccountId,
+        requester_device: DeviceId,
+    ) -> Result<(), KeyRequestManagerError>;
+    /// Removes the deferred requests for `requested` that `device_id` can answer.
+    /// Returns them as `(requester, requester_device, requested_device_ids)`,
+    /// `None` if there are none.
+    async fn remove_requesters(
+        &mut self,
+        requested: AccountId,
+        device_id: DeviceId,
+    ) -> Option<Vec<(AccountId, DeviceId, Vec<u32>)>>;
+    /// Removes deferred key requests that have outlived their ttl.
+    /// Returns them as `(requested, requester, requester_device)`.
+    async fn remove_expired_requesters(&mut self) -> Vec<(AccountId, AccountId, DeviceId)>;
 }
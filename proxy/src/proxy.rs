@@ -1,22 +1,21 @@
 use axum::http::HeaderMap;
 use denim_sam_common::{
-    buffers::DenimMessage,
-    denim_message::{denim_envelope::MessageKind, DenimEnvelope, QStatus},
+    buffers::{DenimMessage, PaddingScheme},
+    denim_message::{denim_envelope::MessageKind, DenimEnvelope, Padding, QStatus},
 };
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt, StreamExt,
-};
-use log::{error, info};
+use futures_util::{stream::SplitStream, Sink, SinkExt, StreamExt};
+use log::{debug, error, info};
 use prost::{bytes::Bytes, Message};
 use sam_common::{AccountId, DeviceId};
 use sam_net::websocket::{WebSocket, WebSocketClient, WebSocketReceiver};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::time::Instant;
 
 use crate::{
     config::websocket_config,
-    denim_routes::denim_router,
+    denim_routes::{denim_router, request_missing_seed},
     error::ServerError,
+    logic::payload::validate_payload_length,
     state::{DenimState, DenimStateType},
     utils::TungsteniteMessage,
     utils::{into_axum_message, AxumMessage, AxumWebSocket},
@@ -46,94 +45,115 @@ pub async fn connect_to_sam_server<T: DenimStateType>(
 }
 
 pub async fn init_proxy_service<T: DenimStateType>(
-    state: DenimState<T>,
+    mut state: DenimState<T>,
     socket: AxumWebSocket,
     server_client: WebSocketClient,
     server_receiver: Receiver<ProxyMessage>,
     account_id: AccountId,
-    _device_id: DeviceId,
+    device_id: DeviceId,
+    padding_offered: bool,
 ) {
     let (mut sender, receiver) = socket.split();
 
+    // both sides pad only if the client offered it and the proxy is configured to
+    let padding = state.padding().filter(|_| padding_offered).cloned();
+    if let Err(e) = state
+        .buffer_manager
+        .set_padding(account_id, device_id, padding.clone())
+        .await
+    {
+        error!("Failed to set padding for {account_id}.{device_id} '{e}'");
+        return;
+    }
+
     // clients need to know what the current q is
+    let q = state.buffer_manager.get_q().await;
     let q_status = DenimEnvelope::builder()
         .message_kind(MessageKind::Status(QStatus {
-            q: state.buffer_manager.get_q().await as f64,
+            q: q as f64,
+            padding: padding.as_ref().map(Padding::from),
         }))
         .build();
     if sender.send(q_status.encode_to_vec().into()).await.is_err() {
         return;
     };
 
+    if let Err(e) = request_missing_seed(&mut state, account_id, device_id).await {
+        error!("Failed to request seed from {account_id}.{device_id} '{e}'");
+    }
+
     tokio::spawn(sam_server_handler(
         state.clone(),
         server_receiver,
         sender,
         account_id,
+        device_id,
     ));
     tokio::spawn(denim_client_receiver(
         state,
         server_client,
         receiver,
         account_id,
+        device_id,
+        q,
+        padding,
     ));
 }
 
 /// Handles messages from SAM Server and send them to client
 /// This is here we should put piggy back denim messages to the client
+/// Decoys are sent in between on the schedule of the decoy policy
 async fn sam_server_handler<T: DenimStateType>(
     mut state: DenimState<T>,
     mut server_receiver: Receiver<ProxyMessage>,
-    mut client_sender: SplitSink<AxumWebSocket, AxumMessage>,
+    mut client_sender: impl Sink<AxumMessage> + Unpin,
     account_id: AccountId,
+    device_id: DeviceId,
 ) {
-    // SAM Server sends proxy a message
-    while let Some(msg) = server_receiver.recv().await {
-        let msg = match msg {
-            AxumMessage::Binary(msg) => msg,
-            AxumMessage::Close(_) => break,
-            _ => continue,
-        };
-        let len = match msg.len().try_into() {
-            Ok(len) => len,
-            Err(_) => {
-                error!("SAM Message too big for Denim!");
-                info!("Disconnecting...");
-                break;
+    let decoy_policy = state.decoy_policy().cloned();
+    let mut next_decoy = decoy_policy
+        .as_ref()
+        .map(|policy| Instant::now() + policy.gap(&mut rand::thread_rng()));
+
+    loop {
+        let decoy_due = async move {
+            match next_decoy {
+                Some(at) => tokio::time::sleep_until(at).await,
+                None => std::future::pending::<()>().await,
             }
         };
-        let payload = match state
-            .buffer_manager
-            .get_deniable_payload(account_id, len)
-            .await
-        {
-            Ok(payload) => payload,
-            Err(e) => {
-                error!("get_deniable_payload failed '{e}'");
-                info!("Disconnecting...");
-                break;
+        let envelope = tokio::select! {
+            // SAM Server sends proxy a message
+            msg = server_receiver.recv() => {
+                let msg = match msg {
+                    Some(AxumMessage::Binary(msg)) => msg,
+                    Some(AxumMessage::Close(_)) | None => break,
+                    Some(_) => continue,
+                };
+                match piggyback(&mut state, account_id, device_id, msg.to_vec()).await {
+                    Some(msg) => MessageKind::DenimMessage(msg),
+                    None => break,
+                }
             }
-        };
-
-        let msg = DenimMessage::builder()
-            .regular_payload(msg.to_vec())
-            .deniable_payload(payload)
-            .q(state.buffer_manager.get_q().await)
-            .build();
-
-        let encoded_msg = match msg.encode() {
-            Ok(encoded_msg) => encoded_msg,
-            Err(e) => {
-                error!("Convertion of Payload Failed '{e}'");
-                info!("Disconnecting...");
-                break;
+            () = decoy_due => {
+                let Some(policy) = decoy_policy.as_ref() else {
+                    continue;
+                };
+                // sent regardless of the backlog, without one the deniable payload is garbage,
+                // so neither timing nor size reveals whether deniable messages are waiting
+                let payload = {
+                    let mut rng = rand::thread_rng();
+                    next_decoy = Some(Instant::now() + policy.gap(&mut rng));
+                    policy.payload(&mut rng)
+                };
+                match piggyback(&mut state, account_id, device_id, payload).await {
+                    Some(msg) => MessageKind::Decoy(msg),
+                    None => break,
+                }
             }
         };
 
-        let envelope = DenimEnvelope::builder()
-            .message_kind(MessageKind::DenimMessage(encoded_msg))
-            .build();
-
+        let envelope = DenimEnvelope::builder().message_kind(envelope).build();
         if client_sender
             .send(AxumMessage::Binary(envelope.encode_to_vec().into()))
             .await
@@ -144,6 +164,51 @@ async fn sam_server_handler<T: DenimStateType>(
     }
 }
 
+/// Encodes a regular payload with as much deniable payload as it can carry.
+/// Returns `None` if the client has to be disconnected.
+async fn piggyback<T: DenimStateType>(
+    state: &mut DenimState<T>,
+    account_id: AccountId,
+    device_id: DeviceId,
+    regular_payload: Vec<u8>,
+) -> Option<Vec<u8>> {
+    let len = match regular_payload.len().try_into() {
+        Ok(len) => len,
+        Err(_) => {
+            error!("SAM Message too big for Denim!");
+            info!("Disconnecting...");
+            return None;
+        }
+    };
+    let payload = match state
+        .buffer_manager
+        .get_deniable_payload(account_id, device_id, len)
+        .await
+    {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("get_deniable_payload failed '{e}'");
+            info!("Disconnecting...");
+            return None;
+        }
+    };
+
+    let msg = DenimMessage::builder()
+        .regular_payload(regular_payload)
+        .deniable_payload(payload)
+        .q(state.buffer_manager.get_q().await)
+        .build();
+
+    match msg.encode() {
+        Ok(encoded_msg) => Some(encoded_msg),
+        Err(e) => {
+            error!("Convertion of Payload Failed '{e}'");
+            info!("Disconnecting...");
+            None
+        }
+    }
+}
+
 /// Handles messages from Denim Client and forward them to SAM Server
 /// This is here we should extract SAM Message and send it
 /// We should also build chunks to Denim Messages here
@@ -152,6 +217,9 @@ async fn denim_client_receiver<T: DenimStateType>(
     mut server_client: WebSocketClient,
     mut client_receiver: SplitStream<AxumWebSocket>,
     account_id: AccountId,
+    device_id: DeviceId,
+    q: f32,
+    padding: Option<PaddingScheme>,
 ) {
     // Client sends proxy a message
     while let Some(Ok(msg)) = client_receiver.next().await {
@@ -168,8 +236,10 @@ async fn denim_client_receiver<T: DenimStateType>(
             }
         };
 
-        let res = match envelope.message_kind {
-            Some(MessageKind::DenimMessage(msg)) => DenimMessage::decode(msg),
+        // clients answer decoys with a decoy, it only carries deniable payload
+        let (res, decoy) = match envelope.message_kind {
+            Some(MessageKind::DenimMessage(msg)) => (DenimMessage::decode(msg), false),
+            Some(MessageKind::Decoy(msg)) => (DenimMessage::decode(msg), true),
             Some(MessageKind::Status(_)) => {
                 error!("Malformed DenimEnvelope (Client sent QStatus)");
                 break;
@@ -188,7 +258,14 @@ async fn denim_client_receiver<T: DenimStateType>(
             }
         };
 
-        if let Err(e) = server_client
+        if !validate_payload_length(&state, &msg, q, padding.as_ref(), account_id, device_id) {
+            info!("Disconnecting...");
+            break;
+        }
+
+        if decoy {
+            debug!("Dropped the regular payload of a decoy reply");
+        } else if let Err(e) = server_client
             .send(TungsteniteMessage::Binary(Bytes::from(msg.regular_payload)))
             .await
         {
@@ -199,16 +276,18 @@ async fn denim_client_receiver<T: DenimStateType>(
 
         let chunks = msg.deniable_payload.denim_chunks().to_owned();
 
-        //TODO: this should not happen if a user is blocked.
+        // block lists are enforced per request by the denim router
         match state
             .buffer_manager
-            .enqueue_chunks(account_id, chunks)
+            .enqueue_chunks(account_id, device_id, chunks)
             .await
         {
             Ok(results) => {
                 for res in results {
                     let response = match res {
-                        Ok(request) => denim_router(&mut state.clone(), request, account_id).await,
+                        Ok(request) => {
+                            denim_router(&mut state.clone(), request, account_id, device_id).await
+                        }
 
                         Err(e) => {
                             error!("failed to process deniable message: '{e}'");
@@ -255,3 +334,58 @@ impl WebSocketReceiver for ProxyWebSocketReceiver {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use denim_sam_common::denim_message::{denim_envelope::MessageKind, DenimEnvelope};
+    use futures_util::sink;
+    use prost::Message;
+    use sam_common::{address::DEFAULT_DEVICE_ID, AccountId};
+    use tokio::sync::mpsc::channel;
+
+    use crate::{
+        decoy::DecoyPolicy,
+        state::{DenimState, InMemoryDenimStateType},
+        utils::AxumMessage,
+    };
+
+    use super::sam_server_handler;
+
+    #[tokio::test]
+    async fn decoys_are_sent_without_a_backlog() {
+        let mut state =
+            DenimState::<InMemoryDenimStateType>::in_memory_test("127.0.0.1:8080".to_string());
+        state.set_decoy_policy(DecoyPolicy {
+            mean_interval: Duration::from_millis(10),
+            payload_size: 10..=20,
+        });
+        let (_server, server_receiver) = channel(1);
+        let (sent, mut received) = channel(10);
+        let client_sender = Box::pin(sink::unfold(sent, |sent, msg: AxumMessage| async move {
+            let result = sent.send(msg).await;
+            result.map(|()| sent)
+        }));
+        tokio::spawn(sam_server_handler(
+            state,
+            server_receiver,
+            client_sender,
+            AccountId::generate(),
+            DEFAULT_DEVICE_ID.into(),
+        ));
+
+        // nothing was ever enqueued for the client
+        for _ in 0..3 {
+            let msg = tokio::time::timeout(Duration::from_secs(5), received.recv())
+                .await
+                .expect("Decoy is sent in time")
+                .expect("Handler is running");
+            let AxumMessage::Binary(msg) = msg else {
+                panic!("Decoys are binary messages");
+            };
+            let envelope = DenimEnvelope::decode(msg).expect("Can decode envelope");
+            assert!(matches!(envelope.message_kind, Some(MessageKind::Decoy(_))));
+        }
+    }
+}
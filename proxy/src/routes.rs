@@ -8,6 +8,7 @@ use axum_extra::{
     headers::{authorization::Basic, Authorization},
     TypedHeader,
 };
+use denim_sam_common::buffers::PADDING_HEADER;
 use log::info;
 
 use sam_server::auth::get_credentials;
@@ -26,9 +27,19 @@ pub async fn websocket_endpoint<T: DenimStateType>(
 ) -> Result<impl IntoResponse, ServerError> {
     let (account_id, device_id) =
         get_credentials(basic.username().to_string()).map_err(|_| ServerError::SAMUnAuth)?;
+    let padding_offered = headers.contains_key(PADDING_HEADER);
     let (client, queue) = connect_to_sam_server(headers, &state).await?;
     Ok(ws.on_upgrade(move |socket| async move {
         info!("A User Connected");
-        init_proxy_service(state, socket, client, queue, account_id, device_id).await
+        init_proxy_service(
+            state,
+            socket,
+            client,
+            queue,
+            account_id,
+            device_id,
+            padding_offered,
+        )
+        .await
     }))
 }
@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::extract::Request;
 use axum::middleware::{from_fn, Next};
@@ -9,7 +9,8 @@ use bon::bon;
 use denim_sam_common::buffers::in_mem::{
     InMemoryReceivingBufferConfig, InMemorySendingBufferConfig,
 };
-use log::info;
+use denim_sam_common::buffers::PaddingScheme;
+use log::{debug, error, info};
 use rustls::{ClientConfig, ServerConfig};
 use sam_server::managers::in_memory::account::InMemoryAccountManager;
 use sam_server::managers::in_memory::device::InMemoryDeviceManager;
@@ -23,8 +24,16 @@ use sqlx::Error;
 
 use crate::managers::in_mem::{
     InMemoryBlockList, InMemoryDenimEcPreKeyManager, InMemoryKeyRequestManager,
+    DEFAULT_KEY_COMPACTION_INTERVAL, DEFAULT_KEY_REQUEST_TTL, DEFAULT_MAX_DEFERRED_KEY_REQUESTS,
+    DEFAULT_PENDING_KEY_TTL, DEFAULT_PRE_KEY_HIGH_WATERMARK, DEFAULT_PRE_KEY_LOW_WATERMARK,
+    DEFAULT_SEED_GRACE_PERIOD, DEFAULT_USED_KEY_TTL,
 };
 
+use crate::decoy::DecoyPolicy;
+use crate::denim_routes::handle_expired_key_requests;
+use crate::logic::keys::expire_pending_keys;
+use crate::logic::payload::PayloadMismatchPolicy;
+use crate::managers::DenimEcPreKeyManager;
 use crate::managers::{BufferManager, DenimKeyManager, InMemoryMessageIdProvider};
 use crate::routes::websocket_endpoint;
 use crate::state::{
@@ -32,10 +41,14 @@ use crate::state::{
     PostgresDenimStateType,
 };
 
+const KEY_REQUEST_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+const PRE_KEY_REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct DenimConfig<T: DenimStateType> {
     pub state: DenimState<T>,
     pub addr: SocketAddr,
     pub tls_config: Option<rustls::ServerConfig>,
+    pub key_compaction_interval: Duration,
 }
 
 #[bon]
@@ -50,6 +63,17 @@ impl DenimConfig<PostgresDenimStateType> {
         #[builder(default = 10)] channel_buffer_size: usize,
         #[builder(default = 10)] key_generate_amount: usize,
         #[builder(default = 1.0)] deniable_ratio: f32,
+        #[builder(default = DEFAULT_KEY_REQUEST_TTL)] key_request_ttl: Duration,
+        #[builder(default = DEFAULT_MAX_DEFERRED_KEY_REQUESTS)] max_deferred_key_requests: usize,
+        #[builder(default = DEFAULT_PENDING_KEY_TTL)] pending_key_ttl: Duration,
+        #[builder(default = DEFAULT_USED_KEY_TTL)] used_key_ttl: Duration,
+        #[builder(default = DEFAULT_KEY_COMPACTION_INTERVAL)] key_compaction_interval: Duration,
+        #[builder(default = DEFAULT_PRE_KEY_LOW_WATERMARK)] pre_key_low_watermark: usize,
+        #[builder(default = DEFAULT_PRE_KEY_HIGH_WATERMARK)] pre_key_high_watermark: usize,
+        #[builder(default = DEFAULT_SEED_GRACE_PERIOD)] seed_grace_period: Duration,
+        decoy_policy: Option<DecoyPolicy>,
+        padding: Option<PaddingScheme>,
+        #[builder(default)] payload_mismatch_policy: PayloadMismatchPolicy,
     ) -> Result<Self, Error> {
         let conn = PostgresConnector::connect(&db_url).await?;
         let rcfg = InMemoryReceivingBufferConfig;
@@ -60,20 +84,34 @@ impl DenimConfig<PostgresDenimStateType> {
         Ok(Self {
             addr,
             tls_config,
+            key_compaction_interval,
             state: DenimState::<PostgresDenimStateType>::builder()
                 .sam_addr(sam_address)
                 .channel_buffer_size(channel_buffer_size)
                 .maybe_ws_proxy_tls_config(ws_proxy_tls_config)
                 .buffer_manager(buffer_mgr)
                 .keys(DenimKeyManager::new(
-                    InMemoryDenimEcPreKeyManager::new(key_generate_amount),
+                    InMemoryDenimEcPreKeyManager::builder()
+                        .key_generate_amount(key_generate_amount)
+                        .pending_key_ttl(pending_key_ttl)
+                        .used_key_ttl(used_key_ttl)
+                        .low_watermark(pre_key_low_watermark)
+                        .high_watermark(pre_key_high_watermark)
+                        .seed_grace_period(seed_grace_period)
+                        .build(),
                     PostgresSignedPreKeyManager::new(conn.pool()),
                 ))
                 .accounts(PostgresAccountManager::new(conn.pool()))
                 .devices(PostgresDeviceManager::new(conn.pool()))
-                .key_request_manager(InMemoryKeyRequestManager::default())
+                .key_request_manager(InMemoryKeyRequestManager::new(
+                    key_request_ttl,
+                    max_deferred_key_requests,
+                ))
                 .message_id_provider(InMemoryMessageIdProvider::default())
                 .block_list(InMemoryBlockList::default())
+                .maybe_decoy_policy(decoy_policy)
+                .maybe_padding(padding)
+                .payload_mismatch_policy(payload_mismatch_policy)
                 .build(),
         })
     }
@@ -90,6 +128,17 @@ impl DenimConfig<InMemoryDenimStateType> {
         #[builder(default = 10)] channel_buffer_size: usize,
         #[builder(default = 10)] key_generate_amount: usize,
         #[builder(default = 1.0)] deniable_ratio: f32,
+        #[builder(default = DEFAULT_KEY_REQUEST_TTL)] key_request_ttl: Duration,
+        #[builder(default = DEFAULT_MAX_DEFERRED_KEY_REQUESTS)] max_deferred_key_requests: usize,
+        #[builder(default = DEFAULT_PENDING_KEY_TTL)] pending_key_ttl: Duration,
+        #[builder(default = DEFAULT_USED_KEY_TTL)] used_key_ttl: Duration,
+        #[builder(default = DEFAULT_KEY_COMPACTION_INTERVAL)] key_compaction_interval: Duration,
+        #[builder(default = DEFAULT_PRE_KEY_LOW_WATERMARK)] pre_key_low_watermark: usize,
+        #[builder(default = DEFAULT_PRE_KEY_HIGH_WATERMARK)] pre_key_high_watermark: usize,
+        #[builder(default = DEFAULT_SEED_GRACE_PERIOD)] seed_grace_period: Duration,
+        decoy_policy: Option<DecoyPolicy>,
+        padding: Option<PaddingScheme>,
+        #[builder(default)] payload_mismatch_policy: PayloadMismatchPolicy,
     ) -> Self {
         let rcfg = InMemoryReceivingBufferConfig;
         let scfg = InMemorySendingBufferConfig::default();
@@ -100,20 +149,34 @@ impl DenimConfig<InMemoryDenimStateType> {
         Self {
             addr,
             tls_config,
+            key_compaction_interval,
             state: DenimState::<InMemoryDenimStateType>::builder()
                 .sam_addr(sam_address)
                 .channel_buffer_size(channel_buffer_size)
                 .maybe_ws_proxy_tls_config(ws_proxy_tls_config)
                 .buffer_manager(buffer_mgr)
                 .keys(DenimKeyManager::new(
-                    InMemoryDenimEcPreKeyManager::new(key_generate_amount),
+                    InMemoryDenimEcPreKeyManager::builder()
+                        .key_generate_amount(key_generate_amount)
+                        .pending_key_ttl(pending_key_ttl)
+                        .used_key_ttl(used_key_ttl)
+                        .low_watermark(pre_key_low_watermark)
+                        .high_watermark(pre_key_high_watermark)
+                        .seed_grace_period(seed_grace_period)
+                        .build(),
                     InMemorySignedPreKeyManager::default(),
                 ))
                 .accounts(InMemoryAccountManager::default()) // TODO: When adding postgres manager, connect for device manager should not take these
                 .devices(InMemoryDeviceManager::new("Test".to_owned(), 120)) // params as they are already set by SAM.
-                .key_request_manager(InMemoryKeyRequestManager::default())
+                .key_request_manager(InMemoryKeyRequestManager::new(
+                    key_request_ttl,
+                    max_deferred_key_requests,
+                ))
                 .message_id_provider(InMemoryMessageIdProvider::default())
                 .block_list(InMemoryBlockList::default())
+                .maybe_decoy_policy(decoy_policy)
+                .maybe_padding(padding)
+                .payload_mismatch_policy(payload_mismatch_policy)
                 .build(),
         }
     }
@@ -127,7 +190,49 @@ async fn log_request(req: Request, next: Next) -> impl IntoResponse {
     next.run(req).await
 }
 
+/// Periodically answers deferred key requests that have timed out.
+async fn expire_key_requests<T: DenimStateType>(mut state: DenimState<T>) {
+    let mut interval = tokio::time::interval(KEY_REQUEST_EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        handle_expired_key_requests(&mut state).await;
+    }
+}
+
+/// Periodically retires expired pending keys, forgets retired seed epochs
+/// and compacts used keys.
+async fn compact_pre_keys<T: DenimStateType>(mut state: DenimState<T>, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        expire_pending_keys(&mut state).await;
+        let retired = state.keys.pre_keys.remove_expired_epochs().await;
+        debug!("Forgot {retired} key indices of retired seed epochs");
+        let compacted = state.keys.pre_keys.compact_used_keys().await;
+        debug!("Compacted {compacted} used pre keys");
+    }
+}
+
+/// Keeps the pre key pools of seeded devices filled, so keys are rarely generated
+/// while answering a key request.
+async fn refill_pre_keys<T: DenimStateType>(mut state: DenimState<T>) {
+    let mut interval = tokio::time::interval(PRE_KEY_REFILL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = state.keys.pre_keys.refill_ec_pre_keys().await {
+            error!("Failed to refill pre keys '{e}'");
+        }
+    }
+}
+
 pub async fn start_proxy<T: DenimStateType>(config: DenimConfig<T>) -> Result<(), std::io::Error> {
+    tokio::spawn(expire_key_requests(config.state.clone()));
+    tokio::spawn(refill_pre_keys(config.state.clone()));
+    tokio::spawn(compact_pre_keys(
+        config.state.clone(),
+        config.key_compaction_interval,
+    ));
+
     let app = Router::new()
         .route("/health", get(|| async { "OK" }))
         .route("/api/v1/websocket", get(websocket_endpoint))
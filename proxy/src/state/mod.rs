@@ -1,12 +1,17 @@
+use crate::decoy::DecoyPolicy;
+use crate::logic::payload::PayloadMismatchPolicy;
 use crate::managers::traits::{BlockList, KeyRequestManager, MessageIdProvider};
 use crate::managers::{BufferManager, DenimKeyManager, DenimKeyManagerType};
 use bon::bon;
-use denim_sam_common::buffers::{ReceivingBufferConfig, SendingBufferConfig};
+use denim_sam_common::buffers::{PaddingScheme, ReceivingBufferConfig, SendingBufferConfig};
 
 use sam_server::managers::traits::{
     account_manager::AccountManager, device_manager::DeviceManager,
 };
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 
 mod in_mem;
 mod postgres;
@@ -43,6 +48,10 @@ pub struct DenimState<T: DenimStateType> {
     sam_addr: String,
     channel_buffer_size: usize,
     ws_proxy_tls_config: Option<Arc<rustls::ClientConfig>>,
+    decoy_policy: Option<DecoyPolicy>,
+    padding: Option<PaddingScheme>,
+    payload_mismatch_policy: PayloadMismatchPolicy,
+    payload_mismatches: Arc<AtomicU64>,
 }
 
 #[bon]
@@ -59,6 +68,9 @@ impl<T: DenimStateType> DenimState<T> {
         key_request_manager: T::KeyRequestManager,
         message_id_provider: T::MessageIdProvider,
         block_list: T::BlockList,
+        decoy_policy: Option<DecoyPolicy>,
+        padding: Option<PaddingScheme>,
+        #[builder(default)] payload_mismatch_policy: PayloadMismatchPolicy,
     ) -> Self {
         Self {
             key_request_manager,
@@ -71,6 +83,10 @@ impl<T: DenimStateType> DenimState<T> {
             accounts,
             message_id_provider,
             block_list,
+            decoy_policy,
+            padding,
+            payload_mismatch_policy,
+            payload_mismatches: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -86,6 +102,39 @@ impl<T: DenimStateType> DenimState<T> {
         self.ws_proxy_tls_config.clone()
     }
 
+    /// Scheme to pad messages with for clients that offer padding, `None` to never pad.
+    pub fn padding(&self) -> Option<&PaddingScheme> {
+        self.padding.as_ref()
+    }
+
+    pub fn payload_mismatch_policy(&self) -> PayloadMismatchPolicy {
+        self.payload_mismatch_policy
+    }
+
+    #[cfg(test)]
+    pub fn set_payload_mismatch_policy(&mut self, policy: PayloadMismatchPolicy) {
+        self.payload_mismatch_policy = policy;
+    }
+
+    /// Deniable payloads with a wrong length counted since the proxy started.
+    pub fn payload_mismatches(&self) -> u64 {
+        self.payload_mismatches.load(Ordering::Relaxed)
+    }
+
+    pub fn count_payload_mismatch(&self) {
+        self.payload_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Decoys are only sent if a policy is set.
+    pub fn decoy_policy(&self) -> Option<&DecoyPolicy> {
+        self.decoy_policy.as_ref()
+    }
+
+    #[cfg(test)]
+    pub fn set_decoy_policy(&mut self, policy: DecoyPolicy) {
+        self.decoy_policy = Some(policy);
+    }
+
     #[cfg(test)]
     pub fn in_memory_test(sam_addr: String) -> DenimState<InMemoryDenimStateType> {
         use denim_sam_common::buffers::in_mem::{